@@ -0,0 +1,104 @@
+/// Lists and dictionaries nested deeper than this are refused rather than
+/// recursed into.
+const MAX_BENCODE_DEPTH: usize = 64;
+
+/// Returns the length of the bencoded value at the start of `bytes`.
+pub(crate) fn bencode_len(bytes: &[u8]) -> Option<usize> {
+    bencode_len_within(bytes, MAX_BENCODE_DEPTH)
+}
+
+fn bencode_len_within(bytes: &[u8], depth: usize) -> Option<usize> {
+    match bytes.first()? {
+        b'i' => Some(bytes.iter().position(|&b| b == b'e')? + 1),
+        b'l' | b'd' => {
+            let depth = depth.checked_sub(1)?;
+            let mut offset = 1;
+            while *bytes.get(offset)? != b'e' {
+                offset += bencode_len_within(&bytes[offset..], depth)?;
+            }
+            Some(offset + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = bytes.iter().position(|&b| b == b':')?;
+            let len: usize = std::str::from_utf8(&bytes[..colon]).ok()?.parse().ok()?;
+            let end = (colon + 1).checked_add(len)?;
+            (end <= bytes.len()).then_some(end)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the bencoded value of `key` in the dictionary `bytes`, as the
+/// bytes it was encoded as.
+pub(crate) fn dict_value<'a>(bytes: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    if bytes.first() != Some(&b'd') {
+        return None;
+    }
+
+    let mut offset = 1;
+    while *bytes.get(offset)? != b'e' {
+        let key_len = bencode_len(&bytes[offset..])?;
+        let colon = bytes[offset..].iter().position(|&b| b == b':')?;
+        let this_key = bytes.get(offset + colon + 1..offset + key_len)?;
+        offset += key_len;
+
+        let value_len = bencode_len(&bytes[offset..])?;
+        if this_key == key {
+            return Some(&bytes[offset..offset + value_len]);
+        }
+        offset += value_len;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bencode_len, dict_value, MAX_BENCODE_DEPTH};
+
+    #[test]
+    fn test_bencode_len() {
+        assert_eq!(bencode_len(b"i42etrailing"), Some(4));
+        assert_eq!(bencode_len(b"4:spam"), Some(6));
+        assert_eq!(bencode_len(b"d3:cow3:moo4:spaml1:a1:bee..."), Some(26));
+
+        // Malformed or truncated input.
+        assert_eq!(bencode_len(b""), None);
+        assert_eq!(bencode_len(b"x"), None);
+        assert_eq!(bencode_len(b"i42"), None);
+        assert_eq!(bencode_len(b"5:spam"), None);
+        assert_eq!(bencode_len(b"4spam"), None);
+        assert_eq!(bencode_len(b"l4:spam"), None);
+        assert_eq!(bencode_len(b"d3:cow"), None);
+    }
+
+    #[test]
+    fn test_dict_value() {
+        let dict = b"d3:cow3:moo4:infod1:ai1ee4:spaml1:a1:bee";
+        assert_eq!(dict_value(dict, b"info"), Some(b"d1:ai1ee".as_slice()));
+        assert_eq!(dict_value(dict, b"spam"), Some(b"l1:a1:be".as_slice()));
+        assert_eq!(dict_value(dict, b"a"), None);
+        assert_eq!(dict_value(b"l4:infoe", b"info"), None);
+        assert_eq!(dict_value(b"d4:info", b"info"), None);
+    }
+
+    #[test]
+    fn test_bencode_len_huge_string() {
+        let huge = format!("{}:spam", usize::MAX);
+        assert_eq!(bencode_len(huge.as_bytes()), None);
+        let huge = format!("{}:spam", usize::MAX - 1);
+        assert_eq!(bencode_len(huge.as_bytes()), None);
+        assert_eq!(bencode_len(b"99999999999999999999999999:spam"), None);
+    }
+
+    #[test]
+    fn test_bencode_len_deeply_nested() {
+        let nested = |depth| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert_eq!(
+            bencode_len(&nested(MAX_BENCODE_DEPTH)),
+            Some(2 * MAX_BENCODE_DEPTH)
+        );
+        assert_eq!(bencode_len(&nested(MAX_BENCODE_DEPTH + 1)), None);
+        assert_eq!(bencode_len(&vec![b'l'; 1 << 20]), None);
+    }
+}
@@ -1,4 +1,4 @@
-use tokio::io::{AsyncRead, AsyncReadExt};
+use bytes::{Buf, Bytes};
 
 pub(crate) const BLOCK_SIZE: u32 = 1 << 14;
 
@@ -10,9 +10,10 @@ pub struct Request {
 }
 
 impl Request {
-    pub fn new(piece_index: u32, remaining_piece: u32, plength: u32) -> Self {
-        let begin = plength - remaining_piece;
-        let block_size = std::cmp::min(BLOCK_SIZE, remaining_piece);
+    /// Requests the `block`-th block of a piece that is `plength` bytes long.
+    pub fn new(piece_index: u32, block: u32, plength: u32) -> Self {
+        let begin = block * BLOCK_SIZE;
+        let block_size = std::cmp::min(BLOCK_SIZE, plength - begin);
 
         Self {
             piece_index,
@@ -21,6 +22,18 @@ impl Request {
         }
     }
 
+    pub fn decode(payload: &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(payload.len() == 12, "request has length {}", payload.len());
+
+        let field =
+            |i: usize| u32::from_be_bytes(payload[i * 4..][..4].try_into().expect("4 bytes"));
+        Ok(Self {
+            piece_index: field(0),
+            begin: field(1),
+            length: field(2),
+        })
+    }
+
     pub fn encode(&self) -> Vec<u8> {
         let mut payload = Vec::new();
 
@@ -32,29 +45,24 @@ impl Request {
     }
 }
 
+/// A block a peer sent, sharing the buffer of the `piece` message it came in.
 #[derive(Debug, Clone)]
 pub struct Response {
     index: u32,
     begin: u32,
-    block: Vec<u8>,
+    block: Bytes,
 }
 
 impl Response {
-    pub async fn new<R>(buf: &mut R, payload_length: usize) -> anyhow::Result<Self>
-    where
-        R: AsyncRead + Unpin,
-    {
-        let index = buf.read_u32().await?;
-        let begin = buf.read_u32().await?;
-
-        let block_len = payload_length - 4 - 4;
-        let mut block = vec![0; block_len];
-        buf.read_exact(&mut block).await?;
+    pub fn decode(mut payload: Bytes) -> anyhow::Result<Self> {
+        anyhow::ensure!(payload.len() >= 8, "piece has length {}", payload.len());
 
+        let index = payload.get_u32();
+        let begin = payload.get_u32();
         Ok(Self {
             index,
             begin,
-            block,
+            block: payload,
         })
     }
 
@@ -66,7 +74,29 @@ impl Response {
         self.begin
     }
 
-    pub fn block(&self) -> &[u8] {
+    pub fn block(&self) -> &Bytes {
         &self.block
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{Response, BLOCK_SIZE};
+
+    #[test]
+    fn test_response_shares_the_message_buffer() {
+        let mut payload = [3_u32.to_be_bytes(), BLOCK_SIZE.to_be_bytes()].concat();
+        payload.extend([9; 100]);
+        let payload = Bytes::from(payload);
+
+        let response = Response::decode(payload.clone()).unwrap();
+        assert_eq!((response.index(), response.begin()), (3, BLOCK_SIZE));
+        assert_eq!(response.block()[..], [9; 100]);
+        // The block is a view into the payload, not a copy of it.
+        assert_eq!(response.block().as_ptr(), payload[8..].as_ptr());
+
+        assert!(Response::decode(payload.slice(..7)).is_err());
+    }
+}
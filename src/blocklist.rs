@@ -0,0 +1,198 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context};
+use tracing::warn;
+
+/// Addresses not to connect to or accept peers from, read from a blocklist
+/// with one range per line, in the PeerGuardian `.p2p` or eMule `ipfilter.dat`
+/// format or as CIDR:
+///
+/// ```text
+/// # Comments and blank lines are skipped.
+/// Some organization:1.2.4.0-1.2.4.255
+/// 001.002.005.000 - 001.002.005.255 , 000 , Other organization
+/// 10.0.0.0/8
+/// 2001:db8::/32
+/// 192.0.2.1
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Blocklist {
+    /// Sorted, disjoint, inclusive ranges, with IPv4 addresses mapped into
+    /// IPv6 so that one list holds both.
+    ranges: Vec<(u128, u128)>,
+}
+
+impl Blocklist {
+    pub async fn read(path: &Path) -> anyhow::Result<Self> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("read {}", path.display()))?;
+
+        Ok(Self::parse(&text))
+    }
+
+    /// Parses the lines of a blocklist; those that can't be parsed are
+    /// skipped with a warning, as published lists often have a few.
+    pub fn parse(text: &str) -> Self {
+        let mut ranges = Vec::new();
+
+        for (line_i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // An `ipfilter.dat` range is followed by its level and name.
+            let range = parse_range(line).or_else(|e| match line.split_once(',') {
+                Some((range, _)) => parse_range(range.trim()),
+                None => Err(e),
+            });
+            match range {
+                Ok(range) => ranges.push(range),
+                Err(e) => warn!(line = line_i + 1, error = %e, "Skipping blocklist line"),
+            }
+        }
+
+        ranges.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        Self { ranges: merged }
+    }
+
+    /// How many ranges are blocked, after merging overlapping ones.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = to_u128(ip);
+        let after = self.ranges.partition_point(|&(start, _)| start <= ip);
+        after > 0 && ip <= self.ranges[after - 1].1
+    }
+}
+
+/// Parses `[name:]start-end`, `ip/prefix` or a single address.
+fn parse_range(line: &str) -> anyhow::Result<(u128, u128)> {
+    if let Some((ip, prefix)) = line.split_once('/') {
+        let ip = parse_ip(ip)?;
+        let prefix: u32 = prefix
+            .trim()
+            .parse()
+            .with_context(|| format!("prefix {prefix}"))?;
+        // IPv4 prefixes count from the start of the mapped address.
+        let (bits, prefix) = match ip {
+            IpAddr::V4(_) => (32, prefix.saturating_add(96)),
+            IpAddr::V6(_) => (128, prefix),
+        };
+        anyhow::ensure!(prefix <= 128, "prefix is longer than {bits} bits");
+        let host = u128::MAX.checked_shr(prefix).unwrap_or(0);
+        let start = to_u128(ip) & !host;
+        return Ok((start, start | host));
+    }
+
+    if let Some((start, end)) = line.rsplit_once('-') {
+        // The name in front of a `.p2p` range may contain colons and dashes
+        // of its own; the last colon is the one before the IPv4 address.
+        let start = match start.rsplit_once(':') {
+            Some((_, ip)) if ip.contains('.') => ip,
+            _ => start,
+        };
+        let (start, end) = (parse_ip(start)?, parse_ip(end)?);
+        anyhow::ensure!(
+            start.is_ipv4() == end.is_ipv4(),
+            "range {start}-{end} mixes IPv4 and IPv6"
+        );
+        let (start, end) = (to_u128(start), to_u128(end));
+        anyhow::ensure!(start <= end, "range ends before it starts");
+        return Ok((start, end));
+    }
+
+    let ip = parse_ip(line).map_err(|_| anyhow!("expected a range, CIDR block or address"))?;
+    Ok((to_u128(ip), to_u128(ip)))
+}
+
+/// Parses an address, allowing the zero-padded IPv4 octets of `ipfilter.dat`
+/// such as `001.002.004.000`, which the standard parser rejects.
+fn parse_ip(ip: &str) -> anyhow::Result<IpAddr> {
+    let ip = ip.trim();
+    if let Ok(ip) = ip.parse() {
+        return Ok(ip);
+    }
+
+    let octets: Vec<u8> = ip
+        .split('.')
+        .map(|octet| octet.parse())
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("address {ip}"))?;
+    let octets: [u8; 4] = octets.try_into().map_err(|_| anyhow!("address {ip}"))?;
+    Ok(Ipv4Addr::from(octets).into())
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    let v6: Ipv6Addr = match ip.to_canonical() {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    };
+    u128::from(v6)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::Blocklist;
+
+    #[test]
+    fn test_blocklist() {
+        let list = Blocklist::parse(
+            "# Blocked\nBad Corp: the-1st:1.2.4.0-1.2.4.255\n10.0.0.0/8\n10.1.0.0/16\n\n2001:db8::/32\n192.0.2.1\n",
+        );
+        assert_eq!(list.len(), 4);
+
+        let blocked = |ip: &str| list.contains(ip.parse::<IpAddr>().unwrap());
+        assert!(blocked("1.2.4.0"));
+        assert!(blocked("1.2.4.255"));
+        assert!(!blocked("1.2.5.0"));
+        assert!(blocked("10.200.3.4"));
+        assert!(!blocked("11.0.0.0"));
+        assert!(blocked("::ffff:10.0.0.1"));
+        assert!(blocked("2001:db8:1::1"));
+        assert!(!blocked("2001:db9::1"));
+        assert!(blocked("192.0.2.1"));
+        assert!(!blocked("192.0.2.2"));
+
+        assert!(Blocklist::parse("1.2.3.4-1.2.3.0").is_empty());
+        assert!(Blocklist::parse("10.0.0.0/33").is_empty());
+        assert!(Blocklist::parse("not an address").is_empty());
+    }
+
+    #[test]
+    fn test_ipfilter_dat() {
+        let list = Blocklist::parse(
+            "001.002.004.000 - 001.002.004.255 , 000 , Some Org, Inc.\n\
+             010.000.000.000 - 010.000.000.009 , 100 , Other-Org\n\
+             001.002.003 - 001.002.003.255 , 000 , Broken\n",
+        );
+        assert_eq!(list.len(), 2);
+
+        let blocked = |ip: &str| list.contains(ip.parse::<IpAddr>().unwrap());
+        assert!(blocked("1.2.4.0"));
+        assert!(blocked("1.2.4.255"));
+        assert!(!blocked("1.2.3.7"));
+        assert!(blocked("10.0.0.9"));
+        assert!(!blocked("10.0.0.10"));
+    }
+}
@@ -0,0 +1,89 @@
+use std::{cmp::Reverse, collections::HashSet, time::Duration};
+
+use rand::seq::SliceRandom;
+
+/// Peers we upload to at once, besides the optimistic unchoke.
+pub const UPLOAD_SLOTS: usize = 3;
+
+/// How often the peers we upload to are chosen again.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Rechokes between two picks of the optimistic unchoke, 30 seconds.
+const OPTIMISTIC_ROUNDS: usize = 3;
+
+/// What the choker knows of a connected peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerRate {
+    pub peer: usize,
+    pub interested: bool,
+    /// How fast the peer has been sending to us, in bytes per second; when
+    /// seeding, how fast we have been sending to it.
+    pub rate: usize,
+}
+
+/// Tit-for-tat: upload to the interested peers that upload the most to us,
+/// plus one more picked at random so that newcomers get a chance to
+/// reciprocate. A seeder gets nothing back, so it uploads to the peers that
+/// take its data the fastest instead.
+#[derive(Debug, Default)]
+pub struct Choker {
+    round: usize,
+    optimistic: Option<usize>,
+}
+
+impl Choker {
+    /// The peers to unchoke until the next rechoke; every other peer gets
+    /// choked.
+    pub fn rechoke(&mut self, peers: &[PeerRate]) -> HashSet<usize> {
+        let mut interested: Vec<&PeerRate> = peers.iter().filter(|p| p.interested).collect();
+        interested.sort_by_key(|p| Reverse(p.rate));
+
+        let mut unchoked: HashSet<usize> = interested
+            .iter()
+            .take(UPLOAD_SLOTS)
+            .map(|p| p.peer)
+            .collect();
+        let rest: Vec<usize> = interested
+            .iter()
+            .map(|p| p.peer)
+            .filter(|peer| !unchoked.contains(peer))
+            .collect();
+
+        let keep = !self.round.is_multiple_of(OPTIMISTIC_ROUNDS)
+            && self.optimistic.is_some_and(|peer| rest.contains(&peer));
+        if !keep {
+            self.optimistic = rest.choose(&mut rand::thread_rng()).copied();
+        }
+        self.round += 1;
+
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Choker, PeerRate, UPLOAD_SLOTS};
+
+    #[test]
+    fn test_unchoke_fastest_interested() {
+        let peers: Vec<PeerRate> = (0..6)
+            .map(|peer| PeerRate {
+                peer,
+                interested: peer != 5,
+                rate: peer * 100,
+            })
+            .collect();
+
+        let mut choker = Choker::default();
+        let unchoked = choker.rechoke(&peers);
+
+        assert_eq!(unchoked.len(), UPLOAD_SLOTS + 1);
+        assert!([2, 3, 4].iter().all(|peer| unchoked.contains(peer)));
+        assert!(!unchoked.contains(&5));
+
+        // The optimistic unchoke stays for a while.
+        let optimistic = *unchoked.iter().find(|&&peer| peer < 2).unwrap();
+        assert!(choker.rechoke(&peers).contains(&optimistic));
+    }
+}
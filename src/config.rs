@@ -0,0 +1,119 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+
+use crate::rate::Schedule;
+
+/// Settings from a config file of `key = value` lines, where `#` starts a
+/// comment:
+///
+/// ```text
+/// # 1 MiB/s during the day, unlimited otherwise.
+/// max_down = 1MB/s 08:00-23:00
+/// max_up = 100KB/s
+/// # Times are in UTC unless shifted.
+/// utc_offset = +02:00
+/// ```
+///
+/// `max_down` and `max_up` may be given several times, see
+/// [`Schedule::add`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    pub max_down: Schedule,
+    pub max_up: Schedule,
+    /// Minutes to add to UTC to get the time of day schedules are in.
+    pub utc_offset: i32,
+}
+
+impl Config {
+    pub async fn read(path: &Path) -> anyhow::Result<Self> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("read {}", path.display()))?;
+
+        Self::parse(&text).with_context(|| format!("parse {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+
+        for (line_i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("line {}: expected KEY = VALUE", line_i + 1))?;
+            let value = value.trim();
+            match key.trim() {
+                "max_down" => config.max_down.add(value),
+                "max_up" => config.max_up.add(value),
+                "utc_offset" => parse_offset(value).map(|offset| config.utc_offset = offset),
+                key => Err(anyhow!("unknown setting {key}")),
+            }
+            .with_context(|| format!("line {}", line_i + 1))?;
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parses `+HH:MM` or `-HH:MM`, at most a day either way, into minutes.
+fn parse_offset(s: &str) -> anyhow::Result<i32> {
+    let (sign, rest) = match s.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let (hours, minutes) = rest
+        .split_once(':')
+        .ok_or_else(|| anyhow!("offset {s} is not +HH:MM"))?;
+    let hours: u32 = hours.parse().with_context(|| format!("offset {s}"))?;
+    let minutes: u32 = minutes.parse().with_context(|| format!("offset {s}"))?;
+    anyhow::ensure!(
+        minutes < 60 && (hours, minutes) <= (24, 0),
+        "offset {s} is out of range"
+    );
+
+    Ok(sign * (hours * 60 + minutes) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_offset, Config};
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            "# Day time\nmax_down = 1MB/s 08:00-23:00\n\nmax_up = 100KB/s # always\nutc_offset = -05:30\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.max_down.rate_at(12 * 60), Some(1 << 20));
+        assert_eq!(config.max_down.rate_at(0), None);
+        assert_eq!(config.max_up.rate_at(0), Some(100 << 10));
+        assert_eq!(config.utc_offset, -330);
+
+        assert!(Config::parse("max_speed = 1").is_err());
+    }
+
+    #[test]
+    fn test_offset_out_of_range() {
+        assert_eq!(parse_offset("+24:00").unwrap(), 24 * 60);
+        assert_eq!(parse_offset("-24:00").unwrap(), -24 * 60);
+        assert_eq!(parse_offset("05:45").unwrap(), 345);
+
+        for offset in [
+            "+24:01",
+            "-25:00",
+            "99999999:00",
+            "+01:60",
+            "-00:99",
+            "+-1:00",
+        ] {
+            assert!(parse_offset(offset).is_err(), "{offset}");
+        }
+        assert!(Config::parse("utc_offset = +01:75").is_err());
+    }
+}
@@ -0,0 +1,332 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    hash, merkle,
+    torrent::{File, FileTree, Hashes, Info, Keys, Torrent, V2File},
+};
+
+/// The smallest and largest piece length picked for a torrent.
+const MIN_PIECE_LENGTH: usize = 1 << 14;
+const MAX_PIECE_LENGTH: usize = 1 << 24;
+
+/// About how many pieces a torrent gets when the piece length is picked for
+/// it; more make the metainfo bigger, fewer make pieces slow to verify.
+const TARGET_PIECES: usize = 1500;
+
+/// Which metadata a created torrent has.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Version {
+    /// SHA-1 piece hashes only, which every client understands.
+    #[default]
+    V1,
+    /// Per-file merkle trees only (BEP 52).
+    V2,
+    /// Both, with the files padded to piece boundaries so that v1 and v2
+    /// pieces line up.
+    Hybrid,
+}
+
+impl Version {
+    fn has_v1(self) -> bool {
+        self != Self::V2
+    }
+
+    fn has_v2(self) -> bool {
+        self != Self::V1
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "v1" => Ok(Self::V1),
+            "v2" => Ok(Self::V2),
+            "hybrid" => Ok(Self::Hybrid),
+            s => Err(anyhow!("unknown version {s}, expected v1, v2 or hybrid")),
+        }
+    }
+}
+
+/// Builds a torrent, without trackers, of the file or directory at `path`,
+/// hashing its contents in pieces of `piece_length` bytes, or a length that
+/// suits the size of the data when `None`.
+///
+/// A directory becomes a multi-file torrent of every file below it, in the
+/// order of their paths. This reads all the data, so it blocks for a while.
+pub fn torrent(
+    path: &Path,
+    piece_length: Option<usize>,
+    version: Version,
+) -> anyhow::Result<Torrent> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("{} has no usable name", path.display()))?
+        .to_string();
+
+    let metadata = fs::metadata(path).with_context(|| format!("read {}", path.display()))?;
+    let single_file = !metadata.is_dir();
+    let files = if single_file {
+        vec![(
+            path.to_path_buf(),
+            vec![name.clone()],
+            metadata.len() as usize,
+        )]
+    } else {
+        let mut files = Vec::new();
+        walk(path, &mut Vec::new(), &mut files)?;
+        anyhow::ensure!(!files.is_empty(), "{} has no files", path.display());
+        files
+    };
+
+    let length: usize = files.iter().map(|(_, _, length)| length).sum();
+    let plength = match piece_length {
+        Some(plength) => {
+            anyhow::ensure!(
+                plength >= MIN_PIECE_LENGTH && plength.is_power_of_two(),
+                "piece length {plength} is not a power of two of at least {MIN_PIECE_LENGTH}"
+            );
+            plength
+        }
+        None => (length / TARGET_PIECES)
+            .next_power_of_two()
+            .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH),
+    };
+
+    let mut v1 = PieceHasher::new(plength);
+    let mut v1_files = Vec::new();
+    let mut file_tree = BTreeMap::new();
+    let mut piece_layers = BTreeMap::new();
+    let mut block = vec![0; merkle::BLOCK_SIZE];
+
+    for (file_i, (disk_path, path, length)) in files.iter().enumerate() {
+        let mut file =
+            fs::File::open(disk_path).with_context(|| format!("open {}", disk_path.display()))?;
+        let mut leaves = Vec::new();
+        loop {
+            let read = read_full(&mut file, &mut block)
+                .with_context(|| format!("read {}", disk_path.display()))?;
+            if read == 0 {
+                break;
+            }
+            if version.has_v1() {
+                v1.update(&block[..read]);
+            }
+            if version.has_v2() {
+                leaves.push(hash::sha256(&[&block[..read]]));
+            }
+        }
+
+        v1_files.push(File {
+            length: *length,
+            path: path.clone(),
+            attr: None,
+        });
+        // Hybrid torrents start every file on a piece boundary, as v2 does.
+        let padding = (plength - length % plength) % plength;
+        if version == Version::Hybrid && file_i + 1 < files.len() && padding > 0 {
+            v1.update(&vec![0; padding]);
+            v1_files.push(File::padding(padding));
+        }
+
+        if version.has_v2() {
+            let pieces_root = (*length > 0).then(|| {
+                let (root, layer) = merkle::piece_layer(leaves, plength);
+                if *length > plength {
+                    piece_layers
+                        .insert(ByteBuf::from(root.to_vec()), ByteBuf::from(layer.concat()));
+                }
+                ByteBuf::from(root.to_vec())
+            });
+            let file = V2File {
+                length: *length,
+                pieces_root,
+            };
+            insert(&mut file_tree, path, file);
+        }
+    }
+
+    let keys = match version {
+        Version::V2 => Keys::V2Only {},
+        _ if single_file => Keys::SingleFile { length },
+        _ => Keys::MultiFile { files: v1_files },
+    };
+
+    Ok(Torrent {
+        announce: None,
+        announce_list: Vec::new(),
+        nodes: Vec::new(),
+        url_list: Vec::new(),
+        info: Info {
+            name,
+            plength,
+            pieces: Hashes(v1.finish()),
+            keys,
+            meta_version: version.has_v2().then_some(2),
+            file_tree: version.has_v2().then_some(file_tree),
+            private: None,
+        },
+        piece_layers,
+        info_bytes: None,
+        length: Default::default(),
+    })
+}
+
+/// Collects the files below `dir` as `(path on disk, path in the torrent,
+/// length)`, sorted by path.
+fn walk(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(PathBuf, Vec<String>, usize)>,
+) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("read {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow!("{name:?} is not valid UTF-8"))?;
+        // Following symlinks, like the data would be read.
+        let metadata = fs::metadata(&path).with_context(|| format!("read {}", path.display()))?;
+
+        prefix.push(name);
+        if metadata.is_dir() {
+            walk(&path, prefix, files)?;
+        } else {
+            files.push((path, prefix.clone(), metadata.len() as usize));
+        }
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+/// Reads until `buf` is full or the file ends, and returns how much was read.
+fn read_full(file: &mut fs::File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// SHA-1 hashes of the v1 pieces of a stream of data.
+struct PieceHasher {
+    plength: usize,
+    piece: Vec<u8>,
+    pieces: Vec<[u8; 20]>,
+}
+
+impl PieceHasher {
+    fn new(plength: usize) -> Self {
+        Self {
+            plength,
+            piece: Vec::with_capacity(plength),
+            pieces: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = data.len().min(self.plength - self.piece.len());
+            self.piece.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            if self.piece.len() == self.plength {
+                self.pieces.push(hash::sha1(&[&self.piece]));
+                self.piece.clear();
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<[u8; 20]> {
+        if !self.piece.is_empty() {
+            self.pieces.push(hash::sha1(&[&self.piece]));
+        }
+        self.pieces
+    }
+}
+
+/// Adds `file` to the v2 file tree at `path`.
+fn insert(tree: &mut BTreeMap<String, FileTree>, path: &[String], file: V2File) {
+    let (name, rest) = path.split_first().expect("paths are not empty");
+    if rest.is_empty() {
+        tree.insert(name.clone(), FileTree::File { file });
+        return;
+    }
+
+    let entry = tree
+        .entry(name.clone())
+        .or_insert_with(|| FileTree::Directory(BTreeMap::new()));
+    if let FileTree::Directory(tree) = entry {
+        insert(tree, rest, file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Version;
+    use crate::{
+        storage::{Layout, PieceStatus},
+        torrent::{FileTree, Keys},
+    };
+
+    #[tokio::test]
+    async fn test_create_multi_file() {
+        let dir = std::env::temp_dir().join(format!("create-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("b.bin"), vec![1; 20_000]).unwrap();
+        std::fs::write(dir.join("sub/a.bin"), vec![2; 30_000]).unwrap();
+
+        let t = super::torrent(&dir, Some(1 << 14), Version::V1).unwrap();
+        let Keys::MultiFile { files } = &t.info.keys else {
+            panic!("a directory makes a multi-file torrent");
+        };
+        assert_eq!(files[0].path, ["b.bin"]);
+        assert_eq!(files[1].path, ["sub", "a.bin"]);
+        assert_eq!(t.info.pieces.0.len(), 4);
+
+        let layout = Layout::new(&t, &dir);
+        for piece_i in 0..t.info.pieces.0.len() {
+            assert_eq!(layout.check_piece(&t, piece_i).await.0, PieceStatus::Valid);
+        }
+
+        // Hybrid torrents pad the first file to the end of its second piece.
+        let t = super::torrent(&dir, Some(1 << 14), Version::Hybrid).unwrap();
+        let Keys::MultiFile { files } = &t.info.keys else {
+            panic!("hybrid torrents have v1 files");
+        };
+        assert_eq!(files[1].length, 2 * (1 << 14) - 20_000);
+        assert_eq!(files[1].attr.as_deref(), Some("p"));
+        let tree = t.info.file_tree.as_ref().unwrap();
+        assert!(matches!(tree["b.bin"], FileTree::File { .. }));
+        assert!(matches!(tree["sub"], FileTree::Directory(_)));
+        assert_eq!(t.piece_layers.len(), 2);
+
+        // Both hashes of every piece match, the padding read as zeros.
+        let layout = Layout::new(&t, &dir);
+        for piece_i in 0..t.info.piece_count() {
+            assert_eq!(layout.check_piece(&t, piece_i).await.0, PieceStatus::Valid);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
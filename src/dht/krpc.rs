@@ -0,0 +1,161 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+/// A KRPC message, the bencoded dictionary exchanged between DHT nodes (BEP 5).
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Message {
+    #[serde(rename = "t")]
+    pub transaction_id: ByteBuf,
+
+    /// `q` for queries, `r` for responses and `e` for errors.
+    #[serde(rename = "y")]
+    pub kind: String,
+
+    /// The method name of a query.
+    #[serde(rename = "q", default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+
+    #[serde(rename = "a", default, skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<Arguments>,
+
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<Response>,
+
+    #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<(i64, String)>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Arguments {
+    pub id: ByteBuf,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Response {
+    pub id: ByteBuf,
+
+    /// Compact node info: 20-byte id, 4-byte IP and 2-byte port per node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+
+    /// Compact peer info: 4-byte IP and 2-byte port per peer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+impl Message {
+    pub fn query(transaction_id: &[u8], method: &str, arguments: Arguments) -> Self {
+        Self {
+            transaction_id: ByteBuf::from(transaction_id),
+            kind: "q".to_string(),
+            query: Some(method.to_string()),
+            arguments: Some(arguments),
+            ..Default::default()
+        }
+    }
+
+    pub fn response(transaction_id: &[u8], response: Response) -> Self {
+        Self {
+            transaction_id: ByteBuf::from(transaction_id),
+            kind: "r".to_string(),
+            response: Some(response),
+            ..Default::default()
+        }
+    }
+
+    pub fn error(transaction_id: &[u8], code: i64, message: &str) -> Self {
+        Self {
+            transaction_id: ByteBuf::from(transaction_id),
+            kind: "e".to_string(),
+            error: Some((code, message.to_string())),
+            ..Default::default()
+        }
+    }
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<([u8; 20], SocketAddrV4)> {
+    bytes
+        .chunks_exact(26)
+        .map(|node| {
+            let id = node[..20].try_into().expect("guaranteed to be length 20");
+            (id, decode_peer(&node[20..]))
+        })
+        .collect()
+}
+
+pub fn encode_nodes(nodes: &[([u8; 20], SocketAddrV4)]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(26 * nodes.len());
+    for (id, addr) in nodes {
+        bytes.extend_from_slice(id);
+        bytes.extend(encode_peer(addr));
+    }
+    bytes
+}
+
+pub fn decode_peer(bytes: &[u8]) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    )
+}
+
+pub fn encode_peer(addr: &SocketAddrV4) -> Vec<u8> {
+    let mut bytes = addr.ip().octets().to_vec();
+    bytes.extend(addr.port().to_be_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use super::{Arguments, Message};
+
+    #[test]
+    fn test_encode_ping() {
+        let ping = Message::query(
+            b"aa",
+            "ping",
+            Arguments {
+                id: ByteBuf::from(b"abcdefghij0123456789".to_vec()),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            serde_bencode::to_bytes(&ping).unwrap(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+    }
+
+    #[test]
+    fn test_decode_get_peers_response() {
+        let bytes = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+        let msg: Message = serde_bencode::from_bytes(bytes).unwrap();
+
+        let response = msg.response.unwrap();
+        assert_eq!(msg.kind, "r");
+        assert_eq!(response.token.unwrap().as_slice(), b"aoeusnth");
+        assert_eq!(response.values.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,437 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde_bytes::ByteBuf;
+use tokio::{net::UdpSocket, time::Instant};
+use tracing::{debug, warn};
+
+use self::{
+    krpc::{Arguments, Message, Response},
+    routing::{distance, Node, RoutingTable, K},
+    storage::PeerStore,
+};
+use crate::hash;
+
+pub mod krpc;
+pub mod routing;
+pub mod storage;
+
+/// Well-known routers used to join the DHT when the routing table is empty.
+const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Number of queries in flight at once during a lookup.
+const ALPHA: usize = 3;
+
+/// How long we wait for the responses of one round of queries.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Upper bound on lookup rounds, so a lookup always terminates.
+const MAX_ROUNDS: usize = 16;
+
+/// How often the secret behind our write tokens changes. Tokens made with the
+/// previous secret are still accepted, so they are good for 5 to 10 minutes.
+const SECRET_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// A mainline DHT node (BEP 5).
+pub struct Dht {
+    socket: Arc<UdpSocket>,
+    table: RoutingTable,
+    secret: [u8; 20],
+    previous_secret: [u8; 20],
+    secret_rotated: Instant,
+
+    /// Peers that announced themselves to us.
+    storage: PeerStore,
+
+    /// Write tokens handed out by the nodes of the last `get_peers` lookup.
+    tokens: HashMap<[u8; 20], Vec<(SocketAddrV4, ByteBuf)>>,
+    next_transaction: u16,
+}
+
+/// What an iterative lookup found on its way towards the target.
+#[derive(Default)]
+struct Lookup {
+    peers: Vec<SocketAddrV4>,
+    tokens: Vec<(SocketAddrV4, ByteBuf)>,
+}
+
+impl Dht {
+    pub async fn bind(port: u16) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))
+            .await
+            .context("bind dht socket")?;
+
+        Ok(Self {
+            socket: Arc::new(socket),
+            table: RoutingTable::new(rand::random()),
+            secret: rand::random(),
+            previous_secret: rand::random(),
+            secret_rotated: Instant::now(),
+            storage: PeerStore::default(),
+            tokens: HashMap::new(),
+            next_transaction: 0,
+        })
+    }
+
+    /// Joins the network through the bootstrap routers and `nodes` (e.g. from a
+    /// torrent's `nodes` key), and fills the routing table with the nodes
+    /// closest to our own id.
+    pub async fn bootstrap(&mut self, nodes: &[(String, u16)]) -> anyhow::Result<()> {
+        let hosts = BOOTSTRAP_NODES
+            .iter()
+            .map(|host| host.to_string())
+            .chain(nodes.iter().map(|(host, port)| format!("{host}:{port}")));
+
+        let mut routers = Vec::new();
+        for host in hosts {
+            match tokio::net::lookup_host(&host).await {
+                Ok(addrs) => routers.extend(addrs.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(e) => warn!(host, error = %e, "Could not resolve DHT router"),
+            }
+        }
+
+        let target = *self.table.id();
+        self.lookup(&target, "find_node", routers).await?;
+        anyhow::ensure!(!self.table.is_empty(), "no DHT node answered");
+
+        Ok(())
+    }
+
+    /// Looks up peers for `info_hash` and remembers the write tokens needed to
+    /// [`announce_peer`](Self::announce_peer) afterwards.
+    pub async fn get_peers(&mut self, info_hash: &[u8; 20]) -> anyhow::Result<Vec<SocketAddrV4>> {
+        let lookup = self.lookup(info_hash, "get_peers", Vec::new()).await?;
+        self.tokens.insert(*info_hash, lookup.tokens);
+
+        let mut peers = lookup.peers;
+        peers.sort();
+        peers.dedup();
+
+        Ok(peers)
+    }
+
+    /// Tells the nodes of the last `get_peers` lookup that we are accepting
+    /// connections for `info_hash` on `port`.
+    pub async fn announce_peer(&mut self, info_hash: &[u8; 20], port: u16) -> anyhow::Result<()> {
+        let tokens = self.tokens.remove(info_hash).unwrap_or_default();
+
+        for (addr, token) in tokens {
+            let arguments = Arguments {
+                id: ByteBuf::from(self.table.id().to_vec()),
+                info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                port: Some(port),
+                token: Some(token),
+                ..Default::default()
+            };
+            let transaction_id = self.transaction_id();
+            self.send(
+                &Message::query(&transaction_id, "announce_peer", arguments),
+                addr,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// The node's socket, to wait on for queries between lookups.
+    pub fn socket(&self) -> Arc<UdpSocket> {
+        Arc::clone(&self.socket)
+    }
+
+    /// Answers the queries that arrived while no lookup was reading the
+    /// socket, and returns once there are none waiting; anything else, such
+    /// as responses that came too late for their lookup, is dropped.
+    pub async fn answer_queries(&mut self) {
+        let mut buf = vec![0; 1500];
+        while let Ok((len, from)) = self.socket.try_recv_from(&mut buf) {
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            let msg: Message = match serde_bencode::from_bytes(&buf[..len]) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!(%from, error = %e, "Ignoring malformed krpc message");
+                    continue;
+                }
+            };
+
+            if msg.kind == "q" {
+                if let Err(e) = self.handle_query(msg, from).await {
+                    debug!(%from, error = %e, "Could not answer DHT query");
+                }
+            }
+        }
+    }
+
+    /// Iteratively queries the nodes closest to `target` with `method` until
+    /// no closer nodes turn up.
+    async fn lookup(
+        &mut self,
+        target: &[u8; 20],
+        method: &str,
+        seeds: Vec<SocketAddrV4>,
+    ) -> anyhow::Result<Lookup> {
+        let mut lookup = Lookup::default();
+        let mut queried = HashSet::new();
+
+        // Seeds have no known id yet; they are queried in the first round.
+        let mut unknown = seeds;
+        let mut candidates = self.table.closest(target, K);
+
+        for _ in 0..MAX_ROUNDS {
+            candidates.sort_by_key(|node| distance(&node.id, target));
+            candidates.dedup_by_key(|node| node.id);
+            candidates.truncate(K);
+
+            let mut round = std::mem::take(&mut unknown);
+            round.extend(
+                candidates
+                    .iter()
+                    .filter(|node| !queried.contains(&node.addr))
+                    .take(ALPHA)
+                    .map(|node| node.addr),
+            );
+            if round.is_empty() {
+                break;
+            }
+
+            let mut pending = HashMap::new();
+            for addr in round {
+                queried.insert(addr);
+
+                let transaction_id = self.transaction_id();
+                let mut arguments = Arguments {
+                    id: ByteBuf::from(self.table.id().to_vec()),
+                    ..Default::default()
+                };
+                if method == "get_peers" {
+                    arguments.info_hash = Some(ByteBuf::from(target.to_vec()));
+                } else {
+                    arguments.target = Some(ByteBuf::from(target.to_vec()));
+                }
+
+                let query = Message::query(&transaction_id, method, arguments);
+                if let Err(e) = self.send(&query, addr).await {
+                    debug!(%addr, error = %e, "Could not query DHT node");
+                    continue;
+                }
+                pending.insert(transaction_id, addr);
+            }
+
+            let deadline = Instant::now() + QUERY_TIMEOUT;
+            while !pending.is_empty() {
+                let Some((msg, from)) = self.recv(deadline).await else {
+                    break;
+                };
+
+                if msg.kind == "q" {
+                    if let Err(e) = self.handle_query(msg, from).await {
+                        debug!(%from, error = %e, "Could not answer DHT query");
+                    }
+                    continue;
+                }
+
+                if pending.remove(msg.transaction_id.as_slice()) != Some(from) {
+                    continue;
+                }
+                let Some(response) = msg.response else {
+                    continue;
+                };
+                let Ok(id) = <[u8; 20]>::try_from(response.id.as_slice()) else {
+                    continue;
+                };
+                self.table.insert(Node::new(id, from));
+
+                if let Some(nodes) = response.nodes {
+                    candidates.extend(
+                        krpc::decode_nodes(&nodes)
+                            .into_iter()
+                            .map(|(id, addr)| Node::new(id, addr)),
+                    );
+                }
+                if let Some(values) = response.values {
+                    lookup.peers.extend(
+                        values
+                            .iter()
+                            .filter(|value| value.len() == 6)
+                            .map(|value| krpc::decode_peer(value)),
+                    );
+                }
+                if let Some(token) = response.token {
+                    lookup.tokens.push((from, token));
+                }
+            }
+
+            // Nodes that never answered are dropped from the table.
+            for addr in pending.into_values() {
+                if let Some(node) = candidates.iter().find(|node| node.addr == addr) {
+                    self.table.remove(&node.id);
+                }
+                candidates.retain(|node| node.addr != addr);
+            }
+        }
+
+        Ok(lookup)
+    }
+
+    /// Answers a query from another node, so that we are a well-behaved member
+    /// of the DHT.
+    async fn handle_query(&mut self, msg: Message, from: SocketAddrV4) -> anyhow::Result<()> {
+        self.rotate_secret();
+
+        let Some(arguments) = msg.arguments else {
+            return self
+                .send(
+                    &Message::error(&msg.transaction_id, 203, "Protocol Error"),
+                    from,
+                )
+                .await;
+        };
+        if let Ok(id) = <[u8; 20]>::try_from(arguments.id.as_slice()) {
+            self.table.insert(Node::new(id, from));
+        }
+
+        let mut response = Response {
+            id: ByteBuf::from(self.table.id().to_vec()),
+            ..Default::default()
+        };
+
+        match msg.query.as_deref() {
+            Some("ping") => {}
+            Some("find_node") => {
+                let target = arguments
+                    .target
+                    .and_then(|target| <[u8; 20]>::try_from(target.as_slice()).ok())
+                    .unwrap_or_default();
+                response.nodes = Some(self.compact_closest(&target));
+            }
+            Some("get_peers") => {
+                let info_hash = arguments
+                    .info_hash
+                    .and_then(|hash| <[u8; 20]>::try_from(hash.as_slice()).ok())
+                    .unwrap_or_default();
+                response.token = Some(ByteBuf::from(self.token(&from, &self.secret)));
+
+                let peers = self.storage.peers(&info_hash, Instant::now());
+                if peers.is_empty() {
+                    response.nodes = Some(self.compact_closest(&info_hash));
+                } else {
+                    response.values = Some(
+                        peers
+                            .iter()
+                            .map(|peer| ByteBuf::from(krpc::encode_peer(peer)))
+                            .collect(),
+                    );
+                }
+            }
+            Some("announce_peer") => {
+                let valid_token = arguments.token.is_some_and(|token| {
+                    token.as_slice() == self.token(&from, &self.secret)
+                        || token.as_slice() == self.token(&from, &self.previous_secret)
+                });
+                let info_hash = arguments
+                    .info_hash
+                    .and_then(|hash| <[u8; 20]>::try_from(hash.as_slice()).ok());
+
+                let (true, Some(info_hash)) = (valid_token, info_hash) else {
+                    return self
+                        .send(&Message::error(&msg.transaction_id, 203, "Bad Token"), from)
+                        .await;
+                };
+
+                let port = if arguments.implied_port == Some(1) {
+                    from.port()
+                } else {
+                    arguments.port.unwrap_or(from.port())
+                };
+                let peer = SocketAddrV4::new(*from.ip(), port);
+                if !self.storage.announce(info_hash, peer, Instant::now()) {
+                    debug!(%from, "No room to store another announced info hash");
+                }
+            }
+            _ => {
+                return self
+                    .send(
+                        &Message::error(&msg.transaction_id, 204, "Method Unknown"),
+                        from,
+                    )
+                    .await;
+            }
+        }
+
+        self.send(&Message::response(&msg.transaction_id, response), from)
+            .await
+    }
+
+    fn compact_closest(&self, target: &[u8; 20]) -> ByteBuf {
+        let nodes: Vec<_> = self
+            .table
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        ByteBuf::from(krpc::encode_nodes(&nodes))
+    }
+
+    /// The write token for `addr` under `secret`.
+    fn token(&self, addr: &SocketAddrV4, secret: &[u8; 20]) -> Vec<u8> {
+        hash::sha1(&[&addr.ip().octets(), secret])[..8].to_vec()
+    }
+
+    /// Replaces the secret every [`SECRET_ROTATION`], so that write tokens
+    /// expire (BEP 5), and lets go of peers that stopped announcing.
+    fn rotate_secret(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.secret_rotated) < SECRET_ROTATION {
+            return;
+        }
+        self.previous_secret = std::mem::replace(&mut self.secret, rand::random());
+        self.secret_rotated = now;
+        self.storage.expire(now);
+    }
+
+    fn transaction_id(&mut self) -> Vec<u8> {
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        self.next_transaction.to_be_bytes().to_vec()
+    }
+
+    async fn send(&self, msg: &Message, addr: SocketAddrV4) -> anyhow::Result<()> {
+        let bytes = serde_bencode::to_bytes(msg).context("encode krpc message")?;
+        self.socket
+            .send_to(&bytes, addr)
+            .await
+            .context("send krpc message")?;
+        Ok(())
+    }
+
+    async fn recv(&self, deadline: Instant) -> Option<(Message, SocketAddrV4)> {
+        let mut buf = vec![0; 1500];
+        loop {
+            let (len, from) = tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf))
+                .await
+                .ok()?
+                .ok()?;
+
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            match serde_bencode::from_bytes(&buf[..len]) {
+                Ok(msg) => return Some((msg, from)),
+                Err(e) => debug!(%from, error = %e, "Ignoring malformed krpc message"),
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+/// Number of nodes kept per bucket.
+pub const K: usize = 8;
+
+/// Nodes that have not been heard from for this long may be replaced.
+const STALE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    pub id: [u8; 20],
+    pub addr: SocketAddrV4,
+    last_seen: Instant,
+}
+
+impl Node {
+    pub fn new(id: [u8; 20], addr: SocketAddrV4) -> Self {
+        Self {
+            id,
+            addr,
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+/// A Kademlia routing table with one bucket per bit of XOR distance from our id.
+pub struct RoutingTable {
+    id: [u8; 20],
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: [u8; 20]) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn id(&self) -> &[u8; 20] {
+        &self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds or refreshes a node. Full buckets only make room by evicting stale nodes.
+    pub fn insert(&mut self, node: Node) {
+        if node.id == self.id {
+            return;
+        }
+
+        let bucket = &mut self.buckets[bucket_index(&self.id, &node.id)];
+        if let Some(existing) = bucket.iter_mut().find(|n| n.id == node.id) {
+            *existing = node;
+        } else if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(stale) = bucket
+            .iter_mut()
+            .find(|n| n.last_seen.elapsed() > STALE_AFTER)
+        {
+            *stale = node;
+        }
+    }
+
+    pub fn remove(&mut self, id: &[u8; 20]) {
+        let bucket = &mut self.buckets[bucket_index(&self.id, id)];
+        bucket.retain(|n| n.id != *id);
+    }
+
+    /// Returns up to `n` known nodes, closest to `target` first.
+    pub fn closest(&self, target: &[u8; 20], n: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().copied().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+}
+
+pub fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut d = [0; 20];
+    for (i, byte) in d.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    d
+}
+
+/// Bucket 0 holds the farthest half of the id space, bucket 159 the closest node.
+fn bucket_index(own: &[u8; 20], other: &[u8; 20]) -> usize {
+    let d = distance(own, other);
+    let leading_zeros = d
+        .iter()
+        .position(|&byte| byte != 0)
+        .map(|i| i * 8 + d[i].leading_zeros() as usize)
+        .unwrap_or(159);
+    leading_zeros.min(159)
+}
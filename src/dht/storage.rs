@@ -0,0 +1,96 @@
+use std::{collections::HashMap, net::SocketAddrV4, time::Duration};
+
+use tokio::time::Instant;
+
+/// How long an announced peer is handed out before it has to announce again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Info hashes we store peers for at most.
+const MAX_TORRENTS: usize = 1024;
+
+/// Peers stored per info hash at most; the oldest makes way for a new one.
+const MAX_PEERS_PER_TORRENT: usize = 64;
+
+/// Peers that announced themselves to us, per info hash, until they expire.
+/// Bounded, so that nodes announcing many info hashes can't fill our memory.
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    torrents: HashMap<[u8; 20], Vec<(SocketAddrV4, Instant)>>,
+}
+
+impl PeerStore {
+    /// Stores `peer` for `info_hash`, or refreshes it if already stored.
+    /// Returns `false` if there is no room for another info hash.
+    pub fn announce(&mut self, info_hash: [u8; 20], peer: SocketAddrV4, now: Instant) -> bool {
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= MAX_TORRENTS {
+            self.expire(now);
+            if self.torrents.len() >= MAX_TORRENTS {
+                return false;
+            }
+        }
+
+        let peers = self.torrents.entry(info_hash).or_default();
+        peers.retain(|&(stored, _)| stored != peer);
+        if peers.len() >= MAX_PEERS_PER_TORRENT {
+            peers.remove(0);
+        }
+        peers.push((peer, now));
+        true
+    }
+
+    /// The peers of `info_hash` that haven't expired yet.
+    pub fn peers(&self, info_hash: &[u8; 20], now: Instant) -> Vec<SocketAddrV4> {
+        self.torrents
+            .get(info_hash)
+            .into_iter()
+            .flatten()
+            .filter(|&&(_, announced)| now.duration_since(announced) < PEER_TTL)
+            .map(|&(peer, _)| peer)
+            .collect()
+    }
+
+    /// Drops expired peers, and info hashes left without any.
+    pub fn expire(&mut self, now: Instant) {
+        self.torrents.retain(|_, peers| {
+            peers.retain(|&(_, announced)| now.duration_since(announced) < PEER_TTL);
+            !peers.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use tokio::time::Instant;
+
+    use super::{PeerStore, MAX_PEERS_PER_TORRENT, MAX_TORRENTS, PEER_TTL};
+
+    #[test]
+    fn test_peer_store_bounds() {
+        let now = Instant::now();
+        let peer = |port| SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        let mut store = PeerStore::default();
+
+        for port in 0..=MAX_PEERS_PER_TORRENT as u16 {
+            assert!(store.announce([0; 20], peer(port), now));
+        }
+        let peers = store.peers(&[0; 20], now);
+        assert_eq!(peers.len(), MAX_PEERS_PER_TORRENT);
+        assert!(!peers.contains(&peer(0)));
+
+        for i in 1..MAX_TORRENTS {
+            let mut info_hash = [0; 20];
+            info_hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            assert!(store.announce(info_hash, peer(1), now));
+        }
+        assert!(!store.announce([0xff; 20], peer(1), now));
+
+        // Once the others expire, there is room again.
+        let later = now + PEER_TTL;
+        assert!(store.peers(&[0; 20], later).is_empty());
+        assert!(store.announce([0xff; 20], peer(1), later));
+        assert_eq!(store.peers(&[0xff; 20], later), [peer(1)]);
+        assert_eq!(store.torrents.len(), 1);
+    }
+}
@@ -1,351 +1,2461 @@
-use std::{collections::BinaryHeap, time::Duration};
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Context};
-use futures_util::StreamExt;
-use sha1::{Digest, Sha1};
-use tokio::net::UdpSocket;
+use bytes::{Bytes, BytesMut};
+use tokio::sync::{broadcast, mpsc, watch, Notify, OwnedSemaphorePermit};
+use tracing::{debug, error, info, instrument, trace, warn, Instrument};
 
 use crate::{
-    block::BLOCK_SIZE,
-    peer::Peer,
-    piece::Piece,
-    torrent::{File, Keys, Torrent},
-    tracker,
+    block::{self, BLOCK_SIZE},
+    blocklist::Blocklist,
+    choke::{self, Choker, PeerRate},
+    memory::{MemoryLimit, Reservation},
+    merkle::{self, HashRequest},
+    mse::Encryption,
+    peer::{Bitfield, Command, Event, Peer, Silent},
+    piece::{Picker, Priority},
+    pipeline::Pipeline,
+    rate::Limits,
+    reputation::{Offense, Reputation},
+    resume::Resume,
+    session::{Incoming, PeerStats, Progress, Shared, TorrentEvent},
+    storage::{self, Disk, Layout, PieceStatus, Storage},
+    torrent::Torrent,
+    tracker::{self, Announce, Announced, Tiers},
+    webtorrent,
 };
 
-pub async fn all(t: &Torrent) -> anyhow::Result<Downloaded> {
+/// The port we tell trackers and DHT nodes that we accept connections on.
+pub const PORT: u16 = 6881;
+
+/// Peers we keep connections to at once.
+const MAX_PEERS: usize = 30;
+
+/// Outgoing connection attempts in flight at once.
+const MAX_CONNECTING: usize = 5;
+
+/// How long an outgoing connection attempt, including the handshake, may take.
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to announce when no tracker told us.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// The shortest re-announce interval we accept from a tracker.
+pub(crate) const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the announces when a download ends may take altogether.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times in a row to go looking for peers again after running out,
+/// before the download fails.
+const MAX_PEER_RETRIES: u32 = 3;
+
+/// How long to wait before trying the peers again after running out the
+/// first time; every retry after waits this much longer.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// How long a peer may leave our requests unanswered before it counts as
+/// snubbing us.
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many times a piece may fail the hash check before the download fails.
+const MAX_PIECE_FAILURES: usize = 5;
+
+/// Hash requests a peer may send per rechoke interval; more are rejected,
+/// and count against the peer.
+const MAX_HASH_REQUESTS: usize = 64;
+
+/// Collects peers for the torrent of `announce` from the trackers, if there
+/// are any, and from the DHT unless the torrent is private.
+pub async fn find_peers(
+    shared: &Shared,
+    tiers: &mut Tiers,
+    nodes: &[(String, u16)],
+    announce: &Announce,
+) -> anyhow::Result<Announced> {
+    let private = tiers.is_private();
+    let from_tracker = async {
+        if tiers.is_empty() {
+            Ok(Announced::default())
+        } else {
+            shared.announce_tiers(tiers, announce).await
+        }
+    };
+    let from_dht = async {
+        if private {
+            Ok(Vec::new())
+        } else {
+            shared.dht_peers(nodes, announce.info_hash).await
+        }
+    };
+
+    let (from_tracker, from_dht) = tokio::join!(from_tracker, from_dht);
+    let mut peers = Vec::new();
+    let mut interval = DEFAULT_INTERVAL;
+    match from_tracker {
+        Ok(announced) => {
+            peers.extend(announced.peers);
+            if !announced.interval.is_zero() {
+                interval = announced.interval;
+            }
+        }
+        Err(e) => warn!(error = %e, "Tracker announce failed"),
+    }
+    match from_dht {
+        Ok(dht_peers) => {
+            info!(peers = dht_peers.len(), "Found peers in the DHT");
+            peers.extend(dht_peers.into_iter().map(SocketAddr::V4));
+        }
+        Err(e) => warn!(error = %e, "DHT lookup failed"),
+    }
+
+    peers.sort();
+    peers.dedup();
+    anyhow::ensure!(!peers.is_empty(), "found no peers");
+
+    Ok(Announced {
+        peers,
+        interval,
+        tracker_id: None,
+    })
+}
+
+/// Asks [`reannounce`] not to wait for the interval.
+#[derive(Default)]
+struct Early {
+    notify: Notify,
+    /// Whether the next announce tells the trackers the download completed.
+    completed: AtomicBool,
+}
+
+impl Early {
+    fn announce(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Tells the trackers as soon as possible that the download completed.
+    fn completed(&self) {
+        self.completed.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+}
+
+/// Announces to the trackers every time the interval they asked for has
+/// passed, or `early` asks to, and hands the peers they return to the
+/// download.
+async fn reannounce(
+    shared: &Shared,
+    tiers: &mut Tiers,
+    info_hashes: &[[u8; 20]],
+    mut interval: Duration,
+    progress: watch::Receiver<Progress>,
+    peers: mpsc::Sender<Vec<SocketAddr>>,
+    early: &Early,
+) {
+    if tiers.is_empty() {
+        return std::future::pending().await;
+    }
+
+    loop {
+        tokio::select! {
+            () = tokio::time::sleep(interval.max(MIN_INTERVAL)) => {}
+            () = early.notify.notified() => {}
+        }
+
+        let current = *progress.borrow();
+        let event = if early.completed.swap(false, Ordering::Relaxed) {
+            tracker::Event::Completed
+        } else {
+            tracker::Event::None
+        };
+        match announce_hashes(shared, tiers, info_hashes, &current, event).await {
+            Ok(announced) => {
+                interval = announced.interval;
+                let _ = peers.send(announced.peers).await;
+            }
+            Err(e) => {
+                warn!(error = %e, "Re-announce failed");
+                // Left for the next announce, or the one when the download ends.
+                if event == tracker::Event::Completed {
+                    early.completed.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// Announces `event` to the trackers under each of the torrent's info hashes,
+/// of which hybrid torrents have two (BEP 52), and merges their answers.
+async fn announce_hashes(
+    shared: &Shared,
+    tiers: &mut Tiers,
+    info_hashes: &[[u8; 20]],
+    progress: &Progress,
+    event: tracker::Event,
+) -> anyhow::Result<Announced> {
+    let mut merged: Option<Announced> = None;
+    let mut last_error = anyhow!("no info hash to announce");
+    for &info_hash in info_hashes {
+        let announce = announce_for(info_hash, progress, event);
+        match shared.announce_tiers(tiers, &announce).await {
+            Ok(announced) => match &mut merged {
+                Some(merged) => {
+                    merged.peers.extend(announced.peers);
+                    merged.interval = merged.interval.min(announced.interval);
+                }
+                None => merged = Some(announced),
+            },
+            Err(e) => last_error = e,
+        }
+    }
+
+    let mut merged = merged.ok_or(last_error)?;
+    merged.peers.sort();
+    merged.peers.dedup();
+    Ok(merged)
+}
+
+fn announce_for(info_hash: [u8; 20], progress: &Progress, event: tracker::Event) -> Announce {
+    Announce {
+        info_hash,
+        downloaded: progress.downloaded,
+        uploaded: progress.uploaded,
+        left: progress.total_bytes - progress.bytes,
+        event,
+    }
+}
+
+pub async fn all(t: &Torrent, output: &Path) -> anyhow::Result<()> {
+    let (_pause, paused) = watch::channel(false);
+    let (_stop, stop) = watch::channel(false);
+    let (progress, _) = watch::channel(Progress::default());
+    let (peers, _) = watch::channel(Vec::new());
+    let (_priorities, priorities) =
+        watch::channel(vec![Priority::Normal; t.info.file_lengths().len()]);
+    let (events, _) = broadcast::channel(1);
+    let control = Control {
+        paused,
+        stop,
+        disk: Disk::default(),
+        priorities,
+        progress,
+        peers,
+        events,
+        seed: false,
+        active: None,
+    };
+
+    run(
+        &Arc::new(t.clone()),
+        output,
+        &Shared::default(),
+        None,
+        control,
+    )
+    .await
+}
+
+/// How a [`TorrentHandle`](crate::session::TorrentHandle) steers its download.
+pub(crate) struct Control {
+    pub(crate) paused: watch::Receiver<bool>,
+    /// Turns true when the download should end early, keeping what it has.
+    pub(crate) stop: watch::Receiver<bool>,
+    pub(crate) disk: Disk,
+    /// How soon to download each file, if at all.
+    pub(crate) priorities: watch::Receiver<Vec<Priority>>,
+    pub(crate) progress: watch::Sender<Progress>,
+    pub(crate) peers: watch::Sender<Vec<PeerStats>>,
+    pub(crate) events: broadcast::Sender<TorrentEvent>,
+    /// Whether to keep uploading once the download is complete, until
+    /// stopped.
+    pub(crate) seed: bool,
+    /// The download's place among the session's active ones, given up once
+    /// it only seeds.
+    pub(crate) active: Option<OwnedSemaphorePermit>,
+}
+
+/// Downloads `t` to `output`, using the session's `shared` resources and
+/// taking over the `incoming` connections routed to this torrent. Once
+/// complete, the torrent is seeded until stopped if `control` says so.
+#[instrument(name = "torrent", skip_all, fields(torrent = %t.info.name))]
+pub(crate) async fn run(
+    t: &Arc<Torrent>,
+    output: &Path,
+    shared: &Shared,
+    mut incoming: Option<mpsc::Receiver<Incoming>>,
+    mut control: Control,
+) -> anyhow::Result<()> {
     let info_hash = t.info_hash();
-    let request = tracker::http::Request::new(&info_hash, t.length());
-    let addr = tracker::get_addr(&t.announce)?;
+    let files = control.priorities.borrow_and_update().clone();
+    let priorities = t.info.piece_priorities(&files);
+    let needed: Vec<bool> = priorities.iter().map(|&p| p != Priority::Skip).collect();
 
-    let peers = match addr {
-        tracker::Addr::Udp(url) => {
-            let socket = UdpSocket::bind("0.0.0.0:0")
-                .await
-                .context("bind to the address")?;
-            socket.connect(url).await.context("connect to tracker")?;
-
-            let mut action = 0;
-            let mut transaction_id = 0;
-            let mut connection_id: u64 = 0;
-
-            loop {
-                match action {
-                    // Connect
-                    0 => {
-                        let mut connect_buffer = Vec::new();
-                        transaction_id = rand::random::<u32>();
-                        let connect_req = tracker::udp::ConnectRequest::new(transaction_id);
-                        let request = tracker::udp::Request::from(connect_req);
-                        request.write(&mut connect_buffer)?;
-
-                        let mut attempts = 0;
-                        let max_retries = 8;
-                        let mut delay = 15;
-                        loop {
-                            eprintln!("attempting to send request: {}", attempts);
-
-                            if attempts > max_retries {
-                                return Err(anyhow!("max retransmission reached"));
-                            }
-                            // Send the connect request
-                            match socket.send_to(&connect_buffer, &url).await {
-                                Ok(_) => break,
-                                Err(e) => {
-                                    println!(
-                                        "attempt {}: Failed to send request, error: {}",
-                                        attempts, e
-                                    );
-                                }
-                            }
-
-                            tokio::time::sleep(Duration::from_secs(delay)).await;
-
-                            attempts += 1;
-
-                            delay *= 2;
-                        }
-                    }
+    // The resume file lives next to the output.
+    if let Some(parent) = output.parent().filter(|p| !p.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("create {}", parent.display()))?;
+    }
 
-                    // Announce
-                    1 => {
-                        let mut announce_buffer = Vec::new();
-                        transaction_id = rand::random::<u32>();
-                        let announce_req = tracker::udp::AnnounceRequest::new(
-                            connection_id,
-                            transaction_id,
-                            t.info_hash(),
-                        );
-                        let request = tracker::udp::Request::from(announce_req);
-                        request.write(&mut announce_buffer)?;
-
-                        let mut attempts = 0;
-                        let max_retries = 8;
-                        let mut delay = 15;
-                        loop {
-                            eprintln!("attempting to send request: {}", attempts);
-
-                            if attempts > max_retries {
-                                return Err(anyhow!("max retransmission reached"));
-                            }
-                            // Send the connect request
-                            match socket.send_to(&announce_buffer, &url).await {
-                                Ok(_) => break,
-                                Err(e) => {
-                                    println!(
-                                        "attempt {}: Failed to send request, error: {}",
-                                        attempts, e
-                                    );
-                                }
-                            }
-
-                            tokio::time::sleep(Duration::from_secs(delay)).await;
-
-                            attempts += 1;
-
-                            delay *= 2;
-                        }
-                    }
-                    _ => {}
+    let wanted: Vec<bool> = files.iter().map(|&p| p != Priority::Skip).collect();
+    let mut layout = Layout::new(t, output);
+    layout.skip(&wanted).await?;
+    let mut completed = Completed::new(t, Arc::new(layout.clone()));
+    let resume = match Resume::load(output, t).await? {
+        // The pieces it lists were verified when written; those whose files
+        // were since removed or cut short are hash-checked below instead.
+        Some(resume) if layout.any_exists() => {
+            let have = Bitfield::from_payload(resume.bitfield.clone());
+            for piece_i in have.pieces().filter(|&i| i < t.info.piece_count()) {
+                if layout.holds(piece_i * t.info.plength, t.piece_len(piece_i)) {
+                    completed.insert(piece_i);
                 }
+            }
+            info!(pieces = completed.count(), "Resuming with verified pieces");
+            Some(resume)
+        }
+        _ => None,
+    };
+    let mut resume = resume.unwrap_or_else(|| Resume::new(t));
+
+    // Data already at the output (e.g. from an earlier run or another client)
+    // only has to be hash-checked, not downloaded again.
+    if layout.any_exists() {
+        let mut found = 0;
+        for piece_i in 0..t.info.piece_count() {
+            if completed.has_piece(piece_i) {
+                continue;
+            }
+
+            let (status, _) = layout.check_piece(t, piece_i).await;
+            if status == PieceStatus::Valid {
+                completed.insert(piece_i);
+                found += 1;
+            }
+        }
+
+        if found > 0 {
+            info!(pieces = found, "Found verified pieces on disk");
+            resume.save(output, t, &completed.have).await?;
+        }
+    }
+
+    let mut progress = completed.progress(t, &needed, 0);
+    progress.downloaded = resume.tracker.downloaded;
+    progress.uploaded = resume.tracker.uploaded;
+    control.progress.send_replace(progress);
+    let complete_at_start = progress.pieces == progress.total_pieces;
+    if complete_at_start && !control.seed {
+        let _ = control.events.send(TorrentEvent::Completed);
+        return Ok(());
+    }
+
+    // Allocated only now, so that the check above doesn't hash empty files.
+    completed.storage = control.disk.open(&layout, &wanted).await?;
 
-                // Buffer to receive the response
-                let mut response: Vec<u8> = vec![0; 1206];
+    let mut tiers = t.tiers();
+    let mut webrtc_peers =
+        webtorrent::join(tiers.websockets(), info_hash, control.progress.subscribe());
+    let started = announce_for(info_hash, &progress, tracker::Event::Started);
+    let announced = match find_peers(shared, &mut tiers, &t.nodes, &started).await {
+        // The WebSocket trackers may yet bring peers.
+        Err(e) if webrtc_peers.is_some() => {
+            warn!(error = %e, "Waiting for WebRTC peers only");
+            Announced {
+                interval: DEFAULT_INTERVAL,
+                ..Default::default()
+            }
+        }
+        // A seeder is found by the peers that want the torrent.
+        Err(e) if complete_at_start => {
+            warn!(error = %e, "Waiting for incoming peers only");
+            Announced {
+                interval: DEFAULT_INTERVAL,
+                ..Default::default()
+            }
+        }
+        result => result?,
+    };
+    let mut candidates = announced.peers;
+    // The peers that know a hybrid torrent by its v2 info hash only.
+    let info_hashes = t.info_hashes();
+    if info_hashes.len() > 1 && !tiers.is_empty() {
+        let started = tracker::Event::Started;
+        match announce_hashes(shared, &mut tiers, &info_hashes[1..], &progress, started).await {
+            Ok(announced) => candidates.extend(announced.peers),
+            Err(e) => warn!(error = %e, "Tracker announce failed for the v2 info hash"),
+        }
+        candidates.sort();
+        candidates.dedup();
+    }
+    let _ = control.events.send(TorrentEvent::TrackerAnnounced {
+        peers: candidates.len(),
+    });
 
-                // Receive the response
-                match socket.recv(&mut response).await {
-                    Ok(_) => {
-                        let res =
-                            tracker::udp::Response::read(&mut response).context("read response")?;
+    let picker = Picker::new(t, |piece_i| completed.has_piece(piece_i), &priorities);
+    let (disk_tx, disk_rx) = mpsc::channel(storage::WRITE_QUEUE);
+    let (written_tx, mut written) = mpsc::unbounded_channel();
+    let (verified_tx, mut verified) = mpsc::unbounded_channel();
+    tokio::spawn(
+        storage::write_pieces(completed.storage(), t.info.plength, disk_rx, written_tx)
+            .in_current_span(),
+    );
+    let completed = Arc::new(RwLock::new(completed));
+    let mut swarm = Swarm {
+        t: Arc::clone(t),
+        picker,
+        needed,
+        completed: Arc::clone(&completed),
+        connections: HashMap::new(),
+        in_progress: HashMap::new(),
+        seen: HashSet::new(),
+        candidates: Vec::new(),
+        connecting: 0,
+        next_peer: 0,
+        downloaded: progress.downloaded,
+        uploaded: progress.uploaded,
+        choker: Choker::default(),
+        limits: Arc::clone(shared.limits()),
+        peer_timeout: shared.peer_timeout(),
+        encryption: shared.encryption(),
+        blocklist: Arc::clone(shared.blocklist()),
+        paused: *control.paused.borrow_and_update(),
+        peer_retries: 0,
+        piece_failures: HashMap::new(),
+        reputation: Arc::clone(shared.reputation()),
+        memory: Arc::clone(shared.memory()),
+        verified: verified_tx,
+        disk: disk_tx,
+        writing: HashMap::new(),
+        piece_trees: HashMap::new(),
+        torrent_events: control.events.clone(),
+    };
 
-                        // Check if the transaction_id matches
-                        match res {
-                            tracker::udp::Response::Connect(connect_res) => {
-                                assert_eq!(connect_res.transaction_id.0, transaction_id);
+    swarm.add_candidates(candidates);
 
-                                println!("Received connection ID: {}", connect_res.connection_id.0);
+    let (events_tx, mut events) = mpsc::channel(64);
+    let (connected_tx, mut connected) = mpsc::channel(MAX_CONNECTING);
+    let (new_peers_tx, mut new_peers) = mpsc::channel(1);
+    let early = Early::default();
+    swarm.connect_more(&connected_tx, info_hash);
 
-                                action = 1;
-                                connection_id = connect_res.connection_id.0;
-                            }
-                            tracker::udp::Response::Announce(announce_res) => {
-                                assert_eq!(announce_res.transaction_id.0, transaction_id);
+    let result: anyhow::Result<()> = async {
+        let reannounce = reannounce(
+            shared,
+            &mut tiers,
+            &info_hashes,
+            announced.interval,
+            control.progress.subscribe(),
+            new_peers_tx,
+            &early,
+        );
+        tokio::pin!(reannounce);
+        let mut rechoke = tokio::time::interval(choke::RECHOKE_INTERVAL);
+        // When to try the peers again after running out of them.
+        let mut retry_at: Option<tokio::time::Instant> = None;
+        // Whether every wanted piece is written, and the files are exported.
+        let mut done = false;
+        let mut was_complete = complete_at_start;
 
-                                eprintln!("Peers");
+        loop {
+            let finished = swarm.picker.is_done() && swarm.writing.is_empty();
+            if finished && !done {
+                // Files asked for since the start are still in the part file.
+                let wanted: Vec<bool> = control
+                    .priorities
+                    .borrow()
+                    .iter()
+                    .map(|&p| p != Priority::Skip)
+                    .collect();
+                layout.export(&wanted).await?;
+                let _ = control.events.send(TorrentEvent::Completed);
 
-                                break announce_res.peers;
-                            }
-                            _ => {}
+                // Having just some of the files doesn't make us a seeder.
+                let complete = completed.read().expect("lock is not poisoned").is_complete();
+                if complete && !was_complete {
+                    was_complete = true;
+                    early.completed();
+                }
+                if !control.seed {
+                    return Ok(());
+                }
+                info!("Download complete, seeding");
+                control.active = None;
+            }
+            // Files given a priority again while seeding are downloaded.
+            done = finished;
+
+            tokio::select! {
+                Some((peer_i, event)) = events.recv() => {
+                    swarm.handle(peer_i, event).await?;
+                    swarm.report(&control.progress);
+                }
+                Some((piece_i, data, valid)) = verified.recv() => {
+                    swarm.finish_piece(piece_i, data, valid).await?;
+                    swarm.report(&control.progress);
+                }
+                Some((piece_i, result)) = written.recv() => {
+                    swarm.piece_written(piece_i, result, output, &mut resume).await?;
+                    swarm.report(&control.progress);
+                }
+                Some((addr, peer)) = connected.recv() => {
+                    swarm.connecting -= 1;
+                    match peer {
+                        Ok(peer) => {
+                            debug!(%addr, "Completed handshake");
+                            swarm.add_peer(peer, &events_tx);
+                            swarm.report(&control.progress);
                         }
+                        Err(e) => debug!(%addr, error = %e, "Could not handshake"),
+                    }
+                }
+                Some(peer) = recv_some(&mut webrtc_peers) => {
+                    let addr = peer.addr();
+                    if swarm.blocklist.contains(addr.ip()) || swarm.reputation.is_banned(addr.ip()) {
+                        debug!(%addr, "Refusing blocked WebRTC peer");
+                        continue;
+                    }
+                    debug!(%addr, "Completed WebRTC handshake");
+                    swarm.add_peer(peer, &events_tx);
+                    swarm.report(&control.progress);
+                }
+                Some((addr, stream, handshake)) = recv_some(&mut incoming) => {
+                    if swarm.reputation.is_banned(addr.ip()) {
+                        debug!(%addr, "Refusing banned peer");
+                        continue;
+                    }
+                    swarm.connecting += 1;
+                    let connected_tx = connected_tx.clone();
+                    tokio::spawn(async move {
+                        let peer = Peer::accept(addr, stream, &handshake).await;
+                        let _ = connected_tx.send((addr, peer)).await;
+                    });
+                }
+                Ok(()) = control.priorities.changed() => {
+                    let files = control.priorities.borrow_and_update().clone();
+                    swarm.set_priorities(&t.info.piece_priorities(&files));
+                    swarm.report(&control.progress);
+                }
+                Ok(()) = control.paused.changed() => {
+                    let paused = *control.paused.borrow_and_update();
+                    swarm.set_paused(paused);
+                }
+                Ok(()) = control.stop.changed() => {
+                    if *control.stop.borrow_and_update() {
+                        anyhow::ensure!(done, "download interrupted");
+                        return Ok(());
                     }
-                    Err(e) => {
-                        eprintln!("Failed to receive response: {:?}", e);
+                }
+                Some(peers) = new_peers.recv() => {
+                    swarm.add_candidates(peers);
+                }
+                _ = rechoke.tick() => {
+                    swarm.check_snubbed();
+                    swarm.rechoke();
+                    swarm.report_peers(&control.peers);
+                }
+                () = &mut reannounce => {}
+                () = tokio::time::sleep_until(retry_at.unwrap_or_else(tokio::time::Instant::now)),
+                    if retry_at.is_some() =>
+                {
+                    retry_at = None;
+                    // Unless the re-announce found new peers meanwhile.
+                    if swarm.connections.is_empty() && swarm.connecting == 0 {
+                        swarm.retry_seen();
                     }
                 }
             }
+
+            swarm.connect_more(&connected_tx, info_hash);
+            let stranded =
+                !swarm.picker.is_done() && swarm.connections.is_empty() && swarm.connecting == 0;
+            if stranded && retry_at.is_none() {
+                anyhow::ensure!(
+                    swarm.peer_retries < MAX_PEER_RETRIES,
+                    "no peers left to get the remaining pieces"
+                );
+                swarm.peer_retries += 1;
+                let delay = RETRY_DELAY * swarm.peer_retries;
+                warn!(
+                    retry = swarm.peer_retries,
+                    ?delay,
+                    "No peers left, looking for more"
+                );
+                retry_at = Some(tokio::time::Instant::now() + delay);
+                early.announce();
+            }
         }
-        tracker::Addr::Http(url) => {
-            let res = reqwest::get(request.url(&url.to_string())).await?;
-            let res: tracker::http::Response =
-                serde_bencode::from_bytes(&res.bytes().await?).context("parse response")?;
+    }
+    .await;
 
-            res.peers.0
+    // Pieces in flight are dropped along with the peers, but the transfer
+    // totals since the last verified piece are worth keeping.
+    if result.is_err() {
+        resume.tracker.downloaded = swarm.downloaded;
+        resume.tracker.uploaded = swarm.uploaded;
+        let have = completed.read().expect("lock is not poisoned").have.clone();
+        if let Err(e) = resume.save(output, t, &have).await {
+            error!(error = format!("{e:#}"), "Could not save resume data");
         }
-    };
+    }
+    // Dropping the connections closes their command channels, which stops them.
+    drop(swarm);
 
-    let mut peers = futures_util::stream::iter(peers)
-        .map(|peer_addr| async move {
-            let peer = Peer::new(peer_addr, &info_hash).await;
-            (peer_addr, peer)
-        })
-        .buffer_unordered(5);
+    // Let the trackers know we are done, whether we finished or failed.
+    if !tiers.is_empty() {
+        let progress = *control.progress.borrow();
+        let mut events = vec![tracker::Event::Stopped];
+        // Unless a re-announce while seeding told them already.
+        if result.is_ok() && early.completed.load(Ordering::Relaxed) {
+            events.insert(0, tracker::Event::Completed);
+        }
 
-    let mut peer_list = Vec::new();
-    while let Some((peer_addr, peer)) = peers.next().await {
-        match peer {
-            Ok(peer) => {
-                eprintln!("Completed handshake with {peer_addr}");
+        let stop = async {
+            for event in events {
+                let announced =
+                    announce_hashes(shared, &mut tiers, &info_hashes, &progress, event).await;
+                if let Err(e) = announced {
+                    warn!(?event, error = %e, "Could not announce");
+                }
+            }
+        };
+        let _ = tokio::time::timeout(STOP_TIMEOUT, stop).await;
+    }
+    result
+}
 
-                peer_list.push(peer);
+/// What a connection ending with `e` says about the peer: nothing if the
+/// connection failed rather than the peer.
+fn offense(e: &anyhow::Error) -> Option<Offense> {
+    if e.is::<Silent>() {
+        Some(Offense::Stall)
+    } else if e.chain().any(|cause| cause.is::<io::Error>()) {
+        None
+    } else {
+        Some(Offense::ProtocolViolation)
+    }
+}
 
-                if peer_list.len() > 5 {
-                    break;
-                }
+/// The next item from `rx`, if there is a channel at all.
+async fn recv_some<T>(rx: &mut Option<mpsc::Receiver<T>>) -> Option<T> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// A finished piece, its data, and whether it passed the hash check.
+type Verdict = (usize, Bytes, bool);
+
+/// The state of one torrent's download: its peer connections, and the pieces
+/// being assembled from the blocks they send.
+struct Swarm {
+    t: Arc<Torrent>,
+    picker: Picker,
+    /// The pieces of the files being downloaded.
+    needed: Vec<bool>,
+    completed: Arc<RwLock<Completed>>,
+    connections: HashMap<usize, Connection>,
+    in_progress: HashMap<usize, InProgress>,
+
+    /// Peer addresses we haven't tried to connect to yet.
+    candidates: Vec<SocketAddr>,
+    /// Every peer address we have been given so far.
+    seen: HashSet<SocketAddr>,
+    connecting: usize,
+    next_peer: usize,
+
+    /// Whether the handle paused the download; peers are kept, but no blocks
+    /// are requested from them.
+    paused: bool,
+
+    /// Bytes of verified pieces received and of blocks sent, for trackers.
+    downloaded: usize,
+    uploaded: usize,
+    choker: Choker,
+    limits: Arc<Limits>,
+    /// How long a peer may stay silent before it is dropped.
+    peer_timeout: Duration,
+    encryption: Encryption,
+    /// Addresses never to connect to.
+    blocklist: Arc<Blocklist>,
+    /// Times we ran out of peers in a row, without verifying a piece since.
+    peer_retries: u32,
+    /// How many times each piece failed the hash check.
+    piece_failures: HashMap<usize, usize>,
+    /// The session's record of misbehaving peers.
+    reputation: Arc<Reputation>,
+    /// The session's cap on piece data held in memory.
+    memory: Arc<MemoryLimit>,
+    /// The verdicts of the hash checks of finished pieces, which are run
+    /// off the swarm loop, with the piece data.
+    verified: mpsc::UnboundedSender<Verdict>,
+    /// Verified pieces for the disk task to write.
+    disk: mpsc::Sender<(usize, Bytes)>,
+    /// The pieces the disk task has yet to write, holding on to their memory
+    /// until it has.
+    writing: HashMap<usize, Reservation>,
+    /// The merkle trees of the files peers asked for hashes of, by their
+    /// pieces root, built on the first request.
+    piece_trees: HashMap<[u8; 32], merkle::Tree>,
+    torrent_events: broadcast::Sender<TorrentEvent>,
+}
+
+/// A running peer connection, as seen from the torrent.
+struct Connection {
+    commands: mpsc::UnboundedSender<Command>,
+    bitfield: Bitfield,
+    peer_choking: bool,
+    am_interested: bool,
+    am_choking: bool,
+    peer_interested: bool,
+
+    addr: SocketAddr,
+    connected_at: Instant,
+
+    /// Block bytes the peer sent since the last rechoke, and the rate that
+    /// made over the rechoke before.
+    received: usize,
+    download_rate: usize,
+    /// Likewise for the block bytes we sent the peer.
+    sent: usize,
+    upload_rate: usize,
+
+    downloaded: usize,
+    uploaded: usize,
+    blocks_served: usize,
+    /// Pieces the peer sent blocks of that failed the hash check.
+    hash_failures: usize,
+    /// Hash requests the peer sent since the last rechoke.
+    hash_requests: usize,
+
+    /// Blocks requested from this peer that haven't arrived yet, as
+    /// `(piece, block)`.
+    requests: Vec<(usize, usize)>,
+    /// How many requests the peer can take at once.
+    pipeline: Pipeline,
+    /// When the peer last sent a block we asked for, or was asked for blocks
+    /// while it owed us none.
+    last_block: Instant,
+    /// Whether the peer stopped sending the blocks we asked for; it is asked
+    /// for one at a time until it sends again.
+    snubbed: bool,
+}
+
+/// A piece whose blocks are being downloaded.
+struct InProgress {
+    /// The piece's one buffer, which goes on to the disk task as it is.
+    data: BytesMut,
+    requested: Vec<bool>,
+    received: Vec<bool>,
+    remaining: usize,
+    /// The address of the peer that sent each block.
+    from: Vec<Option<SocketAddr>>,
+    memory: Reservation,
+}
+
+impl InProgress {
+    fn new(length: usize, memory: Reservation) -> Self {
+        let nblocks = length.div_ceil(BLOCK_SIZE as usize);
+        Self {
+            data: BytesMut::zeroed(length),
+            requested: vec![false; nblocks],
+            received: vec![false; nblocks],
+            remaining: nblocks,
+            from: vec![None; nblocks],
+            memory,
+        }
+    }
+}
+
+impl Swarm {
+    fn connect_more(
+        &mut self,
+        connected: &mpsc::Sender<(SocketAddr, anyhow::Result<Peer>)>,
+        info_hash: [u8; 20],
+    ) {
+        while self.connections.len() + self.connecting < MAX_PEERS
+            && self.connecting < MAX_CONNECTING
+        {
+            let Some(addr) = self.candidates.pop() else {
+                break;
+            };
+
+            self.connecting += 1;
+            let connected = connected.clone();
+            let encryption = self.encryption;
+            tokio::spawn(async move {
+                let connect = Peer::new(addr, &info_hash, encryption);
+                let peer = tokio::time::timeout(CONNECT_TIMEOUT, connect)
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("timed out")));
+                let _ = connected.send((addr, peer)).await;
+            });
+        }
+    }
+
+    /// Queues the peers we haven't heard of before for connecting, unless
+    /// they are blocked.
+    fn add_candidates(&mut self, peers: Vec<SocketAddr>) {
+        for peer in peers {
+            if !self.seen.insert(peer) {
+                continue;
             }
-            Err(e) => {
-                eprintln!("Could not handshake with {peer_addr}. Disconnecting: {e}");
+            if self.blocklist.contains(peer.ip()) || self.reputation.is_banned(peer.ip()) {
+                debug!(addr = %peer, "Skipping blocked peer");
+                continue;
             }
+            self.candidates.push(peer);
         }
     }
-    drop(peers);
 
-    let mut peers = peer_list;
-    let mut need_pieces = BinaryHeap::new();
-    let mut no_peers = Vec::new();
+    /// Queues every peer we were given again, once they have all failed or
+    /// left.
+    fn retry_seen(&mut self) {
+        let (blocklist, reputation) = (&self.blocklist, &self.reputation);
+        self.candidates = self
+            .seen
+            .iter()
+            .copied()
+            .filter(|addr| !blocklist.contains(addr.ip()) && !reputation.is_banned(addr.ip()))
+            .collect();
+    }
 
-    for piece_i in 0..t.info.pieces.0.len() {
-        let piece = Piece::new(piece_i, &t, &peers);
-        if piece.peers().is_empty() {
-            no_peers.push(piece);
-        } else {
-            need_pieces.push(piece);
+    fn add_peer(&mut self, peer: Peer, events: &mpsc::Sender<(usize, Event)>) {
+        let peer_i = self.next_peer;
+        self.next_peer += 1;
+        let addr = peer.addr();
+        let _ = self.torrent_events.send(TorrentEvent::PeerConnected(addr));
+
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        // In the torrent's span, so that the peer's span is within it.
+        tokio::spawn(
+            peer.run(
+                peer_i,
+                Arc::clone(&self.completed),
+                Arc::clone(&self.limits),
+                self.peer_timeout,
+                events.clone(),
+                commands_rx,
+            )
+            .in_current_span(),
+        );
+
+        self.connections.insert(
+            peer_i,
+            Connection {
+                commands,
+                bitfield: Bitfield::new(self.t.info.piece_count()),
+                peer_choking: true,
+                am_interested: false,
+                am_choking: true,
+                peer_interested: false,
+                addr,
+                connected_at: Instant::now(),
+                received: 0,
+                download_rate: 0,
+                sent: 0,
+                upload_rate: 0,
+                downloaded: 0,
+                uploaded: 0,
+                blocks_served: 0,
+                hash_failures: 0,
+                hash_requests: 0,
+                requests: Vec::new(),
+                pipeline: Pipeline::new(),
+                last_block: Instant::now(),
+                snubbed: false,
+            },
+        );
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+
+        let peers: Vec<usize> = self.connections.keys().copied().collect();
+        for peer_i in peers {
+            if paused {
+                let conn = self.connections.get_mut(&peer_i).expect("listed above");
+                let requests = std::mem::take(&mut conn.requests);
+                conn.pipeline.cancelled();
+                self.release(requests);
+            }
+            self.update_interest(peer_i);
         }
     }
 
-    assert!(no_peers.is_empty());
+    /// Reorders the pieces still to download; peers are told whether they
+    /// still have pieces we want.
+    fn set_priorities(&mut self, priorities: &[Priority]) {
+        for (piece_i, &priority) in priorities.iter().enumerate() {
+            self.picker.set_priority(piece_i, priority);
+            self.needed[piece_i] = priority != Priority::Skip;
+        }
+
+        let peers: Vec<usize> = self.connections.keys().copied().collect();
+        for peer_i in peers {
+            self.update_interest(peer_i);
+        }
+    }
 
-    let mut all_pieces = vec![0; t.length()];
-    while let Some(piece) = need_pieces.pop() {
-        let plength = piece.length();
-        let npiece = piece.index();
-        let piece_length = plength.min(t.length() - plength * npiece);
-        let total_blocks = if piece_length % BLOCK_SIZE as usize == 0 {
-            piece_length / BLOCK_SIZE as usize
-        } else {
-            (piece_length / BLOCK_SIZE as usize) + 1
-        };
+    /// Publishes what each connected peer has done so far.
+    fn report_peers(&self, peers: &watch::Sender<Vec<PeerStats>>) {
+        let mut stats: Vec<PeerStats> = self
+            .connections
+            .values()
+            .map(|conn| PeerStats {
+                addr: conn.addr,
+                downloaded: conn.downloaded,
+                uploaded: conn.uploaded,
+                download_rate: conn.download_rate,
+                upload_rate: conn.upload_rate,
+                blocks_served: conn.blocks_served,
+                hash_failures: conn.hash_failures,
+                snubbed: conn.snubbed,
+                connected_secs: conn.connected_at.elapsed().as_secs(),
+            })
+            .collect();
+        stats.sort_by_key(|peer| Reverse(peer.downloaded));
 
-        let peers: Vec<_> = peers
+        peers.send_replace(stats.clone());
+        let _ = self.torrent_events.send(TorrentEvent::PeerStats(stats));
+    }
+
+    /// Marks the peers that left our requests unanswered for
+    /// [`SNUB_TIMEOUT`] as snubbing us and hands their requests to the
+    /// others, again each time a snubbing peer leaves one unanswered that
+    /// long. While there are peers we haven't tried yet, they take the
+    /// snubbing peers' places.
+    fn check_snubbed(&mut self) {
+        let now = Instant::now();
+        let snubbing: Vec<usize> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| {
+                !conn.requests.is_empty() && now.duration_since(conn.last_block) > SNUB_TIMEOUT
+            })
+            .map(|(&peer_i, _)| peer_i)
+            .collect();
+
+        for peer_i in snubbing {
+            let conn = self.connections.get_mut(&peer_i).expect("found above");
+            if !conn.snubbed {
+                debug!(addr = %conn.addr, requests = conn.requests.len(), "Peer snubs us");
+                conn.snubbed = true;
+            }
+            let requests = std::mem::take(&mut conn.requests);
+            conn.pipeline.cancelled();
+            self.release(requests);
+            if !self.candidates.is_empty() {
+                self.disconnect(peer_i);
+            }
+        }
+        self.fill_all();
+    }
+
+    /// Chooses anew which peers we upload to.
+    fn rechoke(&mut self) {
+        let seeding = self.picker.is_done();
+        let rates: Vec<PeerRate> = self
+            .connections
             .iter_mut()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
+            .map(|(&peer, conn)| {
+                conn.download_rate = conn.received / choke::RECHOKE_INTERVAL.as_secs() as usize;
+                conn.received = 0;
+                conn.upload_rate = conn.sent / choke::RECHOKE_INTERVAL.as_secs() as usize;
+                conn.sent = 0;
+                conn.hash_requests = 0;
+                PeerRate {
+                    peer,
+                    interested: conn.peer_interested,
+                    rate: if seeding {
+                        conn.upload_rate
+                    } else {
+                        conn.download_rate
+                    },
+                }
+            })
             .collect();
 
-        let (submit, tasks) = kanal::bounded_async(total_blocks);
-        for block in 0..total_blocks {
-            submit
-                .send(block)
-                .await
-                .expect("bound holds all these limits");
+        let unchoked = self.choker.rechoke(&rates);
+        for peer in rates.iter().map(|rate| rate.peer) {
+            self.set_choking(peer, !unchoked.contains(&peer));
         }
+    }
 
-        let (finish, mut done) = tokio::sync::mpsc::channel(total_blocks);
-        let mut participants = futures_util::stream::FuturesUnordered::new();
-        for peer in peers {
-            participants.push(peer.participate(
-                piece.index() as u32,
-                total_blocks as u32,
-                piece_length as u32,
-                submit.clone(),
-                tasks.clone(),
-                finish.clone(),
-            ));
+    fn set_choking(&mut self, peer_i: usize, choking: bool) {
+        let Some(conn) = self.connections.get_mut(&peer_i) else {
+            return;
+        };
+
+        if choking != conn.am_choking {
+            conn.am_choking = choking;
+            let command = if choking {
+                Command::Choke
+            } else {
+                Command::Unchoke
+            };
+            let _ = conn.commands.send(command);
         }
-        drop(submit);
-        drop(finish);
-        drop(tasks);
+    }
 
-        let mut all_blocks: Vec<u8> = vec![0; piece_length];
-        let mut bytes_received = 0;
-        loop {
-            tokio::select! {
-                joined = participants.next(), if !participants.is_empty() => {
-                    // if a participant ends early, it's either slow or failed.
-                    match joined {
-                        None => {},
-                        Some(Ok(_)) => {},
-                        Some(Err(_)) => {},
-                    }
-                },
-
-                piece = done.recv() => {
-                // keep track of the bytes in message
-                    if let Some(piece) = piece {
-                        // let piece = Piece::ref_from_bytes(&piece.block()[..]).expect("always get all Piece response fields from peer");
-                        all_blocks[piece.begin() as usize ..][..piece.block().len()].copy_from_slice(piece.block());
-                        bytes_received += piece.block().len();
-                        if bytes_received ==  piece_length {
-                            break;
-                        }
-                    } else {
-                        break;
-                    }
+    /// Publishes the download's progress to its handle.
+    fn report(&self, progress: &watch::Sender<Progress>) {
+        let completed = self.completed.read().expect("lock is not poisoned");
+        let mut current = completed.progress(&self.t, &self.needed, self.connections.len());
+        current.choking = self
+            .connections
+            .values()
+            .filter(|conn| conn.peer_choking)
+            .count();
+        current.downloaded = self.downloaded;
+        current.uploaded = self.uploaded;
+        progress.send_if_modified(|progress| {
+            let modified = *progress != current;
+            *progress = current;
+            modified
+        });
+    }
 
-                },
+    async fn handle(&mut self, peer_i: usize, event: Event) -> anyhow::Result<()> {
+        let Some(conn) = self.connections.get_mut(&peer_i) else {
+            return Ok(());
+        };
+
+        match event {
+            Event::Bitfield(bitfield) => {
+                if !bitfield.fits(self.t.info.piece_count()) {
+                    self.penalize(peer_i, Offense::BogusBitfield);
+                    self.disconnect(peer_i);
+                    return Ok(());
+                }
+                self.picker.peer_connected(peer_i, &bitfield);
+                conn.bitfield = bitfield;
+                self.update_interest(peer_i);
+            }
+            Event::Have(piece_i) => {
+                if piece_i >= self.t.info.piece_count() {
+                    debug!(
+                        peer = peer_i,
+                        piece = piece_i,
+                        "Peer has a piece that doesn't exist"
+                    );
+                    self.penalize(peer_i, Offense::BogusBitfield);
+                    self.disconnect(peer_i);
+                    return Ok(());
+                }
+                conn.bitfield.set_piece(piece_i);
+                self.picker.peer_has(peer_i, piece_i);
+                self.update_interest(peer_i);
+            }
+            Event::Choke => {
+                conn.peer_choking = true;
+                let requests = std::mem::take(&mut conn.requests);
+                conn.pipeline.cancelled();
+                self.release(requests);
+                self.fill_all();
+            }
+            Event::Unchoke => {
+                conn.peer_choking = false;
+                self.fill(peer_i);
+            }
+            Event::Interested => {
+                conn.peer_interested = true;
+
+                // No need to wait for the next rechoke while slots are free.
+                let unchoked = self.connections.values().filter(|c| !c.am_choking).count();
+                if unchoked <= choke::UPLOAD_SLOTS {
+                    self.set_choking(peer_i, false);
+                }
+            }
+            Event::NotInterested => {
+                conn.peer_interested = false;
+            }
+            Event::Block(block) => {
+                let piece_i = block.index() as usize;
+                let block_i = block.begin() as usize / BLOCK_SIZE as usize;
+
+                let Some(pos) = conn.requests.iter().position(|&r| r == (piece_i, block_i)) else {
+                    // A block we no longer need or never asked this peer for.
+                    return Ok(());
+                };
+                conn.requests.swap_remove(pos);
+                conn.received += block.block().len();
+                conn.downloaded += block.block().len();
+                conn.pipeline.received(block.block().len(), Instant::now());
+                conn.last_block = Instant::now();
+                if conn.snubbed {
+                    debug!(peer = peer_i, "Peer no longer snubs us");
+                    conn.snubbed = false;
+                }
+
+                let piece_length = self.t.piece_len(piece_i);
+                let begin = block_i * BLOCK_SIZE as usize;
+                let expected = (BLOCK_SIZE as usize).min(piece_length - begin);
+                let progress = self
+                    .in_progress
+                    .get_mut(&piece_i)
+                    .expect("requested blocks belong to pieces in progress");
+
+                let malformed = block.begin() as usize != begin || block.block().len() != expected;
+                let mut finished = false;
+                if malformed {
+                    // Unless it is one of a piece being verified.
+                    progress.requested[block_i] = progress.received[block_i];
+                } else if !progress.received[block_i] {
+                    progress.data[begin..][..expected].copy_from_slice(block.block());
+                    progress.received[block_i] = true;
+                    progress.remaining -= 1;
+                    progress.from[block_i] = Some(conn.addr);
+                    finished = progress.remaining == 0;
+                }
+
+                if finished {
+                    self.verify(piece_i);
+                }
+                if malformed {
+                    self.penalize(peer_i, Offense::ProtocolViolation);
+                }
+                self.fill(peer_i);
+            }
+            Event::Uploaded(length) => {
+                self.uploaded += length;
+                conn.sent += length;
+                conn.uploaded += length;
+                conn.blocks_served += 1;
+            }
+            Event::HashRequest(request) => {
+                conn.hash_requests += 1;
+                let flooding = conn.hash_requests > MAX_HASH_REQUESTS;
+                let commands = conn.commands.clone();
+                if conn.hash_requests == MAX_HASH_REQUESTS + 1 {
+                    debug!(peer = peer_i, "Peer floods us with hash requests");
+                    self.penalize(peer_i, Offense::Flood);
+                }
+
+                let hashes = if flooding {
+                    None
+                } else {
+                    self.hashes(&request)
+                };
+                let command = match hashes {
+                    Some(hashes) => Command::Hashes(request, hashes),
+                    None => Command::HashReject(request),
+                };
+                let _ = commands.send(command);
+            }
+            Event::Disconnected(e) => {
+                debug!(peer = peer_i, error = %e, "Peer failed");
+                if let Some(offense) = offense(&e) {
+                    self.penalize(peer_i, offense);
+                }
+                self.disconnect(peer_i);
             }
         }
-        drop(participants);
 
-        if bytes_received == piece_length {
-            // great, we got all the bytes
-        } else {
-            // we'll need to connect to more peers, and make sure that those additional peers also
-            // have this piece, and then download the piece we _didn't_ get from them.
-            // probably also stick this back onto the pices_heap
-            anyhow::bail!("no peers left to get piece {}", piece.index());
+        Ok(())
+    }
+
+    /// Answers a hash request from the merkle tree of the file it is for.
+    fn hashes(&mut self, request: &HashRequest) -> Option<Vec<[u8; 32]>> {
+        let t = &self.t;
+        let tree = match self.piece_trees.entry(request.pieces_root) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(t.piece_tree(&request.pieces_root)?),
+        };
+        t.hashes(tree, request)
+    }
+
+    /// Forgets a peer, handing its requests to the others. Dropping its
+    /// commands ends the connection, if it isn't over already.
+    fn disconnect(&mut self, peer_i: usize) {
+        let Some(conn) = self.connections.remove(&peer_i) else {
+            return;
+        };
+        self.picker.peer_disconnected(peer_i);
+        self.release(conn.requests);
+        self.fill_all();
+    }
+
+    fn penalize(&mut self, peer_i: usize, offense: Offense) {
+        if let Some(conn) = self.connections.get(&peer_i) {
+            self.penalize_addr(conn.addr, offense);
         }
+    }
 
-        let mut hasher = Sha1::new();
-        hasher.update(&all_blocks);
-        let hash: [u8; 20] = hasher.finalize().try_into().expect("");
-        assert_eq!(hash, piece.hash());
+    /// Counts `offense` against the peer at `addr`, which may be gone, and
+    /// disconnects every peer at its address if that got it banned.
+    fn penalize_addr(&mut self, addr: SocketAddr, offense: Offense) {
+        debug!(%addr, ?offense, "Peer misbehaved");
+        if !self.reputation.penalize(addr.ip(), offense) {
+            return;
+        }
 
-        all_pieces[piece.index() * t.info.plength..][..piece_length].copy_from_slice(&all_blocks);
+        warn!(%addr, ?offense, "Banning peer");
+        self.candidates
+            .retain(|candidate| candidate.ip() != addr.ip());
+        let banned: Vec<usize> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.addr.ip() == addr.ip())
+            .map(|(&peer_i, _)| peer_i)
+            .collect();
+        for peer_i in banned {
+            self.disconnect(peer_i);
+        }
     }
 
-    Ok(Downloaded {
-        bytes: all_pieces,
-        files: match &t.info.keys {
-            Keys::SingleFile { length } => vec![File {
-                length: *length,
-                path: vec![t.info.name.clone()],
-            }],
-            Keys::MultiFile { files } => files.clone(),
-        },
-    })
-}
+    /// Hashes a piece whose blocks have all arrived on a blocking thread,
+    /// rather than hold up the swarm loop. The piece stays in progress, with
+    /// every block received, until the verdict is back.
+    fn verify(&mut self, piece_i: usize) {
+        let progress = self
+            .in_progress
+            .get_mut(&piece_i)
+            .expect("finished pieces are in progress");
+        let data = std::mem::take(&mut progress.data).freeze();
+        let t = Arc::clone(&self.t);
+        let verified = self.verified.clone();
+        tokio::task::spawn_blocking(move || {
+            let valid = t.verify_piece(piece_i, &data);
+            let _ = verified.send((piece_i, data, valid));
+        });
+    }
 
-pub struct Downloaded {
-    pub bytes: Vec<u8>,
-    pub files: Vec<File>,
-}
+    /// Takes the verdict of a piece's hash check, and hands the piece to the
+    /// disk task if it is good.
+    async fn finish_piece(
+        &mut self,
+        piece_i: usize,
+        data: Bytes,
+        valid: bool,
+    ) -> anyhow::Result<()> {
+        let progress = self
+            .in_progress
+            .remove(&piece_i)
+            .expect("pieces being verified are in progress");
+        if !valid {
+            let mut from: Vec<SocketAddr> = progress.from.into_iter().flatten().collect();
+            from.sort_unstable();
+            from.dedup();
+            let offense = if from.len() == 1 {
+                Offense::SoleCorruptData
+            } else {
+                Offense::CorruptData
+            };
+            // Started over ahead of new pieces, so that only it is downloaded
+            // again. That is before any ban, whose refill would start it anew.
+            self.in_progress.insert(
+                piece_i,
+                InProgress::new(self.t.piece_len(piece_i), progress.memory),
+            );
+            for addr in from {
+                if let Some(conn) = self.connections.values_mut().find(|conn| conn.addr == addr) {
+                    conn.hash_failures += 1;
+                }
+                self.penalize_addr(addr, offense);
+            }
+            let failures = self.piece_failures.entry(piece_i).or_default();
+            *failures += 1;
+            anyhow::ensure!(
+                *failures < MAX_PIECE_FAILURES,
+                "piece {piece_i} failed the hash check {failures} times"
+            );
+            warn!(
+                piece = piece_i,
+                failures = *failures,
+                "Piece failed the hash check, downloading it again"
+            );
+            self.fill_all();
+            return Ok(());
+        }
+
+        self.picker.done(piece_i);
+        self.peer_retries = 0;
+        debug!(piece = piece_i, "Piece verified");
+
+        // Announced as ours once it is on disk, so that uploads find it.
+        self.writing.insert(piece_i, progress.memory);
+        self.disk
+            .send((piece_i, data))
+            .await
+            .map_err(|_| anyhow!("disk task stopped"))?;
+        Ok(())
+    }
+
+    /// Takes a piece the disk task wrote, or failed to, as ours.
+    async fn piece_written(
+        &mut self,
+        piece_i: usize,
+        result: anyhow::Result<usize>,
+        output: &Path,
+        resume: &mut Resume,
+    ) -> anyhow::Result<()> {
+        self.writing.remove(&piece_i);
+        let length = result.with_context(|| format!("write piece {piece_i}"))?;
+
+        let have = {
+            let mut completed = self.completed.write().expect("lock is not poisoned");
+            completed.insert(piece_i);
+            completed.have.clone()
+        };
+        let _ = self
+            .torrent_events
+            .send(TorrentEvent::PieceVerified(piece_i));
+
+        self.downloaded += length;
+        resume.tracker.downloaded = self.downloaded;
+        resume.tracker.uploaded = self.uploaded;
+        resume.save(output, &self.t, &have).await?;
+
+        let peers: Vec<usize> = self.connections.keys().copied().collect();
+        for peer_i in peers {
+            let _ = self.connections[&peer_i]
+                .commands
+                .send(Command::Have(piece_i));
+            self.update_interest(peer_i);
+        }
+
+        Ok(())
+    }
+
+    /// Tells the peer whether it has pieces we still want, and requests
+    /// blocks from it if so.
+    fn update_interest(&mut self, peer_i: usize) {
+        let Some(conn) = self.connections.get_mut(&peer_i) else {
+            return;
+        };
+
+        let interested = !self.paused
+            && self
+                .picker
+                .wants(|piece_i| conn.bitfield.has_piece(piece_i));
+        if interested != conn.am_interested {
+            conn.am_interested = interested;
+            let command = if interested {
+                Command::Interested
+            } else {
+                Command::NotInterested
+            };
+            let _ = conn.commands.send(command);
+        }
+
+        self.fill(peer_i);
+    }
 
-impl<'a> IntoIterator for &'a Downloaded {
-    type Item = DownloadedFile<'a>;
-    type IntoIter = DownloadedIter<'a>;
+    /// Makes blocks requested from a peer that is gone or choked us available
+    /// to the other peers.
+    fn release(&mut self, requests: Vec<(usize, usize)>) {
+        for (piece_i, block_i) in requests {
+            if let Some(progress) = self.in_progress.get_mut(&piece_i) {
+                progress.requested[block_i] = progress.received[block_i];
+            }
+        }
+    }
 
-    fn into_iter(self) -> Self::IntoIter {
-        DownloadedIter::new(self)
+    fn fill_all(&mut self) {
+        // The peers that send what they are asked for get the first pick.
+        let mut peers: Vec<(bool, usize)> = self
+            .connections
+            .iter()
+            .map(|(&peer_i, conn)| (conn.snubbed, peer_i))
+            .collect();
+        peers.sort_unstable();
+        for (_, peer_i) in peers {
+            self.fill(peer_i);
+        }
+    }
+
+    /// Requests blocks from the peer until its queue is as deep as its
+    /// pipeline allows, finishing the pieces already underway before starting
+    /// the rarest new one.
+    fn fill(&mut self, peer_i: usize) {
+        let Some(conn) = self.connections.get_mut(&peer_i) else {
+            return;
+        };
+        if conn.peer_choking || !conn.am_interested {
+            return;
+        }
+
+        let depth = if conn.snubbed {
+            1
+        } else {
+            conn.pipeline.depth()
+        };
+        while conn.requests.len() < depth {
+            let underway = self
+                .in_progress
+                .iter()
+                .find(|(&piece_i, progress)| {
+                    conn.bitfield.has_piece(piece_i) && progress.requested.contains(&false)
+                })
+                .map(|(&piece_i, _)| piece_i);
+
+            let piece_i = match underway {
+                Some(piece_i) => piece_i,
+                None => {
+                    let Some(piece) = self.picker.pick(|piece_i| {
+                        conn.bitfield.has_piece(piece_i) && !self.in_progress.contains_key(&piece_i)
+                    }) else {
+                        break;
+                    };
+
+                    // A torrent with no piece underway may always start one,
+                    // so that it can't be starved by the others.
+                    let force = self.in_progress.is_empty();
+                    let Some(memory) = self.memory.reserve(piece.length(), force) else {
+                        trace!(
+                            used = self.memory.used(),
+                            "Memory for pieces is full, waiting for some to be written"
+                        );
+                        break;
+                    };
+                    self.in_progress
+                        .insert(piece.index(), InProgress::new(piece.length(), memory));
+                    piece.index()
+                }
+            };
+
+            let progress = self.in_progress.get_mut(&piece_i).expect("inserted above");
+            let block_i = progress
+                .requested
+                .iter()
+                .position(|requested| !requested)
+                .expect("has unrequested blocks");
+            progress.requested[block_i] = true;
+            if conn.requests.is_empty() {
+                conn.last_block = Instant::now();
+            }
+            conn.requests.push((piece_i, block_i));
+            conn.pipeline.requested(Instant::now());
+
+            let request = block::Request::new(
+                piece_i as u32,
+                block_i as u32,
+                self.t.piece_len(piece_i) as u32,
+            );
+            let _ = conn.commands.send(Command::Request(request));
+        }
     }
 }
 
-pub struct DownloadedIter<'d> {
-    downloaded: &'d Downloaded,
-    file_iter: std::slice::Iter<'d, File>,
-    offset: usize,
+/// The pieces that passed verification so far, which we can upload to other
+/// peers from `storage`.
+pub struct Completed {
+    have: Bitfield,
+    plength: usize,
+    length: usize,
+    storage: Arc<dyn Storage>,
 }
 
-impl<'d> DownloadedIter<'d> {
-    pub fn new(d: &'d Downloaded) -> Self {
+impl Completed {
+    pub(crate) fn new(t: &Torrent, storage: Arc<dyn Storage>) -> Self {
         Self {
-            downloaded: d,
-            file_iter: d.files.iter(),
-            offset: 0,
+            have: Bitfield::new(t.info.piece_count()),
+            plength: t.info.plength,
+            length: t.length(),
+            storage,
         }
     }
-}
 
-impl<'d> Iterator for DownloadedIter<'d> {
-    type Item = DownloadedFile<'d>;
+    /// Marks a piece that is in storage as verified.
+    pub(crate) fn insert(&mut self, piece_i: usize) {
+        self.have.set_piece(piece_i);
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let file = self.file_iter.next()?;
-        let bytes = &self.downloaded.bytes[self.offset..][..file.length];
-        Some(DownloadedFile { file, bytes })
+    pub(crate) fn storage(&self) -> Arc<dyn Storage> {
+        Arc::clone(&self.storage)
     }
-}
 
-pub struct DownloadedFile<'d> {
-    file: &'d File,
-    bytes: &'d [u8],
+    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
+        self.have.has_piece(piece_i)
+    }
+
+    /// Every verified piece, as sent to peers in a `bitfield` message.
+    pub(crate) fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.have.pieces().count()
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.count() == self.length.div_ceil(self.plength)
+    }
+
+    /// How far along the `needed` pieces are.
+    fn progress(&self, t: &Torrent, needed: &[bool], peers: usize) -> Progress {
+        let needed = || (0..needed.len()).filter(|&piece_i| needed[piece_i]);
+        let have = || needed().filter(|&piece_i| self.has_piece(piece_i));
+
+        Progress {
+            pieces: have().count(),
+            total_pieces: needed().count(),
+            bytes: have().map(|piece_i| t.piece_len(piece_i)).sum(),
+            total_bytes: needed().map(|piece_i| t.piece_len(piece_i)).sum(),
+            peers,
+            choking: 0,
+            downloaded: 0,
+            uploaded: 0,
+        }
+    }
+
+    /// Where the requested block is in storage, if its piece has been
+    /// verified.
+    pub(crate) fn block_offset(
+        &self,
+        piece_i: usize,
+        begin: usize,
+        length: usize,
+    ) -> Option<usize> {
+        let offset = piece_i * self.plength + begin;
+        (self.have.has_piece(piece_i)
+            && begin + length <= self.plength
+            && offset + length <= self.length)
+            .then_some(offset)
+    }
 }
 
-impl<'d> DownloadedFile<'d> {
-    pub fn path(&self) -> &'d [String] {
-        &self.file.path
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        net::SocketAddr,
+        path::Path,
+        sync::{Arc, RwLock},
+        time::{Duration, Instant},
+    };
+
+    use bytes::Bytes;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+        sync::{broadcast, mpsc, watch},
+    };
+
+    use super::{
+        find_peers, run, Completed, Connection, Control, Swarm, Verdict, MAX_HASH_REQUESTS,
+        MAX_PIECE_FAILURES, SNUB_TIMEOUT,
+    };
+    use crate::{
+        block::{self, BLOCK_SIZE},
+        choke::{self, Choker},
+        hash,
+        memory::MemoryLimit,
+        merkle::HashRequest,
+        mse::{self, Encryption},
+        peer::{
+            Bitfield, BufferPool, Command, Event, Handshake, Message, MessageId, Peer, Transport,
+        },
+        piece::{Picker, Priority},
+        pipeline::Pipeline,
+        rate::{Limits, RateLimiter},
+        reputation::Offense,
+        resume::Resume,
+        session::{AnnounceMode, Incoming, Shared, TorrentEvent},
+        storage::{Disk, Layout},
+        torrent::{Hashes, Torrent},
+        tracker::{self, udp, Announce},
+    };
+
+    /// A torrent of one piece of two blocks.
+    fn torrent() -> Torrent {
+        let length = 2 * BLOCK_SIZE as usize;
+        Torrent::for_test(length, &[length])
+    }
+
+    fn swarm(t: &Torrent) -> Swarm {
+        let storage = Arc::new(Layout::new(t, &std::env::temp_dir().join("snub")));
+        let (disk, _) = mpsc::channel(1);
+        let (verified, _) = mpsc::unbounded_channel();
+        Swarm {
+            t: Arc::new(t.clone()),
+            picker: Picker::new(t, |_| false, &[Priority::Normal]),
+            needed: vec![true],
+            completed: Arc::new(RwLock::new(Completed::new(t, storage))),
+            connections: HashMap::new(),
+            in_progress: HashMap::new(),
+            candidates: Vec::new(),
+            seen: HashSet::new(),
+            connecting: 0,
+            next_peer: 0,
+            paused: false,
+            downloaded: 0,
+            uploaded: 0,
+            choker: Choker::default(),
+            limits: Default::default(),
+            peer_timeout: Duration::from_secs(120),
+            encryption: Encryption::default(),
+            blocklist: Default::default(),
+            peer_retries: 0,
+            piece_failures: HashMap::new(),
+            reputation: Default::default(),
+            memory: Arc::new(MemoryLimit::default()),
+            verified,
+            disk,
+            writing: HashMap::new(),
+            piece_trees: HashMap::new(),
+            torrent_events: broadcast::channel(1).0,
+        }
+    }
+
+    /// A peer that has the piece, unchoked us, and that we are interested in,
+    /// each at an address of its own.
+    fn connect(swarm: &mut Swarm, peer_i: usize) -> mpsc::UnboundedReceiver<Command> {
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let bitfield = Bitfield::from_payload(vec![0b1000_0000]);
+        swarm.picker.peer_connected(peer_i, &bitfield);
+        swarm.connections.insert(
+            peer_i,
+            Connection {
+                commands,
+                bitfield,
+                peer_choking: false,
+                am_interested: true,
+                am_choking: true,
+                peer_interested: false,
+                addr: ([127, 0, 0, 1 + peer_i as u8], 6881).into(),
+                connected_at: Instant::now(),
+                received: 0,
+                download_rate: 0,
+                sent: 0,
+                upload_rate: 0,
+                downloaded: 0,
+                uploaded: 0,
+                blocks_served: 0,
+                hash_failures: 0,
+                hash_requests: 0,
+                requests: Vec::new(),
+                pipeline: Pipeline::new(),
+                last_block: Instant::now(),
+                snubbed: false,
+            },
+        );
+        commands_rx
+    }
+
+    #[tokio::test]
+    async fn test_check_snubbed() {
+        let t = torrent();
+        let mut swarm = swarm(&t);
+        let _commands = [connect(&mut swarm, 0), connect(&mut swarm, 1)];
+
+        // Peer 0 was asked for both blocks, and hasn't sent either for long.
+        swarm.fill(0);
+        let since = Instant::now() - SNUB_TIMEOUT - Duration::from_secs(1);
+        swarm.connections.get_mut(&0).unwrap().last_block = since;
+        assert_eq!(swarm.connections[&0].requests.len(), 2);
+        assert!(swarm.connections[&1].requests.is_empty());
+
+        // Peer 1 owes us nothing, so it is not snubbing us, and gets both.
+        swarm.check_snubbed();
+        assert!(swarm.connections[&0].snubbed);
+        assert!(swarm.connections[&0].requests.is_empty());
+        assert!(!swarm.connections[&1].snubbed);
+        assert_eq!(swarm.connections[&1].requests.len(), 2);
+
+        // With a peer yet to try, a snubbing peer makes way for it, and what
+        // it owed goes to the one that is left, one block at a time.
+        swarm.connections.get_mut(&1).unwrap().last_block = since;
+        swarm.candidates.push(([127, 0, 0, 1], 1).into());
+        swarm.check_snubbed();
+        assert!(!swarm.connections.contains_key(&1));
+        assert_eq!(swarm.connections[&0].requests.len(), 1);
+        assert!(swarm.in_progress[&0].requested.contains(&false));
+
+        let (connected, _) = mpsc::channel(1);
+        swarm.connect_more(&connected, [0; 20]);
+        assert!(swarm.candidates.is_empty());
+        assert_eq!(swarm.connecting, 1);
+    }
+
+    #[tokio::test]
+    async fn test_seeder_unchokes_fastest_takers() {
+        let t = torrent();
+        let mut swarm = swarm(&t);
+        swarm.picker.done(0);
+        let _commands: Vec<_> = (0..5).map(|peer_i| connect(&mut swarm, peer_i)).collect();
+        // Peer 4 takes our data the fastest, and sends us the least.
+        for (&peer_i, conn) in swarm.connections.iter_mut() {
+            conn.peer_interested = true;
+            conn.sent = peer_i * 100_000;
+            conn.received = (4 - peer_i) * 100_000;
+        }
+
+        swarm.rechoke();
+        for peer_i in [2, 3, 4] {
+            assert!(!swarm.connections[&peer_i].am_choking);
+        }
+        let unchoked = swarm.connections.values().filter(|c| !c.am_choking);
+        assert_eq!(unchoked.count(), choke::UPLOAD_SLOTS + 1);
+    }
+
+    #[tokio::test]
+    async fn test_leecher_unchokes_fastest_givers_until_done() {
+        let t = torrent();
+        let mut swarm = swarm(&t);
+        let _commands: Vec<_> = (0..5).map(|peer_i| connect(&mut swarm, peer_i)).collect();
+        let rates = |swarm: &mut Swarm| {
+            // Peer 0 sends us the most, and takes our data the slowest.
+            for (&peer_i, conn) in swarm.connections.iter_mut() {
+                conn.peer_interested = true;
+                conn.sent = peer_i * 100_000;
+                conn.received = (4 - peer_i) * 100_000;
+            }
+        };
+
+        rates(&mut swarm);
+        swarm.rechoke();
+        for peer_i in [0, 1, 2] {
+            assert!(!swarm.connections[&peer_i].am_choking);
+        }
+
+        // Once seeding, what peers send us doesn't count anymore.
+        swarm.picker.done(0);
+        rates(&mut swarm);
+        swarm.rechoke();
+        for peer_i in [2, 3, 4] {
+            assert!(!swarm.connections[&peer_i].am_choking);
+        }
+        let unchoked = swarm.connections.values().filter(|c| !c.am_choking);
+        assert_eq!(unchoked.count(), choke::UPLOAD_SLOTS + 1);
+    }
+
+    #[tokio::test]
+    async fn test_hash_request_flood() {
+        let t = torrent();
+        let mut swarm = swarm(&t);
+        let mut commands = connect(&mut swarm, 0);
+        let ip = swarm.connections[&0].addr.ip();
+        let request = HashRequest {
+            pieces_root: [0; 32],
+            base_layer: 1,
+            index: 0,
+            length: 2,
+            proof_layers: 0,
+        };
+
+        for _ in 0..MAX_HASH_REQUESTS {
+            let event = Event::HashRequest(request.clone());
+            swarm.handle(0, event).await.unwrap();
+        }
+        assert_eq!(swarm.reputation.score(ip), 0);
+
+        // Past the limit, requests are rejected without a look, and the
+        // peer is penalized once.
+        for _ in 0..3 {
+            let event = Event::HashRequest(request.clone());
+            swarm.handle(0, event).await.unwrap();
+        }
+        let score = swarm.reputation.score(ip);
+        assert!(score > 0);
+        assert!(!swarm.reputation.is_banned(ip));
+        let mut rejected = 0;
+        while let Ok(command) = commands.try_recv() {
+            assert!(matches!(command, Command::HashReject(_)));
+            rejected += 1;
+        }
+        assert_eq!(rejected, MAX_HASH_REQUESTS + 3);
+
+        // Each rechoke starts the count over.
+        swarm.rechoke();
+        swarm.handle(0, Event::HashRequest(request)).await.unwrap();
+        assert_eq!(swarm.reputation.score(ip), score);
+    }
+
+    #[tokio::test]
+    async fn test_peers_with_pieces_that_dont_exist_are_dropped() {
+        let t = torrent();
+        let mut swarm = swarm(&t);
+        let _commands = [connect(&mut swarm, 0), connect(&mut swarm, 1)];
+        let ips = [0, 1].map(|peer_i| swarm.connections[&peer_i].addr.ip());
+
+        swarm.handle(0, Event::Have(0)).await.unwrap();
+        assert!(swarm.connections.contains_key(&0));
+        assert_eq!(swarm.reputation.score(ips[0]), 0);
+
+        // The torrent has a single piece.
+        swarm.handle(0, Event::Have(1)).await.unwrap();
+        assert!(!swarm.connections.contains_key(&0));
+        assert!(swarm.reputation.score(ips[0]) > 0);
+
+        let bitfield = Bitfield::from_payload(vec![0b1100_0000]);
+        swarm.handle(1, Event::Bitfield(bitfield)).await.unwrap();
+        assert!(!swarm.connections.contains_key(&1));
+        assert!(swarm.reputation.score(ips[1]) > 0);
+    }
+
+    #[tokio::test]
+    async fn test_written_piece_is_announced_to_every_peer() {
+        let dir = std::env::temp_dir().join(format!("have-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let t = torrent();
+        let mut swarm = swarm(&t);
+        let mut commands = [connect(&mut swarm, 0), connect(&mut swarm, 1)];
+        swarm.picker.done(0);
+
+        let mut resume = Resume::new(&t);
+        let output = dir.join("out");
+        swarm
+            .piece_written(0, Ok(t.length()), &output, &mut resume)
+            .await
+            .unwrap();
+        assert!(swarm.completed.read().unwrap().has_piece(0));
+        for commands in &mut commands {
+            let mut sent = Vec::new();
+            while let Ok(command) = commands.try_recv() {
+                sent.push(command);
+            }
+            assert!(matches!(
+                sent[..],
+                [Command::Have(0), Command::NotInterested]
+            ));
+        }
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    /// Block `block_i` of piece 0, as a peer sends it.
+    fn block(block_i: usize, data: &[u8]) -> Event {
+        let begin = (block_i * BLOCK_SIZE as usize) as u32;
+        let payload = [&0_u32.to_be_bytes(), &begin.to_be_bytes(), data].concat();
+        Event::Block(block::Response::decode(Bytes::from(payload)).unwrap())
+    }
+
+    /// Answers every block requested of `peer_i` with that block of `piece`.
+    async fn answer(swarm: &mut Swarm, peer_i: usize, piece: &[u8]) {
+        let requests = swarm.connections[&peer_i].requests.clone();
+        for (_, block_i) in requests {
+            let data = &piece[block_i * BLOCK_SIZE as usize..][..BLOCK_SIZE as usize];
+            swarm.handle(peer_i, block(block_i, data)).await.unwrap();
+        }
+    }
+
+    /// Hands the swarm the verdict of the piece it sent off to be hashed.
+    async fn verified(swarm: &mut Swarm, verdicts: &mut mpsc::UnboundedReceiver<Verdict>) {
+        let (piece_i, data, valid) = verdicts.recv().await.unwrap();
+        swarm.finish_piece(piece_i, data, valid).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_peer_stats_follow_what_each_peer_did() {
+        let t = torrent();
+        let mut swarm = swarm(&t);
+        let (events, mut events_rx) = broadcast::channel(4);
+        swarm.torrent_events = events;
+        let _commands = [connect(&mut swarm, 0), connect(&mut swarm, 1)];
+
+        // Peer 1 sends us the piece, and peer 0 takes a block from us.
+        swarm.fill(1);
+        answer(&mut swarm, 1, &[7; 2 * BLOCK_SIZE as usize]).await;
+        let served = BLOCK_SIZE as usize;
+        swarm.handle(0, Event::Uploaded(served)).await.unwrap();
+
+        let (peers, peers_rx) = watch::channel(Vec::new());
+        swarm.report_peers(&peers);
+        let stats = peers_rx.borrow().clone();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].addr, swarm.connections[&1].addr);
+        assert_eq!(stats[0].downloaded, t.length());
+        assert_eq!(stats[0].uploaded, 0);
+        assert_eq!(stats[1].addr, swarm.connections[&0].addr);
+        assert_eq!(stats[1].downloaded, 0);
+        assert_eq!(stats[1].uploaded, served);
+        assert_eq!(stats[1].blocks_served, 1);
+        assert_eq!(
+            events_rx.try_recv().unwrap(),
+            TorrentEvent::PeerStats(stats)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_progress_counts_choking_peers_and_transfers() {
+        let t = torrent();
+        let mut swarm = swarm(&t);
+        let _commands: Vec<_> = (0..3).map(|peer_i| connect(&mut swarm, peer_i)).collect();
+        swarm.connections.get_mut(&2).unwrap().peer_choking = true;
+        swarm.downloaded = 300;
+        swarm.uploaded = 100;
+
+        let (progress, progress_rx) = watch::channel(Default::default());
+        swarm.report(&progress);
+        let reported = *progress_rx.borrow();
+        assert_eq!(reported.peers, 3);
+        assert_eq!(reported.choking, 1);
+        assert_eq!((reported.downloaded, reported.uploaded), (300, 100));
+        assert_eq!((reported.pieces, reported.total_pieces), (0, 1));
+        assert_eq!(reported.total_bytes, t.length());
+
+        swarm.handle(2, Event::Unchoke).await.unwrap();
+        swarm.report(&progress);
+        assert_eq!(progress_rx.borrow().choking, 0);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_piece_is_downloaded_again() {
+        let good = vec![7; 2 * BLOCK_SIZE as usize];
+        let mut t = torrent();
+        t.info.pieces = Hashes(vec![hash::sha1(&[&good])]);
+        let mut swarm = swarm(&t);
+        let (disk, mut written) = mpsc::channel(1);
+        swarm.disk = disk;
+        let (verdicts_tx, mut verdicts) = mpsc::unbounded_channel();
+        swarm.verified = verdicts_tx;
+        let _commands = [connect(&mut swarm, 0), connect(&mut swarm, 1)];
+
+        // Snubbed peers get a block at a time, so each is asked for one.
+        for peer_i in [0, 1] {
+            swarm.connections.get_mut(&peer_i).unwrap().snubbed = true;
+            swarm.fill(peer_i);
+        }
+        assert_eq!(swarm.connections[&0].requests, [(0, 0)]);
+        assert_eq!(swarm.connections[&1].requests, [(0, 1)]);
+
+        // Peer 1's block is bad, but the piece can't tell whose is.
+        answer(&mut swarm, 0, &good).await;
+        let bad = vec![8; BLOCK_SIZE as usize];
+        swarm.handle(1, block(1, &bad)).await.unwrap();
+        verified(&mut swarm, &mut verdicts).await;
+        assert_eq!(swarm.piece_failures[&0], 1);
+        assert!(written.try_recv().is_err());
+        for peer_i in [0, 1] {
+            let conn = &swarm.connections[&peer_i];
+            assert_eq!(conn.hash_failures, 1);
+            assert!(!swarm.reputation.is_banned(conn.addr.ip()));
+        }
+
+        // Both blocks are asked for again, and this time the piece is good.
+        assert!(!swarm.in_progress[&0].requested.contains(&false));
+        assert!(!swarm.in_progress[&0].received.contains(&true));
+        for peer_i in [0, 1] {
+            answer(&mut swarm, peer_i, &good).await;
+        }
+        verified(&mut swarm, &mut verdicts).await;
+        let (piece_i, data) = written.try_recv().unwrap();
+        assert_eq!((piece_i, data.as_ref()), (0, good.as_slice()));
+        assert!(swarm.writing.contains_key(&0));
+    }
+
+    #[tokio::test]
+    async fn test_sole_corrupt_peer_is_banned() {
+        let good = vec![7; 2 * BLOCK_SIZE as usize];
+        let bad = vec![8; 2 * BLOCK_SIZE as usize];
+        let mut t = torrent();
+        t.info.pieces = Hashes(vec![hash::sha1(&[&good])]);
+        let mut swarm = swarm(&t);
+        let (disk, mut written) = mpsc::channel(1);
+        swarm.disk = disk;
+        let (verdicts_tx, mut verdicts) = mpsc::unbounded_channel();
+        swarm.verified = verdicts_tx;
+        let mut commands = connect(&mut swarm, 0);
+        let addr = swarm.connections[&0].addr;
+
+        // Peer 0 sends every block of the piece, so the bad data is its own,
+        // but one bad piece isn't enough for a ban.
+        swarm.fill(0);
+        assert_eq!(swarm.connections[&0].requests.len(), 2);
+        answer(&mut swarm, 0, &bad).await;
+        verified(&mut swarm, &mut verdicts).await;
+        assert!(!swarm.reputation.is_banned(addr.ip()));
+        assert!(swarm.connections.contains_key(&0));
+
+        // A second one is.
+        swarm.fill(0);
+        assert_eq!(swarm.connections[&0].requests.len(), 2);
+        answer(&mut swarm, 0, &bad).await;
+        verified(&mut swarm, &mut verdicts).await;
+        assert!(swarm.reputation.is_banned(addr.ip()));
+        assert!(!swarm.connections.contains_key(&0));
+        // Its connection was dropped, which closes the command channel.
+        while commands.try_recv().is_ok() {}
+        assert!(matches!(
+            commands.try_recv(),
+            Err(mpsc::error::TryRecvError::Disconnected)
+        ));
+
+        // It isn't tried again, while the piece goes to another peer, once.
+        swarm.add_candidates(vec![addr]);
+        assert!(swarm.candidates.is_empty());
+        let _other = connect(&mut swarm, 1);
+        swarm.fill(1);
+        assert_eq!(swarm.connections[&1].requests.len(), 2);
+        answer(&mut swarm, 1, &good).await;
+        verified(&mut swarm, &mut verdicts).await;
+        assert_eq!(written.try_recv().unwrap().0, 0);
     }
 
-    pub fn bytes(&self) -> &'d [u8] {
-        self.bytes
+    #[tokio::test]
+    async fn test_corrupt_pieces_are_blamed_on_their_senders() {
+        let good = vec![7; 2 * BLOCK_SIZE as usize];
+        let bad = vec![8; 2 * BLOCK_SIZE as usize];
+        let mut t = torrent();
+        t.info.pieces = Hashes(vec![hash::sha1(&[&good])]);
+        let mut swarm = swarm(&t);
+        let (verdicts_tx, mut verdicts) = mpsc::unbounded_channel();
+        swarm.verified = verdicts_tx;
+        let _commands = [connect(&mut swarm, 0), connect(&mut swarm, 1)];
+        let ips = [0, 1].map(|peer_i| swarm.connections[&peer_i].addr.ip());
+        for peer_i in [0, 1] {
+            swarm.connections.get_mut(&peer_i).unwrap().snubbed = true;
+            swarm.fill(peer_i);
+        }
+        // Peer 2 sends nothing of the piece, so none of the blame is its.
+        let _idle = connect(&mut swarm, 2);
+        let idle = swarm.connections[&2].addr.ip();
+
+        // Each block comes from the peer it was asked of, and the peers
+        // behind a bad piece share the blame for it.
+        for peer_i in [0, 1] {
+            answer(&mut swarm, peer_i, &bad).await;
+        }
+        verified(&mut swarm, &mut verdicts).await;
+        assert_eq!(ips.map(|ip| swarm.reputation.score(ip)), [50, 50]);
+        assert_eq!(swarm.reputation.score(idle), 0);
+
+        // Having sent a block since, peer 0 is asked for the whole piece; a
+        // second bad piece gets it banned and dropped.
+        assert_eq!(swarm.connections[&0].requests.len(), 2);
+        answer(&mut swarm, 0, &bad).await;
+        verified(&mut swarm, &mut verdicts).await;
+        assert!(swarm.reputation.is_banned(ips[0]));
+        assert!(!swarm.reputation.is_banned(ips[1]));
+        assert_eq!(swarm.reputation.score(ips[1]), 50);
+        assert_eq!(swarm.reputation.score(idle), 0);
+        assert!(!swarm.connections.contains_key(&0));
+        assert!(swarm.connections.contains_key(&1));
+        assert!(swarm.connections.contains_key(&2));
+    }
+
+    #[tokio::test]
+    async fn test_piece_failing_too_often_fails_the_download() {
+        let t = torrent();
+        let mut swarm = swarm(&t);
+        let _commands = connect(&mut swarm, 0);
+        swarm.fill(0);
+
+        // Nobody sent the bad data, so nobody is to blame, and the piece
+        // starts over each time but the last.
+        let data = Bytes::from(vec![0; t.length()]);
+        for _ in 1..MAX_PIECE_FAILURES {
+            swarm.finish_piece(0, data.clone(), false).await.unwrap();
+            assert!(swarm.in_progress.contains_key(&0));
+            assert!(swarm.connections.contains_key(&0));
+        }
+        let e = swarm.finish_piece(0, data, false).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("piece 0 failed the hash check {MAX_PIECE_FAILURES} times")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_seen_peers_are_retried_unless_banned() {
+        let t = torrent();
+        let mut swarm = swarm(&t);
+        let addrs: Vec<SocketAddr> = (1..=3).map(|i| ([127, 0, 0, i], 6881).into()).collect();
+        swarm.add_candidates(addrs.clone());
+        // Each was tried, and failed.
+        swarm.candidates.clear();
+        for _ in 0..2 {
+            swarm.penalize_addr(addrs[1], Offense::SoleCorruptData);
+        }
+
+        swarm.retry_seen();
+        let mut retried = swarm.candidates.clone();
+        retried.sort();
+        assert_eq!(retried, [addrs[0], addrs[2]]);
+    }
+
+    #[tokio::test]
+    async fn test_private_torrent_stays_with_its_trackers() {
+        let bind = || tokio::net::UdpSocket::bind("127.0.0.1:0");
+        let (first, second, dht_node) = (
+            bind().await.unwrap(),
+            bind().await.unwrap(),
+            bind().await.unwrap(),
+        );
+        let url = |socket: &tokio::net::UdpSocket| {
+            format!("udp://{}/announce", socket.local_addr().unwrap())
+        };
+
+        let mut t = torrent();
+        t.info.private = Some(1);
+        t.announce_list = vec![vec![url(&first)], vec![url(&second)]];
+        t.nodes = vec![(
+            "127.0.0.1".to_string(),
+            dht_node.local_addr().unwrap().port(),
+        )];
+        tokio::spawn(udp::serve(
+            first,
+            [10, 0, 0, 1, 0, 1],
+            mpsc::unbounded_channel().0,
+        ));
+
+        // Announcing to every tier would hand the second tracker our peers.
+        let shared = Shared::new(AnnounceMode::AllTiers, tracker::Options::default());
+        let mut tiers = t.tiers();
+        assert!(tiers.is_private());
+        let announce = Announce::new(t.info_hash(), t.length());
+        let announced = find_peers(&shared, &mut tiers, &t.nodes, &announce)
+            .await
+            .unwrap();
+        assert_eq!(announced.peers, ["10.0.0.1:1".parse().unwrap()]);
+
+        // Neither the other tier nor the DHT heard from us.
+        let mut buf = [0; 128];
+        for socket in [&second, &dht_node] {
+            let heard =
+                tokio::time::timeout(Duration::from_millis(100), socket.recv_from(&mut buf));
+            assert!(heard.await.is_err());
+        }
+    }
+
+    /// A control that seeds if `seed` says so, until `stop` turns true.
+    fn control(
+        t: &Torrent,
+        seed: bool,
+    ) -> (
+        Control,
+        watch::Sender<bool>,
+        broadcast::Receiver<TorrentEvent>,
+    ) {
+        let (stop, stop_rx) = watch::channel(false);
+        let (events, events_rx) = broadcast::channel(64);
+        let control = Control {
+            paused: watch::channel(false).1,
+            stop: stop_rx,
+            disk: Disk::default(),
+            priorities: watch::channel(vec![Priority::Normal; t.info.file_lengths().len()]).1,
+            progress: watch::channel(Default::default()).0,
+            peers: watch::channel(Vec::new()).0,
+            events,
+            seed,
+            active: None,
+        };
+        (control, stop, events_rx)
+    }
+
+    /// An incoming connection for the torrent of `info_hash` whose handshake
+    /// was read, and the remote end of it.
+    async fn incoming(info_hash: [u8; 20]) -> (Incoming, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let mut handshake = Handshake::new(&info_hash);
+        handshake.reserved = vec![0; 8];
+        handshake.peer_id = vec![1; 20];
+        let stream = mse::Stream::plaintext(Transport::Tcp(stream));
+        ((addr, stream, handshake), remote)
+    }
+
+    /// Reads messages from `stream` until one with `id`, and returns its
+    /// payload.
+    async fn expect(stream: &mut TcpStream, id: MessageId) -> Bytes {
+        let mut buffers = BufferPool::default();
+        loop {
+            let msg = Message::decode(stream, &mut buffers, 1 << 20)
+                .await
+                .unwrap();
+            if let Some(msg) = msg.filter(|msg| msg.id == id) {
+                return msg.payload;
+            }
+        }
+    }
+
+    /// Asks for block `block_i` of piece 0, as a peer that wants it does,
+    /// and returns the data sent back.
+    async fn request(stream: &mut TcpStream, block_i: u32) -> Bytes {
+        Message::encode(stream, MessageId::Interested, &mut [])
+            .await
+            .unwrap();
+        expect(stream, MessageId::Unchoke).await;
+        let mut request = block::Request::new(0, block_i, 2 * BLOCK_SIZE).encode();
+        Message::encode(stream, MessageId::Request, &mut request)
+            .await
+            .unwrap();
+        expect(stream, MessageId::Piece).await.slice(8..)
+    }
+
+    /// Takes the handshake of an incoming connection to a torrent of one
+    /// piece, and answers the requests for its two blocks with `piece`, as a
+    /// seeder does.
+    async fn send_piece(remote: &mut TcpStream, piece: &[u8]) {
+        let mut handshake = [0; 68];
+        remote.read_exact(&mut handshake).await.unwrap();
+        Message::encode(remote, MessageId::Bitfield, &mut [0b1000_0000])
+            .await
+            .unwrap();
+        Message::encode(remote, MessageId::Unchoke, &mut [])
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let request = expect(remote, MessageId::Request).await;
+            let request = block::Request::decode(&request).unwrap();
+            let data = &piece[request.begin as usize..][..request.length as usize];
+            let mut payload = [&request.encode()[..8], data].concat();
+            Message::encode(remote, MessageId::Piece, &mut payload)
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Runs our end of an incoming connection for `t` with the pieces in
+    /// `have`, stored at `output`, within `limits`, and returns the remote
+    /// end, past our handshake, and the commands to the peer.
+    async fn serve(
+        t: &Torrent,
+        have: &[usize],
+        output: &Path,
+        limits: Limits,
+    ) -> (TcpStream, mpsc::UnboundedSender<Command>) {
+        let storage = Arc::new(Layout::new(t, output));
+        let mut completed = Completed::new(t, storage);
+        for &piece_i in have {
+            completed.insert(piece_i);
+        }
+        let ((addr, stream, handshake), mut remote) = incoming(t.info_hash()).await;
+        let peer = Peer::accept(addr, stream, &handshake).await.unwrap();
+        let mut ours = [0; 68];
+        remote.read_exact(&mut ours).await.unwrap();
+
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (events, mut events_rx) = mpsc::channel(8);
+        tokio::spawn(async move { while events_rx.recv().await.is_some() {} });
+        tokio::spawn(peer.run(
+            0,
+            Arc::new(RwLock::new(completed)),
+            Arc::new(limits),
+            Duration::from_secs(120),
+            events,
+            commands_rx,
+        ));
+        (remote, commands)
+    }
+
+    #[tokio::test]
+    async fn test_bitfield_opens_the_connection_unless_empty() {
+        let t = Torrent::for_test(BLOCK_SIZE as usize, &[3 * BLOCK_SIZE as usize]);
+        let mut buffers = BufferPool::default();
+
+        let output = std::env::temp_dir().join("serve");
+        let (mut remote, _commands) = serve(&t, &[0, 2], &output, Limits::default()).await;
+        let msg = Message::decode(&mut remote, &mut buffers, 1 << 20).await;
+        let msg = msg.unwrap().unwrap();
+        assert_eq!(msg.id, MessageId::Bitfield);
+        assert_eq!(msg.payload[..], [0b1010_0000]);
+
+        // With nothing to announce, what comes first is what we send next.
+        let (mut remote, commands) = serve(&t, &[], &output, Limits::default()).await;
+        commands.send(Command::Interested).unwrap();
+        let msg = Message::decode(&mut remote, &mut buffers, 1 << 20).await;
+        assert_eq!(msg.unwrap().unwrap().id, MessageId::Interested);
+    }
+
+    #[tokio::test]
+    async fn test_commands_do_not_wait_for_uploads() {
+        let t = torrent();
+        let output = std::env::temp_dir().join(format!("upload-test-{}", std::process::id()));
+        tokio::fs::write(&output, vec![1; 2 * BLOCK_SIZE as usize])
+            .await
+            .unwrap();
+        // One block a minute.
+        let limits = Limits {
+            up: RateLimiter::new(Some(BLOCK_SIZE as u64 / 60)),
+            ..Default::default()
+        };
+        let (mut remote, commands) = serve(&t, &[0], &output, limits).await;
+        commands.send(Command::Unchoke).unwrap();
+        expect(&mut remote, MessageId::Unchoke).await;
+
+        // The first block uses up the limit, so the second one waits.
+        for block_i in 0..2 {
+            let mut request = block::Request::new(0, block_i, 2 * BLOCK_SIZE).encode();
+            Message::encode(&mut remote, MessageId::Request, &mut request)
+                .await
+                .unwrap();
+        }
+        expect(&mut remote, MessageId::Piece).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        commands.send(Command::Have(0)).unwrap();
+        let have =
+            tokio::time::timeout(Duration::from_secs(5), expect(&mut remote, MessageId::Have))
+                .await
+                .unwrap();
+        assert_eq!(have[..], 0u32.to_be_bytes());
+        tokio::fs::remove_file(&output).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_seeds_torrent_complete_at_start() {
+        let good = vec![7; 2 * BLOCK_SIZE as usize];
+        let mut t = torrent();
+        t.info.pieces = Hashes(vec![hash::sha1(&[&good])]);
+        // Keeps the DHT out of it.
+        t.info.private = Some(1);
+        let dir = std::env::temp_dir().join(format!("seed-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let output = dir.join("test");
+        tokio::fs::write(&output, &good).await.unwrap();
+        let t = Arc::new(t);
+
+        // Without seeding there is nothing to do.
+        let (leech, _stop, mut events) = control(&t, false);
+        run(&t, &output, &Shared::default(), None, leech)
+            .await
+            .unwrap();
+        assert_eq!(events.recv().await.unwrap(), TorrentEvent::Completed);
+
+        let (control, stop, mut events) = control(&t, true);
+        let (incoming_tx, incoming_rx) = mpsc::channel(1);
+        let seeding = tokio::spawn({
+            let (t, output) = (Arc::clone(&t), output.clone());
+            async move { run(&t, &output, &Shared::default(), Some(incoming_rx), control).await }
+        });
+        while events.recv().await.unwrap() != TorrentEvent::Completed {}
+
+        let (connection, mut remote) = incoming(t.info_hash()).await;
+        incoming_tx.send(connection).await.unwrap();
+        let serve = async {
+            let mut handshake = [0; 68];
+            remote.read_exact(&mut handshake).await.unwrap();
+            expect(&mut remote, MessageId::Bitfield).await;
+            request(&mut remote, 1).await
+        };
+        let block = tokio::time::timeout(Duration::from_secs(10), serve)
+            .await
+            .unwrap();
+        assert_eq!(block, good[BLOCK_SIZE as usize..]);
+
+        // Stopping a seed is how it ends, not a failure.
+        stop.send_replace(true);
+        seeding.await.unwrap().unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_seeds_once_download_completes() {
+        let good: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| i as u8).collect();
+        let mut t = torrent();
+        t.info.pieces = Hashes(vec![hash::sha1(&[&good])]);
+        t.info.private = Some(1);
+        // The tracker only knows a peer that isn't there.
+        let tracker = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        t.announce = Some(format!("udp://{}/announce", tracker.local_addr().unwrap()));
+        tokio::spawn(udp::serve(
+            tracker,
+            [127, 0, 0, 1, 0, 1],
+            mpsc::unbounded_channel().0,
+        ));
+        let dir = std::env::temp_dir().join(format!("seed-after-test-{}", std::process::id()));
+        let output = dir.join("test");
+        let t = Arc::new(t);
+
+        let (control, stop, mut events) = control(&t, true);
+        let (incoming_tx, incoming_rx) = mpsc::channel(1);
+        let seeding = tokio::spawn({
+            let (t, output) = (Arc::clone(&t), output.clone());
+            async move { run(&t, &output, &Shared::default(), Some(incoming_rx), control).await }
+        });
+
+        // The peer that has the piece sends it, and then wants it back.
+        let (connection, mut remote) = incoming(t.info_hash()).await;
+        incoming_tx.send(connection).await.unwrap();
+        let exchange = async {
+            send_piece(&mut remote, &good).await;
+            while events.recv().await.unwrap() != TorrentEvent::Completed {}
+
+            request(&mut remote, 0).await
+        };
+        let block = tokio::time::timeout(Duration::from_secs(10), exchange)
+            .await
+            .unwrap();
+        assert_eq!(block, good[..BLOCK_SIZE as usize]);
+        assert_eq!(tokio::fs::read(&output).await.unwrap(), good);
+
+        stop.send_replace(true);
+        seeding.await.unwrap().unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    /// Downloads a torrent of one piece from a peer that connects to us, and
+    /// returns the event, downloaded and left fields of the announces to its
+    /// tracker; a seed is stopped once complete.
+    async fn announces(seed: bool) -> Vec<(u32, u64, u64)> {
+        let good = vec![5; 2 * BLOCK_SIZE as usize];
+        let mut t = torrent();
+        t.info.pieces = Hashes(vec![hash::sha1(&[&good])]);
+        t.info.private = Some(1);
+        let tracker = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        t.announce = Some(format!("udp://{}/announce", tracker.local_addr().unwrap()));
+        let (announces_tx, mut announces) = mpsc::unbounded_channel();
+        tokio::spawn(udp::serve(tracker, [127, 0, 0, 1, 0, 1], announces_tx));
+        let name = format!("announce-test-{seed}-{}", std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        let output = dir.join("test");
+        let t = Arc::new(t);
+
+        let (control, stop, mut events) = control(&t, seed);
+        let (incoming_tx, incoming_rx) = mpsc::channel(1);
+        let download = tokio::spawn({
+            let (t, output) = (Arc::clone(&t), output.clone());
+            async move { run(&t, &output, &Shared::default(), Some(incoming_rx), control).await }
+        });
+        let (connection, mut remote) = incoming(t.info_hash()).await;
+        incoming_tx.send(connection).await.unwrap();
+        let mut sent = Vec::new();
+        let exchange = async {
+            send_piece(&mut remote, &good).await;
+            while events.recv().await.unwrap() != TorrentEvent::Completed {}
+            if seed {
+                // Completed is announced while seeding, not once stopped.
+                loop {
+                    let announce = announces.recv().await.unwrap();
+                    sent.push(announce);
+                    if announce.0 == 1 {
+                        break;
+                    }
+                }
+                stop.send_replace(true);
+            }
+            download.await.unwrap().unwrap();
+        };
+        tokio::time::timeout(Duration::from_secs(10), exchange)
+            .await
+            .unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+
+        while let Ok(announce) = announces.try_recv() {
+            sent.push(announce);
+        }
+        sent
+    }
+
+    #[tokio::test]
+    async fn test_announces_follow_the_download() {
+        let length = 2 * BLOCK_SIZE as u64;
+        // Started with everything left, then Completed and Stopped with it
+        // all downloaded, whether or not Stopped waits for the seed to end.
+        for seed in [false, true] {
+            assert_eq!(
+                announces(seed).await,
+                [(2, 0, length), (1, length, 0), (3, length, 0)],
+                "seed: {seed}"
+            );
+        }
     }
 }
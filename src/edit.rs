@@ -0,0 +1,220 @@
+use anyhow::{anyhow, Context};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{bencode::bencode_len, torrent::Torrent};
+
+/// Changes to make to a `.torrent` file.
+#[derive(Debug, Clone, Default)]
+pub struct Edit {
+    /// Trackers to add, each as a tier of its own after the existing ones.
+    pub add_trackers: Vec<String>,
+
+    /// Trackers to remove from every tier.
+    pub remove_trackers: Vec<String>,
+
+    /// The new comment; an empty one removes it.
+    pub comment: Option<String>,
+
+    /// Whether the torrent is private (BEP 27). Unlike the other changes,
+    /// this is part of the info dictionary and so changes the info hash.
+    pub private: Option<bool>,
+}
+
+/// Applies `edit` to the bencoded torrent `dot_torrent`, returning the new
+/// torrent and its info hash.
+///
+/// Everything that isn't edited, the info dictionary in particular, is kept
+/// byte for byte, so the info hash stays the same unless the private flag
+/// changes.
+pub fn apply(dot_torrent: &[u8], edit: &Edit) -> anyhow::Result<(Vec<u8>, [u8; 20])> {
+    let mut torrent = RawDict::parse(dot_torrent).context("parse torrent file")?;
+    anyhow::ensure!(
+        torrent.raw("info").is_some(),
+        "torrent has no info dictionary"
+    );
+
+    let mut tiers: Vec<Vec<String>> = torrent.get("announce-list")?.unwrap_or_default();
+    if tiers.iter().all(|tier| tier.is_empty()) {
+        tiers = torrent
+            .get::<String>("announce")?
+            .map(|url| vec![vec![url]])
+            .unwrap_or_default();
+    }
+    for tier in &mut tiers {
+        tier.retain(|url| !edit.remove_trackers.contains(url));
+    }
+    for url in &edit.add_trackers {
+        if !tiers.iter().flatten().any(|existing| existing == url) {
+            tiers.push(vec![url.clone()]);
+        }
+    }
+    tiers.retain(|tier| !tier.is_empty());
+
+    match tiers.first() {
+        Some(tier) => torrent.set("announce", &tier[0])?,
+        None => torrent.remove("announce"),
+    }
+    if tiers.iter().map(Vec::len).sum::<usize>() > 1 {
+        torrent.set("announce-list", &tiers)?;
+    } else {
+        torrent.remove("announce-list");
+    }
+
+    match edit.comment.as_deref() {
+        Some("") => torrent.remove("comment"),
+        Some(comment) => torrent.set("comment", comment)?,
+        None => {}
+    }
+
+    if let Some(private) = edit.private {
+        let mut info = RawDict::parse(torrent.raw("info").expect("checked above"))
+            .context("parse info dictionary")?;
+        if private {
+            info.set("private", 1)?;
+        } else {
+            info.remove("private");
+        }
+        torrent.set_raw("info", info.to_bytes());
+    }
+
+    // Hashed as the torrent would be: v2-only ones by a truncated SHA-256.
+    let bytes = torrent.to_bytes();
+    let t = Torrent::from_bytes(&bytes).context("parse edited torrent")?;
+    Ok((bytes, t.info_hash()))
+}
+
+/// A bencoded dictionary whose values are kept as the bytes they were
+/// encoded as, in their original order.
+struct RawDict {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl RawDict {
+    fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        let len = bencode_len(bytes).ok_or_else(|| anyhow!("invalid bencode"))?;
+        anyhow::ensure!(len == bytes.len(), "trailing data after the dictionary");
+        anyhow::ensure!(bytes[0] == b'd', "not a dictionary");
+
+        let mut entries = Vec::new();
+        let mut offset = 1;
+        while bytes[offset] != b'e' {
+            let key_len = bencode_len(&bytes[offset..]).expect("checked above");
+            let key: serde_bytes::ByteBuf =
+                serde_bencode::from_bytes(&bytes[offset..offset + key_len])
+                    .context("dictionary key is not a string")?;
+            offset += key_len;
+
+            let value_len = bencode_len(&bytes[offset..]).expect("checked above");
+            entries.push((key.into_vec(), bytes[offset..offset + value_len].to_vec()));
+            offset += value_len;
+        }
+
+        Ok(Self { entries })
+    }
+
+    fn raw(&self, key: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key.as_bytes())
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn get<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
+        self.raw(key)
+            .map(|value| serde_bencode::from_bytes(value).with_context(|| format!("parse {key}")))
+            .transpose()
+    }
+
+    fn set(&mut self, key: &str, value: impl Serialize) -> anyhow::Result<()> {
+        let value = serde_bencode::to_bytes(&value).with_context(|| format!("encode {key}"))?;
+        self.set_raw(key, value);
+        Ok(())
+    }
+
+    /// Replaces the value of `key` in place, or inserts it where it sorts.
+    fn set_raw(&mut self, key: &str, value: Vec<u8>) {
+        let key = key.as_bytes();
+        if let Some((_, existing)) = self.entries.iter_mut().find(|(k, _)| k == key) {
+            *existing = value;
+            return;
+        }
+        let at = self
+            .entries
+            .iter()
+            .position(|(k, _)| k.as_slice() > key)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(at, (key.to_vec(), value));
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.entries.retain(|(k, _)| k != key.as_bytes());
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![b'd'];
+        for (key, value) in &self.entries {
+            bytes.extend(key.len().to_string().as_bytes());
+            bytes.push(b':');
+            bytes.extend(key);
+            bytes.extend(value);
+        }
+        bytes.push(b'e');
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, Edit};
+    use crate::hash;
+
+    // The info dictionary's keys are out of order, which re-encoding it
+    // would fix and so change the info hash.
+    const INFO: &[u8] =
+        b"d4:name1:a6:lengthi3e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+
+    fn torrent(before_info: &[u8]) -> Vec<u8> {
+        [b"d".as_slice(), before_info, b"4:info", INFO, b"e"].concat()
+    }
+
+    #[test]
+    fn test_edit_keeps_info() {
+        let before = torrent(b"8:announce15:http://a/trackr");
+        let edit = Edit {
+            add_trackers: vec!["http://b/trackr".to_string()],
+            comment: Some("hi".to_string()),
+            ..Edit::default()
+        };
+        let (after, _) = apply(&before, &edit).unwrap();
+
+        let expected = torrent(
+            b"8:announce15:http://a/trackr13:announce-listll15:http://a/trackrel15:http://b/trackree7:comment2:hi",
+        );
+        assert_eq!(after, expected);
+
+        let edit = Edit {
+            remove_trackers: vec!["http://a/trackr".to_string()],
+            comment: Some(String::new()),
+            ..Edit::default()
+        };
+        let (after, _) = apply(&after, &edit).unwrap();
+        assert_eq!(after, torrent(b"8:announce15:http://b/trackr"));
+    }
+
+    #[test]
+    fn test_info_hash() {
+        let (_, info_hash) = apply(&torrent(b""), &Edit::default()).unwrap();
+        assert_eq!(info_hash, hash::sha1(&[INFO]));
+
+        // A v2-only torrent is known by its truncated SHA-256 info hash.
+        let info = [
+            b"d9:file treed1:ad0:d6:lengthi3e11:pieces root32:".as_slice(),
+            &[0xAA; 32],
+            b"eee12:meta versioni2e4:name1:a12:piece lengthi16384ee",
+        ]
+        .concat();
+        let before = [b"d4:info".as_slice(), &info, b"e"].concat();
+        let (_, info_hash) = apply(&before, &Edit::default()).unwrap();
+        assert_eq!(info_hash, hash::sha256(&[&info])[..20]);
+    }
+}
@@ -0,0 +1,82 @@
+/// The SHA-1 hash of `parts` one after the other.
+pub fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    #[cfg(feature = "openssl")]
+    {
+        let mut hasher = openssl::sha::Sha1::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finish()
+    }
+    #[cfg(not(feature = "openssl"))]
+    {
+        use sha1::Digest;
+
+        let mut hasher = sha1::Sha1::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// The SHA-256 hash of `parts` one after the other.
+pub fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    #[cfg(feature = "openssl")]
+    {
+        let mut hasher = openssl::sha::Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finish()
+    }
+    #[cfg(not(feature = "openssl"))]
+    {
+        use sha2::Digest;
+
+        let mut hasher = sha2::Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// Which implementation the hashes run on, for logging. Hashing takes most
+/// of the CPU a fast download uses.
+///
+/// By default these are the RustCrypto implementations, which use the SHA
+/// extensions of x86 CPUs that have them, as detected at runtime. The `asm`
+/// feature swaps in their assembly implementations for CPUs without, and the
+/// `openssl` feature OpenSSL's.
+pub fn backend() -> &'static str {
+    if cfg!(feature = "openssl") {
+        return "openssl";
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if std::arch::is_x86_feature_detected!("sha") {
+        return "sha-ni";
+    }
+    if cfg!(feature = "asm") {
+        "asm"
+    } else {
+        "soft"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sha1, sha256};
+
+    #[test]
+    fn test_hashes() {
+        assert_eq!(
+            hex::encode(sha1(&[b"a", b"bc"])),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex::encode(sha256(&[b"abc"])),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -1,6 +1,27 @@
+pub mod bencode;
 pub mod block;
+pub mod blocklist;
+pub mod choke;
+pub mod config;
+pub mod create;
+pub mod dht;
 pub mod download;
+pub mod edit;
+pub mod hash;
+pub mod magnet;
+pub mod memory;
+pub mod merkle;
+pub mod mse;
 pub mod peer;
 pub mod piece;
+pub mod pipeline;
+pub mod rate;
+pub mod reputation;
+pub mod resume;
+#[cfg(feature = "webtorrent")]
+pub mod rtc;
+pub mod session;
+pub mod storage;
 pub mod torrent;
 pub mod tracker;
+pub mod webtorrent;
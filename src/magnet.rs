@@ -0,0 +1,416 @@
+use std::{fmt, time::Duration};
+
+use anyhow::{anyhow, Context};
+use futures_util::StreamExt;
+use tracing::{debug, warn};
+
+use crate::{
+    download,
+    peer::Peer,
+    session::Shared,
+    torrent::{Info, Torrent},
+    tracker::{Announce, Tiers},
+};
+
+/// How long a peer may take to send the metadata, and the piece layers of a
+/// v2-only torrent.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A parsed `magnet:?xt=urn:btih:...` URI, or `urn:btmh:` for v2 torrents.
+///
+/// Only the keys needed to bootstrap a download are kept: the info hash, the
+/// display name, the trackers and the web seeds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    /// The v1 info hash, or for v2-only torrents the v2 one truncated.
+    pub info_hash: [u8; 20],
+
+    /// The SHA-256 info hash of a v2 or hybrid torrent (BEP 52).
+    pub info_hash_v2: Option<[u8; 32]>,
+
+    /// `dn`, the suggested display name.
+    pub name: Option<String>,
+
+    /// Every `tr` parameter, in the order they appear.
+    pub trackers: Vec<String>,
+
+    /// Every `ws` parameter, web seeds to download from over HTTP.
+    pub web_seeds: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> anyhow::Result<Self> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| anyhow!("not a magnet uri: {uri}"))?;
+
+        let mut info_hash = None;
+        let mut info_hash_v2 = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut web_seeds = Vec::new();
+
+        for pair in query.split('&') {
+            let Some((key, value)) = pair.split_once('=') else {
+                continue;
+            };
+            let value = urlencoding::decode(value).context("decode magnet parameter")?;
+
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    } else if let Some(hash) = value.strip_prefix("urn:btmh:") {
+                        info_hash_v2 = Some(parse_multihash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "ws" => web_seeds.push(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        let info_hash = match (info_hash, info_hash_v2) {
+            (Some(info_hash), _) => info_hash,
+            (None, Some(v2)) => v2[..20].try_into().expect("20 bytes"),
+            (None, None) => return Err(anyhow!("magnet uri has no urn:btih: or urn:btmh: topic")),
+        };
+
+        Ok(Self {
+            info_hash,
+            info_hash_v2,
+            name,
+            trackers,
+            web_seeds,
+        })
+    }
+
+    /// A magnet of nothing but an info hash, 40 hex or 32 base32 characters.
+    /// Its peers can only come from the DHT.
+    pub fn from_info_hash(hash: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            info_hash: parse_info_hash(hash)?,
+            info_hash_v2: None,
+            name: None,
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+        })
+    }
+
+    /// The magnet of a torrent, with every tracker of every tier.
+    pub fn from_torrent(t: &Torrent) -> Self {
+        let mut trackers: Vec<String> = Vec::new();
+        for url in t.announce.iter().chain(t.announce_list.iter().flatten()) {
+            if !trackers.contains(url) {
+                trackers.push(url.clone());
+            }
+        }
+
+        Self {
+            info_hash: t.info_hash(),
+            info_hash_v2: t.info.has_v2().then(|| t.info_hash_v2()),
+            name: Some(t.info.name.clone()),
+            trackers,
+            web_seeds: t.url_list.clone(),
+        }
+    }
+
+    /// Fetches the info dictionary from the swarm (BEP 9) and turns the magnet
+    /// into a regular [`Torrent`], finding and connecting to peers as the
+    /// session's `shared` settings say.
+    pub async fn resolve(&self, shared: &Shared) -> anyhow::Result<Torrent> {
+        // Each `tr` parameter is a tier of its own, tried in the given order.
+        let announce_list: Vec<Vec<String>> = self
+            .trackers
+            .iter()
+            .map(|tracker| vec![tracker.clone()])
+            .collect();
+
+        // The size is unknown until we have the metadata; claim we still need
+        // something so that trackers hand out seeders.
+        let peers = download::find_peers(
+            shared,
+            &mut Tiers::new(announce_list.clone()),
+            &[],
+            &Announce::new(self.info_hash, 1),
+        )
+        .await?
+        .peers;
+
+        let allowed = peers.into_iter().filter(|addr| {
+            !shared.blocklist().contains(addr.ip()) && !shared.reputation().is_banned(addr.ip())
+        });
+        let mut peers = futures_util::stream::iter(allowed)
+            .map(|peer_addr| async move {
+                let connect = Peer::new(peer_addr, &self.info_hash, shared.encryption());
+                let peer = tokio::time::timeout(download::CONNECT_TIMEOUT, connect)
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("timed out")));
+                (peer_addr, peer)
+            })
+            .buffer_unordered(5);
+
+        while let Some((peer_addr, peer)) = peers.next().await {
+            let mut peer = match peer {
+                Ok(peer) => peer,
+                Err(e) => {
+                    debug!(%peer_addr, error = %e, "Could not handshake");
+                    continue;
+                }
+            };
+
+            match self
+                .fetch_from(&mut peer, announce_list.clone(), METADATA_TIMEOUT)
+                .await
+            {
+                Ok(t) => return Ok(t),
+                Err(e) => warn!(%peer_addr, error = %e, "Could not fetch metadata"),
+            }
+        }
+
+        Err(anyhow!("no peer could provide the metadata"))
+    }
+
+    /// Fetches the metadata from `peer`, and the piece layers too for a
+    /// v2-only torrent, giving up on the peer after `deadline`.
+    async fn fetch_from(
+        &self,
+        peer: &mut Peer,
+        announce_list: Vec<Vec<String>>,
+        deadline: Duration,
+    ) -> anyhow::Result<Torrent> {
+        let fetch = async {
+            let metadata = peer.fetch_metadata(&self.info_hash).await?;
+            let info: Info = serde_bencode::from_bytes(&metadata).context("parse metadata")?;
+            info.check_piece_length()?;
+            info.check_paths()?;
+
+            let mut t = Torrent {
+                announce: self.trackers.first().cloned(),
+                announce_list,
+                nodes: Vec::new(),
+                url_list: self.web_seeds.clone(),
+                info,
+                piece_layers: Default::default(),
+                info_bytes: Some(metadata),
+                length: Default::default(),
+            };
+            // Without v1 hashes, pieces are checked against the piece layers,
+            // which aren't part of the metadata.
+            if !t.info.has_v1() {
+                t.piece_layers = peer
+                    .fetch_piece_layers(&t.info)
+                    .await
+                    .context("fetch piece layers")?;
+            }
+            Ok(t)
+        };
+
+        tokio::time::timeout(deadline, fetch)
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("timed out after {deadline:?}")))
+    }
+}
+
+impl fmt::Display for Magnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.info_hash_v2 {
+            // A v2-only torrent has no v1 info hash to give.
+            Some(v2) if v2[..20] == self.info_hash => {
+                write!(f, "magnet:?xt=urn:btmh:1220{}", hex::encode(v2))?;
+            }
+            Some(v2) => write!(
+                f,
+                "magnet:?xt=urn:btih:{}&xt=urn:btmh:1220{}",
+                hex::encode(self.info_hash),
+                hex::encode(v2)
+            )?,
+            None => write!(f, "magnet:?xt=urn:btih:{}", hex::encode(self.info_hash))?,
+        }
+        if let Some(name) = &self.name {
+            write!(f, "&dn={}", urlencoding::encode(name))?;
+        }
+        for tracker in &self.trackers {
+            write!(f, "&tr={}", urlencoding::encode(tracker))?;
+        }
+        for web_seed in &self.web_seeds {
+            write!(f, "&ws={}", urlencoding::encode(web_seed))?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a `btih` info hash, which is either 40 hex characters or 32 base32
+/// characters.
+fn parse_info_hash(hash: &str) -> anyhow::Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("decode hex info hash")?,
+        32 => base32_decode(hash).ok_or_else(|| anyhow!("invalid base32 info hash: {hash}"))?,
+        len => return Err(anyhow!("info hash has unexpected length {len}")),
+    };
+
+    Ok(bytes.try_into().expect("both encodings decode to 20 bytes"))
+}
+
+/// Decodes a `btmh` info hash: a SHA-256 multihash, `1220` and 64 hex
+/// characters.
+fn parse_multihash(hash: &str) -> anyhow::Result<[u8; 32]> {
+    let hash = hash
+        .strip_prefix("1220")
+        .ok_or_else(|| anyhow!("info hash is not a SHA-256 multihash: {hash}"))?;
+    let bytes = hex::decode(hash).context("decode hex info hash")?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| anyhow!("info hash has unexpected length {}", bytes.len()))
+}
+
+/// RFC 4648 base32 without padding.
+fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::Magnet;
+    use crate::{
+        hash,
+        mse::Encryption,
+        peer::{Handshake, Peer},
+        torrent::Torrent,
+    };
+
+    /// A peer that answers the handshake, advertising the extension protocol
+    /// if `extensions` says so, and then sends nothing.
+    async fn silent_peer(extensions: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            let mut ours = Handshake::from_bytes(&handshake);
+            ours.reserved = vec![0, 0, 0, 0, 0, if extensions { 0x10 } else { 0 }, 0, 0];
+            stream.write_all(&ours.bytes()).await.unwrap();
+            // Read whatever comes, answering nothing.
+            let mut buf = [0; 1024];
+            while stream.read(&mut buf).await.is_ok_and(|len| len > 0) {}
+        });
+        addr
+    }
+
+    #[test]
+    fn test_parse_magnet() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample.torrent&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce",
+        )
+        .unwrap();
+
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+        assert_eq!(magnet.name.as_deref(), Some("sample.torrent"));
+        assert_eq!(
+            magnet.trackers,
+            vec!["http://bittorrent-test-tracker.codecrafters.io/announce"]
+        );
+    }
+
+    #[test]
+    fn test_parse_base32_magnet() {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap();
+
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+    }
+
+    #[test]
+    fn test_from_info_hash() {
+        let hex = Magnet::from_info_hash("d69f91e6b2ae4c542468d1073a71d4ea13879a7f").unwrap();
+        let base32 = Magnet::from_info_hash("22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap();
+
+        assert_eq!(hex, base32);
+        assert!(hex.trackers.is_empty());
+        assert!(Magnet::from_info_hash("sample.torrent").is_err());
+    }
+
+    #[test]
+    fn test_magnet_round_trip() {
+        let magnet = Magnet {
+            info_hash: [0xab; 20],
+            info_hash_v2: Some([0xcd; 32]),
+            name: Some("a file & more".to_string()),
+            trackers: vec![
+                "udp://tracker.example:6969/announce".to_string(),
+                "http://tracker.example/announce?key=1".to_string(),
+            ],
+            web_seeds: vec!["https://example.com/files/".to_string()],
+        };
+
+        assert_eq!(Magnet::parse(&magnet.to_string()).unwrap(), magnet);
+    }
+
+    #[test]
+    fn test_from_torrent_with_unknown_info_keys() {
+        // A private tracker's `source` key, which `Info` doesn't model.
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:XYZe";
+        let dot_torrent = [b"d4:info".as_slice(), info, b"e"].concat();
+        let t = Torrent::from_bytes(&dot_torrent).unwrap();
+
+        let magnet = Magnet::from_torrent(&t).to_string();
+        let xt = format!("xt=urn:btih:{}", hex::encode(hash::sha1(&[info])));
+        assert!(magnet.contains(&xt), "{magnet}");
+    }
+
+    #[tokio::test]
+    async fn test_silent_peers_are_given_up_on() {
+        let magnet = Magnet::from_info_hash("d69f91e6b2ae4c542468d1073a71d4ea13879a7f").unwrap();
+        let connect = |addr| Peer::new(addr, &magnet.info_hash, Encryption::Disable);
+
+        // Without the extension protocol there is no metadata to wait for.
+        let mut peer = connect(silent_peer(false).await).await.unwrap();
+        let fetch = magnet.fetch_from(&mut peer, Vec::new(), Duration::from_secs(60));
+        let e = tokio::time::timeout(Duration::from_secs(5), fetch)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(e.to_string().contains("extension protocol"), "{e:#}");
+
+        let mut peer = connect(silent_peer(true).await).await.unwrap();
+        let fetch = magnet.fetch_from(&mut peer, Vec::new(), Duration::from_millis(100));
+        let e = tokio::time::timeout(Duration::from_secs(5), fetch)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(e.to_string().contains("timed out"), "{e:#}");
+    }
+}
@@ -1,57 +1,289 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Context};
+use anyhow::Context;
 use bittorrent_cli::{
-    download,
-    torrent::{Keys, Torrent},
-    tracker,
+    blocklist::Blocklist,
+    config::Config,
+    create::{self, Version},
+    download, edit, hash,
+    magnet::Magnet,
+    mse::Encryption,
+    peer::Peer,
+    piece::Priority,
+    rate::{self, Schedule},
+    session::{self, AnnounceMode, Progress, Session, Shared, TorrentEvent},
+    storage::{Allocation, Backend, Disk, Layout, PieceStatus},
+    torrent::Torrent,
+    tracker::{self, Announce},
 };
-use clap::{Parser, Subcommand};
-use tokio::net::UdpSocket;
+use clap::{Args, Parser, Subcommand};
+use futures_util::{stream::FuturesUnordered, Stream, StreamExt};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{error, info, warn, Level};
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
+
+/// How often `download` logs the stats of each torrent, with `-v`.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long `peers --stats` waits for a peer to connect, and then for its
+/// pieces.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[clap(rename_all = "snake_case")]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Log more: `-v` for progress, `-vv` for every peer and piece, `-vvv` for
+    /// every message
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Log as JSON lines, one object per event
+    #[arg(long, global = true)]
+    log_json: bool,
 }
 
 #[derive(Subcommand)]
 #[clap(rename_all = "snake_case")]
 enum Commands {
     Info {
-        torrent: PathBuf,
+        /// A `.torrent` file, its URL, a magnet URI or an info hash
+        torrent: String,
+
+        /// Print the metainfo as JSON
+        #[clap(long)]
+        json: bool,
     },
+    /// Print the magnet URI of a torrent
+    Magnet { torrent: PathBuf },
     Peers {
+        /// A `.torrent` file, its URL, a magnet URI or an info hash
         #[arg(long, short)]
-        torrent: PathBuf,
+        torrent: String,
+
+        #[command(flatten)]
+        announce: AnnounceArgs,
+
+        /// Print the peers as a JSON array
+        #[clap(long)]
+        json: bool,
+
+        /// Connect to every peer and show its client, how many pieces it has
+        /// and how long the handshake took
+        #[clap(long)]
+        stats: bool,
     },
+    /// Download one or more torrents
     Download {
+        /// Where to store the torrent; with several torrents, the directory
+        /// each one is stored under by name
         #[clap(short, long)]
         output: PathBuf,
 
+        /// How many torrents to download at the same time
+        #[clap(long, default_value_t = 3)]
+        max_active: usize,
+
+        /// Announce to every tracker tier at once instead of stopping at the
+        /// first tracker that answers
+        #[clap(long)]
+        announce_all: bool,
+
+        #[command(flatten)]
+        announce: AnnounceArgs,
+
+        /// Download at most this many KiB per second, over all torrents;
+        /// overrides the config file
+        #[clap(long)]
+        max_down: Option<u64>,
+
+        /// Upload at most this many KiB per second, over all torrents;
+        /// overrides the config file
+        #[clap(long)]
+        max_up: Option<u64>,
+
+        /// A config file, for rate limits by time of day
+        #[clap(long)]
+        config: Option<PathBuf>,
+
+        /// Drop peers that send nothing, not even a keep-alive, for this many
+        /// seconds
+        #[clap(long, default_value_t = 180)]
+        peer_timeout: u64,
+
+        /// Whether to encrypt peer connections (MSE): `disable`, `prefer` to
+        /// fall back to plaintext for peers that can't, or `require`
+        #[clap(long, default_value = "prefer")]
+        encryption: Encryption,
+
+        /// A blocklist of addresses not to exchange data with, one range per
+        /// line as `name:first-last` (PeerGuardian `.p2p`) or CIDR
+        #[clap(long)]
+        blocklist: Option<PathBuf>,
+
+        /// Hold at most this many MiB of piece data in memory, over all
+        /// torrents, until it is written; for devices low on RAM
+        #[clap(long)]
+        max_memory: Option<usize>,
+
+        /// How to create the output files: `sparse`, or `full` to reserve
+        /// their disk space up front
+        #[clap(long, default_value = "sparse")]
+        allocation: Allocation,
+
+        /// How to write the output files: `files`, `mmap` (Linux only) or
+        /// `uring` (Linux only, with the `io-uring` feature)
+        #[clap(long, default_value = "files")]
+        storage: Backend,
+
+        /// Download only these files: indices into the torrent's file list,
+        /// or globs over their paths such as `*.mkv`
+        #[clap(long, value_delimiter = ',')]
+        files: Vec<String>,
+
+        /// Leave out these files, given like `--files`
+        #[clap(long, value_delimiter = ',')]
+        skip_files: Vec<String>,
+
+        /// Print a JSON summary of every download when they are done, instead
+        /// of a line each
+        #[clap(long)]
+        json: bool,
+
+        /// Keep uploading the torrents once downloaded, until interrupted
+        #[clap(long)]
+        seed: bool,
+
+        /// `.torrent` files, their URLs, magnet URIs or info hashes
+        #[clap(required = true)]
+        torrents: Vec<String>,
+    },
+    /// Make a torrent of a file or directory
+    Create {
+        /// The file, or the directory whose files to include
+        path: PathBuf,
+
+        /// A tracker URL; several make a tier each, tried in order
+        #[clap(long)]
+        announce: Vec<String>,
+
+        /// Bytes per piece, a power of two of at least 16 KiB; picked from
+        /// the size of the data by default
+        #[clap(long)]
+        piece_length: Option<usize>,
+
+        /// Where to write the torrent, `<name>.torrent` by default
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Which metadata to include: `v1`, `v2` (BEP 52), or `hybrid` for
+        /// both
+        #[clap(long, default_value = "v1")]
+        version: Version,
+    },
+    /// Change the trackers, comment or private flag of a torrent file
+    Edit {
         torrent: PathBuf,
+
+        /// A tracker to add, as a tier of its own
+        #[clap(long)]
+        add_tracker: Vec<String>,
+
+        /// A tracker to remove
+        #[clap(long)]
+        remove_tracker: Vec<String>,
+
+        /// The new comment; an empty one removes it
+        #[clap(long)]
+        comment: Option<String>,
+
+        /// Make the torrent private or public (BEP 27); this changes its info
+        /// hash
+        #[clap(long)]
+        private: Option<bool>,
+
+        /// Where to write the torrent, over the original by default
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Ask the trackers how many peers are in the swarms
+    Scrape {
+        /// `.torrent` files, their URLs, magnet URIs or info hashes
+        #[clap(required = true)]
+        torrents: Vec<String>,
+    },
+    /// Hash-check data on disk against a torrent
+    Verify {
+        torrent: String,
+
+        /// The downloaded file, or the directory of a multi-file torrent
+        data: PathBuf,
     },
 }
 
+#[derive(Args)]
+struct AnnounceArgs {
+    /// How many peers to ask trackers for
+    #[clap(long)]
+    numwant: Option<u32>,
+
+    /// The address trackers should hand out to other peers, when not the one
+    /// we announce from
+    #[clap(long)]
+    ip: Option<IpAddr>,
+}
+
+impl AnnounceArgs {
+    fn options(&self) -> tracker::Options {
+        tracker::Options {
+            numwant: self.numwant,
+            ip: self.ip,
+            ..tracker::Options::default()
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let bars = MultiProgress::new();
+    init_logging(cli.verbose, cli.log_json, &bars);
 
     match cli.command {
-        Commands::Info { torrent } => {
-            let t = Torrent::read(torrent).await?;
+        Commands::Info { torrent, json } => {
+            let t = load_torrent(&torrent, &Shared::default()).await?;
+            if json {
+                println!("{:#}", metainfo(&t));
+                return Ok(());
+            }
 
-            let file_length = match t.info.keys {
-                Keys::SingleFile { length } => length,
-                Keys::MultiFile { ref files } => files.iter().map(|file| file.length).sum(),
-            };
+            let file_length = t.length();
 
-            println!("Tracker URL: {}", t.announce);
+            if let Some(announce) = &t.announce {
+                println!("Tracker URL: {}", announce);
+            }
+            for (tier_i, tier) in t.announce_list.iter().enumerate() {
+                println!("Tier {tier_i}: {}", tier.join(" "));
+            }
             println!("Length: {}", file_length);
 
             let info_hash = t.info_hash();
             println!("Info Hash: {}", hex::encode(info_hash));
+            if t.info.has_v2() {
+                println!("Info Hash v2: {}", hex::encode(t.info_hash_v2()));
+            }
 
             println!("Piece Hashes:");
             for piece in &t.info.pieces.0 {
@@ -60,191 +292,737 @@ async fn main() -> anyhow::Result<()> {
 
             t.print_tree();
         }
-        Commands::Peers { torrent } => {
-            let t_bytes = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&t_bytes).context("parse torrent file")?;
+        Commands::Magnet { torrent } => {
+            let t = Torrent::read(torrent).await?;
+            println!("{}", Magnet::from_torrent(&t));
+        }
+        Commands::Peers {
+            torrent,
+            announce,
+            json,
+            stats,
+        } => {
+            let shared = Shared::new(AnnounceMode::default(), announce.options());
+            let t = load_torrent(&torrent, &shared).await?;
+            let mut tiers = t.tiers();
+            let announce = Announce::new(t.info_hash(), t.length());
+            let announced = if tiers.is_empty() {
+                download::find_peers(&shared, &mut tiers, &t.nodes, &announce).await?
+            } else {
+                shared.announce_tiers(&mut tiers, &announce).await?
+            };
+
+            if stats {
+                let mut probes: Vec<PeerProbe> = announced
+                    .peers
+                    .iter()
+                    .map(|&addr| probe(&t, addr))
+                    .collect::<FuturesUnordered<_>>()
+                    .collect()
+                    .await;
+                probes.sort_by_key(|probe| std::cmp::Reverse(probe.pieces));
+
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&probes)?);
+                    return Ok(());
+                }
+                for probe in probes {
+                    match (&probe.error, probe.pieces) {
+                        (Some(e), _) => println!("{}  {e}", probe.addr),
+                        (None, pieces) => println!(
+                            "{}  {}  {}/{} pieces  {} ms",
+                            probe.addr,
+                            probe.client.as_deref().unwrap_or("unknown"),
+                            pieces.unwrap_or_default(),
+                            probe.total_pieces,
+                            probe.connect_ms.unwrap_or_default(),
+                        ),
+                    }
+                }
+                return Ok(());
+            }
 
-            let file_length = match t.info.keys {
-                Keys::SingleFile { length } => length,
-                Keys::MultiFile { ref files } => files.iter().map(|file| file.length).sum(),
+            if json {
+                let peers: Vec<String> = announced.peers.iter().map(|p| p.to_string()).collect();
+                println!("{}", serde_json::json!(peers));
+                return Ok(());
+            }
+            for peer in announced.peers {
+                println!("{peer}");
+            }
+        }
+        Commands::Download {
+            output,
+            max_active,
+            announce_all,
+            announce,
+            max_down,
+            max_up,
+            config,
+            peer_timeout,
+            encryption,
+            blocklist,
+            max_memory,
+            allocation,
+            storage,
+            files,
+            skip_files,
+            json,
+            seed,
+            torrents,
+        } => {
+            let config = match config {
+                Some(path) => Config::read(&path).await?,
+                None => Config::default(),
             };
-            println!("Tracker URL: {}", t.announce);
-            let info_hash = t.info_hash();
-            let request = tracker::http::Request::new(&info_hash, file_length);
+            let down = rate_flag("--max_down", max_down, config.max_down)?;
+            let up = rate_flag("--max_up", max_up, config.max_up)?;
 
-            let addr = bittorrent_cli::tracker::get_addr(&t.announce)?;
+            info!(backend = hash::backend(), "Hashing pieces");
+            let announce_mode = if announce_all {
+                AnnounceMode::AllTiers
+            } else {
+                AnnounceMode::FirstAnswer
+            };
+            let disk = Disk {
+                allocation,
+                backend: storage,
+            };
+            let mut shared = Shared::new(announce_mode, announce.options())
+                .with_peer_timeout(Duration::from_secs(peer_timeout))
+                .with_encryption(encryption);
+            if let Some(path) = blocklist {
+                let blocklist = Blocklist::read(&path).await?;
+                info!(ranges = blocklist.len(), "Loaded blocklist");
+                shared = shared.with_blocklist(blocklist);
+            }
+            if let Some(mib) = max_memory {
+                shared = shared.with_max_memory(mib * 1024 * 1024);
+            }
+            let session = Session::new(max_active, shared, disk)
+                .await
+                .with_seeding(seed);
+            tokio::spawn(rate::follow_schedule(
+                Arc::clone(session.shared().limits()),
+                down,
+                up,
+                config.utc_offset,
+            ));
 
-            match addr {
-                bittorrent_cli::tracker::Addr::Udp(url) => {
-                    let socket = UdpSocket::bind("0.0.0.0:0")
-                        .await
-                        .context("bind to the address")?;
-                    socket.connect(url).await.context("connect to tracker")?;
-
-                    let mut action = 0;
-                    let mut transaction_id = 0;
-                    let mut connection_id: u64 = 0;
-
-                    'transmit: loop {
-                        match action {
-                            // Connect
-                            0 => {
-                                let mut connect_buffer = Vec::new();
-                                transaction_id = rand::random::<u32>();
-                                let connect_req = tracker::udp::ConnectRequest::new(transaction_id);
-                                let request = tracker::udp::Request::from(connect_req);
-                                request.write(&mut connect_buffer)?;
-
-                                let mut attempts = 0;
-                                let max_retries = 8;
-                                let mut delay = 15;
-                                loop {
-                                    eprintln!("attempting to send request: {}", attempts);
-
-                                    if attempts > max_retries {
-                                        return Err(anyhow!("max retransmission reached"));
-                                    }
-                                    // Send the connect request
-                                    match socket.send_to(&connect_buffer, &url).await {
-                                        Ok(_) => break,
-                                        Err(e) => {
-                                            println!(
-                                                "attempt {}: Failed to send request, error: {}",
-                                                attempts, e
-                                            );
-                                        }
-                                    }
-
-                                    tokio::time::sleep(Duration::from_secs(delay)).await;
-
-                                    attempts += 1;
-
-                                    delay *= 2;
-                                }
-                            }
+            let several = torrents.len() > 1;
+            let mut handles = FuturesUnordered::new();
+            for torrent in &torrents {
+                let t = load_torrent(torrent, session.shared()).await?;
+                let output = if several {
+                    output.join(&t.info.name)
+                } else {
+                    output.clone()
+                };
 
-                            // Announce
-                            1 => {
-                                let mut announce_buffer = Vec::new();
-                                transaction_id = rand::random::<u32>();
-                                let announce_req = tracker::udp::AnnounceRequest::new(
-                                    connection_id,
-                                    transaction_id,
-                                    t.info_hash(),
-                                );
-                                let request = tracker::udp::Request::from(announce_req);
-                                request.write(&mut announce_buffer)?;
-
-                                let mut attempts = 0;
-                                let max_retries = 8;
-                                let mut delay = 15;
-                                loop {
-                                    eprintln!("attempting to send request: {}", attempts);
-
-                                    if attempts > max_retries {
-                                        return Err(anyhow!("max retransmission reached"));
-                                    }
-                                    // Send the connect request
-                                    match socket.send_to(&announce_buffer, &url).await {
-                                        Ok(_) => break,
-                                        Err(e) => {
-                                            println!(
-                                                "attempt {}: Failed to send request, error: {}",
-                                                attempts, e
-                                            );
-                                        }
-                                    }
-
-                                    tokio::time::sleep(Duration::from_secs(delay)).await;
-
-                                    attempts += 1;
-
-                                    delay *= 2;
-                                }
-                            }
-                            _ => {}
+                let wanted = t.info.select_files(&files, &skip_files)?;
+                let skipped = wanted.iter().filter(|&&wanted| !wanted).count();
+                if !json {
+                    if skipped > 0 {
+                        println!("Skipping {skipped} of {} files", wanted.len());
+                    }
+                    println!("Starting download for {}", t.info.name);
+                }
+                let info_hash = hex::encode(t.info_hash());
+                let priorities = wanted
+                    .into_iter()
+                    .map(|wanted| {
+                        if wanted {
+                            Priority::Normal
+                        } else {
+                            Priority::Skip
                         }
+                    })
+                    .collect();
+                let handle = session.add_torrent(t, output, priorities);
+
+                let bar = bars.add(ProgressBar::new(0));
+                bar.set_prefix(handle.name().to_string());
+                tokio::spawn(show_progress(bar, handle.events(), handle.watch_progress()));
 
-                        // Buffer to receive the response
-                        let mut response: Vec<u8> = vec![0; 1206];
-
-                        // Receive the response
-                        match socket.recv(&mut response).await {
-                            Ok(_) => {
-                                let res = tracker::udp::Response::read(&mut response)
-                                    .context("read response")?;
-
-                                // Check if the transaction_id matches
-                                match res {
-                                    tracker::udp::Response::Connect(connect_res) => {
-                                        assert_eq!(connect_res.transaction_id.0, transaction_id);
-
-                                        println!(
-                                            "Received connection ID: {}",
-                                            connect_res.connection_id.0
-                                        );
-
-                                        action = 1;
-                                        connection_id = connect_res.connection_id.0;
-                                    }
-                                    tracker::udp::Response::Announce(announce_res) => {
-                                        assert_eq!(announce_res.transaction_id.0, transaction_id);
-
-                                        eprintln!("Peers");
-                                        for (idx, peer) in announce_res.peers.iter().enumerate() {
-                                            eprintln!("Peer {idx}: {peer}");
-                                        }
-
-                                        break 'transmit;
-                                    }
-                                    _ => {}
+                handles.push(async move {
+                    let name = handle.name().to_string();
+                    let progress = handle.watch_progress();
+                    let result = handle.wait().await;
+                    let summary = Summary {
+                        name: name.clone(),
+                        info_hash,
+                        error: result.as_ref().err().map(|e| format!("{e:#}")),
+                        progress: *progress.borrow(),
+                    };
+                    (name, result, summary)
+                });
+            }
+
+            let ctrl_c = tokio::signal::ctrl_c();
+            tokio::pin!(ctrl_c);
+            let mut interrupted = false;
+
+            let mut failed = 0;
+            let mut summaries = Vec::new();
+            loop {
+                tokio::select! {
+                    next = handles.next() => match next {
+                        Some((name, result, summary)) => {
+                            match result {
+                                Ok(()) if !json => bars.suspend(|| println!("Downloaded {name}.")),
+                                Ok(()) => {}
+                                Err(e) => {
+                                    error!(name, error = format!("{e:#}"), "Failed to download");
+                                    failed += 1;
                                 }
                             }
-                            Err(e) => {
-                                eprintln!("Failed to receive response: {:?}", e);
-                            }
+                            summaries.push(summary);
                         }
+                        None => break,
+                    },
+                    Ok(()) = &mut ctrl_c, if !interrupted => {
+                        bars.suspend(|| eprintln!("Stopping, progress is kept for next time..."));
+                        session.shutdown();
+                        interrupted = true;
                     }
                 }
-                bittorrent_cli::tracker::Addr::Http(url) => {
-                    let res = reqwest::get(request.url(&url.to_string())).await?;
-                    let res: tracker::http::Response =
-                        serde_bencode::from_bytes(&res.bytes().await?).context("parse response")?;
-
-                    for peer in res.peers.0 {
-                        println!("{peer}");
-                    }
-                }
             }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&summaries)?);
+            }
+            anyhow::ensure!(
+                failed == 0,
+                "{failed} of {} downloads failed",
+                torrents.len()
+            );
         }
-        Commands::Download { output, torrent } => {
-            let t = Torrent::read(torrent).await?;
+        Commands::Create {
+            path,
+            announce,
+            piece_length,
+            output,
+            version,
+        } => {
+            let mut t =
+                tokio::task::spawn_blocking(move || create::torrent(&path, piece_length, version))
+                    .await??;
+            t.announce = announce.first().cloned();
+            if announce.len() > 1 {
+                t.announce_list = announce.iter().map(|url| vec![url.clone()]).collect();
+            }
 
-            println!("Starting download for {}", t.info.name);
+            let output =
+                output.unwrap_or_else(|| PathBuf::from(format!("{}.torrent", t.info.name)));
+            t.write(&output).await?;
+            println!("Created {}", output.display());
+            if version != Version::V2 {
+                println!("Info Hash: {}", hex::encode(t.info_hash()));
+            }
+            if version != Version::V1 {
+                println!("Info Hash v2: {}", hex::encode(t.info_hash_v2()));
+            }
+        }
+        Commands::Edit {
+            torrent,
+            add_tracker,
+            remove_tracker,
+            comment,
+            private,
+            output,
+        } => {
+            let dot_torrent = tokio::fs::read(&torrent)
+                .await
+                .context("read torrent file")?;
+            let edit = edit::Edit {
+                add_trackers: add_tracker,
+                remove_trackers: remove_tracker,
+                comment,
+                private,
+            };
+            let (dot_torrent, info_hash) = edit::apply(&dot_torrent, &edit)?;
 
-            let files = download::all(&t).await?;
+            let output = output.unwrap_or(torrent);
+            tokio::fs::write(&output, dot_torrent)
+                .await
+                .context("write torrent file")?;
+            println!("Wrote {}", output.display());
+            println!("Info Hash: {}", hex::encode(info_hash));
+        }
+        Commands::Scrape { torrents } => {
+            // Each torrent with its trackers still to try, in tier order.
+            let mut pending: Vec<(Torrent, Vec<String>)> = Vec::new();
+            for torrent in &torrents {
+                let t = load_torrent(torrent, &Shared::default()).await?;
+                let tiers = t.tiers();
+                let mut trackers: Vec<String> = (0..tiers.len())
+                    .flat_map(|tier_i| tiers.tier(tier_i).to_vec())
+                    .collect();
+                if trackers.is_empty() {
+                    warn!(name = t.info.name, "Torrent has no tracker");
+                    continue;
+                }
+                trackers.reverse();
+                pending.push((t, trackers));
+            }
 
-            match &t.info.keys {
-                Keys::SingleFile { .. } => {
-                    eprintln!("{}", t.info.name);
-                    tokio::fs::write(
-                        &output,
-                        files.into_iter().next().expect("always one file").bytes(),
-                    )
-                    .await?;
+            while !pending.is_empty() {
+                // One request per tracker, however many of the torrents it
+                // tracks; those it fails for move on to their next tracker.
+                let mut by_tracker: BTreeMap<String, Vec<(Torrent, Vec<String>)>> = BTreeMap::new();
+                for (t, mut trackers) in pending.drain(..) {
+                    let announce = trackers.pop().expect("torrents left have trackers");
+                    by_tracker.entry(announce).or_default().push((t, trackers));
                 }
-                Keys::MultiFile { .. } => {
-                    while let Some(file) = files.into_iter().next() {
-                        let file_path = file.path().join(std::path::MAIN_SEPARATOR_STR);
-                        eprintln!("{:?}", file_path);
-                        tokio::fs::write(&file_path, file.bytes()).await?;
+
+                for (announce, ts) in by_tracker {
+                    let info_hashes: Vec<_> = ts.iter().map(|(t, _)| t.info_hash()).collect();
+                    let scrape = tracker::scrape_many(&announce, &info_hashes);
+                    let scraped = tokio::time::timeout(session::TRACKER_TIMEOUT, scrape)
+                        .await
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+                    let stats = match scraped {
+                        Ok(stats) => stats,
+                        Err(e) => {
+                            warn!(announce, error = format!("{e:#}"), "Failed to scrape");
+                            for (t, trackers) in ts {
+                                if trackers.is_empty() {
+                                    warn!(name = t.info.name, "No tracker could be scraped");
+                                } else {
+                                    pending.push((t, trackers));
+                                }
+                            }
+                            continue;
+                        }
+                    };
+
+                    for ((t, _), stats) in ts.iter().zip(stats) {
+                        println!(
+                            "{}: {} seeders, {} leechers, {} completed",
+                            t.info.name, stats.seeders, stats.leechers, stats.completed
+                        );
                     }
                 }
             }
+        }
+        Commands::Verify { torrent, data } => {
+            let t = load_torrent(&torrent, &Shared::default()).await?;
+            let layout = Layout::new(&t, &data);
+
+            let mut statuses = Vec::with_capacity(t.info.piece_count());
+            for piece_i in 0..t.info.piece_count() {
+                let (status, _) = layout.check_piece(&t, piece_i).await;
+                println!("Piece {piece_i}: {status:?}");
+                statuses.push(status);
+            }
+
+            println!("Files:");
+            let files = t.info.files();
+            let file_pieces = t.info.file_pieces();
+            for (file_i, (path, _)) in layout.files().iter().enumerate() {
+                if files.get(file_i).is_some_and(|file| file.is_padding()) {
+                    continue;
+                }
+                let pieces = file_pieces[file_i].clone();
+                let total = pieces.len();
+                let valid = statuses[pieces]
+                    .iter()
+                    .filter(|&&status| status == PieceStatus::Valid)
+                    .count();
+                let percent = if total == 0 {
+                    100.0
+                } else {
+                    valid as f64 * 100.0 / total as f64
+                };
+                println!("{}: {valid}/{total} pieces ({percent:.1}%)", path.display());
+            }
 
-            println!("Downloaded test.torrent to {}.", output.display());
+            let valid = statuses
+                .iter()
+                .filter(|&&status| status == PieceStatus::Valid)
+                .count();
+            println!("Verified {valid}/{} pieces", statuses.len());
         }
     }
 
     Ok(())
 }
+
+/// Logs the events of our crate as [`log_targets`] says. Log lines are
+/// written above the progress `bars`.
+fn init_logging(verbose: u8, json: bool, bars: &MultiProgress) {
+    let bars = bars.clone();
+    let writer = move || BarsWriter(bars.clone());
+    let registry = tracing_subscriber::registry().with(log_targets(verbose));
+    if json {
+        registry
+            .with(tracing_subscriber::fmt::layer().json().with_writer(writer))
+            .init();
+    } else {
+        registry
+            .with(tracing_subscriber::fmt::layer().with_writer(writer))
+            .init();
+    }
+}
+
+/// The schedule a `--max_down` or `--max_up` of `kib` KiB per second sets,
+/// if given, instead of the config file's.
+fn rate_flag(flag: &str, kib: Option<u64>, config: Schedule) -> anyhow::Result<Schedule> {
+    let Some(kib) = kib else {
+        return Ok(config);
+    };
+    let rate = kib
+        .checked_mul(1024)
+        .ok_or_else(|| anyhow::anyhow!("{flag} {kib} is too large"))?;
+    Ok(Schedule::constant(Some(rate)))
+}
+
+/// The events of our crate at the level `verbose` asks for, warnings and
+/// errors only by default, and those of dependencies at warning level.
+fn log_targets(verbose: u8) -> Targets {
+    let level = match verbose {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    Targets::new()
+        .with_target("bittorrent_cli", level)
+        .with_default(Level::WARN)
+}
+
+/// Writes to stderr with the progress bars cleared, so that they are redrawn
+/// below rather than overwritten.
+struct BarsWriter(MultiProgress);
+
+impl io::Write for BarsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// The metainfo of `t`, for `info --json`.
+fn metainfo(t: &Torrent) -> serde_json::Value {
+    let files: Vec<_> = t
+        .info
+        .files()
+        .iter()
+        .filter(|file| !file.is_padding())
+        .map(|file| serde_json::json!({ "path": file.path.join("/"), "length": file.length }))
+        .collect();
+    serde_json::json!({
+        "name": t.info.name,
+        "announce": t.announce,
+        "announce_list": t.announce_list,
+        "info_hash": hex::encode(t.info_hash()),
+        "info_hash_v2": t.info.has_v2().then(|| hex::encode(t.info_hash_v2())),
+        "length": t.length(),
+        "piece_length": t.info.plength,
+        "private": t.info.is_private(),
+        "pieces": t.info.pieces.0.iter().map(hex::encode).collect::<Vec<_>>(),
+        "files": files,
+    })
+}
+
+/// How a download went, for `download --json`.
+#[derive(Serialize)]
+struct Summary {
+    name: String,
+    info_hash: String,
+    /// Why the download failed, if it did.
+    error: Option<String>,
+    #[serde(flatten)]
+    progress: Progress,
+}
+
+/// What `peers --stats` found out about a peer.
+#[derive(Serialize)]
+struct PeerProbe {
+    addr: SocketAddr,
+    /// The client, when the peer ID says.
+    client: Option<String>,
+    /// Pieces the peer has, out of all of the torrent's.
+    pieces: Option<usize>,
+    total_pieces: usize,
+    /// How long connecting and the handshake took.
+    connect_ms: Option<u128>,
+    /// Why the peer couldn't be reached, if it couldn't.
+    error: Option<String>,
+}
+
+/// Connects to `addr` and waits a little for the pieces it has.
+async fn probe(t: &Torrent, addr: SocketAddr) -> PeerProbe {
+    let mut probe = PeerProbe {
+        addr,
+        client: None,
+        pieces: None,
+        total_pieces: t.info.piece_count(),
+        connect_ms: None,
+        error: None,
+    };
+
+    let start = Instant::now();
+    let result = async {
+        let mut peer = tokio::time::timeout(
+            PROBE_TIMEOUT,
+            Peer::new(addr, &t.info_hash(), Encryption::default()),
+        )
+        .await
+        .context("timed out")??;
+        probe.connect_ms = Some(start.elapsed().as_millis());
+        probe.client = peer.client();
+
+        let have = peer.availability(probe.total_pieces, PROBE_TIMEOUT).await?;
+        probe.pieces = Some(have.pieces().count());
+        anyhow::Ok(())
+    }
+    .await;
+    probe.error = result.err().map(|e| format!("{e:#}"));
+    probe
+}
+
+/// Draws a torrent's progress on `bar` as its `events` come in: the bytes
+/// done, the transfer rates over the last second and since the start, the
+/// peers and the pieces verified, until the download ends. The same is
+/// logged every [`STATS_INTERVAL`].
+async fn show_progress(
+    bar: ProgressBar,
+    events: impl Stream<Item = TorrentEvent>,
+    progress: watch::Receiver<Progress>,
+) {
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{prefix:.bold} [{bar:30}] {percent:>3}% {bytes}/{total_bytes} {msg} ETA {eta}",
+        )
+        .expect("template is valid")
+        .progress_chars("=> "),
+    );
+
+    let mut events = Box::pin(events);
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let start = (Instant::now(), *progress.borrow());
+    let mut last = start;
+    let mut last_logged = Instant::now();
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(TorrentEvent::Completed) => return bar.finish_with_message("done"),
+                Some(TorrentEvent::Error(e)) => return bar.abandon_with_message(e),
+                Some(_) => {}
+                None => return,
+            },
+            _ = tick.tick() => {
+                let now = (Instant::now(), *progress.borrow());
+                let rate = |since: (Instant, Progress), bytes: fn(&Progress) -> usize| {
+                    let seconds = (now.0 - since.0).as_secs_f64().max(0.001);
+                    (bytes(&now.1).saturating_sub(bytes(&since.1)) as f64 / seconds) as u64
+                };
+                let down = rate(last, |p| p.downloaded);
+                let average = rate(start, |p| p.downloaded);
+                let up = rate(last, |p| p.uploaded);
+                bar.set_message(format!(
+                    "down {}/s (avg {}/s), up {}/s, {} peers ({} choking), {}/{} pieces",
+                    HumanBytes(down),
+                    HumanBytes(average),
+                    HumanBytes(up),
+                    now.1.peers,
+                    now.1.choking,
+                    now.1.pieces,
+                    now.1.total_pieces,
+                ));
+
+                if last_logged.elapsed() >= STATS_INTERVAL {
+                    info!(
+                        torrent = bar.prefix(),
+                        bytes = now.1.bytes,
+                        total_bytes = now.1.total_bytes,
+                        down,
+                        average,
+                        up,
+                        peers = now.1.peers,
+                        choking = now.1.choking,
+                        pieces = now.1.pieces,
+                        total_pieces = now.1.total_pieces,
+                        "Download stats"
+                    );
+                    last_logged = now.0;
+                }
+                last = now;
+            }
+        }
+
+        let current = *progress.borrow();
+        bar.set_length(current.total_bytes as u64);
+        bar.set_position(current.bytes as u64);
+    }
+}
+
+/// Reads a torrent from a file, downloads it when given an `http(s)` URL, or
+/// fetches its metadata from the swarm when given a magnet URI or a bare info
+/// hash, with the peers found and connected to as `shared` says. A file
+/// named like an info hash is still read as a file.
+async fn load_torrent(source: &str, shared: &Shared) -> anyhow::Result<Torrent> {
+    if source.starts_with("magnet:") {
+        Magnet::parse(source)?.resolve(shared).await
+    } else if source.starts_with("http://") || source.starts_with("https://") {
+        Torrent::fetch(source).await
+    } else if Path::new(source).exists() {
+        Torrent::read(source).await
+    } else if let Ok(magnet) = Magnet::from_info_hash(source) {
+        magnet.resolve(shared).await
+    } else {
+        Torrent::read(source).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bittorrent_cli::{
+        rate::Schedule,
+        session::{Progress, TorrentEvent},
+        torrent::Torrent,
+    };
+    use futures_util::{stream, Stream};
+    use indicatif::ProgressBar;
+    use tokio::sync::{mpsc, watch};
+    use tracing::Level;
+
+    use super::{log_targets, metainfo, rate_flag, show_progress, Summary};
+
+    /// The events sent on `rx`.
+    fn events(rx: mpsc::UnboundedReceiver<TorrentEvent>) -> impl Stream<Item = TorrentEvent> {
+        stream::unfold(rx, |mut rx| async move { Some((rx.recv().await?, rx)) })
+    }
+
+    /// Waits for `bar` to reach `position`.
+    async fn reaches(bar: &ProgressBar, position: u64) {
+        let wait = async {
+            while bar.position() != position {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_progress_bar_follows_the_download() {
+        let bar = ProgressBar::hidden();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (progress, progress_rx) = watch::channel(Progress {
+            total_pieces: 2,
+            total_bytes: 100,
+            ..Progress::default()
+        });
+        let shown = tokio::spawn(show_progress(bar.clone(), events(rx), progress_rx));
+
+        progress.send_modify(|progress| {
+            progress.pieces = 1;
+            progress.bytes = 60;
+        });
+        tx.send(TorrentEvent::PieceVerified(0)).unwrap();
+        reaches(&bar, 60).await;
+        assert_eq!(bar.length(), Some(100));
+        assert!(!bar.is_finished());
+
+        tx.send(TorrentEvent::Completed).unwrap();
+        shown.await.unwrap();
+        assert!(bar.is_finished());
+        assert_eq!(bar.message(), "done");
+    }
+
+    #[tokio::test]
+    async fn test_progress_bar_shows_the_error() {
+        let bar = ProgressBar::hidden();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (_progress, progress_rx) = watch::channel(Progress::default());
+        let shown = tokio::spawn(show_progress(bar.clone(), events(rx), progress_rx));
+
+        tx.send(TorrentEvent::Error("download interrupted".into()))
+            .unwrap();
+        shown.await.unwrap();
+        assert!(bar.is_finished());
+        assert_eq!(bar.message(), "download interrupted");
+    }
+
+    #[test]
+    fn test_metainfo_json() {
+        let mut bytes = b"d8:announce13:udp://t:1/ann4:infod5:filesl".to_vec();
+        bytes.extend(b"d6:lengthi10e4:pathl3:dir1:aeed6:lengthi6e4:pathl1:beee");
+        bytes.extend(b"4:name4:show12:piece lengthi16e6:pieces20:");
+        bytes.extend([7; 20]);
+        bytes.extend(b"ee");
+        let t = Torrent::from_bytes(&bytes).unwrap();
+
+        let json = metainfo(&t);
+        assert_eq!(json["name"], "show");
+        assert_eq!(json["announce"], "udp://t:1/ann");
+        assert_eq!(json["info_hash"], hex::encode(t.info_hash()));
+        assert_eq!(json["info_hash_v2"], serde_json::Value::Null);
+        assert_eq!(json["length"], 16);
+        assert_eq!(json["piece_length"], 16);
+        assert_eq!(json["private"], false);
+        assert_eq!(json["pieces"], serde_json::json!([hex::encode([7; 20])]));
+        assert_eq!(
+            json["files"],
+            serde_json::json!([
+                { "path": "dir/a", "length": 10 },
+                { "path": "b", "length": 6 },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_summary_json_carries_the_progress() {
+        let summary = Summary {
+            name: "show".to_string(),
+            info_hash: "ab".to_string(),
+            error: Some("download interrupted".to_string()),
+            progress: Progress {
+                pieces: 1,
+                total_pieces: 2,
+                ..Progress::default()
+            },
+        };
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["name"], "show");
+        assert_eq!(json["error"], "download interrupted");
+        assert_eq!(json["pieces"], 1);
+        assert_eq!(json["total_pieces"], 2);
+        assert_eq!(json["uploaded"], 0);
+    }
+
+    #[test]
+    fn test_verbosity_raises_our_log_level_only() {
+        let enabled = |verbose, target, level| log_targets(verbose).would_enable(target, &level);
+        let ours = "bittorrent_cli::download";
+
+        assert!(enabled(0, ours, Level::WARN));
+        assert!(!enabled(0, ours, Level::INFO));
+        assert!(enabled(1, ours, Level::INFO));
+        assert!(!enabled(1, ours, Level::DEBUG));
+        assert!(enabled(2, ours, Level::DEBUG));
+        assert!(!enabled(2, ours, Level::TRACE));
+        assert!(enabled(5, ours, Level::TRACE));
+
+        assert!(enabled(3, "reqwest", Level::WARN));
+        assert!(!enabled(3, "reqwest", Level::INFO));
+    }
+
+    #[test]
+    fn test_rate_flags_are_in_kib() {
+        let config = Schedule::constant(Some(5));
+        let flag = |kib| rate_flag("--max_down", kib, config.clone());
+
+        assert_eq!(flag(None).unwrap().rate_at(0), Some(5));
+        assert_eq!(flag(Some(3)).unwrap().rate_at(0), Some(3 << 10));
+        let e = flag(Some(99999999999999999)).unwrap_err();
+        assert_eq!(e.to_string(), "--max_down 99999999999999999 is too large");
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// A cap on the bytes of piece data a session holds in memory, from the first
+/// block of a piece until the piece is written, over all its torrents.
+/// Unlimited until given a limit.
+#[derive(Debug, Default)]
+pub struct MemoryLimit {
+    limit: Option<usize>,
+    used: AtomicUsize,
+}
+
+impl MemoryLimit {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Bytes held by reservations right now.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Sets aside `bytes` until the returned reservation is dropped, or
+    /// returns `None` if that would go over the limit. With `force` the bytes
+    /// are set aside regardless, so that a torrent can always make progress
+    /// on one piece, even bigger than the limit.
+    pub fn reserve(self: &Arc<Self>, bytes: usize, force: bool) -> Option<Reservation> {
+        let reserved = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let total = used.saturating_add(bytes);
+                match self.limit {
+                    Some(limit) if total > limit && !force => None,
+                    _ => Some(total),
+                }
+            });
+        reserved.ok().map(|_| Reservation {
+            limit: Arc::clone(self),
+            bytes,
+        })
+    }
+}
+
+/// Bytes of a [`MemoryLimit`] set aside for one piece, given back on drop.
+#[derive(Debug)]
+pub struct Reservation {
+    limit: Arc<MemoryLimit>,
+    bytes: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.limit.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::MemoryLimit;
+
+    #[test]
+    fn test_reserve_within_limit() {
+        let memory = Arc::new(MemoryLimit::new(Some(100)));
+        let first = memory.reserve(60, false).unwrap();
+        assert!(memory.reserve(60, false).is_none());
+        let forced = memory.reserve(60, true).unwrap();
+        assert_eq!(memory.used(), 120);
+
+        drop(first);
+        drop(forced);
+        assert_eq!(memory.used(), 0);
+        assert!(memory.reserve(100, false).is_some());
+
+        let unlimited = Arc::new(MemoryLimit::default());
+        assert!(unlimited.reserve(usize::MAX, false).is_some());
+    }
+}
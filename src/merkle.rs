@@ -0,0 +1,233 @@
+use crate::hash;
+
+/// The leaves of v2 merkle trees hash blocks of this many bytes (BEP 52).
+pub const BLOCK_SIZE: usize = 1 << 14;
+
+/// Peers send at most this many hashes in one `hashes` message.
+pub const MAX_HASHES: usize = 512;
+
+pub fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    hash::sha256(&[left, right])
+}
+
+/// The SHA-256 hashes of the 16 KiB blocks of `data`; the last may be shorter.
+pub fn block_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(BLOCK_SIZE)
+        .map(|block| hash::sha256(&[block]))
+        .collect()
+}
+
+/// The root of a tree of `width` zero leaves, which pads the layers above
+/// the leaves past the end of a file.
+pub fn pad_hash(width: usize) -> [u8; 32] {
+    let mut hash = [0; 32];
+    let mut width = width;
+    while width > 1 {
+        hash = hash_pair(&hash, &hash);
+        width /= 2;
+    }
+    hash
+}
+
+/// The root of the tree over `layer`, padded with `pad` to `width` nodes, a
+/// power of two.
+pub fn root(layer: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    let mut layer = layer.to_vec();
+    layer.resize(width.max(1), pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    layer[0]
+}
+
+/// The root of the merkle tree over a file's block hashes, and the layer of
+/// the tree whose hashes each cover a piece of `plength` bytes, as far as the
+/// file goes. Leaves past the end of the file are zero, up to a power of two.
+pub fn piece_layer(leaves: Vec<[u8; 32]>, plength: usize) -> ([u8; 32], Vec<[u8; 32]>) {
+    let blocks_per_piece = plength / BLOCK_SIZE;
+    let pieces = leaves.len().div_ceil(blocks_per_piece);
+
+    let mut layer = leaves;
+    layer.resize(layer.len().next_power_of_two(), [0; 32]);
+    let mut piece_layer = Vec::new();
+    let mut width = 1;
+    loop {
+        if width == blocks_per_piece {
+            piece_layer = layer[..pieces].to_vec();
+        }
+        if layer.len() == 1 {
+            return (layer[0], piece_layer);
+        }
+
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        width *= 2;
+    }
+}
+
+/// Every layer of a merkle tree, from a base layer padded to a power of two
+/// up to the root, so that proofs are looked up instead of hashed anew.
+#[derive(Debug, Clone)]
+pub struct Tree {
+    layers: Vec<Vec<[u8; 32]>>,
+}
+
+impl Tree {
+    /// The tree over `layer`, padded with `pad` to a power of two.
+    pub fn new(layer: &[[u8; 32]], pad: [u8; 32]) -> Self {
+        let mut base = layer.to_vec();
+        base.resize(layer.len().next_power_of_two(), pad);
+        let mut layers = vec![base];
+        while let Some(top) = layers.last().filter(|top| top.len() > 1) {
+            let parents = top
+                .chunks_exact(2)
+                .map(|pair| hash_pair(&pair[0], &pair[1]))
+                .collect();
+            layers.push(parents);
+        }
+        Self { layers }
+    }
+
+    /// The padded base layer.
+    pub fn base(&self) -> &[[u8; 32]] {
+        &self.layers[0]
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.layers.last().expect("at least the base")[0]
+    }
+
+    /// The sibling of the subtree over `base()[index..index + length]` and
+    /// of each of its ancestors, `count` of them at most, for proving the
+    /// subtree against the root.
+    pub fn uncles(&self, index: usize, length: usize, count: usize) -> Vec<[u8; 32]> {
+        let level = length.trailing_zeros() as usize;
+        self.layers
+            .iter()
+            .enumerate()
+            .skip(level)
+            .filter(|(_, layer)| layer.len() > 1)
+            .take(count)
+            .map(|(level, layer)| layer[(index >> level) ^ 1])
+            .collect()
+    }
+}
+
+/// The root that `hashes`, a run of a layer starting at `index`, and their
+/// `uncles` add up to.
+pub fn proof_root(hashes: &[[u8; 32]], index: usize, uncles: &[[u8; 32]]) -> [u8; 32] {
+    let mut node = root(hashes, hashes.len(), [0; 32]);
+    let mut position = index / hashes.len().max(1);
+    for uncle in uncles {
+        node = if position.is_multiple_of(2) {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        position /= 2;
+    }
+    node
+}
+
+/// A `hash request` for hashes of a file's merkle tree, or the header of the
+/// `hashes` or `hash reject` answering it (BEP 52).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    /// The layer the hashes are from, counted up from the blocks.
+    pub base_layer: u32,
+    /// The first hash, a multiple of `length`.
+    pub index: u32,
+    /// How many hashes, a power of two of at least 2.
+    pub length: u32,
+    /// How many layers above `base_layer` to prove the hashes through.
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    pub const LEN: usize = 48;
+
+    pub fn decode(payload: &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            payload.len() >= Self::LEN,
+            "hash request has length {}",
+            payload.len()
+        );
+
+        let field =
+            |i: usize| u32::from_be_bytes(payload[32 + i * 4..][..4].try_into().expect("4 bytes"));
+        Ok(Self {
+            pieces_root: payload[..32].try_into().expect("32 bytes"),
+            base_layer: field(0),
+            index: field(1),
+            length: field(2),
+            proof_layers: field(3),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::LEN);
+
+        payload.extend(self.pieces_root);
+        payload.extend(u32::to_be_bytes(self.base_layer));
+        payload.extend(u32::to_be_bytes(self.index));
+        payload.extend(u32::to_be_bytes(self.length));
+        payload.extend(u32::to_be_bytes(self.proof_layers));
+
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_pair, pad_hash, piece_layer, proof_root, root, Tree, BLOCK_SIZE};
+    use crate::hash::sha256;
+
+    #[test]
+    fn test_piece_layer() {
+        let leaves: Vec<[u8; 32]> = (0..3u8).map(|i| sha256(&[&[i]])).collect();
+
+        let (root_hash, layer) = piece_layer(leaves.clone(), 2 * BLOCK_SIZE);
+
+        let pieces = [
+            hash_pair(&leaves[0], &leaves[1]),
+            hash_pair(&leaves[2], &[0; 32]),
+        ];
+        assert_eq!(layer, pieces);
+        assert_eq!(root_hash, hash_pair(&pieces[0], &pieces[1]));
+        assert_eq!(root(&layer, 2, pad_hash(2)), root_hash);
+    }
+
+    #[test]
+    fn test_proof() {
+        let layer: Vec<[u8; 32]> = (0..5u8).map(|i| sha256(&[&[i]])).collect();
+        let pad = pad_hash(4);
+        let root_hash = root(&layer, 8, pad);
+
+        let tree = Tree::new(&layer, pad);
+        assert_eq!(tree.root(), root_hash);
+        assert_eq!(tree.base()[5..], [pad; 3]);
+
+        let proof = tree.uncles(4, 2, 8);
+        assert_eq!(proof.len(), 2);
+        let hashes = [layer[4], pad];
+        assert_eq!(proof_root(&hashes, 4, &proof), root_hash);
+
+        // Proofs of single hashes, and ones cut short.
+        for index in 0..8 {
+            let proof = tree.uncles(index, 1, 8);
+            assert_eq!(proof.len(), 3);
+            assert_eq!(
+                proof_root(&tree.base()[index..][..1], index, &proof),
+                root_hash
+            );
+        }
+        assert_eq!(tree.uncles(0, 1, 1), [layer[1]]);
+        assert!(tree.uncles(0, 8, 8).is_empty());
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    io,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context as TaskContext, Poll},
+};
+
+use anyhow::{anyhow, Context};
+use num_bigint::BigUint;
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::peer::Transport;
+
+/// The prime of the Diffie-Hellman key exchange, whose generator is 2.
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+
+/// Public keys are sent as this many big-endian bytes.
+const KEY_LEN: usize = 96;
+
+/// Random padding after a public key is at most this long.
+const MAX_PAD: usize = 512;
+
+/// The verification constant that marks the start of the encrypted header.
+const VC: [u8; 8] = [0; 8];
+
+/// Bits of `crypto_provide` and `crypto_select`.
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// The start of a plaintext BitTorrent handshake, which tells an incoming
+/// plaintext connection from an encrypted one.
+pub const PROTOCOL_PREFIX: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// Whether peer connections use Message Stream Encryption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encryption {
+    /// Plaintext only.
+    Disable,
+    /// Encrypted connections first, falling back to plaintext for peers that
+    /// don't support them; either is accepted from others.
+    #[default]
+    Prefer,
+    /// RC4 encrypted connections only.
+    Require,
+}
+
+impl Encryption {
+    fn crypto_provide(self) -> u32 {
+        match self {
+            Self::Disable => CRYPTO_PLAINTEXT,
+            Self::Prefer => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            Self::Require => CRYPTO_RC4,
+        }
+    }
+
+    /// The method we pick out of those a peer provides.
+    fn select(self, provided: u32) -> anyhow::Result<u32> {
+        let allowed = provided & self.crypto_provide();
+        if allowed & CRYPTO_RC4 != 0 {
+            Ok(CRYPTO_RC4)
+        } else if allowed & CRYPTO_PLAINTEXT != 0 {
+            Ok(CRYPTO_PLAINTEXT)
+        } else {
+            Err(anyhow!("peer provides no encryption we allow"))
+        }
+    }
+}
+
+impl FromStr for Encryption {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "disable" => Ok(Self::Disable),
+            "prefer" => Ok(Self::Prefer),
+            "require" => Ok(Self::Require),
+            s => Err(anyhow!(
+                "unknown encryption {s}, expected disable, prefer or require"
+            )),
+        }
+    }
+}
+
+/// A peer connection, RC4 encrypted or not.
+pub struct Stream<S = Transport> {
+    inner: S,
+    /// Plaintext that came with the handshake, read before `inner`.
+    pending: Vec<u8>,
+    decrypt: Option<Rc4>,
+    encrypt: Option<Rc4>,
+    /// Encrypted bytes not written to `inner` yet.
+    unsent: Vec<u8>,
+}
+
+impl<S> Stream<S> {
+    pub fn plaintext(inner: S) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+            decrypt: None,
+            encrypt: None,
+            unsent: Vec::new(),
+        }
+    }
+
+    /// Whether the connection is RC4 encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream<S> {
+    /// Sets up encryption as the connecting side, for the torrent of
+    /// `info_hash`, and sends `initial` along, typically the handshake.
+    pub async fn initiate(
+        mut inner: S,
+        info_hash: &[u8; 20],
+        encryption: Encryption,
+        initial: &[u8],
+    ) -> anyhow::Result<Self> {
+        let (private, public) = keys();
+        inner.write_all(&public).await?;
+        inner.write_all(&padding()).await?;
+
+        let mut remote = [0; KEY_LEN];
+        inner
+            .read_exact(&mut remote)
+            .await
+            .context("read public key")?;
+        let secret = shared_secret(&remote, &private);
+
+        let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+        let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+        let mut header = Vec::new();
+        header.extend(hash(&[b"req1", &secret]));
+        header.extend(xor(hash(&[b"req2", info_hash]), hash(&[b"req3", &secret])));
+        let mut encrypted = Vec::new();
+        encrypted.extend(VC);
+        encrypted.extend(encryption.crypto_provide().to_be_bytes());
+        encrypted.extend(0u16.to_be_bytes());
+        encrypted.extend((initial.len() as u16).to_be_bytes());
+        encrypted.extend(initial);
+        encrypt.apply(&mut encrypted);
+        header.extend(encrypted);
+        inner.write_all(&header).await?;
+
+        // The peer's padding ends where the verification constant starts.
+        let mut vc = VC;
+        decrypt.clone().apply(&mut vc);
+        sync(&mut inner, &vc, MAX_PAD + vc.len())
+            .await
+            .context("find the verification constant")?;
+        decrypt.apply(&mut [0; VC.len()]);
+
+        let mut select = [0; 6];
+        inner.read_exact(&mut select).await?;
+        decrypt.apply(&mut select);
+        let selected = u32::from_be_bytes(select[..4].try_into().expect("4 bytes"));
+        anyhow::ensure!(
+            selected.count_ones() == 1 && selected & encryption.crypto_provide() != 0,
+            "peer selected encryption {selected:#x}"
+        );
+        let pad_len = u16::from_be_bytes([select[4], select[5]]) as usize;
+        anyhow::ensure!(pad_len <= MAX_PAD, "padding of {pad_len} bytes");
+        let mut pad = vec![0; pad_len];
+        inner.read_exact(&mut pad).await?;
+        decrypt.apply(&mut pad);
+
+        let rc4 = selected == CRYPTO_RC4;
+        Ok(Self {
+            inner,
+            pending: Vec::new(),
+            decrypt: rc4.then_some(decrypt),
+            encrypt: rc4.then_some(encrypt),
+            unsent: Vec::new(),
+        })
+    }
+
+    /// Sets up encryption as the accepting side, whose `prefix` has been
+    /// read already. The torrent is picked by its info hash out of
+    /// `info_hashes`, and returned with the connection.
+    pub async fn accept(
+        mut inner: S,
+        prefix: &[u8],
+        info_hashes: &[[u8; 20]],
+        encryption: Encryption,
+    ) -> anyhow::Result<([u8; 20], Self)> {
+        anyhow::ensure!(encryption != Encryption::Disable, "encryption is disabled");
+
+        let mut remote = [0; KEY_LEN];
+        remote[..prefix.len()].copy_from_slice(prefix);
+        inner
+            .read_exact(&mut remote[prefix.len()..])
+            .await
+            .context("read public key")?;
+
+        let (private, public) = keys();
+        inner.write_all(&public).await?;
+        inner.write_all(&padding()).await?;
+        let secret = shared_secret(&remote, &private);
+
+        sync(&mut inner, &hash(&[b"req1", &secret]), MAX_PAD + 20)
+            .await
+            .context("find the request hash")?;
+        let mut req2 = [0; 20];
+        inner.read_exact(&mut req2).await?;
+        let req2 = xor(req2, hash(&[b"req3", &secret]));
+        let info_hash = *info_hashes
+            .iter()
+            .find(|info_hash| hash(&[b"req2", &info_hash[..]]) == req2)
+            .ok_or_else(|| anyhow!("peer asked for a torrent we don't have"))?;
+
+        let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+        let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+        let mut provide = [0; 14];
+        inner.read_exact(&mut provide).await?;
+        decrypt.apply(&mut provide);
+        anyhow::ensure!(provide[..8] == VC, "wrong verification constant");
+        let provided = u32::from_be_bytes(provide[8..12].try_into().expect("4 bytes"));
+        let pad_len = u16::from_be_bytes([provide[12], provide[13]]) as usize;
+        anyhow::ensure!(pad_len <= MAX_PAD, "padding of {pad_len} bytes");
+        let mut pad = vec![0; pad_len + 2];
+        inner.read_exact(&mut pad).await?;
+        decrypt.apply(&mut pad);
+        let initial_len = u16::from_be_bytes([pad[pad_len], pad[pad_len + 1]]) as usize;
+        let mut initial = vec![0; initial_len];
+        inner.read_exact(&mut initial).await?;
+        decrypt.apply(&mut initial);
+
+        let selected = encryption.select(provided)?;
+        let mut reply = Vec::new();
+        reply.extend(VC);
+        reply.extend(selected.to_be_bytes());
+        reply.extend(0u16.to_be_bytes());
+        encrypt.apply(&mut reply);
+        inner.write_all(&reply).await?;
+
+        let rc4 = selected == CRYPTO_RC4;
+        let stream = Self {
+            inner,
+            pending: initial,
+            decrypt: rc4.then_some(decrypt),
+            encrypt: rc4.then_some(encrypt),
+            unsent: Vec::new(),
+        };
+        Ok((info_hash, stream))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Stream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.pending.is_empty() {
+            let len = this.pending.len().min(buf.remaining());
+            buf.put_slice(&this.pending[..len]);
+            this.pending.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(decrypt) = &mut this.decrypt {
+            decrypt.apply(&mut buf.filled_mut()[before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Stream<S> {
+    fn poll_send_unsent(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while !self.unsent.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.unsent))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.unsent.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Stream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // Encrypted bytes can't be taken back, so they are all accepted and
+        // written out later if need be.
+        ready!(this.poll_send_unsent(cx))?;
+        let start = this.unsent.len();
+        this.unsent.extend_from_slice(buf);
+        let encrypt = this.encrypt.as_mut().expect("checked above");
+        encrypt.apply(&mut this.unsent[start..]);
+        let _ = this.poll_send_unsent(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_unsent(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_unsent(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// The RC4 stream cipher, with the first 1 KiB of keystream discarded as
+/// MSE requires.
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        let mut rc4 = Self { state, i: 0, j: 0 };
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+/// A random private key and its public key.
+fn keys() -> (BigUint, [u8; KEY_LEN]) {
+    let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+    let public = BigUint::from(2u8).modpow(&private, &prime());
+    (private, to_key(&public))
+}
+
+fn shared_secret(remote: &[u8; KEY_LEN], private: &BigUint) -> [u8; KEY_LEN] {
+    to_key(&BigUint::from_bytes_be(remote).modpow(private, &prime()))
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).expect("valid hex")
+}
+
+fn to_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    crate::hash::sha1(parts)
+}
+
+fn xor(mut a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    for (a, b) in a.iter_mut().zip(b) {
+        *a ^= b;
+    }
+    a
+}
+
+/// Reads until the last bytes read are `marker`, giving up after `max`
+/// bytes.
+async fn sync<S: AsyncRead + Unpin>(
+    inner: &mut S,
+    marker: &[u8],
+    max: usize,
+) -> anyhow::Result<()> {
+    let mut window = Vec::with_capacity(max);
+    while !window.ends_with(marker) {
+        anyhow::ensure!(window.len() < max, "not found");
+        window.push(inner.read_u8().await?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{Encryption, Stream};
+
+    async fn connect(initiator: Encryption, acceptor: Encryption) -> anyhow::Result<bool> {
+        let info_hash = [7; 20];
+        let (a, b) = tokio::io::duplex(1 << 16);
+
+        let accept = tokio::spawn(async move {
+            let mut prefix = [0; 20];
+            let mut b = b;
+            b.read_exact(&mut prefix).await?;
+            let (found, mut stream) =
+                Stream::accept(b, &prefix, &[[1; 20], info_hash], acceptor).await?;
+            assert_eq!(found, info_hash);
+
+            let mut hello = [0; 5];
+            stream.read_exact(&mut hello).await?;
+            assert_eq!(&hello, b"hello");
+            stream.write_all(b"world").await?;
+            stream.flush().await?;
+            anyhow::Ok(stream.is_encrypted())
+        });
+
+        let mut stream = Stream::initiate(a, &info_hash, initiator, b"hel").await?;
+        stream.write_all(b"lo").await?;
+        stream.flush().await?;
+        let mut world = [0; 5];
+        stream.read_exact(&mut world).await?;
+        assert_eq!(&world, b"world");
+
+        let encrypted = accept.await??;
+        assert_eq!(encrypted, stream.is_encrypted());
+        Ok(encrypted)
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        assert!(connect(Encryption::Prefer, Encryption::Prefer)
+            .await
+            .unwrap());
+        assert!(connect(Encryption::Require, Encryption::Prefer)
+            .await
+            .unwrap());
+        assert!(!connect(Encryption::Disable, Encryption::Prefer)
+            .await
+            .unwrap());
+        assert!(connect(Encryption::Disable, Encryption::Require)
+            .await
+            .is_err());
+    }
+}
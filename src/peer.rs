@@ -1,12 +1,89 @@
-use std::{io, net::SocketAddrV4};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use bytes::{Bytes, BytesMut};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     net::TcpStream,
+    sync::mpsc,
+    time::{interval_at, Instant},
+};
+
+use tracing::{instrument, trace, Instrument};
+
+use crate::{
+    bencode::bencode_len,
+    block,
+    download::Completed,
+    hash,
+    merkle::{self, HashRequest},
+    mse::{self, Encryption},
+    rate::Limits,
+    torrent::Info,
 };
 
-use crate::block::{self, BLOCK_SIZE};
+#[cfg(feature = "webtorrent")]
+use crate::rtc::DataChannel;
+
+/// The message id we ask peers to use when sending us `ut_metadata` messages.
+const UT_METADATA_ID: u8 = 1;
+
+/// Requests for more than 128 KiB are considered abusive and close the connection.
+const MAX_REQUEST_LENGTH: u32 = 1 << 17;
+
+/// Requests beyond this many waiting to be served are dropped.
+const MAX_QUEUED_REQUESTS: usize = 256;
+
+/// Metadata is exchanged in pieces of 16 KiB (BEP 9).
+const METADATA_PIECE_SIZE: usize = 1 << 14;
+
+/// Info dictionaries advertised as bigger than 16 MiB are refused rather than
+/// allocated for.
+const MAX_METADATA_SIZE: usize = 16 << 20;
+
+/// Messages longer than this are refused before their payload is read, unless
+/// a torrent's bitfield needs more: a `piece` message carrying a block, with
+/// room for the headers of `ut_metadata` data and the proofs of `hashes`.
+const MAX_MESSAGE_LEN: usize = 1 + 8 + block::BLOCK_SIZE as usize + (1 << 12);
+
+/// How much a connection's [`BufferPool`] allocates at once: room for a few
+/// `piece` messages.
+const POOL_CHUNK: usize = 4 * (block::BLOCK_SIZE as usize + 8);
+
+/// How often a keep-alive is sent, so that the peer doesn't drop us while
+/// neither side has anything to say.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+
+/// Client code and version at the front of our peer ID, Azureus style.
+const PEER_ID_PREFIX: &[u8; 8] = b"-BC0001-";
+
+/// Our peer ID: [`PEER_ID_PREFIX`] and 12 random characters, picked once per
+/// run so that trackers and peers all see the same one.
+pub fn local_id() -> &'static [u8; 20] {
+    static ID: OnceLock<[u8; 20]> = OnceLock::new();
+    ID.get_or_init(|| {
+        let mut id = [0; 20];
+        id[..8].copy_from_slice(PEER_ID_PREFIX);
+        for byte in &mut id[8..] {
+            *byte = rand::thread_rng().sample(Alphanumeric);
+        }
+        id
+    })
+}
 
 #[derive(Debug, Clone)]
 pub struct Handshake {
@@ -18,155 +95,724 @@ pub struct Handshake {
 }
 
 pub struct Peer {
-    addr: SocketAddrV4,
-    stream: TcpStream,
-    bitfield: Bitfield,
-    choked: bool,
+    addr: SocketAddr,
+    stream: mse::Stream,
+    peer_id: Vec<u8>,
+    /// Whether the peer's handshake advertised the extension protocol
+    /// (BEP 10); without it, `extensions` never comes.
+    supports_extensions: bool,
+    extensions: Option<ExtensionHandshake>,
+    buffers: BufferPool,
 }
 
-impl Peer {
-    pub async fn new(addr: SocketAddrV4, info_hash: &[u8; 20]) -> anyhow::Result<Self> {
-        let mut stream = TcpStream::connect(addr).await.context("connect to peer")?;
+/// What a peer connection runs over.
+pub enum Transport {
+    Tcp(TcpStream),
+    /// A WebRTC data channel to a WebTorrent peer.
+    #[cfg(feature = "webtorrent")]
+    WebRtc(DataChannel),
+}
 
-        let handshake = Handshake::new(info_hash);
-        {
-            let mut handshake_bytes = handshake.bytes();
-            stream.write_all(&mut handshake_bytes).await?;
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            #[cfg(feature = "webtorrent")]
+            Transport::WebRtc(channel) => Pin::new(channel).poll_read(cx, buf),
+        }
+    }
+}
 
-            stream.read_exact(&mut handshake_bytes).await?;
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tcp(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            #[cfg(feature = "webtorrent")]
+            Transport::WebRtc(channel) => Pin::new(channel).poll_write(cx, buf),
         }
+    }
 
-        anyhow::ensure!(handshake.length == 19);
-        anyhow::ensure!(handshake.protocol == *b"BitTorrent protocol");
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(tcp) => Pin::new(tcp).poll_flush(cx),
+            #[cfg(feature = "webtorrent")]
+            Transport::WebRtc(channel) => Pin::new(channel).poll_flush(cx),
+        }
+    }
 
-        let bitfield = Message::decode(&mut stream).await?;
-        anyhow::ensure!(bitfield.id == MessageId::Bitfield);
-        eprintln!("Received bitfield");
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            #[cfg(feature = "webtorrent")]
+            Transport::WebRtc(channel) => Pin::new(channel).poll_shutdown(cx),
+        }
+    }
+}
 
-        Ok(Self {
-            addr,
-            stream,
-            bitfield: Bitfield::from_payload(bitfield.payload),
-            choked: true,
-        })
+/// The error a connection ends with when the peer sent nothing for too long.
+#[derive(Debug)]
+pub(crate) struct Silent(Duration);
+
+impl fmt::Display for Silent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer was silent for {}s", self.0.as_secs())
     }
+}
 
-    pub(crate) async fn download_piece(
-        &mut self,
-        file_length: u32,
-        npiece: u32,
-        plength: u32,
-    ) -> anyhow::Result<Vec<u8>> {
-        eprintln!("start downloading piece: {npiece}, piece length: {plength}");
-
-        Message::encode(&mut self.stream, MessageId::Interested, &mut []).await?;
-
-        let unchoke = Message::decode(&mut self.stream).await?;
-        anyhow::ensure!(unchoke.id == MessageId::Unchoke);
-        eprintln!("Received unchoke");
-
-        let mut all_pieces: Vec<u8> = Vec::new();
-        let piece_length = plength.min(file_length - plength * npiece);
-        let total_blocks = if piece_length % BLOCK_SIZE == 0 {
-            piece_length / BLOCK_SIZE
-        } else {
-            (piece_length / BLOCK_SIZE) + 1
+impl std::error::Error for Silent {}
+
+/// What a running peer connection reports to the torrent driving it.
+#[derive(Debug)]
+pub(crate) enum Event {
+    Bitfield(Bitfield),
+    Have(usize),
+    Choke,
+    Unchoke,
+    /// The peer wants to download from us, or no longer does.
+    Interested,
+    NotInterested,
+    Block(block::Response),
+    /// We sent the peer a block of this many bytes.
+    Uploaded(usize),
+    /// The peer wants hashes of a file's merkle tree (BEP 52).
+    HashRequest(HashRequest),
+    Disconnected(anyhow::Error),
+}
+
+/// What the torrent asks a running peer connection to send.
+#[derive(Debug, Clone)]
+pub(crate) enum Command {
+    Interested,
+    NotInterested,
+    /// Stop or start serving the peer's requests.
+    Choke,
+    Unchoke,
+    Request(block::Request),
+    /// Tell the peer we have verified a piece.
+    Have(usize),
+    /// Answer a hash request with the hashes and their proof.
+    Hashes(HashRequest, Vec<[u8; 32]>),
+    HashReject(HashRequest),
+}
+
+impl Peer {
+    /// Connects to the peer at `addr` for the torrent of `info_hash`,
+    /// encrypting the connection as `encryption` says.
+    pub async fn new(
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+        encryption: Encryption,
+    ) -> anyhow::Result<Self> {
+        if encryption == Encryption::Disable {
+            return Self::connect(addr, info_hash, None).await;
+        }
+
+        match Self::connect(addr, info_hash, Some(encryption)).await {
+            Err(e) if encryption == Encryption::Prefer => {
+                trace!(%addr, error = %e, "Encrypted handshake failed, trying plaintext");
+                Self::connect(addr, info_hash, None).await
+            }
+            result => result,
+        }
+    }
+
+    async fn connect(
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+        encryption: Option<Encryption>,
+    ) -> anyhow::Result<Self> {
+        let tcp = TcpStream::connect(addr).await.context("connect to peer")?;
+        let transport = Transport::Tcp(tcp);
+
+        let Some(encryption) = encryption else {
+            return Self::over(addr, transport, info_hash).await;
         };
+        let mut handshake_bytes = Handshake::new(info_hash).bytes();
+        // The handshake goes along with the encryption handshake.
+        let mut stream = mse::Stream::initiate(transport, info_hash, encryption, &handshake_bytes)
+            .await
+            .context("encryption handshake")?;
+        stream.read_exact(&mut handshake_bytes).await?;
+
+        let remote = Handshake::from_bytes(&handshake_bytes);
+        anyhow::ensure!(remote.info_hash == *info_hash, "info hash mismatch");
+
+        Self::connected(addr, stream, &remote).await
+    }
+
+    /// Handshakes in plaintext over a `transport` that is already connected,
+    /// such as a WebRTC data channel. Our handshake goes first, so either side
+    /// may start.
+    pub async fn over(
+        addr: SocketAddr,
+        transport: Transport,
+        info_hash: &[u8; 20],
+    ) -> anyhow::Result<Self> {
+        let mut handshake_bytes = Handshake::new(info_hash).bytes();
+        let mut stream = mse::Stream::plaintext(transport);
+        stream.write_all(&handshake_bytes).await?;
+        stream.read_exact(&mut handshake_bytes).await?;
+
+        let remote = Handshake::from_bytes(&handshake_bytes);
+        anyhow::ensure!(remote.info_hash == *info_hash, "info hash mismatch");
+
+        Self::connected(addr, stream, &remote).await
+    }
+
+    /// Completes the handshake of an incoming connection, whose handshake
+    /// `remote` has already been read.
+    pub async fn accept(
+        addr: SocketAddr,
+        mut stream: mse::Stream,
+        remote: &Handshake,
+    ) -> anyhow::Result<Self> {
+        let info_hash: [u8; 20] = remote.info_hash[..]
+            .try_into()
+            .context("invalid info hash")?;
+        stream
+            .write_all(&Handshake::new(&info_hash).bytes())
+            .await?;
 
-        for nblock in 0..total_blocks {
-            let block_req = block::Request::new(npiece as u32, nblock, piece_length);
-            let mut block_payload = block_req.encode();
+        Self::connected(addr, stream, remote).await
+    }
 
-            Message::encode(&mut self.stream, MessageId::Request, &mut block_payload).await?;
+    async fn connected(
+        addr: SocketAddr,
+        stream: mse::Stream,
+        remote: &Handshake,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(remote.length == 19);
+        anyhow::ensure!(remote.protocol == *b"BitTorrent protocol");
 
-            let piece = Message::decode(&mut self.stream).await?;
-            let payload_len = piece.payload.len();
-            let mut payload = io::Cursor::new(piece.payload);
+        let mut peer = Self {
+            addr,
+            stream,
+            peer_id: remote.peer_id.clone(),
+            supports_extensions: remote.supports_extensions(),
+            extensions: None,
+            buffers: BufferPool::default(),
+        };
 
-            let block_res = block::Response::new(&mut payload, payload_len).await?;
-            all_pieces.extend(block_res.block());
+        if peer.supports_extensions {
+            let mut payload = vec![0];
+            payload.extend(serde_bencode::to_bytes(&ExtensionHandshake::ours())?);
+            Message::encode(&mut peer.stream, MessageId::Extended, &mut payload).await?;
         }
 
-        Ok(all_pieces)
+        Ok(peer)
     }
 
-    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
-        self.bitfield.has_piece(piece_i)
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The client the peer runs, from an Azureus style peer ID such as
+    /// `-TR4050-...`.
+    pub fn client(&self) -> Option<String> {
+        match self.peer_id.get(..8)? {
+            [b'-', client @ .., b'-'] => Some(String::from_utf8_lossy(client).into_owned()),
+            _ => None,
+        }
     }
 
-    pub(crate) async fn participate(
+    /// The pieces the peer says it has, out of `npieces`: its bitfield and
+    /// the `Have`s that follow, until it sends anything else or `wait` is
+    /// over.
+    pub async fn availability(
         &mut self,
-        npiece: u32,
-        _nblocks: u32,
-        piece_length: u32,
-        submit: kanal::AsyncSender<usize>,
-        tasks: kanal::AsyncReceiver<usize>,
-        finish: tokio::sync::mpsc::Sender<block::Response>,
-    ) -> anyhow::Result<()> {
-        Message::encode(&mut self.stream, MessageId::Interested, &mut []).await?;
-
-        'task: loop {
-            while self.choked {
-                let unchoke = Message::decode(&mut self.stream).await?;
-                match unchoke.id {
-                    MessageId::Unchoke => {
-                        self.choked = false;
-                        anyhow::ensure!(unchoke.payload.is_empty());
-                        eprintln!("Received unchoke");
-                        break;
+        npieces: usize,
+        wait: Duration,
+    ) -> anyhow::Result<Bitfield> {
+        let mut have = Bitfield::new(npieces);
+        let max_len = max_message_len(npieces);
+        let deadline = Instant::now() + wait;
+        loop {
+            let msg = match tokio::time::timeout_at(
+                deadline,
+                Message::decode(&mut self.stream, &mut self.buffers, max_len),
+            )
+            .await
+            {
+                Ok(msg) => msg?,
+                Err(_) => return Ok(have),
+            };
+            let Some(msg) = msg else {
+                continue;
+            };
+            match msg.id {
+                MessageId::Bitfield => {
+                    for piece_i in Bitfield::from_payload(msg.payload.into()).pieces() {
+                        if piece_i < npieces {
+                            have.set_piece(piece_i);
+                        }
                     }
-                    _ => {}
                 }
+                MessageId::Have => {
+                    let piece: [u8; 4] = msg.payload[..].try_into().context("have payload")?;
+                    have.set_piece(u32::from_be_bytes(piece) as usize);
+                }
+                MessageId::Extended => self.handle_extended(&msg.payload)?,
+                _ => return Ok(have),
             }
+        }
+    }
 
-            let Ok(block) = tasks.recv().await else {
-                break;
+    /// Downloads the info dictionary of the torrent using the `ut_metadata`
+    /// extension (BEP 9) and checks it against `info_hash`, either the SHA-1
+    /// hash of the dictionary or its truncated SHA-256 hash.
+    pub async fn fetch_metadata(&mut self, info_hash: &[u8; 20]) -> anyhow::Result<Vec<u8>> {
+        anyhow::ensure!(
+            self.supports_extensions,
+            "peer does not support the extension protocol"
+        );
+        // Enough for the bitfield of any torrent whose metadata we accept.
+        let max_len = max_message_len(MAX_METADATA_SIZE / 20);
+        while self.extensions.is_none() {
+            let Some(msg) = Message::decode(&mut self.stream, &mut self.buffers, max_len).await?
+            else {
+                continue;
             };
+            if msg.id == MessageId::Extended {
+                self.handle_extended(&msg.payload)?;
+            }
+        }
 
-            let block_req = block::Request::new(npiece as u32, block as u32, piece_length);
-            let mut block_payload = block_req.encode();
+        let extensions = self.extensions.as_ref().expect("set by the loop above");
+        let remote_id = *extensions
+            .m
+            .get("ut_metadata")
+            .filter(|&&id| id != 0)
+            .ok_or_else(|| anyhow!("peer does not support ut_metadata"))?;
+        let metadata_size = extensions.checked_metadata_size()?;
 
-            Message::encode(&mut self.stream, MessageId::Request, &mut block_payload).await?;
+        let mut metadata = vec![0; metadata_size];
+        for piece in 0..metadata_size.div_ceil(METADATA_PIECE_SIZE) {
+            let request = MetadataMessage {
+                msg_type: MetadataMessage::REQUEST,
+                piece,
+                total_size: None,
+            };
+            let mut payload = vec![remote_id];
+            payload.extend(serde_bencode::to_bytes(&request)?);
+            Message::encode(&mut self.stream, MessageId::Extended, &mut payload).await?;
 
-            // TODO: timeout and return block to submit if timed out
-            let mut msg;
-            loop {
-                msg = Message::decode(&mut self.stream).await?;
+            let data = loop {
+                let Some(msg) =
+                    Message::decode(&mut self.stream, &mut self.buffers, max_len).await?
+                else {
+                    continue;
+                };
+                if msg.id != MessageId::Extended || msg.payload.first() != Some(&UT_METADATA_ID) {
+                    continue;
+                }
 
-                match msg.id {
-                    MessageId::Choke => {
-                        self.choked = true;
-                        submit.send(block).await.expect("we still have a receiver");
-                        continue 'task;
+                let payload = &msg.payload[1..];
+                let dict_len =
+                    bencode_len(payload).ok_or_else(|| anyhow!("malformed ut_metadata message"))?;
+                let response: MetadataMessage = serde_bencode::from_bytes(&payload[..dict_len])
+                    .context("parse ut_metadata message")?;
+
+                match response.msg_type {
+                    MetadataMessage::DATA if response.piece == piece => {
+                        break payload[dict_len..].to_vec();
+                    }
+                    MetadataMessage::REJECT => {
+                        return Err(anyhow!("peer rejected metadata piece {piece}"));
                     }
-                    MessageId::Piece => {
-                        let payload_len = msg.payload.len();
-                        let mut payload = io::Cursor::new(msg.payload);
+                    _ => {}
+                }
+            };
+
+            let begin = piece * METADATA_PIECE_SIZE;
+            let expected = METADATA_PIECE_SIZE.min(metadata_size - begin);
+            anyhow::ensure!(
+                data.len() == expected,
+                "metadata piece {piece} has wrong size"
+            );
+            metadata[begin..][..expected].copy_from_slice(&data);
+        }
 
-                        let block_res = block::Response::new(&mut payload, payload_len).await?;
-                        anyhow::ensure!(!block_res.block().is_empty());
-                        eprintln!("Received piece");
+        anyhow::ensure!(
+            hash::sha1(&[&metadata]) == *info_hash
+                || hash::sha256(&[&metadata])[..20] == info_hash[..],
+            "metadata does not match info hash"
+        );
 
-                        if block_res.index() != npiece
-                            || block_res.begin() as usize != block * BLOCK_SIZE as usize
+        Ok(metadata)
+    }
+
+    /// Asks the peer for the piece layers of the files of `info` longer than
+    /// a piece, which v2 torrents need to verify pieces but their info
+    /// dictionary doesn't have, and checks them against the files' roots.
+    pub async fn fetch_piece_layers(
+        &mut self,
+        info: &Info,
+    ) -> anyhow::Result<BTreeMap<ByteBuf, ByteBuf>> {
+        let base_layer = (info.plength / merkle::BLOCK_SIZE).trailing_zeros();
+        let max_len = max_message_len(info.piece_count());
+        let mut piece_layers = BTreeMap::new();
+
+        for file in info.files() {
+            let Some(root) = info.pieces_root(&file.path) else {
+                continue;
+            };
+            if file.length <= info.plength {
+                continue;
+            }
+
+            let pieces = file.length.div_ceil(info.plength);
+            let width = pieces.next_power_of_two();
+            let length = width.min(merkle::MAX_HASHES);
+            let mut layer = Vec::with_capacity(width);
+            for index in (0..width).step_by(length) {
+                let request = HashRequest {
+                    pieces_root: root,
+                    base_layer,
+                    index: index as u32,
+                    length: length as u32,
+                    proof_layers: width.trailing_zeros(),
+                };
+                Message::encode(
+                    &mut self.stream,
+                    MessageId::HashRequest,
+                    &mut request.encode(),
+                )
+                .await?;
+
+                let hashes = loop {
+                    let Some(msg) =
+                        Message::decode(&mut self.stream, &mut self.buffers, max_len).await?
+                    else {
+                        continue;
+                    };
+                    match msg.id {
+                        MessageId::Hashes
+                            if msg.payload.get(..HashRequest::LEN)
+                                == Some(&request.encode()[..]) =>
                         {
-                            // msg that we no longer need/are responsible for
-                        } else {
-                            // assert_eq!(block_res.block().len(), block_size);
-                            finish.send(block_res).await.expect("");
+                            break msg.payload[HashRequest::LEN..]
+                                .chunks_exact(32)
+                                .map(|hash| hash.try_into().expect("32 bytes"))
+                                .collect::<Vec<[u8; 32]>>();
+                        }
+                        MessageId::HashReject => {
+                            return Err(anyhow!("peer rejected the hash request"));
+                        }
+                        _ => {}
+                    }
+                };
 
-                            break;
+                let proof = (width / length).trailing_zeros() as usize;
+                anyhow::ensure!(hashes.len() >= length + proof, "peer sent too few hashes");
+                let (hashes, uncles) = hashes.split_at(length);
+                let uncles = &uncles[..proof];
+                anyhow::ensure!(
+                    merkle::proof_root(hashes, index, uncles) == root,
+                    "hashes do not match the file's root"
+                );
+                layer.extend_from_slice(hashes);
+            }
+
+            layer.truncate(pieces);
+            piece_layers.insert(ByteBuf::from(root.to_vec()), ByteBuf::from(layer.concat()));
+        }
+
+        Ok(piece_layers)
+    }
+
+    fn handle_extended(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        // Id 0 is the extension handshake, everything else is an extension message.
+        if payload.first() == Some(&0) {
+            let handshake: ExtensionHandshake =
+                serde_bencode::from_bytes(&payload[1..]).context("parse extension handshake")?;
+            self.extensions = Some(handshake);
+        }
+
+        Ok(())
+    }
+
+    /// Drives the connection until it fails or `commands` is closed: first
+    /// the pieces in `completed` are announced to the peer, then messages
+    /// from the peer are reported as [`Event`]s, commands are sent to the peer
+    /// and block requests are answered from `completed`, all within the
+    /// session's rate `limits`. A peer that sends nothing, not even a
+    /// keep-alive, for `idle_timeout` is disconnected.
+    #[instrument(name = "peer", skip_all, fields(peer = peer_i, addr = %self.addr()))]
+    pub(crate) async fn run(
+        self,
+        peer_i: usize,
+        completed: Arc<RwLock<Completed>>,
+        limits: Arc<Limits>,
+        idle_timeout: Duration,
+        events: mpsc::Sender<(usize, Event)>,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+    ) {
+        let (mut reader, mut writer) = tokio::io::split(self.stream);
+        let mut buffers = self.buffers;
+        let max_len = {
+            let completed = completed.read().expect("lock is not poisoned");
+            max_message_len(completed.have().as_bytes().len() * 8)
+        };
+
+        // Decoding is not cancel safe, so it gets a task of its own.
+        let (message_tx, mut messages) = mpsc::channel(32);
+        let read_limits = Arc::clone(&limits);
+        let read_task = tokio::spawn(
+            async move {
+                loop {
+                    let msg = tokio::time::timeout(
+                        idle_timeout,
+                        Message::decode(&mut reader, &mut buffers, max_len),
+                    )
+                    .await
+                    .unwrap_or_else(|_| Err(Silent(idle_timeout).into()));
+                    // Keep-alives only restart the timeout.
+                    let Some(msg) = msg.transpose() else {
+                        continue;
+                    };
+                    if let Ok(msg) = &msg {
+                        // Not reading on while over the limit slows the peer
+                        // down through TCP flow control.
+                        read_limits.down.acquire(5 + msg.payload.len()).await;
+                    }
+                    let failed = msg.is_err();
+                    if message_tx.send(msg).await.is_err() || failed {
+                        break;
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        // Requests are served by a task of their own as well, so that waiting
+        // on the upload limit never holds up the messages we send.
+        let (request_tx, requests) = mpsc::channel(MAX_QUEUED_REQUESTS);
+        let (block_tx, mut blocks) = mpsc::channel(1);
+        let mut state = Upload {
+            am_choking: true,
+            chokes: Arc::new(AtomicU64::new(0)),
+            requests: request_tx,
+        };
+        let upload_task = tokio::spawn(
+            serve_requests(
+                requests,
+                block_tx,
+                Arc::clone(&state.chokes),
+                Arc::clone(&completed),
+                limits,
+            )
+            .in_current_span(),
+        );
+        let mut keep_alive = interval_at(Instant::now() + KEEP_ALIVE_INTERVAL, KEEP_ALIVE_INTERVAL);
+        let result: anyhow::Result<()> = async {
+            // Without the fast extension a peer must not be sent an empty
+            // bitfield; it assumes we have nothing until a `Have`.
+            let have = completed.read().expect("lock is not poisoned").have().clone();
+            if have.pieces().next().is_some() {
+                Message::encode(&mut writer, MessageId::Bitfield, &mut have.as_bytes().to_vec()).await?;
+            }
+
+            loop {
+                tokio::select! {
+                    _ = keep_alive.tick() => {
+                        Message::encode_keep_alive(&mut writer).await?;
+                    }
+                    msg = messages.recv() => {
+                        let msg = msg.ok_or_else(|| anyhow!("connection closed"))??;
+                        let event = match msg.id {
+                            MessageId::Bitfield => Some(Event::Bitfield(Bitfield::from_payload(msg.payload.into()))),
+                            MessageId::Have => {
+                                let piece: [u8; 4] = msg.payload[..].try_into().context("have payload")?;
+                                Some(Event::Have(u32::from_be_bytes(piece) as usize))
+                            }
+                            MessageId::Choke => Some(Event::Choke),
+                            MessageId::Unchoke => Some(Event::Unchoke),
+                            MessageId::Interested => Some(Event::Interested),
+                            MessageId::NotInterested => Some(Event::NotInterested),
+                            MessageId::Piece => {
+                                Some(Event::Block(block::Response::decode(msg.payload)?))
+                            }
+                            MessageId::HashRequest => {
+                                Some(Event::HashRequest(HashRequest::decode(&msg.payload)?))
+                            }
+                            MessageId::Request => {
+                                state.request(block::Request::decode(&msg.payload)?)?;
+                                None
+                            }
+                            _ => None,
+                        };
+
+                        if let Some(event) = event {
+                            if events.send((peer_i, event)).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                    Some(block) = blocks.recv() => {
+                        let (chokes, mut payload) = block?;
+                        if state.is_current(chokes) {
+                            let uploaded = payload.len() - 8;
+                            Message::encode(&mut writer, MessageId::Piece, &mut payload).await?;
+                            if events.send((peer_i, Event::Uploaded(uploaded))).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                    command = commands.recv() => {
+                        let Some(command) = command else {
+                            return Ok(());
+                        };
+
+                        match command {
+                            Command::Interested => {
+                                Message::encode(&mut writer, MessageId::Interested, &mut []).await?;
+                            }
+                            Command::NotInterested => {
+                                Message::encode(&mut writer, MessageId::NotInterested, &mut []).await?;
+                            }
+                            Command::Choke | Command::Unchoke => {
+                                let choke = matches!(command, Command::Choke);
+                                if choke != state.am_choking {
+                                    let id = if choke { MessageId::Choke } else { MessageId::Unchoke };
+                                    Message::encode(&mut writer, id, &mut []).await?;
+                                    state.choke(choke);
+                                }
+                            }
+                            Command::Request(request) => {
+                                Message::encode(&mut writer, MessageId::Request, &mut request.encode()).await?;
+                            }
+                            Command::Have(piece_i) => {
+                                let mut payload = (piece_i as u32).to_be_bytes();
+                                Message::encode(&mut writer, MessageId::Have, &mut payload).await?;
+                            }
+                            Command::Hashes(request, hashes) => {
+                                let mut payload = request.encode();
+                                payload.extend(hashes.concat());
+                                Message::encode(&mut writer, MessageId::Hashes, &mut payload).await?;
+                            }
+                            Command::HashReject(request) => {
+                                Message::encode(&mut writer, MessageId::HashReject, &mut request.encode()).await?;
+                            }
                         }
                     }
-                    _ => {}
                 }
             }
         }
+        .await;
+
+        read_task.abort();
+        upload_task.abort();
+        if let Err(e) = result {
+            let _ = events.send((peer_i, Event::Disconnected(e))).await;
+        }
+    }
+}
+
+/// Our side of the upload half of a connection; whom to choke is up to the
+/// torrent.
+struct Upload {
+    /// Whether we are choking the remote peer, i.e. refusing its requests.
+    am_choking: bool,
+    /// How often we have choked the peer. Requests are tagged with it, as a
+    /// choke drops all those made before.
+    chokes: Arc<AtomicU64>,
+    /// Requests waiting for [`serve_requests`].
+    requests: mpsc::Sender<(u64, block::Request)>,
+}
+
+impl Upload {
+    fn choke(&mut self, choke: bool) {
+        if choke {
+            self.chokes.fetch_add(1, Ordering::Relaxed);
+        }
+        self.am_choking = choke;
+    }
+
+    /// Whether a request tagged with `chokes` still stands.
+    fn is_current(&self, chokes: u64) -> bool {
+        !self.am_choking && chokes == self.chokes.load(Ordering::Relaxed)
+    }
+
+    /// Queues a block request, unless we are choking the peer.
+    fn request(&mut self, request: block::Request) -> anyhow::Result<()> {
+        if self.am_choking {
+            return Ok(());
+        }
+        anyhow::ensure!(
+            request.length <= MAX_REQUEST_LENGTH,
+            "peer requested {} bytes",
+            request.length
+        );
 
+        let chokes = self.chokes.load(Ordering::Relaxed);
+        let (piece, begin) = (request.piece_index, request.begin);
+        if self.requests.try_send((chokes, request)).is_err() {
+            // Too many requests at once; the peer will ask again.
+            trace!(piece, begin, "dropping request");
+        }
         Ok(())
     }
 }
 
+/// Answers the block requests queued by [`Upload::request`] from
+/// `completed`, as fast as `limits` allow, and hands each block back as the
+/// payload of a `Piece` message, along with the tag of its request. Requests
+/// dropped by a choke in the meantime are skipped.
+async fn serve_requests(
+    mut requests: mpsc::Receiver<(u64, block::Request)>,
+    blocks: mpsc::Sender<anyhow::Result<(u64, Vec<u8>)>>,
+    chokes: Arc<AtomicU64>,
+    completed: Arc<RwLock<Completed>>,
+    limits: Arc<Limits>,
+) {
+    while let Some((tag, request)) = requests.recv().await {
+        if tag != chokes.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        let block = {
+            let completed = completed.read().expect("lock is not poisoned");
+            completed
+                .block_offset(
+                    request.piece_index as usize,
+                    request.begin as usize,
+                    request.length as usize,
+                )
+                .map(|offset| (completed.storage(), offset))
+        };
+        let Some((storage, offset)) = block else {
+            // We don't have that piece (yet); the request is silently dropped.
+            continue;
+        };
+        let block = match storage.read(offset, request.length as usize).await {
+            Ok(block) => block,
+            Err(e) => {
+                let _ = blocks.send(Err(e)).await;
+                return;
+            }
+        };
+
+        let mut payload = Vec::with_capacity(8 + block.len());
+        payload.extend(request.piece_index.to_be_bytes());
+        payload.extend(request.begin.to_be_bytes());
+        payload.extend(block);
+
+        limits.up.acquire(5 + payload.len()).await;
+        if blocks.send(Ok((tag, payload))).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Bitfield {
     payload: Vec<u8>,
 }
@@ -183,7 +829,7 @@ impl Bitfield {
         byte & (1u8.rotate_right(bit_i + 1)) != 0
     }
 
-    pub(crate) fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
         self.payload.iter().enumerate().flat_map(|(byte_i, &byte)| {
             (0..u8::BITS).filter_map(move |bit_i| {
                 let piece_i = byte_i * (u8::BITS as usize) + (bit_i as usize);
@@ -193,9 +839,35 @@ impl Bitfield {
         })
     }
 
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.payload
+    }
+
     pub(crate) fn from_payload(payload: Vec<u8>) -> Self {
         Self { payload }
     }
+
+    /// Whether the bitfield is as long as `npieces` pieces need, with the
+    /// spare bits at the end clear.
+    pub(crate) fn fits(&self, npieces: usize) -> bool {
+        self.payload.len() == npieces.div_ceil(8) && self.pieces().all(|piece_i| piece_i < npieces)
+    }
+
+    /// An empty bitfield with room for `npieces` pieces.
+    pub(crate) fn new(npieces: usize) -> Self {
+        Self {
+            payload: vec![0; npieces.div_ceil(8)],
+        }
+    }
+
+    pub(crate) fn set_piece(&mut self, piece_i: usize) {
+        let byte_i = piece_i / 8;
+        let bit_i = (piece_i % 8) as u32;
+
+        if let Some(byte) = self.payload.get_mut(byte_i) {
+            *byte |= 1u8.rotate_right(bit_i + 1);
+        }
+    }
 }
 
 impl Handshake {
@@ -203,9 +875,11 @@ impl Handshake {
         Self {
             length: 19,
             protocol: b"BitTorrent protocol".to_vec(),
-            reserved: vec![0; 8],
+            // Advertise support for the extension protocol (BEP 10) and for
+            // v2 torrents (BEP 52).
+            reserved: vec![0, 0, 0, 0, 0, 0x10, 0, 0x10],
             info_hash: info_hash.to_vec(),
-            peer_id: b"00112233445566778899".to_vec(),
+            peer_id: local_id().to_vec(),
         }
     }
 
@@ -219,6 +893,10 @@ impl Handshake {
         }
     }
 
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved.get(5).is_some_and(|byte| byte & 0x10 != 0)
+    }
+
     pub fn bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(68);
 
@@ -243,6 +921,10 @@ pub enum MessageId {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Extended = 20,
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
     Error,
 }
 
@@ -258,6 +940,10 @@ impl From<u8> for MessageId {
             6 => MessageId::Request,
             7 => MessageId::Piece,
             8 => MessageId::Cancel,
+            20 => MessageId::Extended,
+            21 => MessageId::HashRequest,
+            22 => MessageId::Hashes,
+            23 => MessageId::HashReject,
             _ => MessageId::Error,
         }
     }
@@ -275,35 +961,74 @@ impl From<MessageId> for u8 {
             MessageId::Request => 6,
             MessageId::Piece => 7,
             MessageId::Cancel => 8,
+            MessageId::Extended => 20,
+            MessageId::HashRequest => 21,
+            MessageId::Hashes => 22,
+            MessageId::HashReject => 23,
             MessageId::Error => panic!(),
         }
     }
 }
 
+/// Receive buffers for the messages of one connection, split off a shared
+/// allocation that is used again once every message read into it is dropped,
+/// instead of allocating for each message.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    free: BytesMut,
+}
+
+impl BufferPool {
+    /// A zeroed buffer of `len` bytes.
+    pub fn take(&mut self, len: usize) -> BytesMut {
+        if self.free.capacity() < len {
+            // Reclaims the allocation if nothing holds on to it anymore.
+            self.free.reserve(len.max(POOL_CHUNK));
+        }
+        self.free.resize(len, 0);
+        self.free.split_to(len)
+    }
+}
+
 pub struct Message {
     pub length: u32,
     pub id: MessageId,
-    pub payload: Vec<u8>,
+    /// Read into a buffer from the connection's pool, which blocks keep
+    /// sharing.
+    pub payload: Bytes,
 }
 
 impl Message {
-    pub async fn decode<R>(buf: &mut R) -> anyhow::Result<Self>
+    /// Reads the next message into a buffer from `buffers`, or `None` for a
+    /// keep-alive, which has no id. Fails on messages longer than `max_len`
+    /// without reading them.
+    pub async fn decode<R>(
+        buf: &mut R,
+        buffers: &mut BufferPool,
+        max_len: usize,
+    ) -> anyhow::Result<Option<Self>>
     where
         R: AsyncRead + Unpin,
     {
-        eprintln!("got a response");
         let length = buf.read_u32().await.context("can not read length u32")?;
-        eprintln!("Length: {length}");
+        if length == 0 {
+            trace!("Received keep-alive");
+            return Ok(None);
+        }
+        anyhow::ensure!(
+            length as usize <= max_len,
+            "message of {length} bytes is too long"
+        );
         let id = buf.read_u8().await.context("can not id length u32")?;
-        eprintln!("id: {id}");
-        let mut payload = vec![0; (length - 1) as usize];
+        trace!(length, id, "Received message");
+        let mut payload = buffers.take((length - 1) as usize);
         buf.read_exact(&mut payload).await?;
 
-        Ok(Self {
+        Ok(Some(Self {
             length,
             id: MessageId::from(id),
-            payload,
-        })
+            payload: payload.freeze(),
+        }))
     }
 
     pub async fn encode<W>(w: &mut W, id: MessageId, payload: &mut [u8]) -> anyhow::Result<()>
@@ -312,11 +1037,181 @@ impl Message {
     {
         let len_buf = (payload.len() + 1) as u32;
 
-        w.write_u32_le(len_buf).await?;
+        w.write_u32(len_buf).await?;
         w.write_u8(id.into()).await?;
         w.write_all(payload).await?;
         w.flush().await?;
 
         Ok(())
     }
+
+    /// Writes a keep-alive: a length of zero and nothing else.
+    pub async fn encode_keep_alive<W>(w: &mut W) -> anyhow::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        w.write_u32(0).await?;
+        w.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// The longest message a peer may send for a torrent of `npieces` pieces.
+fn max_message_len(npieces: usize) -> usize {
+    MAX_MESSAGE_LEN.max(1 + npieces.div_ceil(8))
+}
+
+/// The payload of the extension handshake (BEP 10).
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ExtensionHandshake {
+    /// Maps each supported extension to the message id it should be sent with.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+
+    /// Size of the info dictionary in bytes, sent by peers supporting `ut_metadata`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    fn ours() -> Self {
+        Self {
+            m: BTreeMap::from([("ut_metadata".to_string(), UT_METADATA_ID)]),
+            metadata_size: None,
+        }
+    }
+
+    /// The advertised `metadata_size`, if it is one worth allocating for.
+    fn checked_metadata_size(&self) -> anyhow::Result<usize> {
+        let size = self
+            .metadata_size
+            .ok_or_else(|| anyhow!("peer did not advertise metadata_size"))?;
+        anyhow::ensure!(
+            size > 0 && size <= MAX_METADATA_SIZE,
+            "peer advertised a metadata_size of {size} bytes"
+        );
+        Ok(size)
+    }
+}
+
+/// The bencoded header of a `ut_metadata` message (BEP 9).
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    const REQUEST: u8 = 0;
+    const DATA: u8 = 1;
+    const REJECT: u8 = 2;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        local_id, max_message_len, BufferPool, ExtensionHandshake, Handshake, Message, MessageId,
+        MAX_MESSAGE_LEN, MAX_METADATA_SIZE, PEER_ID_PREFIX, POOL_CHUNK,
+    };
+
+    #[tokio::test]
+    async fn test_decode_keep_alive() {
+        let mut bytes = Vec::new();
+        Message::encode_keep_alive(&mut bytes).await.unwrap();
+        Message::encode(&mut bytes, MessageId::Have, &mut [0, 0, 0, 7])
+            .await
+            .unwrap();
+        assert_eq!(bytes[..4], [0, 0, 0, 0]);
+
+        let mut reader = &bytes[..];
+        let mut buffers = BufferPool::default();
+        assert!(Message::decode(&mut reader, &mut buffers, MAX_MESSAGE_LEN)
+            .await
+            .unwrap()
+            .is_none());
+        let msg = Message::decode(&mut reader, &mut buffers, MAX_MESSAGE_LEN)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.id, MessageId::Have);
+        assert_eq!(msg.payload[..], [0, 0, 0, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_decode_too_long() {
+        let mut buffers = BufferPool::default();
+        let mut reader = &[0xff, 0xff, 0xff, 0xff, 7][..];
+        let err = Message::decode(&mut reader, &mut buffers, MAX_MESSAGE_LEN)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("too long"));
+
+        // A piece message carrying a block gets through, as does the bitfield
+        // of a torrent with many pieces.
+        let mut bytes = Vec::new();
+        Message::encode(&mut bytes, MessageId::Piece, &mut [0; 8 + 16384])
+            .await
+            .unwrap();
+        Message::encode(&mut bytes, MessageId::Bitfield, &mut [0; 100_000])
+            .await
+            .unwrap();
+        let mut reader = &bytes[..];
+        let max_len = max_message_len(800_000);
+        for _ in 0..2 {
+            Message::decode(&mut reader, &mut buffers, max_len)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(max_message_len(8), MAX_MESSAGE_LEN);
+    }
+
+    #[test]
+    fn test_local_id_is_azureus_style_and_stable() {
+        let id = local_id();
+        assert_eq!(&id[..8], PEER_ID_PREFIX);
+        assert!(id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_eq!(local_id(), id);
+        assert_eq!(Handshake::new(&[0; 20]).peer_id, id);
+    }
+
+    #[test]
+    fn test_buffer_pool_reuse() {
+        let mut buffers = BufferPool::default();
+        let first = buffers.take(POOL_CHUNK / 2).freeze();
+        let second = buffers.take(POOL_CHUNK / 2);
+        assert_eq!(first.as_ptr().wrapping_add(POOL_CHUNK / 2), second.as_ptr());
+
+        // Still in use, so the next buffer comes from a new allocation.
+        let third = buffers.take(POOL_CHUNK / 2);
+        assert_ne!(third.as_ptr(), first.as_ptr());
+
+        let reused = third.as_ptr();
+        drop((first, second, third));
+        assert_eq!(buffers.take(POOL_CHUNK).as_ptr(), reused);
+    }
+
+    #[test]
+    fn test_metadata_size_bounds() {
+        let advertised = |metadata_size| {
+            ExtensionHandshake {
+                metadata_size,
+                ..Default::default()
+            }
+            .checked_metadata_size()
+        };
+        assert!(advertised(None).is_err());
+        assert!(advertised(Some(0)).is_err());
+        assert!(advertised(Some(MAX_METADATA_SIZE + 1)).is_err());
+        assert!(advertised(Some(usize::MAX)).is_err());
+        assert_eq!(advertised(Some(1)).unwrap(), 1);
+        assert_eq!(
+            advertised(Some(MAX_METADATA_SIZE)).unwrap(),
+            MAX_METADATA_SIZE
+        );
+    }
 }
@@ -1,66 +1,257 @@
-use std::collections::HashSet;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashSet},
+};
 
-use crate::{peer::Peer, torrent::Torrent};
+use crate::{peer::Bitfield, torrent::Torrent};
+
+/// How soon a file's pieces are downloaded, compared to the other files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Not downloaded at all.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Piece {
     peers: HashSet<usize>,
     piece_i: usize,
     length: usize,
-    hash: [u8; 20],
+    priority: Priority,
+    done: bool,
 }
 
-impl Ord for Piece {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.peers
-            .len()
-            .cmp(&other.peers.len())
-            .then(self.peers.iter().cmp(other.peers.iter()))
-            .then(self.hash.cmp(&other.hash))
-            .then(self.length.cmp(&other.length))
-            .then(self.piece_i.cmp(&other.piece_i))
+impl Piece {
+    pub(crate) fn new(piece_i: usize, t: &Torrent) -> Self {
+        Self {
+            peers: HashSet::new(),
+            piece_i,
+            length: t.piece_len(piece_i),
+            priority: Priority::Normal,
+            done: false,
+        }
+    }
+
+    pub(crate) fn index(&self) -> usize {
+        self.piece_i
+    }
+
+    pub(crate) fn length(&self) -> usize {
+        self.length
     }
-}
 
-impl PartialOrd for Piece {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+    /// Where the piece is in [`Picker::wanted`].
+    fn key(&self) -> (Reverse<Priority>, usize, usize) {
+        (Reverse(self.priority), self.peers.len(), self.piece_i)
     }
 }
 
-impl Piece {
-    pub(crate) fn new(piece_i: usize, t: &Torrent, peers: &[Peer]) -> Self {
-        let piece_hash = t.info.pieces.0[piece_i];
-        let plength = t.info.plength;
-        let piece_size = plength.min(t.length() - plength * piece_i);
+/// Chooses which piece to download next: the highest priority first, and
+/// among those the rarest.
+///
+/// Availability is kept up to date as peers connect, disconnect and announce
+/// new pieces, so the order changes with the swarm instead of being fixed when
+/// the download starts.
+pub struct Picker {
+    pieces: Vec<Piece>,
+
+    /// Pieces still to be downloaded, keyed by [`Piece::key`].
+    wanted: BTreeSet<(Reverse<Priority>, usize, usize)>,
+}
+
+impl Picker {
+    /// A picker for every piece of `t` that `have` doesn't have yet, at the
+    /// given piece `priorities`.
+    pub(crate) fn new(t: &Torrent, have: impl Fn(usize) -> bool, priorities: &[Priority]) -> Self {
+        let mut picker = Self {
+            pieces: (0..t.info.piece_count())
+                .map(|piece_i| Piece::new(piece_i, t))
+                .collect(),
+            wanted: BTreeSet::new(),
+        };
+        for (piece, &priority) in picker.pieces.iter_mut().zip(priorities) {
+            piece.done = have(piece.piece_i);
+            piece.priority = priority;
+            if !piece.done && piece.priority != Priority::Skip {
+                picker.wanted.insert(piece.key());
+            }
+        }
+
+        picker
+    }
+
+    /// Changes the priority of a piece that isn't downloaded yet; a skipped
+    /// piece is no longer picked, and doesn't keep [`Picker::is_done`] false.
+    pub(crate) fn set_priority(&mut self, piece_i: usize, priority: Priority) {
+        let piece = &mut self.pieces[piece_i];
+        if piece.done || piece.priority == priority {
+            return;
+        }
+
+        self.wanted.remove(&piece.key());
+        piece.priority = priority;
+        if priority != Priority::Skip {
+            self.wanted.insert(piece.key());
+        }
+    }
+
+    pub(crate) fn peer_connected(&mut self, peer_i: usize, bitfield: &Bitfield) {
+        for piece_i in bitfield.pieces() {
+            self.peer_has(peer_i, piece_i);
+        }
+    }
+
+    pub(crate) fn peer_disconnected(&mut self, peer_i: usize) {
+        for piece_i in 0..self.pieces.len() {
+            self.update(piece_i, |peers| {
+                peers.remove(&peer_i);
+            });
+        }
+    }
+
+    /// Records that `peer_i` has `piece_i`, e.g. after a `Have` message.
+    pub(crate) fn peer_has(&mut self, peer_i: usize, piece_i: usize) {
+        self.update(piece_i, |peers| {
+            peers.insert(peer_i);
+        });
+    }
 
-        let peers = peers
+    /// The first wanted piece that at least one connected peer has, out of
+    /// those accepted by `eligible`.
+    pub(crate) fn pick(&self, eligible: impl Fn(usize) -> bool) -> Option<&Piece> {
+        self.wanted
             .iter()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| peer.has_piece(piece_i).then_some(peer_i))
-            .collect();
+            .find(|&&(_, availability, piece_i)| availability > 0 && eligible(piece_i))
+            .map(|&(_, _, piece_i)| &self.pieces[piece_i])
+    }
 
-        Self {
-            peers,
-            piece_i,
-            length: piece_size,
-            hash: piece_hash,
+    /// Whether any wanted piece is accepted by `has`.
+    pub(crate) fn wants(&self, has: impl Fn(usize) -> bool) -> bool {
+        self.wanted.iter().any(|&(_, _, piece_i)| has(piece_i))
+    }
+
+    /// Marks `piece_i` as downloaded, so it is never picked again.
+    pub(crate) fn done(&mut self, piece_i: usize) {
+        let piece = &mut self.pieces[piece_i];
+        piece.done = true;
+        self.wanted.remove(&piece.key());
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.wanted.is_empty()
+    }
+
+    fn update(&mut self, piece_i: usize, f: impl FnOnce(&mut HashSet<usize>)) {
+        let Some(piece) = self.pieces.get_mut(piece_i) else {
+            return;
+        };
+
+        let before = piece.key();
+        f(&mut piece.peers);
+
+        if before != piece.key() && self.wanted.remove(&before) {
+            self.wanted.insert(piece.key());
         }
     }
+}
 
-    pub(crate) fn peers(&self) -> &HashSet<usize> {
-        &self.peers
+#[cfg(test)]
+mod tests {
+    use super::{Picker, Priority};
+    use crate::{peer::Bitfield, torrent::Torrent};
+
+    /// A torrent of four 4-byte pieces.
+    fn torrent() -> Torrent {
+        Torrent::for_test(4, &[16])
     }
 
-    pub(crate) fn index(&self) -> usize {
-        self.piece_i
+    fn picked(picker: &Picker) -> Option<usize> {
+        picker.pick(|_| true).map(|piece| piece.index())
     }
 
-    pub(crate) fn length(&self) -> usize {
-        self.length
+    #[test]
+    fn test_pick_rarest() {
+        let mut picker = Picker::new(&torrent(), |_| false, &[Priority::Normal; 4]);
+        assert_eq!(picked(&picker), None);
+
+        // Peer 0 has every piece, peer 1 all but piece 2.
+        picker.peer_connected(0, &Bitfield::from_payload(vec![0b1111_0000]));
+        picker.peer_connected(1, &Bitfield::from_payload(vec![0b1101_0000]));
+        assert_eq!(picked(&picker), Some(2));
+
+        picker.peer_has(1, 2);
+        picker.peer_has(2, 0);
+        picker.peer_has(2, 2);
+        assert_eq!(picked(&picker), Some(1));
+
+        // Left with peer 1, which has them all, the lowest index goes first.
+        picker.peer_disconnected(2);
+        picker.peer_disconnected(0);
+        assert_eq!(picked(&picker), Some(0));
+        picker.peer_disconnected(1);
+        assert_eq!(picked(&picker), None);
     }
 
-    pub(crate) fn hash(&self) -> &[u8] {
-        &self.hash
+    #[test]
+    fn test_skip_and_done() {
+        let mut picker = Picker::new(&torrent(), |piece_i| piece_i == 0, &[Priority::Normal; 4]);
+        picker.peer_connected(0, &Bitfield::from_payload(vec![0b1111_0000]));
+        picker.peer_has(1, 3);
+
+        // Piece 0 is had already; piece 1 is as rare as piece 2 but first.
+        assert_eq!(picked(&picker), Some(1));
+        picker.done(1);
+        assert_eq!(picked(&picker), Some(2));
+        assert_eq!(picker.pick(|piece_i| piece_i != 2).unwrap().index(), 3);
+
+        picker.set_priority(2, Priority::Skip);
+        assert_eq!(picked(&picker), Some(3));
+        // A done piece stays done, whatever else comes in about it.
+        picker.set_priority(3, Priority::High);
+        picker.peer_has(1, 1);
+        picker.set_priority(1, Priority::High);
+        assert_eq!(picked(&picker), Some(3));
+
+        // The skipped piece is all that is left, which counts as done.
+        picker.done(3);
+        assert!(!picker.wants(|piece_i| piece_i == 2));
+        assert!(picker.is_done());
+        assert_eq!(picked(&picker), None);
+    }
+
+    #[test]
+    fn test_priority_before_rarity() {
+        // Pieces 0 and 1 belong to a, piece 1 also to b, pieces 2 and 3 to c.
+        let t = Torrent::for_test(4, &[6, 2, 8]);
+        let priorities = t
+            .info
+            .piece_priorities(&[Priority::Low, Priority::High, Priority::Skip]);
+        assert_eq!(
+            priorities,
+            [
+                Priority::Low,
+                Priority::High,
+                Priority::Skip,
+                Priority::Skip
+            ]
+        );
+
+        let mut picker = Picker::new(&t, |_| false, &priorities);
+        picker.peer_connected(0, &Bitfield::from_payload(vec![0b1111_0000]));
+        picker.peer_connected(1, &Bitfield::from_payload(vec![0b0111_0000]));
+        // Piece 0 is rarer, but piece 1 is wanted sooner.
+        assert_eq!(picked(&picker), Some(1));
+        picker.done(1);
+        assert_eq!(picked(&picker), Some(0));
+
+        picker.set_priority(3, Priority::High);
+        assert_eq!(picked(&picker), Some(3));
+        picker.done(3);
+        picker.done(0);
+        assert!(picker.is_done());
     }
 }
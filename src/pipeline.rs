@@ -0,0 +1,145 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::block::BLOCK_SIZE;
+
+/// The fewest and the most blocks requested from a single peer at once.
+pub const MIN_DEPTH: usize = 2;
+pub const MAX_DEPTH: usize = 250;
+
+/// Blocks requested from a peer before its speed is known.
+const START_DEPTH: usize = 4;
+
+/// How long the blocks requested from a peer should keep it busy beyond the
+/// round trip, so that its link never idles waiting for our next request.
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+
+/// The rate is sampled over windows at least this long.
+const SAMPLE_WINDOW: Duration = Duration::from_secs(1);
+
+/// How much a new sample moves the smoothed rate and latency.
+const SMOOTHING: f64 = 0.3;
+
+/// Sizes the request queue of a peer to what it can deliver, like libtorrent
+/// does: enough blocks to cover its throughput over the round trip plus
+/// [`QUEUE_TIME`]. Fast peers get deep queues, slow peers few blocks to sit
+/// on.
+#[derive(Debug)]
+pub struct Pipeline {
+    /// When each outstanding request was sent, oldest first; peers answer
+    /// requests in order.
+    sent: VecDeque<Instant>,
+    /// Smoothed time from a request to its block.
+    latency: Option<Duration>,
+    /// Smoothed throughput in bytes per second.
+    rate: Option<f64>,
+    window_start: Option<Instant>,
+    window_bytes: usize,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self {
+            sent: VecDeque::new(),
+            latency: None,
+            rate: None,
+            window_start: None,
+            window_bytes: 0,
+        }
+    }
+
+    /// Records a request sent at `now`.
+    pub fn requested(&mut self, now: Instant) {
+        self.window_start.get_or_insert(now);
+        self.sent.push_back(now);
+    }
+
+    /// Records a block of `bytes` that arrived at `now`.
+    pub fn received(&mut self, bytes: usize, now: Instant) {
+        if let Some(sent) = self.sent.pop_front() {
+            let latency = now.saturating_duration_since(sent);
+            self.latency = Some(match self.latency {
+                Some(smoothed) => smoothed.mul_f64(1.0 - SMOOTHING) + latency.mul_f64(SMOOTHING),
+                None => latency,
+            });
+        }
+
+        self.window_bytes += bytes;
+        let start = *self.window_start.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed >= SAMPLE_WINDOW {
+            let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.rate = Some(match self.rate {
+                Some(smoothed) => smoothed * (1.0 - SMOOTHING) + sample * SMOOTHING,
+                None => sample,
+            });
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+        }
+    }
+
+    /// Forgets the outstanding requests, e.g. when the peer chokes us.
+    pub fn cancelled(&mut self) {
+        self.sent.clear();
+    }
+
+    /// How many blocks to keep requested from the peer.
+    pub fn depth(&self) -> usize {
+        let Some(rate) = self.rate else {
+            return START_DEPTH;
+        };
+        let time = QUEUE_TIME + self.latency.unwrap_or_default();
+        let depth = (rate * time.as_secs_f64() / BLOCK_SIZE as f64).ceil() as usize;
+        depth.clamp(MIN_DEPTH, MAX_DEPTH)
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Pipeline, MAX_DEPTH, MIN_DEPTH, START_DEPTH};
+    use crate::block::BLOCK_SIZE;
+
+    /// A peer that sends `blocks_per_sec` blocks for 20 seconds, each
+    /// arriving `latency` after it was requested.
+    fn measure(blocks_per_sec: u32, latency: Duration) -> usize {
+        let mut pipeline = Pipeline::new();
+        let start = Instant::now();
+        let gap = Duration::from_secs(1) / blocks_per_sec;
+        for i in 0..blocks_per_sec * 20 {
+            let sent = start + gap * i;
+            pipeline.requested(sent);
+            pipeline.received(BLOCK_SIZE as usize, sent + latency);
+        }
+        pipeline.depth()
+    }
+
+    #[test]
+    fn test_depth_follows_rate() {
+        assert_eq!(Pipeline::new().depth(), START_DEPTH);
+
+        let slow = measure(1, Duration::from_millis(500));
+        let fast = measure(40, Duration::from_millis(50));
+        let far = measure(40, Duration::from_secs(1));
+        // 16 KiB/s over 3.5 s, and 640 KiB/s over 3.05 s and 4 s.
+        assert_eq!(slow, 4);
+        assert_eq!(fast, 122);
+        assert_eq!(far, 160);
+        assert_eq!(measure(1000, Duration::ZERO), MAX_DEPTH);
+
+        let mut idle = Pipeline::new();
+        let start = Instant::now();
+        idle.requested(start);
+        idle.received(BLOCK_SIZE as usize, start + Duration::from_secs(20));
+        assert_eq!(idle.depth(), MIN_DEPTH);
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+
+/// How often a [`Schedule`] is checked against the clock.
+const SCHEDULE_CHECK: Duration = Duration::from_secs(60);
+
+/// The download and upload limits of a session, shared by all its peer
+/// connections.
+#[derive(Debug, Default)]
+pub struct Limits {
+    pub down: RateLimiter,
+    pub up: RateLimiter,
+}
+
+/// A token bucket letting through a number of bytes per second, in bursts of
+/// at most a second's worth. Unlimited until given a rate.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    rate: Option<u64>,
+    /// Bytes that may pass right away; negative after a transfer bigger than
+    /// what was left, which later ones then wait out.
+    tokens: f64,
+    refilled: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        let limiter = Self::default();
+        limiter.set_rate(rate);
+        limiter
+    }
+
+    /// Bytes per second, or `None` for no limit. Takes effect for transfers
+    /// that are waiting too.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().expect("lock is not poisoned");
+        bucket.rate = rate.filter(|&rate| rate > 0);
+        bucket.tokens = 0.0;
+        bucket.refilled = None;
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().expect("lock is not poisoned").rate
+    }
+
+    /// Waits until `bytes` may be transferred.
+    pub async fn acquire(&self, bytes: usize) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().expect("lock is not poisoned");
+                let Some(rate) = bucket.rate else {
+                    return;
+                };
+
+                let now = Instant::now();
+                let elapsed = bucket
+                    .refilled
+                    .map_or(Duration::ZERO, |refilled| now - refilled);
+                bucket.tokens =
+                    (bucket.tokens + elapsed.as_secs_f64() * rate as f64).min(rate as f64);
+                bucket.refilled = Some(now);
+
+                if bucket.tokens >= 0.0 {
+                    bucket.tokens -= bytes as f64;
+                    return;
+                }
+                Duration::from_secs_f64(-bucket.tokens / rate as f64)
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// A rate limit that depends on the time of day, e.g. 1 MiB/s from 08:00 to
+/// 23:00 and unlimited otherwise.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    /// The first rule whose period includes the current time applies.
+    pub rules: Vec<Rule>,
+    /// The rate outside every rule's period.
+    pub otherwise: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    /// Bytes per second, or `None` for no limit.
+    pub rate: Option<u64>,
+    /// Minutes since midnight; a period may wrap around midnight.
+    pub from: u32,
+    pub to: u32,
+}
+
+impl Rule {
+    fn includes(&self, minute: u32) -> bool {
+        if self.from <= self.to {
+            (self.from..self.to).contains(&minute)
+        } else {
+            minute >= self.from || minute < self.to
+        }
+    }
+}
+
+impl Schedule {
+    /// A rate at all times.
+    pub fn constant(rate: Option<u64>) -> Self {
+        Self {
+            rules: Vec::new(),
+            otherwise: rate,
+        }
+    }
+
+    /// Parses a rate followed, for a [`Rule`], by a period: `1MB/s`,
+    /// `unlimited 23:00-08:00`, `500K/s 08:00-23:00`.
+    pub fn add(&mut self, s: &str) -> anyhow::Result<()> {
+        let mut words = s.split_whitespace();
+        let rate = parse_rate(words.next().ok_or_else(|| anyhow!("no rate"))?)?;
+
+        match words.next() {
+            None => self.otherwise = rate,
+            Some(period) => {
+                let (from, to) = period
+                    .split_once('-')
+                    .ok_or_else(|| anyhow!("period {period} is not FROM-TO"))?;
+                self.rules.push(Rule {
+                    rate,
+                    from: parse_time(from)?,
+                    to: parse_time(to)?,
+                });
+            }
+        }
+        anyhow::ensure!(words.next().is_none(), "unexpected words after {s}");
+
+        Ok(())
+    }
+
+    /// The rate at `minute` minutes since midnight.
+    pub fn rate_at(&self, minute: u32) -> Option<u64> {
+        self.rules
+            .iter()
+            .find(|rule| rule.includes(minute))
+            .map_or(self.otherwise, |rule| rule.rate)
+    }
+}
+
+/// Parses bytes per second, with an optional `K`/`M`/`G` (binary) unit and
+/// `/s`, or `unlimited`.
+pub fn parse_rate(s: &str) -> anyhow::Result<Option<u64>> {
+    if s.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+
+    let s = s.strip_suffix("/s").unwrap_or(s);
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let number: u64 = number.parse().with_context(|| format!("rate {s}"))?;
+    let unit = match unit.trim_end_matches("iB").trim_end_matches('B') {
+        "" => 1,
+        "K" | "k" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => anyhow::bail!("unknown unit in rate {s}"),
+    };
+
+    let rate = number
+        .checked_mul(unit)
+        .ok_or_else(|| anyhow!("rate {s} is too large"))?;
+    Ok(Some(rate))
+}
+
+/// Parses `HH:MM` into minutes since midnight.
+fn parse_time(s: &str) -> anyhow::Result<u32> {
+    let (hours, minutes) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("time {s} is not HH:MM"))?;
+    let hours: u32 = hours.parse().with_context(|| format!("time {s}"))?;
+    let minutes: u32 = minutes.parse().with_context(|| format!("time {s}"))?;
+    anyhow::ensure!(hours < 24 && minutes < 60, "time {s} is out of range");
+
+    Ok(hours * 60 + minutes)
+}
+
+/// Keeps `limits` at the rates the schedules set for the time of day, which is
+/// UTC shifted by `utc_offset` minutes. Runs forever.
+pub async fn follow_schedule(limits: Arc<Limits>, down: Schedule, up: Schedule, utc_offset: i32) {
+    loop {
+        let since_epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let minute = ((since_epoch / 60 + i64::from(utc_offset)).rem_euclid(24 * 60)) as u32;
+
+        for (limiter, schedule) in [(&limits.down, &down), (&limits.up, &up)] {
+            let rate = schedule.rate_at(minute).filter(|&rate| rate > 0);
+            if limiter.rate() != rate {
+                limiter.set_rate(rate);
+            }
+        }
+
+        tokio::time::sleep(SCHEDULE_CHECK).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_rate, RateLimiter, Schedule};
+
+    #[test]
+    fn test_schedule() {
+        let mut schedule = Schedule::default();
+        schedule.add("1MB/s 08:00-23:00").unwrap();
+        schedule.add("100K/s 23:00-01:00").unwrap();
+
+        assert_eq!(schedule.rate_at(8 * 60), Some(1 << 20));
+        assert_eq!(schedule.rate_at(23 * 60 + 30), Some(100 << 10));
+        assert_eq!(schedule.rate_at(30), Some(100 << 10));
+        assert_eq!(schedule.rate_at(2 * 60), None);
+
+        schedule.add("5000").unwrap();
+        assert_eq!(schedule.rate_at(2 * 60), Some(5000));
+        assert!(schedule.add("1MB/s 8-23").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("512").unwrap(), Some(512));
+        assert_eq!(parse_rate("2KiB/s").unwrap(), Some(2 << 10));
+        assert_eq!(parse_rate("3G").unwrap(), Some(3 << 30));
+        assert_eq!(parse_rate("unlimited").unwrap(), None);
+        assert!(parse_rate("1T").is_err());
+
+        let e = parse_rate("99999999999999G").unwrap_err();
+        assert!(e.to_string().contains("too large"), "{e}");
+        assert!(parse_rate("99999999999999999999").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_limit_rate() {
+        let limiter = RateLimiter::new(Some(10_000));
+        let start = std::time::Instant::now();
+
+        // The first transfer goes through and puts the bucket in debt.
+        limiter.acquire(3000).await;
+        limiter.acquire(1).await;
+
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(290), "{waited:?}");
+
+        limiter.set_rate(None);
+        let start = std::time::Instant::now();
+        limiter.acquire(1 << 20).await;
+        limiter.acquire(1 << 20).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
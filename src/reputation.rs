@@ -0,0 +1,91 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
+
+/// The score at which a peer is banned for the rest of the session.
+pub const BAN_SCORE: u32 = 100;
+
+/// Ways a peer can misbehave, each worth a penalty to its score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    /// A message that can't be decoded or breaks the protocol, like a block
+    /// of the wrong size.
+    ProtocolViolation,
+    /// A bitfield or `Have` for pieces the torrent doesn't have.
+    BogusBitfield,
+    /// Nothing sent, not even a keep-alive, for the peer timeout.
+    Stall,
+    /// Blocks of a piece that failed the hash check, along with other peers.
+    CorruptData,
+    /// Every block of a piece that failed the hash check, so most likely the
+    /// peer's fault; still not enough for a ban on its own, as the peer may
+    /// have been fed bad data itself.
+    SoleCorruptData,
+    /// More requests in a short while than any download needs, like hash
+    /// requests that each cost us hashing.
+    Flood,
+}
+
+impl Offense {
+    fn penalty(self) -> u32 {
+        match self {
+            Self::Stall => 10,
+            Self::ProtocolViolation | Self::Flood => 25,
+            Self::BogusBitfield | Self::CorruptData => 50,
+            Self::SoleCorruptData => 75,
+        }
+    }
+}
+
+/// The misbehavior scores of peer addresses, shared by every torrent of a
+/// session, so that a peer banned by one is neither connected to again when
+/// a tracker returns it, nor accepted by any.
+#[derive(Debug, Default)]
+pub struct Reputation {
+    scores: Mutex<HashMap<IpAddr, u32>>,
+}
+
+impl Reputation {
+    /// Adds the penalty for `offense` to the score of `ip`, and returns
+    /// whether that got it banned just now.
+    pub fn penalize(&self, ip: IpAddr, offense: Offense) -> bool {
+        let mut scores = self.scores.lock().expect("lock is not poisoned");
+        let score = scores.entry(ip).or_default();
+        let was_banned = *score >= BAN_SCORE;
+        *score = score.saturating_add(offense.penalty());
+        !was_banned && *score >= BAN_SCORE
+    }
+
+    pub fn score(&self, ip: IpAddr) -> u32 {
+        let scores = self.scores.lock().expect("lock is not poisoned");
+        scores.get(&ip).copied().unwrap_or_default()
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.score(ip) >= BAN_SCORE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{Offense, Reputation};
+
+    #[test]
+    fn test_ban_after_offenses() {
+        let reputation = Reputation::default();
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        assert!(!reputation.penalize(peer, Offense::CorruptData));
+        assert!(!reputation.penalize(peer, Offense::Stall));
+        assert!(!reputation.is_banned(peer));
+        assert!(reputation.penalize(peer, Offense::CorruptData));
+        assert!(reputation.is_banned(peer));
+        // Banned once only.
+        assert!(!reputation.penalize(peer, Offense::Stall));
+
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        assert!(!reputation.penalize(other, Offense::SoleCorruptData));
+        assert!(reputation.penalize(other, Offense::SoleCorruptData));
+        assert_eq!(reputation.score(IpAddr::V4(Ipv4Addr::LOCALHOST)), 0);
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{peer::Bitfield, torrent::Torrent};
+
+/// Fast-resume state, persisted next to the output after every verified piece,
+/// which is synced to the output files by then.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Resume {
+    #[serde(with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+
+    /// Bitfield of the pieces that passed verification.
+    #[serde(with = "serde_bytes")]
+    pub bitfield: Vec<u8>,
+
+    /// Verified bytes per file, in the order of the torrent's file list.
+    pub files: Vec<usize>,
+
+    pub tracker: TrackerState,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TrackerState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+    pub downloaded: usize,
+    pub uploaded: usize,
+}
+
+impl Resume {
+    pub fn new(t: &Torrent) -> Self {
+        Self {
+            info_hash: t.info_hash().to_vec(),
+            bitfield: Vec::new(),
+            files: Vec::new(),
+            tracker: TrackerState {
+                announce: t.announce.clone(),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn path(output: &Path) -> PathBuf {
+        with_suffix(output, ".resume")
+    }
+
+    /// Loads the resume file for `output`, ignoring it if it belongs to another torrent.
+    pub async fn load(output: &Path, t: &Torrent) -> anyhow::Result<Option<Self>> {
+        let bytes = match tokio::fs::read(Self::path(output)).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("read resume file"),
+        };
+        let resume: Self = serde_bencode::from_bytes(&bytes).context("parse resume file")?;
+
+        if resume.info_hash != t.info_hash() {
+            tracing::warn!("Ignoring resume file for another torrent");
+            return Ok(None);
+        }
+
+        Ok(Some(resume))
+    }
+
+    /// Records `bitfield` and writes the resume file atomically.
+    pub async fn save(
+        &mut self,
+        output: &Path,
+        t: &Torrent,
+        bitfield: &Bitfield,
+    ) -> anyhow::Result<()> {
+        self.bitfield = bitfield.as_bytes().to_vec();
+        self.files = file_progress(t, bitfield);
+
+        let tmp = with_suffix(output, ".resume.tmp");
+        // The pieces' data was synced as it was written; sync the new file
+        // too, so a crash can't leave an empty one in place of the old.
+        let mut file = tokio::fs::File::create(&tmp)
+            .await
+            .context("create resume file")?;
+        file.write_all(&serde_bencode::to_bytes(self)?)
+            .await
+            .context("write resume file")?;
+        file.sync_all().await.context("sync resume file")?;
+        tokio::fs::rename(&tmp, Self::path(output))
+            .await
+            .context("replace resume file")?;
+
+        Ok(())
+    }
+
+    /// Deletes the resume file once the download is complete.
+    pub async fn remove(output: &Path) -> anyhow::Result<()> {
+        match tokio::fs::remove_file(Self::path(output)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How many verified bytes each file has. Only the pieces a file spans are
+/// looked at, so that each piece is counted about once in all.
+fn file_progress(t: &Torrent, bitfield: &Bitfield) -> Vec<usize> {
+    let lengths = t.info.file_lengths();
+
+    let plength = t.info.plength;
+    let mut offset = 0;
+    lengths
+        .into_iter()
+        .map(|length| {
+            let (start, end) = (offset, offset + length);
+            offset = end;
+
+            (start / plength..end.div_ceil(plength))
+                .filter(|&piece_i| bitfield.has_piece(piece_i))
+                .map(|piece_i| {
+                    let (piece_start, piece_end) = (piece_i * plength, (piece_i + 1) * plength);
+                    piece_end.min(end).saturating_sub(piece_start.max(start))
+                })
+                .sum()
+        })
+        .collect()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_progress, Resume};
+    use crate::{peer::Bitfield, torrent::Torrent};
+
+    /// Files of 3, 6 and 2 bytes in pieces of 4: the first piece spans the
+    /// first two files, the last the last two.
+    fn torrent(name: &str) -> Torrent {
+        let mut t = Torrent::for_test(4, &[3, 6, 2]);
+        t.announce = Some("http://tracker.example/announce".to_string());
+        t.info.name = name.to_string();
+        t
+    }
+
+    #[test]
+    fn test_file_progress() {
+        let t = torrent("files");
+        let progress = |bits| file_progress(&t, &Bitfield::from_payload(vec![bits]));
+
+        assert_eq!(progress(0b0000_0000), [0, 0, 0]);
+        assert_eq!(progress(0b0100_0000), [0, 4, 0]);
+        assert_eq!(progress(0b1010_0000), [3, 2, 2]);
+        assert_eq!(progress(0b1110_0000), [3, 6, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let output = std::env::temp_dir().join(format!("resume-test-{}", std::process::id()));
+        let t = torrent("files");
+        assert!(Resume::load(&output, &t).await.unwrap().is_none());
+
+        let mut resume = Resume::new(&t);
+        resume.tracker.uploaded = 7;
+        resume
+            .save(&output, &t, &Bitfield::from_payload(vec![0b1010_0000]))
+            .await
+            .unwrap();
+
+        let loaded = Resume::load(&output, &t).await.unwrap().unwrap();
+        assert_eq!(loaded.bitfield, [0b1010_0000]);
+        assert_eq!(loaded.files, [3, 2, 2]);
+        assert_eq!(loaded.tracker.uploaded, 7);
+        assert_eq!(loaded.tracker.announce, t.announce);
+
+        // The same output, but another torrent.
+        let other = torrent("other");
+        assert!(Resume::load(&output, &other).await.unwrap().is_none());
+
+        Resume::remove(&output).await.unwrap();
+        assert!(Resume::load(&output, &t).await.unwrap().is_none());
+    }
+}
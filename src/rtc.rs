@@ -0,0 +1,272 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{anyhow, Context};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::oneshot,
+};
+use webrtc::{
+    api::{setting_engine::SettingEngine, APIBuilder},
+    data::data_channel::PollDataChannel,
+    data_channel::RTCDataChannel,
+    ice_transport::ice_server::RTCIceServer,
+    peer_connection::{
+        configuration::RTCConfiguration, sdp::session_description::RTCSessionDescription,
+        RTCPeerConnection,
+    },
+};
+
+/// The STUN servers WebTorrent clients use to find their public addresses.
+const STUN_SERVERS: &[&str] = &[
+    "stun:stun.l.google.com:19302",
+    "stun:global.stun.twilio.com:3478",
+];
+
+/// The largest message a data channel carries; writes are cut to this size,
+/// and reads are sized to take any message whole.
+const MAX_MESSAGE: usize = 1 << 16;
+
+/// An offer of a data channel, to be relayed to a WebTorrent peer by a
+/// tracker and completed with the peer's answer.
+pub struct Offer {
+    connection: Connection,
+    opened: oneshot::Receiver<Arc<RTCDataChannel>>,
+    sdp: String,
+}
+
+impl Offer {
+    pub async fn new() -> anyhow::Result<Self> {
+        let connection = Connection::new().await?;
+        let channel = connection
+            .0
+            .create_data_channel("webtorrent", None)
+            .await
+            .context("create data channel")?;
+        let (tx, opened) = oneshot::channel();
+        on_open(channel, tx);
+
+        let offer = connection.0.create_offer(None).await?;
+        let sdp = connection.describe(offer).await?;
+
+        Ok(Self {
+            connection,
+            opened,
+            sdp,
+        })
+    }
+
+    /// The session description to send, with every ICE candidate in it.
+    pub fn sdp(&self) -> &str {
+        &self.sdp
+    }
+
+    /// Connects with the peer that answered, once the data channel is open.
+    pub async fn accept(self, answer: &str) -> anyhow::Result<(SocketAddr, DataChannel)> {
+        let answer = RTCSessionDescription::answer(answer.to_string())?;
+        let addr = candidate_addr(&answer.sdp);
+        self.connection.0.set_remote_description(answer).await?;
+
+        let channel = open(self.connection, self.opened).await?;
+        Ok((addr, channel))
+    }
+}
+
+/// Our answer to a WebTorrent peer's offer, whose data channel opens once the
+/// tracker has relayed the answer back.
+pub struct Answer {
+    connection: Connection,
+    opened: oneshot::Receiver<Arc<RTCDataChannel>>,
+    sdp: String,
+    addr: SocketAddr,
+}
+
+impl Answer {
+    pub async fn new(offer: &str) -> anyhow::Result<Self> {
+        let connection = Connection::new().await?;
+        // The peer that offers creates the channel.
+        let (tx, opened) = oneshot::channel();
+        let tx = Mutex::new(Some(tx));
+        connection.0.on_data_channel(Box::new(move |channel| {
+            let tx = tx.lock().expect("lock is not poisoned").take();
+            if let Some(tx) = tx {
+                on_open(channel, tx);
+            }
+            Box::pin(async {})
+        }));
+
+        let offer = RTCSessionDescription::offer(offer.to_string())?;
+        let addr = candidate_addr(&offer.sdp);
+        connection.0.set_remote_description(offer).await?;
+        let answer = connection.0.create_answer(None).await?;
+        let sdp = connection.describe(answer).await?;
+
+        Ok(Self {
+            connection,
+            opened,
+            sdp,
+            addr,
+        })
+    }
+
+    /// The session description to send back, with every ICE candidate in it.
+    pub fn sdp(&self) -> &str {
+        &self.sdp
+    }
+
+    /// Waits for the peer to open its data channel.
+    pub async fn open(self) -> anyhow::Result<(SocketAddr, DataChannel)> {
+        let channel = open(self.connection, self.opened).await?;
+        Ok((self.addr, channel))
+    }
+}
+
+/// An open data channel to a WebTorrent peer, read and written as a stream.
+pub struct DataChannel {
+    inner: PollDataChannel,
+    _connection: Connection,
+}
+
+impl AsyncRead for DataChannel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for DataChannel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let len = buf.len().min(MAX_MESSAGE);
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, &buf[..len])
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// A peer connection, closed when dropped.
+struct Connection(Arc<RTCPeerConnection>);
+
+impl Connection {
+    async fn new() -> anyhow::Result<Self> {
+        let mut settings = SettingEngine::default();
+        // Data channels are read and written as streams, not through callbacks.
+        settings.detach_data_channels();
+        let api = APIBuilder::new().with_setting_engine(settings).build();
+
+        let config = RTCConfiguration {
+            ice_servers: vec![RTCIceServer {
+                urls: STUN_SERVERS.iter().map(|url| url.to_string()).collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let connection = api
+            .new_peer_connection(config)
+            .await
+            .context("create peer connection")?;
+
+        Ok(Self(Arc::new(connection)))
+    }
+
+    /// Sets `description` as ours and returns its SDP once ICE gathering is
+    /// done: trackers relay a single message each way, so candidates can't
+    /// trickle in after it.
+    async fn describe(&self, description: RTCSessionDescription) -> anyhow::Result<String> {
+        let mut gathered = self.0.gathering_complete_promise().await;
+        self.0.set_local_description(description).await?;
+        let _ = gathered.recv().await;
+
+        let description = self
+            .0
+            .local_description()
+            .await
+            .ok_or_else(|| anyhow!("no local description"))?;
+        Ok(description.sdp)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let connection = Arc::clone(&self.0);
+        tokio::spawn(async move {
+            let _ = connection.close().await;
+        });
+    }
+}
+
+/// Sends `channel` on `opened` once it opens.
+fn on_open(channel: Arc<RTCDataChannel>, opened: oneshot::Sender<Arc<RTCDataChannel>>) {
+    let open = Arc::clone(&channel);
+    channel.on_open(Box::new(move || {
+        let _ = opened.send(open);
+        Box::pin(async {})
+    }));
+}
+
+/// Waits for the channel of `connection` to open, and detaches it to be used
+/// as a stream.
+async fn open(
+    connection: Connection,
+    opened: oneshot::Receiver<Arc<RTCDataChannel>>,
+) -> anyhow::Result<DataChannel> {
+    let channel = opened
+        .await
+        .map_err(|_| anyhow!("data channel closed before opening"))?;
+    let raw = channel.detach().await.context("detach data channel")?;
+
+    let mut inner = PollDataChannel::new(raw);
+    inner.set_read_buf_capacity(MAX_MESSAGE);
+    Ok(DataChannel {
+        inner,
+        _connection: connection,
+    })
+}
+
+/// The address of the first ICE candidate in `sdp` with an IP address, which
+/// stands for the peer in logs, stats and bans. Browsers that hide their
+/// addresses behind mDNS names get the unspecified address.
+fn candidate_addr(sdp: &str) -> SocketAddr {
+    sdp.lines()
+        .filter_map(|line| line.trim().strip_prefix("a=candidate:"))
+        .find_map(|candidate| {
+            // foundation, component, transport, priority, address, port, ...
+            let mut fields = candidate.split_whitespace().skip(4);
+            let ip: IpAddr = fields.next()?.parse().ok()?;
+            let port: u16 = fields.next()?.parse().ok()?;
+            Some(SocketAddr::new(ip, port))
+        })
+        .unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::candidate_addr;
+
+    #[test]
+    fn test_candidate_addr_skips_mdns_names() {
+        let sdp = "v=0\r\n\
+            a=candidate:1 1 udp 2113937151 0d1e4a2c-2f1b.local 54321 typ host\r\n\
+            a=candidate:2 1 udp 1677729535 203.0.113.7 61234 typ srflx raddr 0.0.0.0 rport 0\r\n";
+
+        assert_eq!(candidate_addr(sdp), "203.0.113.7:61234".parse().unwrap());
+        assert!(candidate_addr("v=0\r\n").ip().is_unspecified());
+    }
+}
@@ -0,0 +1,945 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use futures_util::{stream, Stream};
+use serde::Serialize;
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{broadcast, mpsc, watch, Semaphore},
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    blocklist::Blocklist,
+    dht::Dht,
+    download::{self, Control, PORT},
+    memory::MemoryLimit,
+    mse::{self, Encryption, PROTOCOL_PREFIX},
+    peer::{Handshake, Transport},
+    piece::Priority,
+    rate::Limits,
+    reputation::Reputation,
+    resume::Resume,
+    storage::Disk,
+    torrent::Torrent,
+    tracker::{self, Announce, Announced, Tiers},
+};
+
+/// An incoming connection whose handshake has been read, handed to the torrent
+/// it asked for.
+pub(crate) type Incoming = (SocketAddr, mse::Stream, Handshake);
+
+/// How long a tracker may take to answer before the next one is tried.
+pub const TRACKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a peer may stay silent before it is dropped, unless the session
+/// is given another timeout.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// How long an incoming connection may take to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which trackers of a torrent to announce to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AnnounceMode {
+    /// Walk the tiers in order and stop at the first tracker that answers.
+    #[default]
+    FirstAnswer,
+
+    /// Announce to every tier at once and merge the peers they return, for
+    /// swarms split across trackers.
+    AllTiers,
+}
+
+/// Resources shared by every torrent of a session: one DHT node, one UDP
+/// tracker socket and the routing of incoming peer connections.
+#[derive(Default)]
+pub struct Shared {
+    announce_mode: AnnounceMode,
+    options: tracker::Options,
+    /// Tracker IDs by announce URL.
+    tracker_ids: Mutex<HashMap<String, String>>,
+    limits: Arc<Limits>,
+    dht: Arc<tokio::sync::Mutex<Option<Dht>>>,
+    tracker_socket_v4: tokio::sync::Mutex<Option<UdpSocket>>,
+    tracker_socket_v6: tokio::sync::Mutex<Option<UdpSocket>>,
+    incoming: Mutex<HashMap<[u8; 20], mpsc::Sender<Incoming>>>,
+    peer_timeout: Option<Duration>,
+    encryption: Encryption,
+    blocklist: Arc<Blocklist>,
+    reputation: Arc<Reputation>,
+    memory: Arc<MemoryLimit>,
+    /// The port peers can connect to us on, if the session listens at all.
+    port: Option<u16>,
+}
+
+impl Shared {
+    pub fn new(announce_mode: AnnounceMode, options: tracker::Options) -> Self {
+        Self {
+            announce_mode,
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// Drops peers that send nothing for `timeout` instead of
+    /// [`PEER_TIMEOUT`].
+    pub fn with_peer_timeout(mut self, timeout: Duration) -> Self {
+        self.peer_timeout = Some(timeout);
+        self
+    }
+
+    /// How long a peer may stay silent before it is dropped.
+    pub fn peer_timeout(&self) -> Duration {
+        self.peer_timeout.unwrap_or(PEER_TIMEOUT)
+    }
+
+    /// Encrypts peer connections as `encryption` says, instead of preferring
+    /// to.
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    pub fn encryption(&self) -> Encryption {
+        self.encryption
+    }
+
+    /// Neither connects to nor accepts peers at the addresses `blocklist`
+    /// lists.
+    pub fn with_blocklist(mut self, blocklist: Blocklist) -> Self {
+        self.blocklist = Arc::new(blocklist);
+        self
+    }
+
+    pub fn blocklist(&self) -> &Arc<Blocklist> {
+        &self.blocklist
+    }
+
+    /// Holds at most `bytes` of piece data in memory, over all torrents,
+    /// before requesting blocks of new pieces waits for some to be written.
+    pub fn with_max_memory(mut self, bytes: usize) -> Self {
+        self.memory = Arc::new(MemoryLimit::new(Some(bytes)));
+        self
+    }
+
+    pub fn memory(&self) -> &Arc<MemoryLimit> {
+        &self.memory
+    }
+
+    /// How badly the peers behaved, and which are banned for it.
+    pub fn reputation(&self) -> &Arc<Reputation> {
+        &self.reputation
+    }
+
+    /// The download and upload rate limits of every peer connection.
+    pub fn limits(&self) -> &Arc<Limits> {
+        &self.limits
+    }
+
+    /// Announces to `url` with the session's options and the tracker's ID, if
+    /// it gave us one.
+    pub async fn announce(&self, url: &str, announce: &Announce) -> anyhow::Result<Announced> {
+        let options = tracker::Options {
+            tracker_id: self
+                .tracker_ids
+                .lock()
+                .expect("lock is not poisoned")
+                .get(url)
+                .cloned(),
+            ..self.options.clone()
+        };
+
+        let announced = self.announce_with(url, announce, &options).await?;
+        if let Some(tracker_id) = &announced.tracker_id {
+            self.tracker_ids
+                .lock()
+                .expect("lock is not poisoned")
+                .insert(url.to_string(), tracker_id.clone());
+        }
+
+        Ok(announced)
+    }
+
+    /// Reuses the session's sockets for UDP trackers unless another announce
+    /// is using them.
+    async fn announce_with(
+        &self,
+        url: &str,
+        announce: &Announce,
+        options: &tracker::Options,
+    ) -> anyhow::Result<Announced> {
+        match tracker::get_addr(url)? {
+            tracker::Addr::Udp(addr) => {
+                let socket = match addr {
+                    SocketAddr::V4(_) => self.tracker_socket_v4.try_lock(),
+                    SocketAddr::V6(_) => self.tracker_socket_v6.try_lock(),
+                };
+                let Ok(mut socket) = socket else {
+                    // Responses are told apart by sender only, so concurrent
+                    // announces can't share a socket.
+                    let socket = tracker::bind_udp(addr).await?;
+                    return tracker::announce_udp(&socket, addr, announce, options).await;
+                };
+
+                if socket.is_none() {
+                    *socket = Some(tracker::bind_udp(addr).await?);
+                }
+                let socket = socket.as_ref().expect("bound above");
+
+                tracker::announce_udp(socket, addr, announce, options).await
+            }
+            tracker::Addr::Http(_) => tracker::announce_http(url, announce, options).await,
+        }
+    }
+
+    /// Announces to the trackers of `tiers` (BEP 12) as the session's
+    /// [`AnnounceMode`] says; the trackers of a private torrent are never
+    /// announced to all at once, so that peers from one don't reach another.
+    pub async fn announce_tiers(
+        &self,
+        tiers: &mut Tiers,
+        announce: &Announce,
+    ) -> anyhow::Result<Announced> {
+        let mut last_error = anyhow!("no trackers");
+
+        let mode = if tiers.is_private() {
+            AnnounceMode::FirstAnswer
+        } else {
+            self.announce_mode
+        };
+        match mode {
+            AnnounceMode::FirstAnswer => {
+                for tier_i in 0..tiers.len() {
+                    match self.announce_tier(tiers.tier(tier_i), announce).await {
+                        Ok((i, announced)) => {
+                            tiers.promote(tier_i, i);
+                            return Ok(announced);
+                        }
+                        Err(e) => last_error = e,
+                    }
+                }
+
+                Err(last_error)
+            }
+            AnnounceMode::AllTiers => {
+                let announces =
+                    (0..tiers.len()).map(|tier_i| self.announce_tier(tiers.tier(tier_i), announce));
+                let results = futures_util::future::join_all(announces).await;
+
+                let mut merged: Option<Announced> = None;
+                for (tier_i, result) in results.into_iter().enumerate() {
+                    match result {
+                        Ok((i, announced)) => {
+                            tiers.promote(tier_i, i);
+                            match &mut merged {
+                                // Announce again as soon as any tracker wants us to.
+                                Some(merged) => {
+                                    merged.peers.extend(announced.peers);
+                                    merged.interval = merged.interval.min(announced.interval);
+                                }
+                                None => merged = Some(announced),
+                            }
+                        }
+                        Err(e) => last_error = e,
+                    }
+                }
+
+                let mut merged = merged.ok_or(last_error)?;
+                merged.peers.sort();
+                merged.peers.dedup();
+                Ok(merged)
+            }
+        }
+    }
+
+    /// Announces to the trackers of `tier` in turn until one answers, and
+    /// returns its position in the tier along with its answer.
+    async fn announce_tier(
+        &self,
+        tier: &[String],
+        announce: &Announce,
+    ) -> anyhow::Result<(usize, Announced)> {
+        let mut last_error = anyhow!("empty tier");
+        for (i, url) in tier.iter().enumerate() {
+            match tokio::time::timeout(TRACKER_TIMEOUT, self.announce(url, announce)).await {
+                Ok(Ok(announced)) => return Ok((i, announced)),
+                Ok(Err(e)) => {
+                    warn!(url, error = %e, "Tracker failed");
+                    last_error = e;
+                }
+                Err(_) => {
+                    warn!(url, "Tracker timed out");
+                    last_error = anyhow!("{url} timed out");
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Looks up peers in the session's DHT node, joining the DHT on first use,
+    /// and announces that we accept connections for `info_hash` if the
+    /// session listens for them.
+    pub async fn dht_peers(
+        &self,
+        nodes: &[(String, u16)],
+        info_hash: [u8; 20],
+    ) -> anyhow::Result<Vec<SocketAddrV4>> {
+        let mut dht = self.dht.lock().await;
+        if dht.is_none() {
+            let mut node = Dht::bind(0).await?;
+            node.bootstrap(nodes).await?;
+            tokio::spawn(answer_dht_queries(Arc::downgrade(&self.dht), node.socket()));
+            *dht = Some(node);
+        }
+        let dht = dht.as_mut().expect("bootstrapped above");
+
+        let peers = dht.get_peers(&info_hash).await?;
+        if let Some(port) = self.port {
+            dht.announce_peer(&info_hash, port).await?;
+        }
+
+        Ok(peers)
+    }
+
+    /// Routes incoming connections for any of `info_hashes` to the returned
+    /// receiver.
+    pub(crate) fn register(&self, info_hashes: &[[u8; 20]]) -> mpsc::Receiver<Incoming> {
+        let (tx, rx) = mpsc::channel(8);
+        let mut incoming = self.incoming.lock().expect("lock is not poisoned");
+        for &info_hash in info_hashes {
+            incoming.insert(info_hash, tx.clone());
+        }
+        rx
+    }
+
+    pub(crate) fn unregister(&self, info_hashes: &[[u8; 20]]) {
+        let mut incoming = self.incoming.lock().expect("lock is not poisoned");
+        for info_hash in info_hashes {
+            incoming.remove(info_hash);
+        }
+    }
+
+    /// Reads the handshake of an incoming connection, first setting up
+    /// encryption if the peer asks for it and it is allowed.
+    async fn read_handshake(&self, mut tcp: TcpStream) -> anyhow::Result<(mse::Stream, Handshake)> {
+        let mut handshake = [0; 68];
+        tcp.read_exact(&mut handshake[..20]).await?;
+
+        let stream = if handshake[..20] == *PROTOCOL_PREFIX {
+            anyhow::ensure!(
+                self.encryption != Encryption::Require,
+                "peer did not encrypt"
+            );
+            tcp.read_exact(&mut handshake[20..]).await?;
+            mse::Stream::plaintext(Transport::Tcp(tcp))
+        } else {
+            let info_hashes: Vec<[u8; 20]> = self
+                .incoming
+                .lock()
+                .expect("lock is not poisoned")
+                .keys()
+                .copied()
+                .collect();
+            let prefix = handshake[..20].to_vec();
+            let (_, mut stream) =
+                mse::Stream::accept(Transport::Tcp(tcp), &prefix, &info_hashes, self.encryption)
+                    .await?;
+            stream.read_exact(&mut handshake).await?;
+            stream
+        };
+
+        Ok((stream, Handshake::from_bytes(&handshake)))
+    }
+
+    /// Accepts peer connections and hands each to the torrent it is for.
+    async fn listen(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(error = %e, "Failed to accept connection");
+                    continue;
+                }
+            };
+            if self.blocklist.contains(addr.ip()) || self.reputation.is_banned(addr.ip()) {
+                debug!(%addr, "Refusing blocked peer");
+                continue;
+            }
+            let shared = Arc::clone(&self);
+            tokio::spawn(async move {
+                let read = tokio::time::timeout(HANDSHAKE_TIMEOUT, shared.read_handshake(stream));
+                let (stream, handshake) = match read.await {
+                    Ok(Ok(read)) => read,
+                    Ok(Err(e)) => {
+                        debug!(%addr, error = %e, "Incoming handshake failed");
+                        return;
+                    }
+                    Err(_) => return,
+                };
+
+                let Ok(info_hash) = <[u8; 20]>::try_from(&handshake.info_hash[..]) else {
+                    return;
+                };
+                let torrent = shared
+                    .incoming
+                    .lock()
+                    .expect("lock is not poisoned")
+                    .get(&info_hash)
+                    .cloned();
+                if let Some(torrent) = torrent {
+                    let _ = torrent.send((addr, stream, handshake)).await;
+                }
+            });
+        }
+    }
+}
+
+/// Answers the queries that reach the session's DHT node on `socket` between
+/// lookups, which read the socket themselves, for as long as the node exists.
+async fn answer_dht_queries(dht: Weak<tokio::sync::Mutex<Option<Dht>>>, socket: Arc<UdpSocket>) {
+    while socket.readable().await.is_ok() {
+        let Some(dht) = dht.upgrade() else {
+            return;
+        };
+        let mut dht = dht.lock().await;
+        let Some(dht) = dht.as_mut() else {
+            return;
+        };
+        dht.answer_queries().await;
+    }
+}
+
+/// How far along a torrent's download is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Progress {
+    /// Pieces downloaded and verified so far, out of those of the files being
+    /// downloaded; the bytes likewise.
+    pub pieces: usize,
+    pub total_pieces: usize,
+    pub bytes: usize,
+    pub total_bytes: usize,
+
+    /// Peers currently connected, and how many of them are choking us.
+    pub peers: usize,
+    pub choking: usize,
+
+    /// Bytes of verified pieces received from peers and of blocks sent to
+    /// them, including earlier runs of the download.
+    pub downloaded: usize,
+    pub uploaded: usize,
+}
+
+/// What a connected peer has done for a torrent, see
+/// [`TorrentHandle::peer_stats`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+
+    /// Bytes of blocks received from and sent to the peer.
+    pub downloaded: usize,
+    pub uploaded: usize,
+
+    /// Bytes per second over the last rechoke interval.
+    pub download_rate: usize,
+    pub upload_rate: usize,
+
+    /// Blocks we sent the peer.
+    pub blocks_served: usize,
+
+    /// Pieces the peer sent blocks of that failed the hash check.
+    pub hash_failures: usize,
+
+    /// Whether the peer stopped sending the blocks we asked for.
+    pub snubbed: bool,
+
+    /// Seconds since we connected.
+    pub connected_secs: u64,
+}
+
+/// Something that happened to a torrent, see [`TorrentHandle::events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentEvent {
+    PeerConnected(SocketAddr),
+    PieceVerified(usize),
+
+    /// The connected peers, best contributors first, sent every rechoke.
+    PeerStats(Vec<PeerStats>),
+
+    /// Peers were looked up from the tracker and the DHT.
+    TrackerAnnounced {
+        peers: usize,
+    },
+
+    /// Every piece was downloaded and the files were written.
+    Completed,
+
+    /// The download stopped because of this error.
+    Error(String),
+}
+
+/// Downloads several torrents at once, sharing the peer listener, the DHT node
+/// and the tracker socket between them.
+pub struct Session {
+    shared: Arc<Shared>,
+    active: Arc<Semaphore>,
+    stop: watch::Sender<bool>,
+    disk: Disk,
+    seed: bool,
+}
+
+impl Session {
+    /// Starts a session that listens for peers on [`PORT`] and downloads at
+    /// most `max_active` torrents at a time; the others wait in line. Their
+    /// output files are created and written as `disk` says.
+    pub async fn new(max_active: usize, mut shared: Shared, disk: Disk) -> Self {
+        let listener = match TcpListener::bind(("0.0.0.0", PORT)).await {
+            Ok(listener) => {
+                shared.port = listener.local_addr().ok().map(|addr| addr.port());
+                Some(listener)
+            }
+            Err(e) => {
+                warn!(port = PORT, error = %e, "Not accepting incoming peers");
+                None
+            }
+        };
+        let shared = Arc::new(shared);
+        if let Some(listener) = listener {
+            tokio::spawn(Arc::clone(&shared).listen(listener));
+        }
+
+        Self {
+            shared,
+            active: Arc::new(Semaphore::new(max_active)),
+            stop: watch::channel(false).0,
+            disk,
+            seed: false,
+        }
+    }
+
+    /// Keeps uploading each torrent once its download is complete, until the
+    /// session is shut down. Seeding torrents don't count against
+    /// `max_active`.
+    pub fn with_seeding(mut self, seed: bool) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn shared(&self) -> &Arc<Shared> {
+        &self.shared
+    }
+
+    /// Ends every download early: pieces in flight are dropped, the progress
+    /// is kept for resuming and the trackers are told we are leaving. The
+    /// handles then finish with an error, except those of torrents being
+    /// seeded.
+    pub fn shutdown(&self) {
+        self.stop.send_replace(true);
+    }
+
+    /// Queues `t` for download to `output`, its files at the given
+    /// `priorities`, and returns a handle to control it.
+    pub fn add_torrent(
+        &self,
+        t: Torrent,
+        output: PathBuf,
+        priorities: Vec<Priority>,
+    ) -> TorrentHandle {
+        let shared = Arc::clone(&self.shared);
+        let active = Arc::clone(&self.active);
+        let name = t.info.name.clone();
+
+        let (pause, paused) = watch::channel(false);
+        let pieces = t.info.piece_priorities(&priorities);
+        let (priorities, priorities_rx) = watch::channel(priorities);
+        let needed = || (0..pieces.len()).filter(|&piece_i| pieces[piece_i] != Priority::Skip);
+        let (progress, progress_rx) = watch::channel(Progress {
+            total_pieces: needed().count(),
+            total_bytes: needed().map(|piece_i| t.piece_len(piece_i)).sum(),
+            ..Progress::default()
+        });
+        let (peers, peers_rx) = watch::channel(Vec::new());
+        let (events, _) = broadcast::channel(64);
+        let mut stop = self.stop.subscribe();
+        let mut control = Control {
+            paused,
+            stop: stop.clone(),
+            disk: self.disk,
+            priorities: priorities_rx,
+            progress,
+            peers,
+            events: events.clone(),
+            seed: self.seed,
+            active: None,
+        };
+        let task_events = events.clone();
+
+        let task = tokio::spawn(async move {
+            control.active = tokio::select! {
+                permit = active.acquire_owned() => Some(permit.expect("never closed")),
+                _ = stop.wait_for(|stop| *stop) => {
+                    let _ = task_events.send(TorrentEvent::Error("download interrupted".into()));
+                    anyhow::bail!("download interrupted");
+                }
+            };
+
+            // Shared with the threads that hash its pieces.
+            let t = Arc::new(t);
+            let info_hashes = t.info_hashes();
+            let incoming = shared.register(&info_hashes);
+            let result = async {
+                download::run(&t, &output, &shared, Some(incoming), control).await?;
+                Resume::remove(&output).await
+            }
+            .await;
+            shared.unregister(&info_hashes);
+
+            // The download reports its completion itself.
+            if let Err(e) = &result {
+                let _ = task_events.send(TorrentEvent::Error(format!("{e:#}")));
+            }
+            result
+        });
+
+        TorrentHandle {
+            name,
+            pause,
+            priorities,
+            progress: progress_rx,
+            peers: peers_rx,
+            events,
+            task,
+        }
+    }
+}
+
+/// A torrent added to a [`Session`].
+///
+/// Dropping the handle leaves the download running in the background.
+pub struct TorrentHandle {
+    name: String,
+    pause: watch::Sender<bool>,
+    priorities: watch::Sender<Vec<Priority>>,
+    progress: watch::Receiver<Progress>,
+    peers: watch::Receiver<Vec<PeerStats>>,
+    events: broadcast::Sender<TorrentEvent>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TorrentHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Stops requesting pieces; connected peers are kept and still served.
+    pub fn pause(&self) {
+        self.pause.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.pause.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.pause.borrow()
+    }
+
+    /// Downloads the pieces of file `file_i` before or after those of other
+    /// files, or not at all; pieces shared with other files go at the
+    /// highest priority of the files.
+    pub fn set_file_priority(&self, file_i: usize, priority: Priority) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        self.priorities
+            .send_if_modified(|priorities| match priorities.get_mut(file_i) {
+                Some(current) if *current != priority => {
+                    *current = priority;
+                    true
+                }
+                Some(_) => false,
+                None => {
+                    result = Err(anyhow!("{} has no file {file_i}", self.name));
+                    false
+                }
+            });
+        result
+    }
+
+    pub fn file_priorities(&self) -> Vec<Priority> {
+        self.priorities.borrow().clone()
+    }
+
+    pub fn progress(&self) -> Progress {
+        *self.progress.borrow()
+    }
+
+    /// The progress, which can be waited on for changes and outlives the
+    /// handle.
+    pub fn watch_progress(&self) -> watch::Receiver<Progress> {
+        self.progress.clone()
+    }
+
+    /// The connected peers and what they have done, best contributors
+    /// first, as of the last rechoke.
+    pub fn peer_stats(&self) -> Vec<PeerStats> {
+        self.peers.borrow().clone()
+    }
+
+    /// The events of this torrent from now on. The stream ends once the
+    /// download has finished and the handle is gone; events missed by a slow
+    /// reader are skipped.
+    pub fn events(&self) -> impl Stream<Item = TorrentEvent> {
+        stream::unfold(self.events.subscribe(), |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => return Some((event, events)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Waits for the download to finish and its files to be written, or for
+    /// the session to shut down if the torrent is seeded.
+    pub async fn wait(self) -> anyhow::Result<()> {
+        self.task.await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use futures_util::StreamExt;
+    use serde_bytes::ByteBuf;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream, UdpSocket},
+        sync::mpsc,
+    };
+
+    use super::{answer_dht_queries, AnnounceMode, Session, Shared, TorrentEvent};
+    use crate::{
+        block::BLOCK_SIZE,
+        dht::{
+            krpc::{Arguments, Message},
+            Dht,
+        },
+        peer::Handshake,
+        piece::Priority,
+        resume::Resume,
+        storage::Disk,
+        torrent::Torrent,
+        tracker::{udp, Announce, Tiers},
+    };
+
+    #[tokio::test]
+    async fn test_dht_queries_are_answered_between_lookups() {
+        let shared = Shared::default();
+        let node = Dht::bind(0).await.unwrap();
+        let port = node.socket().local_addr().unwrap().port();
+        tokio::spawn(answer_dht_queries(
+            Arc::downgrade(&shared.dht),
+            node.socket(),
+        ));
+        *shared.dht.lock().await = Some(node);
+
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ping = Message::query(
+            b"aa",
+            "ping",
+            Arguments {
+                id: ByteBuf::from(vec![1; 20]),
+                ..Default::default()
+            },
+        );
+        let ping = serde_bencode::to_bytes(&ping).unwrap();
+        other.send_to(&ping, ("127.0.0.1", port)).await.unwrap();
+
+        let mut buf = [0; 1500];
+        let recv = other.recv_from(&mut buf);
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), recv)
+            .await
+            .unwrap()
+            .unwrap();
+        let pong: Message = serde_bencode::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(pong.kind, "r");
+        assert_eq!(pong.transaction_id, b"aa");
+    }
+
+    #[tokio::test]
+    async fn test_incoming_peers_reach_the_torrent_they_ask_for() {
+        let shared = Arc::new(Shared::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::clone(&shared).listen(listener));
+        let mut incoming = shared.register(&[[1; 20]]);
+
+        // A peer for a torrent we don't have is dropped.
+        let mut stranger = TcpStream::connect(addr).await.unwrap();
+        stranger
+            .write_all(&Handshake::new(&[2; 20]).bytes())
+            .await
+            .unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stranger.read(&mut buf));
+        assert_eq!(read.await.unwrap().unwrap(), 0);
+
+        let mut peer = TcpStream::connect(addr).await.unwrap();
+        let handshake = Handshake::new(&[1; 20]);
+        peer.write_all(&handshake.bytes()).await.unwrap();
+        let routed = tokio::time::timeout(Duration::from_secs(5), incoming.recv());
+        let (from, _, got) = routed.await.unwrap().unwrap();
+        assert_eq!(from, peer.local_addr().unwrap());
+        assert_eq!(got.info_hash, handshake.info_hash);
+        assert_eq!(got.peer_id, handshake.peer_id);
+
+        // Once the torrent is gone, so is its receiver.
+        shared.unregister(&[[1; 20]]);
+        let closed = tokio::time::timeout(Duration::from_secs(5), incoming.recv());
+        assert!(closed.await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handle_controls_a_queued_torrent() {
+        let t = Torrent::for_test(BLOCK_SIZE as usize, &[10, 20]);
+        let dir = std::env::temp_dir().join(format!("handle-test-{}", std::process::id()));
+        // With no room to start, the torrent stays queued.
+        let session = Session::new(0, Shared::default(), Disk::default()).await;
+        let handle = session.add_torrent(t, dir, vec![Priority::Normal; 2]);
+        assert_eq!(handle.name(), "test");
+
+        assert!(!handle.is_paused());
+        handle.pause();
+        assert!(handle.is_paused());
+        handle.resume();
+        assert!(!handle.is_paused());
+
+        handle.set_file_priority(1, Priority::High).unwrap();
+        handle.set_file_priority(0, Priority::Skip).unwrap();
+        assert_eq!(
+            handle.file_priorities(),
+            vec![Priority::Skip, Priority::High]
+        );
+        assert!(handle.set_file_priority(2, Priority::Low).is_err());
+        assert_eq!(
+            handle.file_priorities(),
+            vec![Priority::Skip, Priority::High]
+        );
+
+        assert_eq!(handle.progress().total_pieces, 1);
+        assert_eq!(handle.progress().pieces, 0);
+        assert!(handle.peer_stats().is_empty());
+        session.shutdown();
+        assert!(handle.wait().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_events_reach_every_stream_until_the_torrent_is_gone() {
+        let t = Torrent::for_test(BLOCK_SIZE as usize, &[BLOCK_SIZE as usize]);
+        let dir = std::env::temp_dir().join(format!("events-test-{}", std::process::id()));
+        let session = Session::new(0, Shared::default(), Disk::default()).await;
+        let handle = session.add_torrent(t, dir, vec![Priority::Normal]);
+        let mut first = Box::pin(handle.events());
+        let mut second = Box::pin(handle.events());
+
+        session.shutdown();
+        let interrupted = TorrentEvent::Error("download interrupted".to_string());
+        assert_eq!(first.next().await.unwrap(), interrupted);
+        assert_eq!(second.next().await.unwrap(), interrupted);
+        // A stream opened later only sees what comes after it.
+        let mut late = Box::pin(handle.events());
+
+        assert!(handle.wait().await.is_err());
+        assert!(first.next().await.is_none());
+        assert!(second.next().await.is_none());
+        assert!(late.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_all_tiers_merge_their_peers_unless_private() {
+        let mut urls = Vec::new();
+        for peer in [[127, 0, 0, 1, 0, 1], [127, 0, 0, 1, 0, 2]] {
+            let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            urls.push(format!("udp://{}/announce", tracker.local_addr().unwrap()));
+            tokio::spawn(udp::serve(tracker, peer, mpsc::unbounded_channel().0));
+        }
+        let tiers = || Tiers::new(urls.iter().map(|url| vec![url.clone()]).collect());
+        let announce = Announce::new([1; 20], 100);
+        let peer = |port| SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+
+        let first = Shared::new(AnnounceMode::FirstAnswer, Default::default());
+        let announced = first.announce_tiers(&mut tiers(), &announce).await;
+        assert_eq!(announced.unwrap().peers, vec![peer(1)]);
+
+        let all = Shared::new(AnnounceMode::AllTiers, Default::default());
+        let announced = all.announce_tiers(&mut tiers(), &announce).await;
+        assert_eq!(announced.unwrap().peers, vec![peer(1), peer(2)]);
+
+        let mut private = tiers().private(true);
+        let announced = all.announce_tiers(&mut private, &announce).await;
+        assert_eq!(announced.unwrap().peers, vec![peer(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_active_and_queued_torrents() {
+        let length = 2 * BLOCK_SIZE as usize;
+        let mut t = Torrent::for_test(length, &[length]);
+        t.info.private = Some(1);
+        // The tracker only knows a peer that isn't there.
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        t.announce = Some(format!("udp://{}/announce", tracker.local_addr().unwrap()));
+        let (announces_tx, mut announces) = mpsc::unbounded_channel();
+        tokio::spawn(udp::serve(tracker, [127, 0, 0, 1, 0, 1], announces_tx));
+        let dir = std::env::temp_dir().join(format!("session-test-{}", std::process::id()));
+
+        let session = Session::new(1, Shared::default(), Disk::default()).await;
+        let active = session.add_torrent(t.clone(), dir.join("a"), vec![Priority::Normal]);
+        let queued = session.add_torrent(t, dir.join("b"), vec![Priority::Normal]);
+        let mut events = Box::pin(active.events());
+        let mut queued_events = Box::pin(queued.events());
+        assert_eq!(active.progress().total_pieces, 1);
+        assert_eq!(active.progress().total_bytes, length);
+
+        let announced = tokio::time::timeout(Duration::from_secs(10), events.next());
+        let announced = announced.await.unwrap().unwrap();
+        assert_eq!(announced, TorrentEvent::TrackerAnnounced { peers: 1 });
+        active.pause();
+        assert!(active.is_paused());
+
+        // The queued torrent gives up its place in line, the active one stops
+        // with what it has, and neither counts as done.
+        session.shutdown();
+        let interrupted = TorrentEvent::Error("download interrupted".to_string());
+        while events.next().await.unwrap() != interrupted {}
+        assert_eq!(queued_events.next().await.unwrap(), interrupted);
+        assert_eq!(active.progress().pieces, 0);
+        assert!(active.wait().await.is_err());
+        assert!(queued.wait().await.is_err());
+
+        // The tracker heard that we started and that we left, and the
+        // progress is kept for next time.
+        let mut sent = Vec::new();
+        while let Ok((event, _, _)) = announces.try_recv() {
+            sent.push(event);
+        }
+        assert_eq!(sent.first(), Some(&2));
+        assert_eq!(sent.last(), Some(&3));
+        assert!(Resume::path(&dir.join("a")).exists());
+        assert!(!Resume::path(&dir.join("b")).exists());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
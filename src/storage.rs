@@ -0,0 +1,1255 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::torrent::{File, Torrent};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    Valid,
+    Corrupt,
+    /// At least one of the files the piece spans is missing or too short.
+    Missing,
+}
+
+/// How the output files are created before the download starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Allocation {
+    /// At their final sizes, with the disk space taken as pieces arrive.
+    #[default]
+    Sparse,
+    /// With all their disk space reserved up front, so that writes can't fail
+    /// for lack of it and the files are less fragmented.
+    Full,
+}
+
+impl FromStr for Allocation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "sparse" => Ok(Self::Sparse),
+            "full" => Ok(Self::Full),
+            s => Err(anyhow!("unknown allocation {s}, expected sparse or full")),
+        }
+    }
+}
+
+/// How verified pieces get into the output files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Plain file writes and reads.
+    #[default]
+    Files,
+    /// Copies into memory maps of the files, which saves a system call and a
+    /// copy per block on large torrents. Linux only.
+    Mmap,
+    /// Reads and writes through io_uring, with many of them in flight at
+    /// once. Linux only, and only with the `io-uring` feature.
+    Uring,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "files" => Ok(Self::Files),
+            "mmap" => Ok(Self::Mmap),
+            "uring" => Ok(Self::Uring),
+            s => Err(anyhow!(
+                "unknown storage {s}, expected files, mmap or uring"
+            )),
+        }
+    }
+}
+
+/// How a session stores its torrents.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Disk {
+    pub allocation: Allocation,
+    pub backend: Backend,
+}
+
+impl Disk {
+    /// Allocates the files of `layout` and opens them with the backend. The
+    /// files not `wanted` are not created; see [`Layout::skip`].
+    pub async fn open(&self, layout: &Layout, wanted: &[bool]) -> anyhow::Result<Arc<dyn Storage>> {
+        layout.allocate(self.allocation, wanted).await?;
+
+        match self.backend {
+            Backend::Files => Ok(Arc::new(layout.clone())),
+            #[cfg(target_os = "linux")]
+            Backend::Mmap => Ok(Arc::new(MmapStorage::open(layout)?)),
+            #[cfg(not(target_os = "linux"))]
+            Backend::Mmap => Err(anyhow!("mmap storage is only supported on Linux")),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Backend::Uring => Ok(Arc::new(UringStorage::open(layout)?)),
+            #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+            Backend::Uring => Err(anyhow!(
+                "uring storage needs Linux and a build with the io-uring feature"
+            )),
+        }
+    }
+}
+
+/// How many verified pieces may wait for [`write_pieces`] at once; past that,
+/// the download waits for the disk.
+pub(crate) const WRITE_QUEUE: usize = 8;
+
+/// Writes the pieces sent on `pieces`, as `(piece, data)`, to `storage` in
+/// the order they come, and reports each on `written` with the bytes written.
+///
+/// It runs as a task of its own, so that a slow disk holds up neither the
+/// peer connections nor the download loop, which only waits for it when the
+/// queue in front of it is full.
+pub(crate) async fn write_pieces(
+    storage: Arc<dyn Storage>,
+    plength: usize,
+    mut pieces: mpsc::Receiver<(usize, Bytes)>,
+    written: mpsc::UnboundedSender<(usize, anyhow::Result<usize>)>,
+) {
+    while let Some((piece_i, data)) = pieces.recv().await {
+        let result = storage
+            .write(piece_i * plength, &data)
+            .await
+            .map(|()| data.len());
+        if written.send((piece_i, result)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Where a torrent's verified pieces are written as they come in, and read
+/// back from for uploading. Offsets are into the torrent's concatenated files.
+/// A write returns once its data is on disk, so the piece can be recorded as
+/// done in the resume file.
+pub trait Storage: Send + Sync {
+    fn write<'a>(&'a self, offset: usize, data: &'a [u8]) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    fn read(&self, offset: usize, length: usize) -> BoxFuture<'_, anyhow::Result<Vec<u8>>>;
+}
+
+/// Maps the torrent's concatenated byte stream onto the files under the output
+/// path.
+///
+/// A single-file torrent is stored at the output path itself; the files of a
+/// multi-file torrent are stored below the output directory. Padding files
+/// (BEP 47) are not stored at all: they read as zeros and writes to them are
+/// dropped.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    files: Vec<(PathBuf, usize)>,
+    padding: Vec<bool>,
+    /// The files left out of the download that aren't on disk. What we get
+    /// of them, their bytes in the pieces they share with wanted files, goes
+    /// to the part file instead.
+    parted: Vec<bool>,
+    parts: Arc<Parts>,
+}
+
+impl Layout {
+    pub fn new(t: &Torrent, output: &Path) -> Self {
+        let parts = Arc::new(Parts::new(output.join(".parts"), t.info.plength));
+        if t.info.is_single_file() {
+            return Self {
+                files: vec![(output.to_path_buf(), t.length())],
+                padding: vec![false],
+                parted: vec![false],
+                parts,
+            };
+        }
+
+        let files = t.info.files();
+        Self {
+            padding: files.iter().map(File::is_padding).collect(),
+            parted: vec![false; files.len()],
+            parts,
+            files: files
+                .into_iter()
+                .map(|file| {
+                    (
+                        output.join(file.path.iter().collect::<PathBuf>()),
+                        file.length,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn files(&self) -> &[(PathBuf, usize)] {
+        &self.files
+    }
+
+    /// The stored files overlapping `length` bytes at `offset`, with the
+    /// offset into each file and the number of bytes that fall in it.
+    pub fn spans(
+        &self,
+        offset: usize,
+        length: usize,
+    ) -> impl Iterator<Item = (&Path, usize, usize)> + '_ {
+        self.file_spans(offset, length)
+            .map(|(file_i, file_offset, len, _)| (self.files[file_i].0.as_path(), file_offset, len))
+    }
+
+    /// Like [`Layout::spans`], with the files by index, and where each span
+    /// starts in the `length` bytes.
+    fn file_spans(
+        &self,
+        offset: usize,
+        length: usize,
+    ) -> impl Iterator<Item = (usize, usize, usize, usize)> + '_ {
+        let end = offset + length;
+        let mut file_start = 0;
+
+        self.files
+            .iter()
+            .enumerate()
+            .filter_map(move |(file_i, (_, file_length))| {
+                let start = file_start;
+                file_start += file_length;
+
+                let (from, to) = (offset.max(start), end.min(start + file_length));
+                (from < to && !self.padding[file_i])
+                    .then(|| (file_i, from - start, to - from, from - offset))
+            })
+    }
+
+    /// The stored files, without the padding ones.
+    fn stored(&self) -> impl Iterator<Item = (usize, &(PathBuf, usize))> + '_ {
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|&(file_i, _)| !self.padding[file_i])
+    }
+
+    pub fn any_exists(&self) -> bool {
+        self.stored().any(|(_, (path, _))| path.exists())
+    }
+
+    /// Whether all the files that `length` bytes at `offset` span are on
+    /// disk at their full length, or, if parted, have the bytes' pieces in the
+    /// part file. A piece recorded as written is only still there if they are.
+    pub fn holds(&self, offset: usize, length: usize) -> bool {
+        self.file_spans(offset, length).all(|(file_i, _, len, at)| {
+            if self.parted[file_i] {
+                return self
+                    .parts
+                    .pieces(offset + at, len)
+                    .all(|(piece_i, _, _)| self.parts.has(piece_i));
+            }
+            let (path, length) = &self.files[file_i];
+            std::fs::metadata(path).is_ok_and(|m| m.len() == *length as u64)
+        })
+    }
+
+    /// Keeps the files not `wanted` off the disk, unless they are there
+    /// already, and moves what the part file holds of the wanted ones into
+    /// them.
+    pub async fn skip(&mut self, wanted: &[bool]) -> anyhow::Result<()> {
+        self.parts.load().await?;
+        self.export(wanted).await?;
+
+        self.parted = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(file_i, (path, _))| !self.padding[file_i] && !wanted[file_i] && !path.exists())
+            .collect();
+        // Nothing left that it would hold bytes of.
+        if !self.parted.contains(&true) {
+            self.parts.remove().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates the `wanted` files that aren't on disk but have bytes in the
+    /// part file, with those bytes, e.g. once a skipped file is asked for.
+    /// The part file keeps its copy, for as long as it is read from.
+    pub async fn export(&self, wanted: &[bool]) -> anyhow::Result<()> {
+        let mut file_start = 0;
+        for (file_i, (path, length)) in self.files.iter().enumerate() {
+            let start = file_start;
+            file_start += length;
+            if self.padding[file_i] || !wanted[file_i] || path.exists() {
+                continue;
+            }
+
+            let ranges = self.parts.ranges(start, *length);
+            if ranges.is_empty() {
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut file = tokio::fs::File::create(path)
+                .await
+                .with_context(|| format!("create {}", path.display()))?;
+            file.set_len(*length as u64).await?;
+            for (offset, len) in ranges {
+                let data = self.parts.read(offset, len).await?;
+                file.seek(SeekFrom::Start((offset - start) as u64)).await?;
+                file.write_all(&data)
+                    .await
+                    .with_context(|| format!("write {}", path.display()))?;
+            }
+            file.flush().await?;
+            file.sync_data().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads `length` bytes at `offset` of the torrent from disk.
+    pub async fn read(&self, offset: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0; length];
+
+        for (file_i, file_offset, len, at) in self.file_spans(offset, length) {
+            if self.parted[file_i] {
+                let span = self.parts.read(offset + at, len).await?;
+                data[at..][..len].copy_from_slice(&span);
+                continue;
+            }
+
+            let path = &self.files[file_i].0;
+            let mut file = tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("open {}", path.display()))?;
+            file.seek(SeekFrom::Start(file_offset as u64)).await?;
+            file.read_exact(&mut data[at..][..len]).await?;
+        }
+
+        Ok(data)
+    }
+
+    /// Writes `data` at `offset` of the torrent to the files it spans,
+    /// creating them as needed.
+    pub async fn write_at(&self, offset: usize, data: &[u8]) -> anyhow::Result<()> {
+        for (file_i, file_offset, len, at) in self.file_spans(offset, data.len()) {
+            if self.parted[file_i] {
+                self.parts.write(offset + at, &data[at..][..len]).await?;
+                continue;
+            }
+
+            let path = &self.files[file_i].0;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)
+                .await
+                .with_context(|| format!("open {}", path.display()))?;
+            file.seek(SeekFrom::Start(file_offset as u64)).await?;
+            file.write_all(&data[at..][..len])
+                .await
+                .with_context(|| format!("write {}", path.display()))?;
+            file.flush().await?;
+            file.sync_data().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates the files at their final sizes, keeping what is in them already.
+    /// Files not `wanted` that are on disk anyway are left sparse whatever the
+    /// allocation; the others go to the part file.
+    pub async fn allocate(&self, allocation: Allocation, wanted: &[bool]) -> anyhow::Result<()> {
+        for (file_i, (path, length)) in self.stored() {
+            if self.parted[file_i] {
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)
+                .await
+                .with_context(|| format!("open {}", path.display()))?;
+
+            if file.metadata().await?.len() < *length as u64 {
+                file.set_len(*length as u64).await?;
+            }
+            if allocation == Allocation::Full && wanted[file_i] && *length > 0 {
+                reserve(file, *length)
+                    .await
+                    .with_context(|| format!("allocate {}", path.display()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads piece `piece_i` and checks it against its hash.
+    pub async fn check_piece(&self, t: &Torrent, piece_i: usize) -> (PieceStatus, Vec<u8>) {
+        let Ok(data) = self
+            .read(piece_i * t.info.plength, t.piece_len(piece_i))
+            .await
+        else {
+            return (PieceStatus::Missing, Vec::new());
+        };
+
+        if t.verify_piece(piece_i, &data) {
+            (PieceStatus::Valid, data)
+        } else {
+            (PieceStatus::Corrupt, data)
+        }
+    }
+}
+
+/// Where the bytes of the skipped files go that fall in pieces we download
+/// for the wanted files they share them with, rather than in files of their
+/// own: a slot per piece, holding its index as 4 big-endian bytes and then the
+/// piece, of which only the skipped files' bytes are written.
+#[derive(Debug)]
+struct Parts {
+    path: PathBuf,
+    plength: usize,
+    /// The piece in each slot.
+    slots: Mutex<Vec<usize>>,
+}
+
+impl Parts {
+    fn new(path: PathBuf, plength: usize) -> Self {
+        Self {
+            path,
+            plength,
+            slots: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn slot_len(&self) -> usize {
+        4 + self.plength
+    }
+
+    /// Reads the slots of the part file on disk, if any.
+    async fn load(&self) -> anyhow::Result<()> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+        let mut file = tokio::fs::File::open(&self.path)
+            .await
+            .with_context(|| format!("open {}", self.path.display()))?;
+
+        // A slot cut short by a crash only held a piece that wasn't written.
+        let count = file.metadata().await?.len() as usize / self.slot_len();
+        let mut slots = Vec::with_capacity(count);
+        for slot in 0..count {
+            file.seek(SeekFrom::Start((slot * self.slot_len()) as u64))
+                .await?;
+            slots.push(file.read_u32().await? as usize);
+        }
+        *self.slots.lock().expect("lock is not poisoned") = slots;
+        Ok(())
+    }
+
+    fn has(&self, piece_i: usize) -> bool {
+        self.slots
+            .lock()
+            .expect("lock is not poisoned")
+            .contains(&piece_i)
+    }
+
+    async fn remove(&self) -> anyhow::Result<()> {
+        self.slots.lock().expect("lock is not poisoned").clear();
+        if self.path.exists() {
+            tokio::fs::remove_file(&self.path)
+                .await
+                .with_context(|| format!("remove {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// The pieces that `length` bytes at `offset` of the torrent span, with
+    /// the offset and length of each part.
+    fn pieces(&self, offset: usize, length: usize) -> impl Iterator<Item = (usize, usize, usize)> {
+        let plength = self.plength;
+        let end = offset + length;
+        (offset / plength..end.div_ceil(plength)).map(move |piece_i| {
+            let (from, to) = (
+                offset.max(piece_i * plength),
+                end.min((piece_i + 1) * plength),
+            );
+            (piece_i, from, to - from)
+        })
+    }
+
+    /// Where in the part file the byte at `offset` of piece `piece_i` is.
+    fn position(&self, slot: usize, piece_i: usize, offset: usize) -> u64 {
+        (slot * self.slot_len() + 4 + offset - piece_i * self.plength) as u64
+    }
+
+    /// The bytes the part file holds of the `length` bytes at `offset`, as
+    /// `(offset, length)`.
+    fn ranges(&self, offset: usize, length: usize) -> Vec<(usize, usize)> {
+        let slots = self.slots.lock().expect("lock is not poisoned");
+        self.pieces(offset, length)
+            .filter(|(piece_i, _, _)| slots.contains(piece_i))
+            .map(|(_, from, len)| (from, len))
+            .collect()
+    }
+
+    async fn write(&self, offset: usize, data: &[u8]) -> anyhow::Result<()> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.path)
+            .await
+            .with_context(|| format!("open {}", self.path.display()))?;
+
+        for (piece_i, from, len) in self.pieces(offset, data.len()) {
+            let (slot, new) = {
+                let mut slots = self.slots.lock().expect("lock is not poisoned");
+                match slots.iter().position(|&p| p == piece_i) {
+                    Some(slot) => (slot, false),
+                    None => {
+                        slots.push(piece_i);
+                        (slots.len() - 1, true)
+                    }
+                }
+            };
+            if new {
+                file.seek(SeekFrom::Start((slot * self.slot_len()) as u64))
+                    .await?;
+                file.write_u32(piece_i as u32).await?;
+            }
+            file.seek(SeekFrom::Start(self.position(slot, piece_i, from)))
+                .await?;
+            file.write_all(&data[from - offset..][..len])
+                .await
+                .with_context(|| format!("write {}", self.path.display()))?;
+        }
+        file.flush().await?;
+        file.sync_data().await?;
+
+        Ok(())
+    }
+
+    async fn read(&self, offset: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let mut file = tokio::fs::File::open(&self.path)
+            .await
+            .with_context(|| format!("open {}", self.path.display()))?;
+
+        let mut data = vec![0; length];
+        for (piece_i, from, len) in self.pieces(offset, length) {
+            let slot = self
+                .slots
+                .lock()
+                .expect("lock is not poisoned")
+                .iter()
+                .position(|&p| p == piece_i)
+                .with_context(|| format!("piece {piece_i} is not in the part file"))?;
+            file.seek(SeekFrom::Start(self.position(slot, piece_i, from)))
+                .await?;
+            file.read_exact(&mut data[from - offset..][..len]).await?;
+        }
+
+        Ok(data)
+    }
+}
+
+/// Reserves disk space for the first `length` bytes of `file`.
+#[cfg(target_os = "linux")]
+async fn reserve(file: tokio::fs::File, length: usize) -> anyhow::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let file = file.into_std().await;
+    tokio::task::spawn_blocking(move || {
+        // SAFETY: the descriptor belongs to `file`, which outlives the call.
+        let error = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) };
+        match error {
+            0 => Ok(()),
+            error => Err(std::io::Error::from_raw_os_error(error).into()),
+        }
+    })
+    .await?
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn reserve(_file: tokio::fs::File, _length: usize) -> anyhow::Result<()> {
+    tracing::warn!("Full allocation is not supported on this platform; the files stay sparse");
+    Ok(())
+}
+
+impl Storage for Layout {
+    fn write<'a>(&'a self, offset: usize, data: &'a [u8]) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(self.write_at(offset, data))
+    }
+
+    fn read(&self, offset: usize, length: usize) -> BoxFuture<'_, anyhow::Result<Vec<u8>>> {
+        Box::pin(Layout::read(self, offset, length))
+    }
+}
+
+/// The output files mapped into memory, in the order of the layout.
+#[cfg(target_os = "linux")]
+pub struct MmapStorage {
+    layout: Layout,
+    maps: Vec<Mmap>,
+}
+
+#[cfg(target_os = "linux")]
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the mapping is shared memory that stays valid until dropped; pieces
+// are written once, before any peer may request them, so accesses from
+// different threads never overlap with a write.
+#[cfg(target_os = "linux")]
+unsafe impl Send for Mmap {}
+#[cfg(target_os = "linux")]
+unsafe impl Sync for Mmap {}
+
+#[cfg(target_os = "linux")]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: `ptr` and `len` describe a mapping made by `mmap`.
+            unsafe { libc::munmap(self.ptr.cast(), self.len) };
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Mmap {
+    /// Flushes `len` bytes at `offset` of the mapping to the file. `msync`
+    /// wants a page-aligned address, so the range is widened down to a page.
+    fn sync(&self, offset: usize, len: usize) -> anyhow::Result<()> {
+        // SAFETY: sysconf has no preconditions.
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let start = offset - offset % page;
+        // SAFETY: the range lies within the mapping, and `start` is page-aligned.
+        let error = unsafe {
+            libc::msync(
+                self.ptr.add(start).cast(),
+                offset + len - start,
+                libc::MS_SYNC,
+            )
+        };
+        if error != 0 {
+            return Err(std::io::Error::last_os_error()).context("msync");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl MmapStorage {
+    /// Maps the files of `layout`, which must be allocated to their full
+    /// lengths already; touching a mapping past the end of a file kills us.
+    pub fn open(layout: &Layout) -> anyhow::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let mut maps = Vec::with_capacity(layout.files.len());
+        for (file_i, (path, length)) in layout.files.iter().enumerate() {
+            if *length == 0 || layout.padding[file_i] || layout.parted[file_i] {
+                maps.push(Mmap {
+                    ptr: std::ptr::null_mut(),
+                    len: 0,
+                });
+                continue;
+            }
+
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .with_context(|| format!("open {}", path.display()))?;
+            anyhow::ensure!(
+                file.metadata()?.len() >= *length as u64,
+                "{} is shorter than {length} bytes",
+                path.display()
+            );
+
+            // SAFETY: a fresh shared mapping of an open file, checked below.
+            // It stays valid after the file is closed.
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    *length,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("map {}", path.display()));
+            }
+            maps.push(Mmap {
+                ptr: ptr.cast(),
+                len: *length,
+            });
+        }
+
+        Ok(Self {
+            layout: layout.clone(),
+            maps,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Storage for MmapStorage {
+    fn write<'a>(&'a self, offset: usize, data: &'a [u8]) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            for (file_i, file_offset, len, at) in self.layout.file_spans(offset, data.len()) {
+                if self.layout.parted[file_i] {
+                    self.layout
+                        .parts
+                        .write(offset + at, &data[at..][..len])
+                        .await?;
+                    continue;
+                }
+
+                let map = &self.maps[file_i];
+                // SAFETY: spans lie within their file, and so within its mapping.
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        data[at..].as_ptr(),
+                        map.ptr.add(file_offset),
+                        len,
+                    );
+                }
+                map.sync(file_offset, len)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn read(&self, offset: usize, length: usize) -> BoxFuture<'_, anyhow::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let mut data = vec![0; length];
+            for (file_i, file_offset, len, at) in self.layout.file_spans(offset, length) {
+                if self.layout.parted[file_i] {
+                    let span = self.layout.parts.read(offset + at, len).await?;
+                    data[at..][..len].copy_from_slice(&span);
+                    continue;
+                }
+
+                let map = &self.maps[file_i];
+                // SAFETY: as in `write`.
+                let span = unsafe { std::slice::from_raw_parts(map.ptr.add(file_offset), len) };
+                data[at..][..len].copy_from_slice(span);
+            }
+            Ok(data)
+        })
+    }
+}
+
+/// The output files driven by an io_uring runtime on a thread of its own,
+/// since its files can't leave the thread that opened them.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub struct UringStorage {
+    layout: Layout,
+    requests: tokio::sync::mpsc::UnboundedSender<UringRequest>,
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+enum UringRequest {
+    Write {
+        file_i: usize,
+        file_offset: usize,
+        data: Vec<u8>,
+        done: tokio::sync::oneshot::Sender<std::io::Result<()>>,
+    },
+    Read {
+        file_i: usize,
+        file_offset: usize,
+        len: usize,
+        done: tokio::sync::oneshot::Sender<std::io::Result<Vec<u8>>>,
+    },
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl UringStorage {
+    /// Opens the files of `layout`, which must exist already, and starts the
+    /// thread serving them. The thread stops once the storage is dropped.
+    pub fn open(layout: &Layout) -> anyhow::Result<Self> {
+        // Padding files are never opened, as no span reaches them, nor are
+        // the files whose spans go to the part file.
+        let files = layout
+            .files
+            .iter()
+            .enumerate()
+            .map(|(file_i, (path, _))| {
+                (!layout.padding[file_i] && !layout.parted[file_i])
+                    .then(|| {
+                        std::fs::OpenOptions::new()
+                            .read(true)
+                            .write(true)
+                            .open(path)
+                            .with_context(|| format!("open {}", path.display()))
+                    })
+                    .transpose()
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let (requests, mut incoming) = tokio::sync::mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("io-uring".to_string())
+            .spawn(move || {
+                tokio_uring::start(async move {
+                    let files: std::rc::Rc<Vec<Option<tokio_uring::fs::File>>> = std::rc::Rc::new(
+                        files
+                            .into_iter()
+                            .map(|file| file.map(tokio_uring::fs::File::from_std))
+                            .collect(),
+                    );
+
+                    // Every request gets a task, so that the ring has as many
+                    // operations in flight as there are blocks waiting.
+                    while let Some(request) = incoming.recv().await {
+                        let files = files.clone();
+                        tokio_uring::spawn(async move {
+                            match request {
+                                UringRequest::Write {
+                                    file_i,
+                                    file_offset,
+                                    data,
+                                    done,
+                                } => {
+                                    let file =
+                                        files[file_i].as_ref().expect("files with spans are open");
+                                    let result = uring_write(file, file_offset, data);
+                                    let _ = done.send(result.await);
+                                }
+                                UringRequest::Read {
+                                    file_i,
+                                    file_offset,
+                                    len,
+                                    done,
+                                } => {
+                                    let file =
+                                        files[file_i].as_ref().expect("files with spans are open");
+                                    let result = uring_read(file, file_offset, len);
+                                    let _ = done.send(result.await);
+                                }
+                            }
+                        });
+                    }
+                })
+            })
+            .context("start the io_uring thread")?;
+
+        Ok(Self {
+            layout: layout.clone(),
+            requests,
+        })
+    }
+
+    fn send(&self, request: UringRequest) -> anyhow::Result<()> {
+        self.requests
+            .send(request)
+            .map_err(|_| anyhow!("the io_uring thread has stopped"))
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+async fn uring_write(
+    file: &tokio_uring::fs::File,
+    file_offset: usize,
+    mut data: Vec<u8>,
+) -> std::io::Result<()> {
+    use tokio_uring::buf::IoBuf;
+
+    let mut written = 0;
+    while written < data.len() {
+        let (result, slice) = file
+            .write_at(data.slice(written..), (file_offset + written) as u64)
+            .await;
+        data = slice.into_inner();
+        match result? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => written += n,
+        }
+    }
+
+    file.sync_data().await
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+async fn uring_read(
+    file: &tokio_uring::fs::File,
+    file_offset: usize,
+    len: usize,
+) -> std::io::Result<Vec<u8>> {
+    use tokio_uring::buf::IoBuf;
+
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        let start = data.len();
+        let (result, slice) = file
+            .read_at(data.slice(start..len), (file_offset + start) as u64)
+            .await;
+        data = slice.into_inner();
+        if result? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+
+    Ok(data)
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl Storage for UringStorage {
+    fn write<'a>(&'a self, offset: usize, data: &'a [u8]) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let mut pending = Vec::new();
+            for (file_i, file_offset, len, at) in self.layout.file_spans(offset, data.len()) {
+                if self.layout.parted[file_i] {
+                    self.layout
+                        .parts
+                        .write(offset + at, &data[at..][..len])
+                        .await?;
+                    continue;
+                }
+
+                let (done, result) = tokio::sync::oneshot::channel();
+                self.send(UringRequest::Write {
+                    file_i,
+                    file_offset,
+                    data: data[at..][..len].to_vec(),
+                    done,
+                })?;
+                pending.push((file_i, result));
+            }
+
+            for (file_i, result) in pending {
+                let path = self.layout.files[file_i].0.display();
+                result
+                    .await
+                    .map_err(|_| anyhow!("the io_uring thread has stopped"))?
+                    .with_context(|| format!("write {path}"))?;
+            }
+            Ok(())
+        })
+    }
+
+    fn read(&self, offset: usize, length: usize) -> BoxFuture<'_, anyhow::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let mut data = vec![0; length];
+            let mut pending = Vec::new();
+            for (file_i, file_offset, len, at) in self.layout.file_spans(offset, length) {
+                if self.layout.parted[file_i] {
+                    let span = self.layout.parts.read(offset + at, len).await?;
+                    data[at..][..len].copy_from_slice(&span);
+                    continue;
+                }
+
+                let (done, result) = tokio::sync::oneshot::channel();
+                self.send(UringRequest::Read {
+                    file_i,
+                    file_offset,
+                    len,
+                    done,
+                })?;
+                pending.push((file_i, at, result));
+            }
+
+            for (file_i, at, result) in pending {
+                let path = self.layout.files[file_i].0.display();
+                let span = result
+                    .await
+                    .map_err(|_| anyhow!("the io_uring thread has stopped"))?
+                    .with_context(|| format!("read {path}"))?;
+                data[at..][..span.len()].copy_from_slice(&span);
+            }
+            Ok(data)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use super::{Allocation, Backend, Disk, Layout, Parts, PieceStatus};
+    use crate::{
+        hash,
+        torrent::{File, Hashes, Keys, Torrent},
+    };
+
+    #[test]
+    fn test_spans_across_files() {
+        let layout = Layout {
+            files: vec![
+                (PathBuf::from("a"), 10),
+                (PathBuf::from("b"), 5),
+                (PathBuf::from("c"), 20),
+            ],
+            padding: vec![false; 3],
+            parted: vec![false; 3],
+            parts: Arc::new(Parts::new(PathBuf::from(".parts"), 16)),
+        };
+
+        let spans: Vec<_> = layout.spans(8, 10).collect();
+        assert_eq!(
+            spans,
+            vec![
+                (Path::new("a"), 8, 2),
+                (Path::new("b"), 0, 5),
+                (Path::new("c"), 0, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_padding_is_not_stored() {
+        let layout = Layout {
+            files: vec![
+                (PathBuf::from("a"), 10),
+                (PathBuf::from(".pad/6"), 6),
+                (PathBuf::from("c"), 20),
+            ],
+            padding: vec![false, true, false],
+            parted: vec![false; 3],
+            parts: Arc::new(Parts::new(PathBuf::from(".parts"), 16)),
+        };
+
+        let spans: Vec<_> = layout.spans(8, 10).collect();
+        assert_eq!(spans, vec![(Path::new("a"), 8, 2), (Path::new("c"), 0, 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_padding_files_keep_pieces_aligned() {
+        let dir = std::env::temp_dir().join(format!("padding-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        // The padding file makes c start at piece 1.
+        let (a, c): (Vec<u8>, Vec<u8>) = ((1..=10).collect(), (11..=30).collect());
+        let data = [a.as_slice(), &[0; 6], &c].concat();
+        let mut t = Torrent::for_test(16, &[10, 6, 20]);
+        t.info.pieces = Hashes(data.chunks(16).map(|piece| hash::sha1(&[piece])).collect());
+        if let Keys::MultiFile { files } = &mut t.info.keys {
+            files[1] = File::padding(6);
+        }
+
+        let mut layout = Layout::new(&t, &dir);
+        layout.skip(&[true; 3]).await.unwrap();
+        layout
+            .allocate(Allocation::Sparse, &[true; 3])
+            .await
+            .unwrap();
+        for (piece_i, piece) in data.chunks(16).enumerate() {
+            layout.write_at(piece_i * 16, piece).await.unwrap();
+        }
+
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), a);
+        assert_eq!(std::fs::read(dir.join("c")).unwrap(), c);
+        let padding = dir.join(File::padding(6).path.iter().collect::<PathBuf>());
+        assert!(!padding.exists());
+        let spans: Vec<_> = layout.spans(16, 16).collect();
+        assert_eq!(spans, vec![(dir.join("c").as_path(), 0, 16)]);
+        for piece_i in 0..t.info.piece_count() {
+            assert_eq!(layout.check_piece(&t, piece_i).await.0, PieceStatus::Valid);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_check_piece_tells_valid_corrupt_and_missing() {
+        let dir = std::env::temp_dir().join(format!("check-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let data: Vec<u8> = (0..30).collect();
+        let mut t = Torrent::for_test(8, &[20, 10]);
+        t.info.pieces = Hashes(data.chunks(8).map(|piece| hash::sha1(&[piece])).collect());
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut a = data[..20].to_vec();
+        a[9] ^= 0xff;
+        std::fs::write(dir.join("a"), a).unwrap();
+        // b stops short of the last piece.
+        std::fs::write(dir.join("b"), &data[20..28]).unwrap();
+
+        let layout = Layout::new(&t, &dir);
+        let statuses = [
+            PieceStatus::Valid,
+            PieceStatus::Corrupt,
+            PieceStatus::Valid,
+            PieceStatus::Missing,
+        ];
+        for (piece_i, status) in statuses.into_iter().enumerate() {
+            let (got, piece) = layout.check_piece(&t, piece_i).await;
+            assert_eq!(got, status, "piece {piece_i}");
+            if status == PieceStatus::Valid {
+                assert_eq!(piece, &data[piece_i * 8..][..8]);
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_allocation_sizes_files_and_keeps_their_data() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = std::env::temp_dir().join(format!("allocate-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let length = 1 << 20;
+        let t = Torrent::for_test(1 << 18, &[length, length, 10]);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("c"), b"abc").unwrap();
+
+        let layout = Layout::new(&t, &dir);
+        // b is skipped, so it is only sized.
+        layout
+            .allocate(Allocation::Full, &[true, false, true])
+            .await
+            .unwrap();
+        let a = std::fs::metadata(dir.join("a")).unwrap();
+        let b = std::fs::metadata(dir.join("b")).unwrap();
+        assert_eq!(a.len(), length as u64);
+        assert!(a.blocks() * 512 >= length as u64);
+        assert_eq!(b.len(), length as u64);
+        assert!(b.blocks() * 512 < length as u64);
+        let c = std::fs::read(dir.join("c")).unwrap();
+        assert_eq!(c.len(), 10);
+        assert_eq!(&c[..3], b"abc");
+
+        assert_eq!("sparse".parse::<Allocation>().unwrap(), Allocation::Sparse);
+        assert_eq!("full".parse::<Allocation>().unwrap(), Allocation::Full);
+        assert!("dense".parse::<Allocation>().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Writes through `backend`, with a block spanning all three files, and
+    /// reads it back before and after reopening the files.
+    async fn round_trip(backend: Backend) {
+        let dir = std::env::temp_dir().join(format!("{backend:?}-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut layout = Layout {
+            files: vec![(dir.join("a"), 10), (dir.join("b"), 5), (dir.join("c"), 20)],
+            padding: vec![false; 3],
+            parted: vec![false; 3],
+            parts: Arc::new(Parts::new(dir.join(".parts"), 16)),
+        };
+        layout.skip(&[true; 3]).await.unwrap();
+        let disk = Disk {
+            allocation: Allocation::Sparse,
+            backend,
+        };
+
+        let data: Vec<u8> = (1..=35).collect();
+        let storage = disk.open(&layout, &[true; 3]).await.unwrap();
+        storage.write(8, &data[8..18]).await.unwrap();
+        storage.write(0, &data[..8]).await.unwrap();
+        storage.write(18, &data[18..]).await.unwrap();
+        assert_eq!(storage.read(8, 10).await.unwrap(), &data[8..18]);
+        drop(storage);
+
+        let storage = disk.open(&layout, &[true; 3]).await.unwrap();
+        assert_eq!(storage.read(0, 35).await.unwrap(), data);
+        assert_eq!(storage.read(9, 7).await.unwrap(), &data[9..16]);
+        drop(storage);
+        // Each file holds its own bytes, not those the first one starts with.
+        for (name, bytes) in [("a", 0..10), ("b", 10..15), ("c", 15..35)] {
+            assert_eq!(std::fs::read(dir.join(name)).unwrap(), &data[bytes]);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_files_round_trip() {
+        round_trip(Backend::Files).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_mmap_round_trip() {
+        round_trip(Backend::Mmap).await;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[tokio::test]
+    async fn test_uring_round_trip() {
+        round_trip(Backend::Uring).await;
+    }
+
+    #[tokio::test]
+    async fn test_skipped_files_go_to_part_file() {
+        let dir = std::env::temp_dir().join(format!("parts-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        // Pieces of 8 bytes: piece 1 is shared by a and b, piece 3 by b and c.
+        let mut layout = Layout {
+            files: vec![(dir.join("a"), 10), (dir.join("b"), 20), (dir.join("c"), 6)],
+            padding: vec![false; 3],
+            parted: vec![false; 3],
+            parts: Arc::new(Parts::new(dir.join(".parts"), 8)),
+        };
+        layout.skip(&[true, false, true]).await.unwrap();
+        layout
+            .allocate(Allocation::Sparse, &[true, false, true])
+            .await
+            .unwrap();
+        assert!(!dir.join("b").exists());
+
+        let data: Vec<u8> = (0..36).collect();
+        for piece_i in [0, 1, 3, 4] {
+            let piece = &data[piece_i * 8..][..8.min(36 - piece_i * 8)];
+            layout.write_at(piece_i * 8, piece).await.unwrap();
+        }
+        assert!(!dir.join("b").exists());
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), &data[..10]);
+        assert_eq!(std::fs::read(dir.join("c")).unwrap(), &data[30..]);
+        assert_eq!(layout.read(8, 8).await.unwrap(), &data[8..16]);
+        assert_eq!(layout.read(24, 8).await.unwrap(), &data[24..32]);
+        assert!(layout.read(16, 8).await.is_err());
+        assert!(layout.holds(8, 8));
+        assert!(!layout.holds(16, 8));
+
+        // Once b is wanted, it gets the bytes the part file has of it.
+        layout.export(&[true; 3]).await.unwrap();
+        let b = std::fs::read(dir.join("b")).unwrap();
+        assert_eq!(b.len(), 20);
+        assert_eq!(&b[..6], &data[10..16]);
+        assert_eq!(&b[14..], &data[24..30]);
+
+        // A file cut short no longer holds its pieces.
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join("c"))
+            .unwrap()
+            .set_len(4)
+            .unwrap();
+        assert!(layout.holds(0, 8));
+        assert!(!layout.holds(32, 4));
+
+        // With nothing skipped, the part file goes.
+        let mut layout = Layout {
+            parted: vec![false; 3],
+            parts: Arc::new(Parts::new(dir.join(".parts"), 8)),
+            ..layout
+        };
+        layout.skip(&[true; 3]).await.unwrap();
+        assert!(!dir.join(".parts").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,20 +1,84 @@
-use std::{fmt, path::Path};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt,
+    ops::Range,
+    path::{Component, Path},
+    sync::OnceLock,
+    time::Duration,
+};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer, Serialize, Serializer,
 };
-use sha1::{Digest, Sha1};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    bencode, download, hash,
+    merkle::{self, HashRequest},
+    piece::Priority,
+    tracker::Tiers,
+};
 
-use crate::download::{self, Downloaded};
+/// How long downloading a `.torrent` file may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
 
-#[derive(Debug, Clone, Deserialize)]
+/// The largest `.torrent` file we download; real ones are far smaller.
+const MAX_FETCH_LEN: usize = 16 << 20;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Torrent {
-    /// The URL of the tracker
-    pub announce: String,
+    /// The URL of the tracker, absent for trackerless (DHT-only) torrents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+
+    /// Tiers of tracker URLs that supersede `announce` when present (BEP 12)
+    #[serde(
+        default,
+        rename = "announce-list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub announce_list: Vec<Vec<String>>,
+
+    /// DHT nodes to bootstrap from, as `(host, port)` pairs (BEP 5)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<(String, u16)>,
+
+    /// Web seeds, HTTP URLs that serve the data (BEP 19); a single URL may
+    /// be given as a plain string
+    #[serde(
+        default,
+        rename = "url-list",
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub url_list: Vec<String>,
 
     pub info: Info,
+
+    /// For v2 torrents, the hashes of each file's merkle tree layer that
+    /// covers a piece apiece, concatenated, by the file's `pieces root`; only
+    /// files longer than a piece have one (BEP 52)
+    #[serde(
+        default,
+        rename = "piece layers",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+
+    /// The info dictionary as it was read, or as peers sent it for torrents
+    /// resolved from a magnet: `info` drops the keys it doesn't model (e.g.
+    /// `source`), so encoding it again may not give back the bytes the info
+    /// hash is taken over
+    #[serde(skip)]
+    pub info_bytes: Option<Vec<u8>>,
+
+    /// The total length, added up on first use: piece lengths depend on it,
+    /// and are asked for piece by piece
+    #[serde(skip)]
+    pub(crate) length: OnceLock<usize>,
 }
 
 impl Torrent {
@@ -22,41 +86,259 @@ impl Torrent {
         let dot_torrent = tokio::fs::read(torrent)
             .await
             .context("read torrent file")?;
-        let torrent: Torrent =
-            serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+        Self::from_bytes(&dot_torrent)
+    }
+
+    /// Parses a `.torrent` file, keeping its info dictionary as it is.
+    pub fn from_bytes(dot_torrent: &[u8]) -> anyhow::Result<Self> {
+        let mut torrent: Torrent =
+            serde_bencode::from_bytes(dot_torrent).context("parse torrent file")?;
+        torrent.info.check_piece_length()?;
+        torrent.info.check_paths()?;
+        torrent.info_bytes = bencode::dict_value(dot_torrent, b"info").map(<[u8]>::to_vec);
 
         Ok(torrent)
     }
 
+    /// Downloads a `.torrent` file from an `http(s)` URL, giving up after
+    /// [`FETCH_TIMEOUT`] or [`MAX_FETCH_LEN`] bytes.
+    pub async fn fetch(url: &str) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .context("build HTTP client")?;
+        let mut res = client
+            .get(url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .with_context(|| format!("fetch {url}"))?;
+        let too_long = || anyhow!("{url} is longer than a torrent file can be");
+        if res
+            .content_length()
+            .is_some_and(|len| len > MAX_FETCH_LEN as u64)
+        {
+            return Err(too_long());
+        }
+
+        let mut dot_torrent = Vec::new();
+        while let Some(chunk) = res.chunk().await.with_context(|| format!("fetch {url}"))? {
+            if dot_torrent.len() + chunk.len() > MAX_FETCH_LEN {
+                return Err(too_long());
+            }
+            dot_torrent.extend_from_slice(&chunk);
+        }
+        // Sites that want a login tend to answer with an HTML page instead.
+        anyhow::ensure!(
+            dot_torrent.first() == Some(&b'd'),
+            "{url} did not return a bencoded torrent"
+        );
+        Self::from_bytes(&dot_torrent)
+    }
+
+    /// Writes the torrent as a `.torrent` file.
+    pub async fn write(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let dot_torrent = serde_bencode::to_bytes(self).context("encode torrent")?;
+        tokio::fs::write(path, dot_torrent)
+            .await
+            .context("write torrent file")
+    }
+
+    /// A torrent of files of `lengths` bytes, named `a`, `b` and so on, in
+    /// pieces of `plength` whose hashes are all zeros; a single length makes
+    /// a single-file torrent.
+    #[cfg(test)]
+    pub(crate) fn for_test(plength: usize, lengths: &[usize]) -> Self {
+        let total: usize = lengths.iter().sum();
+        let keys = match lengths {
+            [length] => Keys::SingleFile { length: *length },
+            _ => Keys::MultiFile {
+                files: lengths
+                    .iter()
+                    .zip('a'..)
+                    .map(|(&length, name)| File {
+                        length,
+                        path: vec![name.to_string()],
+                        attr: None,
+                    })
+                    .collect(),
+            },
+        };
+        Self {
+            announce: None,
+            announce_list: Vec::new(),
+            nodes: Vec::new(),
+            url_list: Vec::new(),
+            info: Info {
+                name: "test".to_string(),
+                plength,
+                pieces: Hashes(vec![[0; 20]; total.div_ceil(plength)]),
+                keys,
+                meta_version: None,
+                file_tree: None,
+                private: None,
+            },
+            piece_layers: Default::default(),
+            info_bytes: None,
+            length: Default::default(),
+        }
+    }
+
+    /// The info hash that trackers and peers know the torrent by: the SHA-1
+    /// one, or for v2-only torrents the SHA-256 one truncated to 20 bytes.
     pub fn info_hash(&self) -> [u8; 20] {
-        let info_bytes = serde_bencode::to_bytes(&self.info).expect("parse into bytes");
-        let mut hasher = Sha1::new();
-        hasher.update(&info_bytes);
-        hasher.finalize().try_into().expect("")
+        if !self.info.has_v1() {
+            return self.info_hash_v2()[..20].try_into().expect("20 bytes");
+        }
+        hash::sha1(&[&self.encoded_info()])
     }
 
-    pub fn length(&self) -> usize {
-        match &self.info.keys {
-            Keys::SingleFile { length } => *length,
-            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+    /// Every info hash the torrent is known by in swarms: [`Torrent::info_hash`]
+    /// and, for hybrid torrents, the truncated SHA-256 one too.
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        let mut info_hashes = vec![self.info_hash()];
+        if self.info.has_v1() && self.info.has_v2() {
+            info_hashes.push(self.info_hash_v2()[..20].try_into().expect("20 bytes"));
         }
+        info_hashes
+    }
+
+    /// The SHA-256 info hash of a v2 or hybrid torrent (BEP 52).
+    pub fn info_hash_v2(&self) -> [u8; 32] {
+        hash::sha256(&[&self.encoded_info()])
+    }
+
+    /// The bencoded info dictionary the info hashes are taken over.
+    fn encoded_info(&self) -> Cow<'_, [u8]> {
+        match &self.info_bytes {
+            Some(info_bytes) => Cow::Borrowed(info_bytes),
+            None => Cow::Owned(serde_bencode::to_bytes(&self.info).expect("parse into bytes")),
+        }
+    }
+
+    /// The trackers to announce to: the `announce-list` tiers if there are
+    /// any, otherwise just `announce`.
+    pub fn tiers(&self) -> Tiers {
+        let tiers = if self.announce_list.iter().any(|tier| !tier.is_empty()) {
+            Tiers::new(self.announce_list.clone())
+        } else {
+            Tiers::new(self.announce.iter().map(|url| vec![url.clone()]).collect())
+        };
+        tiers.private(self.info.is_private())
+    }
+
+    pub fn length(&self) -> usize {
+        *self.length.get_or_init(|| self.info.length())
     }
 
     pub fn print_tree(&self) {
-        match &self.info.keys {
-            Keys::SingleFile { .. } => {
-                eprintln!("{}", self.info.name);
+        if self.info.is_single_file() {
+            eprintln!("{}", self.info.name);
+            return;
+        }
+        for file in self.info.files().iter().filter(|file| !file.is_padding()) {
+            eprintln!("{:?}", file.path.join(std::path::MAIN_SEPARATOR_STR));
+        }
+    }
+
+    /// The length of piece `piece_i`; only the last piece may be shorter.
+    pub fn piece_len(&self, piece_i: usize) -> usize {
+        self.info
+            .plength
+            .min(self.length() - self.info.plength * piece_i)
+    }
+
+    /// Whether `data` is piece `piece_i`: by its SHA-1 hash when the torrent
+    /// has v1 metadata, by the merkle tree of its file (BEP 52) when it has
+    /// v2 metadata, and by both for hybrid torrents, as far as the v2 hashes
+    /// are known.
+    pub fn verify_piece(&self, piece_i: usize, data: &[u8]) -> bool {
+        if !self.info.has_v1() {
+            return self.verify_piece_v2(piece_i, data) == Some(true);
+        }
+
+        let v1 = self
+            .info
+            .pieces
+            .0
+            .get(piece_i)
+            .is_some_and(|hash| hash::sha1(&[data]) == *hash);
+        v1 && (!self.info.has_v2() || self.verify_piece_v2(piece_i, data) != Some(false))
+    }
+
+    /// Checks piece `piece_i` against the merkle tree of its file, if the
+    /// hash that covers it is known.
+    fn verify_piece_v2(&self, piece_i: usize, data: &[u8]) -> Option<bool> {
+        let plength = self.info.plength;
+        let offset = piece_i * plength;
+        let mut start = 0;
+        for file in self.info.files() {
+            if offset >= start + file.length {
+                start += file.length;
+                continue;
             }
-            Keys::MultiFile { files } => {
-                for file in files {
-                    eprintln!("{:?}", file.path.join(std::path::MAIN_SEPARATOR_STR));
-                }
+            let root = self.info.pieces_root(&file.path)?;
+
+            // Blocks past the end of the file hash to zero.
+            let length = (start + file.length - offset).min(data.len());
+            let leaves = merkle::block_hashes(&data[..length]);
+            if file.length <= plength {
+                let width = leaves.len().next_power_of_two();
+                return Some(merkle::root(&leaves, width, [0; 32]) == root);
             }
+            let layer = self.piece_layers.get(serde_bytes::Bytes::new(&root))?;
+            let index = (offset - start) / plength;
+            let piece_hash = merkle::root(&leaves, plength / merkle::BLOCK_SIZE, [0; 32]);
+            return Some(layer.get(index * 32..(index + 1) * 32) == Some(&piece_hash[..]));
+        }
+
+        None
+    }
+
+    /// The merkle tree above the piece layer of the file with `pieces_root`,
+    /// if we have its piece layer, for answering hash requests.
+    pub fn piece_tree(&self, pieces_root: &[u8; 32]) -> Option<merkle::Tree> {
+        let blocks_per_piece = self.info.plength / merkle::BLOCK_SIZE;
+        let layer: Vec<[u8; 32]> = self
+            .piece_layers
+            .get(serde_bytes::Bytes::new(pieces_root))?
+            .chunks_exact(32)
+            .map(|hash| hash.try_into().expect("32 bytes"))
+            .collect();
+        Some(merkle::Tree::new(
+            &layer,
+            merkle::pad_hash(blocks_per_piece),
+        ))
+    }
+
+    /// The hashes and proof that answer a hash `request` for part of a
+    /// file's piece layer from the file's [`Torrent::piece_tree`], if the
+    /// request is valid.
+    pub fn hashes(&self, tree: &merkle::Tree, request: &HashRequest) -> Option<Vec<[u8; 32]>> {
+        let blocks_per_piece = self.info.plength / merkle::BLOCK_SIZE;
+        let (index, length) = (request.index as usize, request.length as usize);
+        let width = tree.base().len();
+        let valid = request.base_layer == blocks_per_piece.trailing_zeros()
+            && length.is_power_of_two()
+            && (2..=merkle::MAX_HASHES).contains(&length)
+            && index.is_multiple_of(length)
+            && index < width;
+        if !valid {
+            return None;
         }
+
+        let pad = merkle::pad_hash(blocks_per_piece);
+        let mut hashes: Vec<[u8; 32]> = (index..index + length)
+            .map(|i| tree.base().get(i).copied().unwrap_or(pad))
+            .collect();
+        let proof =
+            (request.proof_layers as usize).saturating_sub(length.trailing_zeros() as usize);
+        hashes.extend(tree.uncles(index, length, proof));
+        Some(hashes)
     }
 
-    pub async fn donwload_all(&self) -> anyhow::Result<Downloaded> {
-        download::all(self).await
+    pub async fn donwload_all(&self, output: &Path) -> anyhow::Result<()> {
+        download::all(self, output).await
     }
 }
 
@@ -76,28 +358,391 @@ pub struct Info {
     /// `pieces` maps to a string whose length is a multiple of 20.
     /// It is to be subdivided into strings of length 20,
     /// each of which is the SHA1 hash of the piece at the corresponding index.
+    /// v2-only torrents have none.
+    #[serde(default, skip_serializing_if = "Hashes::is_empty")]
     pub pieces: Hashes,
 
     /// There is also a key length or a key files, but not both or neither.
     #[serde(flatten)]
     pub keys: Keys,
+
+    /// 2 for v2 and hybrid torrents (BEP 52).
+    #[serde(
+        default,
+        rename = "meta version",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u32>,
+
+    /// The files of a v2 or hybrid torrent, by path (BEP 52).
+    #[serde(default, rename = "file tree", skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<BTreeMap<String, FileTree>>,
+
+    /// 1 if peers may only come from the trackers (BEP 27).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+}
+
+impl Info {
+    /// Whether there are v1 piece hashes, as in v1 and hybrid torrents.
+    pub fn has_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    /// Whether there is a v2 file tree, as in v2 and hybrid torrents.
+    pub fn has_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    /// Fails if pieces can't be laid out or verified with the piece length:
+    /// v2 pieces are whole subtrees of 16 KiB merkle blocks (BEP 52).
+    pub fn check_piece_length(&self) -> anyhow::Result<()> {
+        if self.meta_version == Some(2) {
+            anyhow::ensure!(
+                self.plength.is_power_of_two() && self.plength >= merkle::BLOCK_SIZE,
+                "piece length must be a power of two ≥ 16 KiB, not {}",
+                self.plength
+            );
+        }
+        anyhow::ensure!(self.plength > 0, "piece length must not be 0");
+        Ok(())
+    }
+
+    /// Fails if the name or a file path could lead outside the directory
+    /// the torrent is stored in: each of their components has to be a plain
+    /// file or directory name.
+    pub fn check_paths(&self) -> anyhow::Result<()> {
+        let plain = |component: &str| {
+            let mut components = Path::new(component).components();
+            !component.contains(['/', '\\'])
+                && matches!(components.next(), Some(Component::Normal(name)) if name == component)
+                && components.next().is_none()
+        };
+
+        anyhow::ensure!(plain(&self.name), "invalid torrent name {:?}", self.name);
+        for file in self.files() {
+            anyhow::ensure!(
+                !file.path.is_empty() && file.path.iter().all(|component| plain(component)),
+                "invalid file path {:?}",
+                file.path
+            );
+        }
+        Ok(())
+    }
+
+    pub fn piece_count(&self) -> usize {
+        if self.has_v1() {
+            return self.pieces.0.len();
+        }
+        self.length().div_ceil(self.plength)
+    }
+
+    /// The root of the merkle tree of the file at `path` in the v2 file tree;
+    /// empty files have none.
+    pub fn pieces_root(&self, path: &[String]) -> Option<[u8; 32]> {
+        let (name, rest) = path.split_first()?;
+        let mut entry = self.file_tree.as_ref()?.get(name)?;
+        for name in rest {
+            let FileTree::Directory(tree) = entry else {
+                return None;
+            };
+            entry = tree.get(name)?;
+        }
+        let FileTree::File { file } = entry else {
+            return None;
+        };
+        file.pieces_root.as_ref()?[..].try_into().ok()
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// The files, in the order they are concatenated in; a single-file
+    /// torrent has one named after the torrent. Those of v2-only torrents
+    /// come with padding files, so that each starts on a piece boundary.
+    pub fn files(&self) -> Vec<File> {
+        match &self.keys {
+            Keys::SingleFile { length } => vec![File {
+                length: *length,
+                path: vec![self.name.clone()],
+                attr: None,
+            }],
+            Keys::MultiFile { files } => files.clone(),
+            Keys::V2Only {} => {
+                let mut files = Vec::new();
+                if let Some(tree) = &self.file_tree {
+                    FileTree::collect(tree, &mut Vec::new(), &mut files);
+                }
+
+                let mut padded = Vec::with_capacity(2 * files.len());
+                let count = files.len();
+                for (file_i, file) in files.into_iter().enumerate() {
+                    let padding = (self.plength - file.length % self.plength) % self.plength;
+                    padded.push(file);
+                    if padding > 0 && file_i + 1 < count {
+                        padded.push(File::padding(padding));
+                    }
+                }
+                padded
+            }
+        }
+    }
+
+    /// Whether the torrent is a single file, rather than a directory of them.
+    pub fn is_single_file(&self) -> bool {
+        match &self.keys {
+            Keys::SingleFile { .. } => true,
+            Keys::MultiFile { .. } => false,
+            Keys::V2Only {} => self.file_tree.as_ref().is_some_and(|tree| {
+                tree.len() == 1 && matches!(tree.get(&self.name), Some(FileTree::File { .. }))
+            }),
+        }
+    }
+
+    /// The lengths of the files, in the order they are concatenated in.
+    pub fn file_lengths(&self) -> Vec<usize> {
+        self.files().iter().map(|file| file.length).collect()
+    }
+
+    /// The total length of the files, padding included; [`Torrent::length`]
+    /// keeps it.
+    pub fn length(&self) -> usize {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+            Keys::V2Only {} => self.file_lengths().iter().sum(),
+        }
+    }
+
+    /// The paths of the files relative to the torrent's directory, with `/`
+    /// between components; just the name for a single-file torrent.
+    pub fn file_paths(&self) -> Vec<String> {
+        self.files()
+            .iter()
+            .map(|file| file.path.join("/"))
+            .collect()
+    }
+
+    /// The range of pieces that overlap each file.
+    pub fn file_pieces(&self) -> Vec<Range<usize>> {
+        let mut start = 0;
+        self.file_lengths()
+            .into_iter()
+            .map(|length| {
+                let first = start / self.plength;
+                start += length;
+                if length == 0 {
+                    return first..first;
+                }
+                first..start.div_ceil(self.plength)
+            })
+            .collect()
+    }
+
+    /// The priority of each piece given those of the files: the highest of
+    /// the files it overlaps, so that a piece is skipped only if all of them
+    /// are.
+    pub fn piece_priorities(&self, files: &[Priority]) -> Vec<Priority> {
+        let mut pieces = vec![Priority::Skip; self.piece_count()];
+        for (range, &priority) in self.file_pieces().into_iter().zip(files) {
+            for piece_i in range {
+                pieces[piece_i] = pieces[piece_i].max(priority);
+            }
+        }
+        pieces
+    }
+
+    /// Which files to download: those matching `files`, or all of them when
+    /// it is empty, except those matching `skip`. Both hold file indices or
+    /// globs over [`Info::file_paths`], where `*` matches any run of
+    /// characters and `?` any one. Padding files are left out, of the
+    /// indices too.
+    pub fn select_files(&self, files: &[String], skip: &[String]) -> anyhow::Result<Vec<bool>> {
+        let all = self.files();
+        let paths = self.file_paths();
+        let listed: Vec<usize> = (0..all.len())
+            .filter(|&file_i| !all[file_i].is_padding())
+            .collect();
+        let matching = |selector: &String| -> anyhow::Result<Vec<usize>> {
+            let matched: Vec<usize> = match selector.parse::<usize>() {
+                Ok(i) if i < listed.len() => vec![listed[i]],
+                Ok(i) => anyhow::bail!("{} has no file {i}", self.name),
+                Err(_) => listed
+                    .iter()
+                    .copied()
+                    .filter(|&file_i| glob_match(selector, &paths[file_i]))
+                    .collect(),
+            };
+            if matched.is_empty() {
+                return Err(anyhow!("no file of {} matches {selector}", self.name));
+            }
+            Ok(matched)
+        };
+
+        let mut wanted: Vec<bool> = all
+            .iter()
+            .map(|file| files.is_empty() && !file.is_padding())
+            .collect();
+        for selector in files {
+            for file_i in matching(selector)? {
+                wanted[file_i] = true;
+            }
+        }
+        for selector in skip {
+            for file_i in matching(selector)? {
+                wanted[file_i] = false;
+            }
+        }
+
+        Ok(wanted)
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters
+/// and `?` any one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Where the last `*` was, and the text position it was tried up to.
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Keys {
-    SingleFile { length: usize },
-    MultiFile { files: Vec<File> },
+    SingleFile {
+        length: usize,
+    },
+    MultiFile {
+        files: Vec<File>,
+    },
+    /// A v2 torrent without v1 metadata, whose files are only in the
+    /// `file tree`.
+    V2Only {},
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct File {
     pub length: usize,
     pub path: Vec<String>,
+
+    /// Attributes, one letter each; `p` marks a padding file (BEP 47).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+impl File {
+    /// A padding file of `length` zeros (BEP 47).
+    pub fn padding(length: usize) -> Self {
+        Self {
+            length,
+            path: vec![".pad".to_string(), length.to_string()],
+            attr: Some("p".to_string()),
+        }
+    }
+
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+/// An entry of a v2 `file tree`: a file, keyed by the empty name, or a
+/// directory of more entries.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileTree {
+    File {
+        #[serde(rename = "")]
+        file: V2File,
+    },
+    Directory(BTreeMap<String, FileTree>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct V2File {
+    pub length: usize,
+
+    /// The root of the merkle tree over the file's 16 KiB blocks; empty files
+    /// have none.
+    #[serde(
+        default,
+        rename = "pieces root",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<ByteBuf>,
+}
+
+impl FileTree {
+    /// Appends the files below `tree` to `files`, in the order of their
+    /// paths, which is the order their data is concatenated in.
+    fn collect(tree: &BTreeMap<String, FileTree>, prefix: &mut Vec<String>, files: &mut Vec<File>) {
+        for (name, entry) in tree {
+            prefix.push(name.clone());
+            match entry {
+                FileTree::File { file } => files.push(File {
+                    length: file.length,
+                    path: prefix.clone(),
+                    attr: None,
+                }),
+                FileTree::Directory(tree) => FileTree::collect(tree, prefix, files),
+            }
+            prefix.pop();
+        }
+    }
+}
+
+/// Accepts either a single string or a list of them.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) if url.is_empty() => Vec::new(),
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    })
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Hashes(pub Vec<[u8; 20]>);
+
+impl Hashes {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
 struct HashesVisitor;
 
 impl<'de> Visitor<'de> for HashesVisitor {
@@ -111,7 +756,7 @@ impl<'de> Visitor<'de> for HashesVisitor {
     where
         E: de::Error,
     {
-        if v.len() % 20 != 0 {
+        if !v.len().is_multiple_of(20) {
             return Err(E::custom(format!("length is {}", v.len())));
         }
 
@@ -141,3 +786,235 @@ impl Serialize for Hashes {
         serializer.serialize_bytes(&single_file)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{File, Hashes, Info, Keys, Torrent, MAX_FETCH_LEN};
+    use crate::{
+        create::{self, Version},
+        hash,
+        merkle::{self, HashRequest},
+        piece::Priority,
+    };
+
+    #[test]
+    fn test_select_files() {
+        let file = |path: &str, length| File {
+            length,
+            path: path.split('/').map(String::from).collect(),
+            attr: None,
+        };
+        let info = Info {
+            name: "show".to_string(),
+            plength: 10,
+            pieces: Hashes(vec![[0; 20]; 4]),
+            keys: Keys::MultiFile {
+                files: vec![
+                    file("info.nfo", 5),
+                    file("s01/e01.mkv", 20),
+                    file("s01/e02.mkv", 15),
+                ],
+            },
+            meta_version: None,
+            file_tree: None,
+            private: None,
+        };
+        let select = |files: &[&str], skip: &[&str]| {
+            let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            info.select_files(&strings(files), &strings(skip))
+        };
+
+        assert_eq!(select(&[], &[]).unwrap(), [true, true, true]);
+        assert_eq!(select(&["*.mkv"], &["1"]).unwrap(), [false, false, true]);
+        assert_eq!(
+            select(&["s0?/e01*", "0"], &[]).unwrap(),
+            [true, true, false]
+        );
+        assert!(select(&["*.avi"], &[]).is_err());
+        assert!(select(&["3"], &[]).is_err());
+
+        // Padding files are never wanted, nor counted by the indices.
+        let mut padded = info.clone();
+        if let Keys::MultiFile { files } = &mut padded.keys {
+            files.insert(1, File::padding(5));
+        }
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            padded.select_files(&[], &strings(&["1"])).unwrap(),
+            [true, false, false, true]
+        );
+
+        // The first episode shares its first and last pieces with the others.
+        assert_eq!(info.file_pieces()[1], 0..3);
+        assert_eq!(
+            info.piece_priorities(&[Priority::Skip, Priority::High, Priority::Low]),
+            [
+                Priority::High,
+                Priority::High,
+                Priority::High,
+                Priority::Low
+            ]
+        );
+    }
+
+    #[test]
+    fn test_v2_pieces() {
+        let path = std::env::temp_dir().join(format!("v2-test-{}", std::process::id()));
+        let data: Vec<u8> = (0..5 * (1 << 14) + 100).map(|i| (i / 1000) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let t = create::torrent(&path, Some(1 << 14), Version::V2).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(t.info.piece_count(), 6);
+        for (piece_i, piece) in data.chunks(1 << 14).enumerate() {
+            assert!(t.verify_piece(piece_i, piece));
+        }
+        assert!(!t.verify_piece(0, &data[1 << 14..2 << 14]));
+
+        // The piece layer, as a peer would get it, proves itself against the
+        // file's root.
+        let root = t
+            .info
+            .pieces_root(std::slice::from_ref(&t.info.name))
+            .unwrap();
+        let request = HashRequest {
+            pieces_root: root,
+            base_layer: 0,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
+        };
+        let tree = t.piece_tree(&root).unwrap();
+        assert_eq!(tree.root(), root);
+        let hashes = t.hashes(&tree, &request).unwrap();
+        let (hashes, uncles) = hashes.split_at(2);
+        assert_eq!(uncles.len(), 2);
+        assert_eq!(merkle::proof_root(hashes, 4, uncles), root);
+    }
+
+    #[test]
+    fn test_info_hash_of_received_metadata() {
+        let mut t = Torrent::for_test(4, &[4]);
+        // A `source` key, which `Info` has no field for.
+        let mut metadata = serde_bencode::to_bytes(&t.info).unwrap();
+        metadata.pop();
+        metadata.extend(b"6:source7:privatee");
+        t.info = serde_bencode::from_bytes(&metadata).unwrap();
+        assert_ne!(t.info_hash(), hash::sha1(&[&metadata]));
+
+        t.info_bytes = Some(metadata.clone());
+        assert_eq!(t.info_hash(), hash::sha1(&[&metadata]));
+        assert_eq!(t.info_hashes(), [hash::sha1(&[&metadata])]);
+    }
+
+    #[test]
+    fn test_info_hash_of_read_torrent() {
+        let t = create::torrent(Path::new("Cargo.toml"), Some(1 << 14), Version::V1).unwrap();
+        let mut info = serde_bencode::to_bytes(&t.info).unwrap();
+        info.pop();
+        info.extend(b"6:source3:XYZe");
+        let dot_torrent = [b"d4:info".as_slice(), &info, b"e"].concat();
+
+        let t = Torrent::from_bytes(&dot_torrent).unwrap();
+        assert_eq!(t.info_hash(), hash::sha1(&[&info]));
+    }
+
+    #[test]
+    fn test_paths_outside_the_output_are_rejected() {
+        let read = |name: &str, path: &[&str]| {
+            let mut t = Torrent::for_test(4, &[4, 4]);
+            t.info.name = name.to_string();
+            let Keys::MultiFile { files } = &mut t.info.keys else {
+                unreachable!("two files")
+            };
+            files[1].path = path.iter().map(|component| component.to_string()).collect();
+            Torrent::from_bytes(&serde_bencode::to_bytes(&t).unwrap())
+        };
+
+        assert!(read("test", &["dir", "b"]).is_ok());
+        for path in [
+            &["..", "..", ".bashrc"][..],
+            &["/etc", "passwd"],
+            &["dir/../../b"],
+            &["dir\\..\\..\\b"],
+            &[".", "b"],
+            &["", "b"],
+            &[],
+        ] {
+            assert!(read("test", path).is_err(), "{path:?}");
+        }
+        assert!(read("..", &["b"]).is_err());
+    }
+
+    #[test]
+    fn test_v2_piece_length_is_checked() {
+        let v2 = |plength: usize| {
+            let mut bytes = b"d4:infod9:file treed1:ad0:d6:lengthi1eeee".to_vec();
+            bytes.extend(b"12:meta versioni2e4:name1:a12:piece lengthi");
+            bytes.extend(plength.to_string().as_bytes());
+            bytes.extend(b"eee");
+            Torrent::from_bytes(&bytes)
+        };
+
+        for plength in [0, 1000, 1 << 13, 3 << 14] {
+            let e = v2(plength).unwrap_err();
+            assert!(e.to_string().contains("power of two ≥ 16 KiB"), "{e}");
+        }
+        assert!(v2(1 << 14).is_ok());
+        assert!(v2(1 << 20).is_ok());
+
+        let mut t = Torrent::for_test(4, &[4]);
+        t.info.plength = 0;
+        assert!(Torrent::from_bytes(&serde_bencode::to_bytes(&t).unwrap()).is_err());
+    }
+
+    /// Serves every connection a response with `head` for the headers after
+    /// the status line, and `body`, and returns the URL to get it from.
+    async fn serve(head: &str, body: Vec<u8>) -> String {
+        let response = format!("HTTP/1.1 200 OK\r\nConnection: close\r\n{head}\r\n");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/a.torrent", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                // The request is small, and read only to be done with it.
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let t = Torrent::for_test(4, &[4]);
+        let dot_torrent = serde_bencode::to_bytes(&t).unwrap();
+        let head = format!("Content-Length: {}\r\n", dot_torrent.len());
+        let fetched = Torrent::fetch(&serve(&head, dot_torrent).await)
+            .await
+            .unwrap();
+        assert_eq!(fetched.info_hash(), t.info_hash());
+
+        // A login page instead of the torrent.
+        let html = b"<html>log in first</html>".to_vec();
+        let url = serve("Content-Length: 25\r\n", html).await;
+        let e = Torrent::fetch(&url).await.unwrap_err();
+        assert!(
+            e.to_string().contains("did not return a bencoded torrent"),
+            "{e:#}"
+        );
+
+        // Too long, whether it says so up front or not.
+        let url = serve("Content-Length: 1000000000\r\n", Vec::new()).await;
+        let e = Torrent::fetch(&url).await.unwrap_err();
+        assert!(e.to_string().contains("longer than"), "{e:#}");
+        let endless = vec![b'd'; MAX_FETCH_LEN + 1];
+        let e = Torrent::fetch(&serve("", endless).await).await.unwrap_err();
+        assert!(e.to_string().contains("longer than"), "{e:#}");
+    }
+}
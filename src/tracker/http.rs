@@ -1,12 +1,16 @@
 use std::{
+    collections::BTreeMap,
     fmt,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
 use serde::{
-    de::{self, Visitor},
+    de::{self, DeserializeOwned, Visitor},
     Deserialize, Deserializer, Serialize, Serializer,
 };
+use serde_bytes::ByteBuf;
+
+use crate::peer;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Request<'caller> {
@@ -17,18 +21,28 @@ pub struct Request<'caller> {
     pub downloaded: usize,
     pub left: usize,
     pub compact: u8,
+    pub event: Option<&'static str>,
+    pub numwant: Option<u32>,
+    pub key: Option<u32>,
+    pub trackerid: Option<&'caller str>,
+    pub ip: Option<IpAddr>,
 }
 
 impl<'a> Request<'a> {
     pub fn new(info_hash: &'a [u8], left: usize) -> Self {
         Self {
             info_hash,
-            peer_id: b"00112233445566778899",
+            peer_id: peer::local_id(),
             port: 6881,
             uploaded: 0,
             downloaded: 0,
             left,
             compact: 1,
+            event: None,
+            numwant: None,
+            key: None,
+            trackerid: None,
+            ip: None,
         }
     }
 
@@ -58,65 +72,277 @@ impl<'a> Request<'a> {
         url.push_str(&self.left.to_string());
         url.push('&');
         url.push_str("compact=");
-        url.push_str(&(self.compact as u8).to_string());
+        url.push_str(&self.compact.to_string());
+        if let Some(event) = self.event {
+            url.push_str("&event=");
+            url.push_str(event);
+        }
+        if let Some(numwant) = self.numwant {
+            url.push_str("&numwant=");
+            url.push_str(&numwant.to_string());
+        }
+        if let Some(key) = self.key {
+            url.push_str(&format!("&key={key:08X}"));
+        }
+        if let Some(trackerid) = self.trackerid {
+            url.push_str("&trackerid=");
+            url.push_str(&urlencoding::encode(trackerid));
+        }
+        if let Some(ip) = self.ip {
+            url.push_str("&ip=");
+            url.push_str(&urlencoding::encode(&ip.to_string()));
+        }
 
         url
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Why an HTTP tracker request got no usable answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The tracker refused the request, e.g. because the torrent isn't
+    /// registered with it.
+    Failure(String),
+    /// The answer isn't the bencoded dictionary we asked for.
+    Malformed(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Failure(reason) => write!(f, "tracker failure: {reason}"),
+            Error::Malformed(e) => write!(f, "malformed tracker response: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A refusal, which replaces every other key of a response.
+#[derive(Deserialize)]
+struct Failure {
+    #[serde(rename = "failure reason")]
+    reason: String,
+}
+
+/// Parses a tracker response, announce or scrape, turning a `failure reason`
+/// into [`Error::Failure`].
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    if let Ok(failure) = serde_bencode::from_bytes::<Failure>(bytes) {
+        return Err(Error::Failure(failure.reason));
+    }
+
+    serde_bencode::from_bytes(bytes).map_err(|e| Error::Malformed(e.to_string()))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Response {
-    pub interval: u16,
-    pub peers: Peers,
+    pub interval: u32,
+
+    /// Something the tracker wants the user to know, though it did answer.
+    #[serde(rename = "warning message", default)]
+    pub warning_message: Option<String>,
+
+    /// To be sent back as `trackerid` in later announces.
+    #[serde(rename = "tracker id", default)]
+    pub tracker_id: Option<String>,
+
+    #[serde(default)]
+    pub peers: PeerList,
+
+    /// Compact IPv6 peers (BEP 7).
+    #[serde(default, deserialize_with = "Peers::deserialize_v6")]
+    pub peers6: Peers,
 }
 
 impl Response {
     pub fn new() -> Self {
-        Self {
-            interval: 0,
-            peers: Peers(Vec::new()),
+        Self::default()
+    }
+
+    /// The IPv4 and IPv6 peers together.
+    pub async fn into_peers(self) -> Vec<SocketAddr> {
+        let mut peers = self.peers.into_addrs().await;
+        peers.extend(self.peers6.0);
+        peers
+    }
+}
+
+/// The `peers` of an announce response, in whichever model the tracker
+/// chose; not every tracker honours `compact=1`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum PeerList {
+    Compact(Peers),
+    Dictionary(Vec<DictionaryPeer>),
+}
+
+impl Default for PeerList {
+    fn default() -> Self {
+        Self::Compact(Peers::default())
+    }
+}
+
+impl PeerList {
+    /// The peer addresses, with DNS names resolved; peers whose names don't
+    /// resolve are left out.
+    pub async fn into_addrs(self) -> Vec<SocketAddr> {
+        match self {
+            PeerList::Compact(peers) => peers.0,
+            PeerList::Dictionary(peers) => {
+                let mut addrs = Vec::with_capacity(peers.len());
+                for peer in peers {
+                    addrs.extend(peer.addr().await);
+                }
+                addrs
+            }
         }
     }
 }
 
+/// A peer of the original, non-compact peer list.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DictionaryPeer {
+    #[serde(rename = "peer id", default)]
+    pub peer_id: Option<ByteBuf>,
+    /// An IPv4 or IPv6 address, or a DNS name.
+    pub ip: String,
+    pub port: u16,
+}
+
+impl DictionaryPeer {
+    pub async fn addr(&self) -> Option<SocketAddr> {
+        if let Ok(ip) = self.ip.parse::<IpAddr>() {
+            return Some(SocketAddr::new(ip, self.port));
+        }
+
+        match tokio::net::lookup_host((self.ip.as_str(), self.port)).await {
+            Ok(mut addrs) => addrs.next(),
+            Err(e) => {
+                tracing::warn!(ip = self.ip, error = %e, "Cannot resolve peer");
+                None
+            }
+        }
+    }
+}
+
+/// A scrape of one or more torrents, sent to the tracker's scrape URL.
 #[derive(Debug, Clone)]
-pub struct Peers(pub Vec<SocketAddrV4>);
-struct PeersVisitor;
+pub struct ScrapeRequest<'caller> {
+    pub info_hashes: &'caller [[u8; 20]],
+}
+
+impl<'a> ScrapeRequest<'a> {
+    pub fn new(info_hashes: &'a [[u8; 20]]) -> Self {
+        Self { info_hashes }
+    }
+
+    /// The scrape URL that goes with `announce`, by the convention of
+    /// replacing `announce` in its last path segment with `scrape`; `None` if
+    /// the tracker doesn't follow it and so can't be scraped.
+    pub fn url(&self, announce: &str) -> Option<String> {
+        let (base, last) = announce.rsplit_once('/')?;
+        let rest = last.strip_prefix("announce")?;
+
+        let mut url = format!("{base}/scrape{rest}");
+        let mut separator = if rest.contains('?') { '&' } else { '?' };
+        for info_hash in self.info_hashes {
+            url.push(separator);
+            url.push_str("info_hash=");
+            url.push_str(&urlencoding::encode_binary(info_hash));
+            separator = '&';
+        }
+
+        Some(url)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ScrapeResponse {
+    /// Statistics keyed by info hash.
+    pub files: BTreeMap<ByteBuf, ScrapeFile>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct ScrapeFile {
+    /// Seeders.
+    pub complete: u32,
+    pub downloaded: u32,
+    /// Leechers.
+    pub incomplete: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Peers(pub Vec<SocketAddr>);
+
+/// Reads compact peers: 6 bytes each for IPv4, 18 bytes each for IPv6.
+struct PeersVisitor {
+    ipv6: bool,
+}
+
+impl PeersVisitor {
+    fn peer_len(&self) -> usize {
+        if self.ipv6 {
+            18
+        } else {
+            6
+        }
+    }
+}
 
 impl<'de> Visitor<'de> for PeersVisitor {
     type Value = Peers;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a byte string whose length is multiple of 6")
+        write!(
+            formatter,
+            "a byte string whose length is multiple of {}",
+            self.peer_len()
+        )
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: de::Error,
     {
-        if v.len() % 6 != 0 {
+        let peer_len = self.peer_len();
+        if !v.len().is_multiple_of(peer_len) {
             return Err(E::custom(format!("length is {}", v.len())));
         }
 
         Ok(Peers(
-            v.chunks_exact(6)
-                .map(|slice_6| {
-                    SocketAddrV4::new(
-                        Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
-                        u16::from_be_bytes([slice_6[4], slice_6[5]]),
-                    )
+            v.chunks_exact(peer_len)
+                .map(|peer| {
+                    let (ip, port) = peer.split_at(peer_len - 2);
+                    let port = u16::from_be_bytes([port[0], port[1]]);
+                    if self.ipv6 {
+                        let ip: [u8; 16] = ip.try_into().expect("chunk of 18");
+                        SocketAddr::from((Ipv6Addr::from(ip), port))
+                    } else {
+                        let ip: [u8; 4] = ip.try_into().expect("chunk of 6");
+                        SocketAddr::from((Ipv4Addr::from(ip), port))
+                    }
                 })
                 .collect(),
         ))
     }
 }
 
+impl Peers {
+    fn deserialize_v6<'de, D>(deserializer: D) -> Result<Peers, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(PeersVisitor { ipv6: true })
+    }
+}
+
 impl<'de> Deserialize<'de> for Peers {
     fn deserialize<D>(deserializer: D) -> Result<Peers, D::Error>
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_bytes(PeersVisitor)
+        deserializer.deserialize_bytes(PeersVisitor { ipv6: false })
     }
 }
 
@@ -127,7 +353,10 @@ impl Serialize for Peers {
     {
         let mut single_slice = Vec::with_capacity(6 * self.0.len());
         for peer in &self.0 {
-            single_slice.extend_from_slice(&peer.ip().octets());
+            match peer {
+                SocketAddr::V4(peer) => single_slice.extend_from_slice(&peer.ip().octets()),
+                SocketAddr::V6(peer) => single_slice.extend_from_slice(&peer.ip().octets()),
+            }
             single_slice.extend_from_slice(&peer.port().to_be_bytes());
         }
         serializer.serialize_bytes(&single_slice)
@@ -136,35 +365,30 @@ impl Serialize for Peers {
 
 #[cfg(test)]
 mod tests {
-    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::net::{Ipv4Addr, SocketAddr};
 
     use actix_web::{test, web, App, HttpResponse, Responder};
 
     use crate::{
-        torrent::{Hashes, Info, Keys, Torrent},
+        torrent::{Hashes, Torrent},
         tracker,
     };
 
     #[test]
     async fn test_build_tracker_url() {
-        let t = Torrent {
-            announce: "http://bttracker.debian.org:6969/announce".to_string(),
-            info: Info {
-                name: "debian-10.2.0-amd64-netinst.iso".to_string(),
-                plength: 262144,
-                pieces: Hashes(vec![
-                    [
-                        49, 50, 51, 52, 53, 54, 55, 56, 57, 48, 97, 98, 99, 100, 101, 102, 103,
-                        104, 105, 106,
-                    ],
-                    [
-                        97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 49, 50, 51, 52, 53, 54, 55,
-                        56, 57, 48,
-                    ],
-                ]),
-                keys: Keys::SingleFile { length: 351272960 },
-            },
-        };
+        let mut t = Torrent::for_test(262144, &[351272960]);
+        t.announce = Some("http://bttracker.debian.org:6969/announce".to_string());
+        t.info.name = "debian-10.2.0-amd64-netinst.iso".to_string();
+        t.info.pieces = Hashes(vec![
+            [
+                49, 50, 51, 52, 53, 54, 55, 56, 57, 48, 97, 98, 99, 100, 101, 102, 103, 104, 105,
+                106,
+            ],
+            [
+                97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 49, 50, 51, 52, 53, 54, 55, 56, 57,
+                48,
+            ],
+        ]);
 
         let info_hash = vec![
             216, 247, 57, 206, 195, 40, 149, 108, 204, 91, 191, 31, 134, 217, 253, 207, 219, 168,
@@ -176,9 +400,18 @@ mod tests {
         let _port = 6882_u16;
         let length = t.length();
 
-        let tracker_req = tracker::http::Request::new(&info_hash, length);
+        let mut tracker_req = tracker::http::Request::new(&info_hash, length);
+        tracker_req.peer_id = b"00112233445566778899";
+
+        assert_eq!(tracker_req.url(t.announce.as_deref().unwrap()), "http://bttracker.debian.org:6969/announce?info_hash=%D8%F79%CE%C3%28%95l%CC%5B%BF%1F%86%D9%FD%CF%DB%A8%CE%B6&peer_id=00112233445566778899&port=6881&uploaded=0&downloaded=0&left=351272960&compact=1");
 
-        assert_eq!(tracker_req.url(&t.announce), "http://bttracker.debian.org:6969/announce?info_hash=%D8%F79%CE%C3%28%95l%CC%5B%BF%1F%86%D9%FD%CF%DB%A8%CE%B6&peer_id=00112233445566778899&port=6881&uploaded=0&downloaded=0&left=351272960&compact=1");
+        tracker_req.numwant = Some(50);
+        tracker_req.key = Some(0xBEEF);
+        tracker_req.trackerid = Some("a b");
+        tracker_req.ip = Some("2001:db8::1".parse().unwrap());
+        assert!(tracker_req
+            .url("http://example.com/announce")
+            .ends_with("&compact=1&numwant=50&key=0000BEEF&trackerid=a%20b&ip=2001%3Adb8%3A%3A1"));
     }
 
     async fn mock_response() -> impl Responder {
@@ -194,10 +427,10 @@ mod tests {
 
     #[actix_rt::test]
     async fn test_request_peers() {
-        let mut app = test::init_service(App::new().route("/", web::get().to(mock_response))).await;
+        let app = test::init_service(App::new().route("/", web::get().to(mock_response))).await;
 
         let req = test::TestRequest::get().uri("/").to_request();
-        let res = test::call_service(&mut app, req).await;
+        let res = test::call_service(&app, req).await;
         let result = test::read_body(res).await;
 
         eprintln!("{:?}", result);
@@ -205,10 +438,105 @@ mod tests {
         let tracker_res: tracker::http::Response = serde_bencode::from_bytes(&result).unwrap();
 
         let expected = vec![
-            SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 123), 6881),
-            SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 6889),
+            SocketAddr::from((Ipv4Addr::new(192, 0, 2, 123), 6881)),
+            SocketAddr::from((Ipv4Addr::new(127, 0, 0, 1), 6889)),
         ];
 
-        assert_eq!(tracker_res.peers.0, expected);
+        assert_eq!(tracker_res.into_peers().await, expected);
+    }
+
+    #[test]
+    async fn test_parse_dictionary_peers() {
+        let mut body: Vec<u8> = Vec::new();
+        body.extend(b"d8:intervali900e5:peersl");
+        body.extend(b"d2:ip11:192.0.2.1237:peer id20:");
+        body.extend([0xAB; 20]);
+        body.extend(b"4:porti6881ee");
+        body.extend(b"d2:ip11:2001:db8::14:porti6889ee");
+        body.extend(b"ee");
+
+        let tracker_res: tracker::http::Response = serde_bencode::from_bytes(&body).unwrap();
+
+        assert_eq!(
+            tracker_res.into_peers().await,
+            vec![
+                "192.0.2.123:6881".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:6889".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    async fn test_scrape_url() {
+        let info_hashes = [[0xAA; 20]];
+        let req = tracker::http::ScrapeRequest::new(&info_hashes);
+        let encoded = "%AA".repeat(20);
+
+        assert_eq!(
+            req.url("http://example.com/announce"),
+            Some(format!("http://example.com/scrape?info_hash={encoded}"))
+        );
+        assert_eq!(
+            req.url("http://example.com/x/announce.php?passkey=1"),
+            Some(format!(
+                "http://example.com/x/scrape.php?passkey=1&info_hash={encoded}"
+            ))
+        );
+        assert_eq!(req.url("http://example.com/a"), None);
+        assert_eq!(req.url("http://example.com/announce/x"), None);
+
+        let mut body: Vec<u8> = b"d5:filesd20:".to_vec();
+        body.extend([0xAA; 20]);
+        body.extend(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let res: tracker::http::ScrapeResponse = serde_bencode::from_bytes(&body).unwrap();
+        assert_eq!(
+            res.files[serde_bytes::Bytes::new(&info_hashes[0])].complete,
+            5
+        );
+    }
+
+    #[test]
+    async fn test_parse_failure() {
+        let body = b"d14:failure reason22:torrent not registerede";
+        let res = tracker::http::from_bytes::<tracker::http::Response>(body);
+        assert_eq!(
+            res.unwrap_err(),
+            tracker::http::Error::Failure("torrent not registered".to_string())
+        );
+
+        let body = b"d8:intervali900e5:peers0:15:warning message7:go awaye";
+        let res: tracker::http::Response = tracker::http::from_bytes(body).unwrap();
+        assert_eq!(res.warning_message.as_deref(), Some("go away"));
+
+        assert!(matches!(
+            tracker::http::from_bytes::<tracker::http::Response>(b"<html>"),
+            Err(tracker::http::Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    async fn test_parse_peers6() {
+        let mut body: Vec<u8> = Vec::new();
+        body.extend(b"d8:intervali900e5:peers6:");
+        body.extend([192, 0, 2, 123, 0x1A, 0xE1]);
+        body.extend(b"6:peers618:");
+        body.extend(
+            "2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        body.extend([0x1A, 0xE1]);
+        body.extend(b"e");
+
+        let tracker_res: tracker::http::Response = serde_bencode::from_bytes(&body).unwrap();
+
+        assert_eq!(
+            tracker_res.into_peers().await,
+            vec![
+                "192.0.2.123:6881".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+            ]
+        );
     }
 }
@@ -1,11 +1,102 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::{
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context};
+use rand::seq::SliceRandom;
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
 
 pub mod http;
 pub mod udp;
+pub mod ws;
 
-pub struct Tracker {
+pub struct Tracker {}
+
+/// The trackers of a torrent, grouped in tiers (BEP 12).
+///
+/// Tiers are tried in order, and the trackers within a tier in random order.
+/// A tracker that answers moves to the front of its tier, so it is tried first
+/// next time.
+#[derive(Debug, Clone, Default)]
+pub struct Tiers {
+    tiers: Vec<Vec<String>>,
+
+    /// Whether these are the trackers of a private torrent (BEP 27), which
+    /// must get its peers from a single tracker and nowhere else.
+    private: bool,
+
+    /// The `ws(s)://` trackers of WebTorrent, which relay WebRTC offers
+    /// instead of handing out addresses; kept out of `tiers`.
+    websockets: Vec<String>,
+}
+
+impl Tiers {
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut websockets = Vec::new();
+        let mut tiers: Vec<Vec<String>> = tiers
+            .into_iter()
+            .map(|tier| {
+                let (ws, tier): (Vec<_>, Vec<_>) =
+                    tier.into_iter().partition(|url| is_websocket(url));
+                websockets.extend(ws);
+                tier
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rand::thread_rng());
+        }
+        websockets.dedup();
+
+        Self {
+            tiers,
+            private: false,
+            websockets,
+        }
+    }
+
+    /// Marks the trackers as those of a private torrent.
+    pub fn private(self, private: bool) -> Self {
+        Self { private, ..self }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiers.len()
+    }
+
+    /// The trackers of tier `tier_i`, in the order to try them.
+    pub fn tier(&self, tier_i: usize) -> &[String] {
+        &self.tiers[tier_i]
+    }
+
+    /// Moves tracker `i` of tier `tier` to the front of its tier.
+    pub fn promote(&mut self, tier: usize, i: usize) {
+        self.tiers[tier][..=i].rotate_right(1);
+    }
+
+    /// The WebSocket trackers, none for a private torrent.
+    pub fn websockets(&self) -> &[String] {
+        if self.private {
+            &[]
+        } else {
+            &self.websockets
+        }
+    }
+}
+
+/// Whether `url` is that of a WebSocket tracker.
+pub fn is_websocket(url: &str) -> bool {
+    url.starts_with("ws://") || url.starts_with("wss://")
 }
 
 pub enum Addr {
@@ -16,20 +107,29 @@ pub enum Addr {
 pub fn get_addr(announce: &str) -> anyhow::Result<Addr> {
     if let Some((protocol, addr)) = announce.split_once("://") {
         match protocol {
-            "http" => Ok(Addr::Http(
-                announce
-                    .to_socket_addrs()
-                    .context("parse socket addr")?
-                    .next()
-                    .unwrap(),
-            )),
+            "http" | "https" => {
+                let host = addr.split('/').next().unwrap_or(addr);
+                let default_port = if protocol == "http" { 80 } else { 443 };
+                let addr = if host.contains(':') {
+                    host.to_socket_addrs()
+                } else {
+                    (host, default_port).to_socket_addrs()
+                };
+
+                Ok(Addr::Http(
+                    addr.context("parse socket addr")?
+                        .next()
+                        .ok_or_else(|| anyhow!("cannot resolve {host}"))?,
+                ))
+            }
             "udp" => {
                 if let Some((url, _)) = addr.split_once("/announce") {
+                    // Whichever of the A and AAAA records comes first.
                     Ok(Addr::Udp(
                         url.to_socket_addrs()
                             .context("parse socket addr")?
                             .next()
-                            .unwrap(),
+                            .ok_or_else(|| anyhow!("cannot resolve {url}"))?,
                     ))
                 } else {
                     Err(anyhow!("cannot find announce"))
@@ -41,3 +141,431 @@ pub fn get_addr(announce: &str) -> anyhow::Result<Addr> {
         Err(anyhow!("cannot find announce"))
     }
 }
+
+/// Why we announce, if for anything more than a regular update.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Event {
+    #[default]
+    None,
+    Completed,
+    Started,
+    Stopped,
+}
+
+impl Event {
+    /// The `event` parameter of HTTP announces, which is left out for
+    /// [`Event::None`].
+    pub fn as_str(self) -> Option<&'static str> {
+        match self {
+            Event::None => None,
+            Event::Completed => Some("completed"),
+            Event::Started => Some("started"),
+            Event::Stopped => Some("stopped"),
+        }
+    }
+
+    /// The `event` field of UDP announces.
+    pub fn as_u32(self) -> u32 {
+        match self {
+            Event::None => 0,
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        }
+    }
+}
+
+/// What we tell a tracker when announcing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub downloaded: usize,
+    pub uploaded: usize,
+    pub left: usize,
+    pub event: Event,
+}
+
+impl Announce {
+    pub fn new(info_hash: [u8; 20], left: usize) -> Self {
+        Self {
+            info_hash,
+            downloaded: 0,
+            uploaded: 0,
+            left,
+            event: Event::None,
+        }
+    }
+}
+
+/// Announce parameters that don't depend on the torrent's progress.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// How many peers to ask for; the tracker decides when `None`.
+    pub numwant: Option<u32>,
+    /// Lets trackers recognise us after our IP changes, so it must stay the
+    /// same for as long as we run.
+    pub key: u32,
+    /// The address to advertise instead of the one we announce from.
+    pub ip: Option<IpAddr>,
+    /// What the tracker asked us to send back with every announce, if it did.
+    pub tracker_id: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            numwant: None,
+            key: rand::random(),
+            ip: None,
+            tracker_id: None,
+        }
+    }
+}
+
+/// What a tracker answers to an announce.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Announced {
+    pub peers: Vec<SocketAddr>,
+
+    /// How long to wait before announcing again.
+    pub interval: Duration,
+
+    /// Sent back in [`Options::tracker_id`] from now on.
+    pub tracker_id: Option<String>,
+}
+
+/// Announces to the tracker behind `url` and returns the peers it knows about.
+pub async fn announce(
+    url: &str,
+    announce: &Announce,
+    options: &Options,
+) -> anyhow::Result<Announced> {
+    match get_addr(url)? {
+        Addr::Udp(addr) => {
+            let socket = bind_udp(addr).await?;
+
+            announce_udp(&socket, addr, announce, options).await
+        }
+        Addr::Http(_) => announce_http(url, announce, options).await,
+    }
+}
+
+pub async fn announce_http(
+    url: &str,
+    announce: &Announce,
+    options: &Options,
+) -> anyhow::Result<Announced> {
+    let mut request = http::Request::new(&announce.info_hash, announce.left);
+    request.downloaded = announce.downloaded;
+    request.uploaded = announce.uploaded;
+    request.event = announce.event.as_str();
+    request.numwant = options.numwant;
+    request.key = Some(options.key);
+    request.ip = options.ip;
+    request.trackerid = options.tracker_id.as_deref();
+
+    let res = reqwest::get(request.url(url)).await?;
+    let res: http::Response = http::from_bytes(&res.bytes().await?)?;
+    if let Some(warning) = &res.warning_message {
+        warn!(url, warning, "Tracker warns");
+    }
+
+    Ok(Announced {
+        interval: Duration::from_secs(res.interval.into()),
+        tracker_id: res.tracker_id.clone(),
+        peers: res.into_peers().await,
+    })
+}
+
+/// Binds a UDP socket of the same address family as the tracker at `url`.
+pub async fn bind_udp(url: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let local = match url {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+
+    UdpSocket::bind(local)
+        .await
+        .with_context(|| format!("bind to {local}"))
+}
+
+/// Announces to the UDP tracker at `url` (BEP 15) over `socket`, which may be
+/// shared with other announces as long as they don't run concurrently. UDP
+/// trackers have no tracker ID, and take an IPv4 address to advertise only.
+pub async fn announce_udp(
+    socket: &UdpSocket,
+    url: SocketAddr,
+    announce: &Announce,
+    options: &Options,
+) -> anyhow::Result<Announced> {
+    let connection_id = connect_udp(socket, url).await?;
+
+    let mut announce_req =
+        udp::AnnounceRequest::new(connection_id, rand::random(), announce.info_hash);
+    announce_req.downloaded = announce.downloaded as u64;
+    announce_req.left = announce.left as u64;
+    announce_req.uploaded = announce.uploaded as u64;
+    announce_req.event = announce.event.as_u32();
+    announce_req.key = options.key;
+    if let Some(numwant) = options.numwant {
+        announce_req.num_want = numwant.try_into().unwrap_or(i32::MAX);
+    }
+    if let Some(IpAddr::V4(ip)) = options.ip {
+        announce_req.ip_address = ip.into();
+    }
+
+    match send_udp(socket, url, announce_req.into()).await? {
+        udp::Response::Announce(announce_res) => Ok(Announced {
+            peers: announce_res.peers,
+            interval: Duration::from_secs(announce_res.interval.into()),
+            tracker_id: None,
+        }),
+        res => Err(anyhow!("unexpected response to an announce: {res:?}")),
+    }
+}
+
+/// Asks the tracker behind `announce` how many peers are in the swarm of
+/// `info_hash`.
+pub async fn scrape(
+    announce: &str,
+    info_hash: [u8; 20],
+) -> anyhow::Result<udp::TorrentScrapeStatistics> {
+    let stats = scrape_many(announce, &[info_hash]).await?;
+    stats
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("tracker sent no statistics"))
+}
+
+/// Scrapes several torrents tracked by the same tracker at once; the
+/// statistics are in the order of `info_hashes`.
+pub async fn scrape_many(
+    announce: &str,
+    info_hashes: &[udp::InfoHash],
+) -> anyhow::Result<Vec<udp::TorrentScrapeStatistics>> {
+    match get_addr(announce)? {
+        Addr::Udp(url) => {
+            let socket = bind_udp(url).await?;
+
+            udp::scrape_many(&socket, url, info_hashes).await
+        }
+        Addr::Http(_) => {
+            let request = http::ScrapeRequest::new(info_hashes);
+            let url = request
+                .url(announce)
+                .ok_or_else(|| anyhow!("{announce} does not support scraping"))?;
+            let res = reqwest::get(url).await?;
+            let res: http::ScrapeResponse = http::from_bytes(&res.bytes().await?)?;
+
+            // Torrents the tracker doesn't list have no peers.
+            Ok(info_hashes
+                .iter()
+                .map(|info_hash| {
+                    let file = res
+                        .files
+                        .get(serde_bytes::Bytes::new(info_hash))
+                        .copied()
+                        .unwrap_or_default();
+                    udp::TorrentScrapeStatistics {
+                        seeders: file.complete,
+                        completed: file.downloaded,
+                        leechers: file.incomplete,
+                    }
+                })
+                .collect())
+        }
+    }
+}
+
+/// Obtains a connection ID, which the tracker requires in every other request.
+pub(crate) async fn connect_udp(socket: &UdpSocket, url: SocketAddr) -> anyhow::Result<u64> {
+    let connect_req = udp::ConnectRequest::new(rand::random());
+
+    match send_udp(socket, url, connect_req.into()).await? {
+        udp::Response::Connect(connect_res) => Ok(connect_res.connection_id.0),
+        res => Err(anyhow!("unexpected response to a connect: {res:?}")),
+    }
+}
+
+/// The largest UDP payload, so that a response is never cut short, whatever
+/// number of peers we asked for.
+const MAX_UDP_RESPONSE: usize = 65_536;
+
+/// Sends `request` to the tracker and waits for the response to it,
+/// retransmitting as BEP 15 prescribes.
+pub(crate) async fn send_udp(
+    socket: &UdpSocket,
+    url: SocketAddr,
+    request: udp::Request,
+) -> anyhow::Result<udp::Response> {
+    let transaction_id = request.transaction_id();
+    let mut buffer = Vec::new();
+    request.write(&mut buffer)?;
+
+    let mut response: Vec<u8> = vec![0; MAX_UDP_RESPONSE];
+
+    // Retransmit with a timeout of 15 * 2 ^ n seconds
+    let mut attempts = 0;
+    let max_retries = 8;
+    let mut delay = 15;
+    while attempts <= max_retries {
+        if let Err(e) = socket.send_to(&buffer, &url).await {
+            debug!(attempts, error = %e, "Failed to send request");
+        }
+
+        // Late answers to earlier transactions share the socket; they are
+        // skipped rather than failing this one.
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(delay);
+        while let Some(len) = recv_from(socket, url, &mut response, deadline).await {
+            match udp::Response::read(&response[..len], url.is_ipv6()) {
+                Ok(res) if res.transaction_id() == transaction_id => {
+                    return match res {
+                        udp::Response::Error(error_res) => {
+                            Err(anyhow!("tracker error: {}", error_res.message))
+                        }
+                        res => Ok(res),
+                    };
+                }
+                Ok(res) => {
+                    debug!(
+                        transaction_id = res.transaction_id().0,
+                        "Ignoring response to another transaction"
+                    );
+                }
+                Err(e) => debug!(error = %e, "Ignoring malformed response"),
+            }
+        }
+
+        attempts += 1;
+
+        delay *= 2;
+    }
+
+    Err(anyhow!("max retransmission reached"))
+}
+
+/// Receives the next datagram from `from`, dropping anything else that
+/// arrives on the socket in the meantime.
+async fn recv_from(
+    socket: &UdpSocket,
+    from: SocketAddr,
+    buf: &mut [u8],
+    deadline: tokio::time::Instant,
+) -> Option<usize> {
+    loop {
+        match tokio::time::timeout_at(deadline, socket.recv_from(buf)).await {
+            Ok(Ok((len, addr))) if addr == from => return Some(len),
+            Ok(Ok(_)) => continue,
+            Ok(Err(e)) => {
+                debug!(error = %e, "Failed to receive response");
+                return None;
+            }
+            Err(_) => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::{send_udp, udp, Tiers};
+
+    #[test]
+    fn test_promote_within_tier() {
+        let mut tiers = Tiers {
+            tiers: vec![
+                vec!["a".to_string(), "b".to_string(), "c".to_string()],
+                vec!["d".to_string()],
+            ],
+            private: false,
+            websockets: Vec::new(),
+        };
+        tiers.promote(0, 2);
+
+        assert_eq!(tiers.tier(0), ["c", "a", "b"]);
+        assert_eq!(tiers.tier(1), ["d"]);
+    }
+
+    #[test]
+    fn test_websocket_trackers_are_kept_apart() {
+        let tiers = Tiers::new(vec![
+            vec!["wss://tracker.example".to_string()],
+            vec![
+                "udp://tracker.example:6969/announce".to_string(),
+                "ws://tracker.example".to_string(),
+            ],
+        ]);
+
+        assert_eq!(tiers.len(), 1);
+        assert_eq!(tiers.tier(0), ["udp://tracker.example:6969/announce"]);
+        assert_eq!(
+            tiers.websockets(),
+            ["wss://tracker.example", "ws://tracker.example"]
+        );
+        assert!(tiers.private(true).websockets().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_udp_skips_other_transactions() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = tracker.local_addr().unwrap();
+
+        let responder = tokio::spawn(async move {
+            let mut buf = [0; 16];
+            let (_, from) = tracker.recv_from(&mut buf).await.unwrap();
+            let reply = |transaction_id: u32| {
+                let mut reply = vec![0; 4];
+                reply.extend(transaction_id.to_be_bytes());
+                reply.extend(42_u64.to_be_bytes());
+                reply
+            };
+            // A late reply to another transaction, then garbage, then ours.
+            tracker.send_to(&reply(6), from).await.unwrap();
+            tracker.send_to(&[1, 2, 3], from).await.unwrap();
+            tracker.send_to(&reply(7), from).await.unwrap();
+        });
+
+        let res = send_udp(&socket, url, udp::ConnectRequest::new(7).into())
+            .await
+            .unwrap();
+        responder.await.unwrap();
+        let udp::Response::Connect(res) = res else {
+            panic!("expected a connect response");
+        };
+        assert_eq!(res.transaction_id.0, 7);
+        assert_eq!(res.connection_id.0, 42);
+    }
+
+    #[tokio::test]
+    async fn test_send_udp_receives_many_peers() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = tracker.local_addr().unwrap();
+
+        // Far more peers than fit in a single Ethernet frame.
+        const PEERS: u16 = 1000;
+        let responder = tokio::spawn(async move {
+            let mut buf = [0; 128];
+            let (_, from) = tracker.recv_from(&mut buf).await.unwrap();
+            let mut reply = 1_u32.to_be_bytes().to_vec();
+            reply.extend(9_u32.to_be_bytes());
+            reply.extend([0; 12]);
+            for port in 1..=PEERS {
+                reply.extend([10, 0, 0, 1]);
+                reply.extend(port.to_be_bytes());
+            }
+            tracker.send_to(&reply, from).await.unwrap();
+        });
+
+        let request = udp::AnnounceRequest::new(1, 9, [0; 20]);
+        let res = send_udp(&socket, url, request.into()).await.unwrap();
+        responder.await.unwrap();
+        let udp::Response::Announce(res) = res else {
+            panic!("expected an announce response");
+        };
+        assert_eq!(res.peers.len(), PEERS as usize);
+        assert_eq!(res.peers.last().unwrap().port(), PEERS);
+    }
+}
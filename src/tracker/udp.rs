@@ -1,16 +1,22 @@
 use std::{
     borrow::Cow,
-    io::{self, Cursor, Read, Write},
-    net::{Ipv4Addr, SocketAddrV4},
+    io::{self, Cursor, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
 use serde::Deserialize;
+use tokio::net::UdpSocket;
 
-use crate::torrent::Hashes;
+use crate::{peer, torrent::Hashes};
 
 const PROTOCOL_IDENTIFIER: u64 = 0x0417_2710_1980;
 
+/// The most info hashes a single scrape request may carry.
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+pub type InfoHash = [u8; 20];
+
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Deserialize)]
 pub struct TransactionId(pub u32);
 
@@ -56,7 +62,7 @@ impl ConnectRequest {
 /// 64      64-bit integer  left
 /// 72      64-bit integer  uploaded
 /// 80      32-bit integer  event           0 // 0: none; 1: completed; 2: started; 3: stopped
-/// 84      32-bit integer  IP address      0 // default
+/// 84      32-bit integer  IP address      0 // default; always 0 over IPv6
 /// 88      32-bit integer  key
 /// 92      32-bit integer  num_want        -1 // default
 /// 96      16-bit integer  port
@@ -83,7 +89,7 @@ impl AnnounceRequest {
             connection_id: ConnectionId(connection_id),
             transaction_id: TransactionId(transaction_id),
             info_hash,
-            peer_id: *b"00112233445566778899",
+            peer_id: *peer::local_id(),
             downloaded: 0,
             left: 0,
             uploaded: 0,
@@ -96,6 +102,12 @@ impl AnnounceRequest {
     }
 }
 
+/// Offset          Size            Name            Value
+/// 0               64-bit integer  connection_id
+/// 8               32-bit integer  action          2 // scrape
+/// 12              32-bit integer  transaction_id
+/// 16 + 20 * n     20-byte string  info_hash
+/// 16 + 20 * N
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct ScrapeRequest {
     pub connection_id: ConnectionId,
@@ -103,6 +115,16 @@ pub struct ScrapeRequest {
     pub info_hashes: Hashes,
 }
 
+impl ScrapeRequest {
+    pub fn new(connection_id: u64, transaction_id: u32, info_hashes: Vec<InfoHash>) -> Self {
+        Self {
+            connection_id: ConnectionId(connection_id),
+            transaction_id: TransactionId(transaction_id),
+            info_hashes: Hashes(info_hashes),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Request {
     Connect(ConnectRequest),
@@ -129,6 +151,14 @@ impl From<ScrapeRequest> for Request {
 }
 
 impl Request {
+    pub fn transaction_id(&self) -> TransactionId {
+        match self {
+            Request::Connect(r) => r.transaction_id,
+            Request::Announce(r) => r.transaction_id,
+            Request::Scrape(r) => r.transaction_id,
+        }
+    }
+
     pub fn write(self, bytes: &mut impl Write) -> Result<(), io::Error> {
         match self {
             Request::Connect(r) => {
@@ -147,13 +177,20 @@ impl Request {
                 bytes.write_u64::<NetworkEndian>(r.downloaded)?;
                 bytes.write_u64::<NetworkEndian>(r.left)?;
                 bytes.write_u64::<NetworkEndian>(r.uploaded)?;
-                bytes.write_u32::<NetworkEndian>(0)?;
-                bytes.write_u32::<NetworkEndian>(0)?;
+                bytes.write_u32::<NetworkEndian>(r.event)?;
+                bytes.write_u32::<NetworkEndian>(r.ip_address)?;
                 bytes.write_u32::<NetworkEndian>(r.key)?;
                 bytes.write_i32::<NetworkEndian>(r.num_want)?;
                 bytes.write_u16::<NetworkEndian>(r.port)?;
             }
             Request::Scrape(r) => {
+                if r.info_hashes.0.len() > MAX_SCRAPE_HASHES {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("more than {MAX_SCRAPE_HASHES} info hashes"),
+                    ));
+                }
+
                 bytes.write_u64::<NetworkEndian>(r.connection_id.0)?;
                 bytes.write_u32::<NetworkEndian>(2)?;
                 bytes.write_u32::<NetworkEndian>(r.transaction_id.0)?;
@@ -188,13 +225,16 @@ pub struct ConnectResponse {
 /// 20 + 6 * n  32-bit integer  IP address
 /// 24 + 6 * n  16-bit integer  TCP port
 /// 20 + 6 * N
+///
+/// Over IPv6 (BEP 15's IPv6 extension) each peer is a 16-byte address followed
+/// by the port, 18 bytes in total.
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct AnnounceResponse {
     pub transaction_id: TransactionId,
     pub interval: u32,
     pub leechers: u32,
     pub seeders: u32,
-    pub peers: Vec<SocketAddrV4>,
+    pub peers: Vec<SocketAddr>,
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -218,7 +258,18 @@ pub enum Response {
 }
 
 impl Response {
-    pub fn read(bytes: &[u8]) -> Result<Self, io::Error> {
+    pub fn transaction_id(&self) -> TransactionId {
+        match self {
+            Response::Connect(r) => r.transaction_id,
+            Response::Announce(r) => r.transaction_id,
+            Response::Scrape(r) => r.transaction_id,
+            Response::Error(r) => r.transaction_id,
+        }
+    }
+
+    /// Parses a response received over IPv6 if `ipv6` is set, which changes
+    /// the size of the peers in announce responses.
+    pub fn read(bytes: &[u8], ipv6: bool) -> Result<Self, io::Error> {
         let mut cursor = Cursor::new(bytes);
         let action = cursor.read_u32::<NetworkEndian>()?;
 
@@ -239,20 +290,28 @@ impl Response {
                 let interval = cursor.read_u32::<NetworkEndian>()?;
                 let leechers = cursor.read_u32::<NetworkEndian>()?;
                 let seeders = cursor.read_u32::<NetworkEndian>()?;
-                let mut peers = Vec::new();
-                loop {
-                    let mut buf = [0; 6];
-                    match cursor.read_exact(&mut buf) {
-                        Ok(_) => {
-                            let peer = SocketAddrV4::new(
-                                Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]),
-                                u16::from_be_bytes([buf[4], buf[5]]),
-                            );
-                            peers.push(peer);
-                        }
-                        Err(_) => break,
-                    }
-                }
+                let position = cursor.position() as usize;
+                let compact = &cursor.into_inner()[position..];
+
+                let peers = if ipv6 {
+                    compact
+                        .chunks_exact(18)
+                        .map(|peer| {
+                            let ip: [u8; 16] = peer[..16].try_into().expect("chunk of 18");
+                            let port = u16::from_be_bytes([peer[16], peer[17]]);
+                            SocketAddr::from((Ipv6Addr::from(ip), port))
+                        })
+                        .collect()
+                } else {
+                    compact
+                        .chunks_exact(6)
+                        .map(|peer| {
+                            let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+                            let port = u16::from_be_bytes([peer[4], peer[5]]);
+                            SocketAddr::from((ip, port))
+                        })
+                        .collect()
+                };
 
                 Ok(Self::Announce(AnnounceResponse {
                     transaction_id,
@@ -303,7 +362,106 @@ impl Response {
                         .into(),
                 }))
             }
-            op => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{op}"))),
+            op => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{op}"))),
         }
     }
 }
+
+/// Scrapes the tracker at `url` for every torrent in `info_hashes`, sending
+/// up to [`MAX_SCRAPE_HASHES`] of them per request. The statistics are in the
+/// order of `info_hashes`.
+pub async fn scrape_many(
+    socket: &UdpSocket,
+    url: SocketAddr,
+    info_hashes: &[InfoHash],
+) -> anyhow::Result<Vec<TorrentScrapeStatistics>> {
+    let connection_id = super::connect_udp(socket, url).await?;
+
+    let mut stats = Vec::with_capacity(info_hashes.len());
+    for batch in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+        let scrape_req = ScrapeRequest::new(connection_id, rand::random(), batch.to_vec());
+        match super::send_udp(socket, url, scrape_req.into()).await? {
+            Response::Scrape(scrape_res) => {
+                anyhow::ensure!(
+                    scrape_res.torrent_stats.len() == batch.len(),
+                    "tracker sent statistics for {} of {} torrents",
+                    scrape_res.torrent_stats.len(),
+                    batch.len()
+                );
+                stats.extend(scrape_res.torrent_stats);
+            }
+            res => anyhow::bail!("unexpected response to a scrape: {res:?}"),
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Answers connects and announces on `socket` like a tracker with `peer` as
+/// the only one, and hands the `event`, `downloaded` and `left` fields of each
+/// announce to `announces`.
+#[cfg(test)]
+pub(crate) async fn serve(
+    socket: UdpSocket,
+    peer: [u8; 6],
+    announces: tokio::sync::mpsc::UnboundedSender<(u32, u64, u64)>,
+) {
+    let mut buf = [0; 128];
+    while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+        // A connect request is 16 bytes; the action goes first.
+        let connect = len == 16;
+        let mut reply = u32::from(!connect).to_be_bytes().to_vec();
+        reply.extend(&buf[12..16]);
+        if connect {
+            reply.extend(42_u64.to_be_bytes());
+        } else {
+            reply.extend([0, 0, 0, 60, 0, 0, 0, 0, 0, 0, 0, 1]);
+            reply.extend(peer);
+            let field =
+                |at: usize| u64::from_be_bytes(buf[at..at + 8].try_into().expect("8 bytes"));
+            let event = u32::from_be_bytes(buf[80..84].try_into().expect("4 bytes"));
+            let _ = announces.send((event, field(56), field(64)));
+        }
+        let _ = socket.send_to(&reply, from).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{Request, Response, ScrapeRequest, MAX_SCRAPE_HASHES};
+
+    #[test]
+    fn test_scrape_request_limit() {
+        let full = ScrapeRequest::new(1, 2, vec![[0; 20]; MAX_SCRAPE_HASHES]);
+        let mut bytes = Vec::new();
+        Request::from(full).write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 16 + 20 * MAX_SCRAPE_HASHES);
+
+        let too_many = ScrapeRequest::new(1, 2, vec![[0; 20]; MAX_SCRAPE_HASHES + 1]);
+        assert!(Request::from(too_many).write(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_read_ipv6_announce() {
+        let mut bytes = vec![0, 0, 0, 1, 0, 0, 0, 7];
+        bytes.extend([0, 0, 7, 8, 0, 0, 0, 1, 0, 0, 0, 2]);
+        bytes.extend(
+            "2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        bytes.extend(6881u16.to_be_bytes());
+
+        let Response::Announce(announce) = Response::read(&bytes, true).unwrap() else {
+            panic!("not an announce response");
+        };
+        assert_eq!(announce.seeders, 2);
+        assert_eq!(
+            announce.peers,
+            vec!["[2001:db8::1]:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+}
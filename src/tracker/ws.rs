@@ -0,0 +1,199 @@
+use std::fmt;
+
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+#[cfg(feature = "webtorrent")]
+use {
+    anyhow::{anyhow, Context},
+    futures_util::{SinkExt, StreamExt},
+    tokio::net::TcpStream,
+    tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream},
+    tracing::debug,
+};
+
+/// A 20-byte id as WebTorrent trackers send it: a JSON string of one
+/// character per byte, U+0000 to U+00FF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Binary(pub [u8; 20]);
+
+impl Serialize for Binary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let s: String = self.0.iter().map(|&byte| char::from(byte)).collect();
+        serializer.serialize_str(&s)
+    }
+}
+
+impl<'de> Deserialize<'de> for Binary {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BinaryVisitor;
+
+        impl<'de> Visitor<'de> for BinaryVisitor {
+            type Value = Binary;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a string of 20 characters up to U+00FF")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                let bytes = v
+                    .chars()
+                    .map(|c| u8::try_from(c).map_err(|_| E::custom("character above U+00FF")))
+                    .collect::<Result<Vec<u8>, E>>()?;
+                let bytes = bytes
+                    .try_into()
+                    .map_err(|bytes: Vec<u8>| E::invalid_length(bytes.len(), &self))?;
+                Ok(Binary(bytes))
+            }
+        }
+
+        deserializer.deserialize_str(BinaryVisitor)
+    }
+}
+
+/// A WebRTC session description, as relayed between peers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Description {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub sdp: String,
+}
+
+impl Description {
+    pub fn offer(sdp: &str) -> Self {
+        Self {
+            kind: "offer".to_string(),
+            sdp: sdp.to_string(),
+        }
+    }
+
+    pub fn answer(sdp: &str) -> Self {
+        Self {
+            kind: "answer".to_string(),
+            sdp: sdp.to_string(),
+        }
+    }
+}
+
+/// An offer for the tracker to hand to one of the peers of the torrent.
+#[derive(Debug, Clone, Serialize)]
+pub struct Offer {
+    pub offer: Description,
+    pub offer_id: Binary,
+}
+
+/// An announce, which carries our offers along.
+#[derive(Debug, Clone, Serialize)]
+pub struct Request {
+    pub action: &'static str,
+    pub info_hash: Binary,
+    pub peer_id: Binary,
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<&'static str>,
+    pub numwant: usize,
+    pub offers: Vec<Offer>,
+}
+
+/// Our answer to the offer `offer_id` of `to_peer_id`, for the tracker to
+/// relay back.
+#[derive(Debug, Clone, Serialize)]
+pub struct Answer {
+    pub action: &'static str,
+    pub info_hash: Binary,
+    pub peer_id: Binary,
+    pub to_peer_id: Binary,
+    pub answer: Description,
+    pub offer_id: Binary,
+}
+
+/// Anything the tracker sends: the answer to an announce, or a peer's offer
+/// or answer.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Response {
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    pub warning_message: Option<String>,
+    pub interval: Option<u64>,
+    pub info_hash: Option<Binary>,
+    pub peer_id: Option<Binary>,
+    pub offer: Option<Description>,
+    pub answer: Option<Description>,
+    pub offer_id: Option<Binary>,
+}
+
+/// A connection to a WebSocket tracker.
+#[cfg(feature = "webtorrent")]
+pub struct Connection {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+#[cfg(feature = "webtorrent")]
+impl Connection {
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let (socket, _) = tokio_tungstenite::connect_async(url)
+            .await
+            .with_context(|| format!("connect to {url}"))?;
+        Ok(Self { socket })
+    }
+
+    pub async fn send(&mut self, message: &impl Serialize) -> anyhow::Result<()> {
+        let text = serde_json::to_string(message)?;
+        self.socket.send(Message::Text(text)).await?;
+        Ok(())
+    }
+
+    /// The next message from the tracker, skipping pings and anything that
+    /// isn't JSON.
+    pub async fn recv(&mut self) -> anyhow::Result<Response> {
+        while let Some(message) = self.socket.next().await {
+            if let Message::Text(text) = message? {
+                match serde_json::from_str(&text) {
+                    Ok(response) => return Ok(response),
+                    Err(e) => debug!(error = %e, "Skipping tracker message"),
+                }
+            }
+        }
+
+        Err(anyhow!("tracker closed the connection"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Binary, Response};
+
+    #[test]
+    fn test_binary_round_trip() {
+        let id = Binary([
+            0, 0x7f, 0x80, 0xff, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+        ]);
+        let json = serde_json::to_string(&id).unwrap();
+
+        assert!(json.starts_with(r#""\u0000"#), "{json}");
+        assert_eq!(serde_json::from_str::<Binary>(&json).unwrap(), id);
+        assert!(serde_json::from_str::<Binary>(r#""short""#).is_err());
+        assert!(serde_json::from_str::<Binary>(r#""ĀĀĀĀĀĀĀĀĀĀĀĀĀĀĀĀĀĀĀĀ""#).is_err());
+    }
+
+    #[test]
+    fn test_parse_relayed_offer() {
+        let json = r#"{
+            "action": "announce",
+            "offer": {"type": "offer", "sdp": "v=0\r\n"},
+            "offer_id": "aaaaaaaaaaaaaaaaaaaa",
+            "peer_id": "-WW0105-bbbbbbbbbbbb",
+            "info_hash": "cccccccccccccccccccc"
+        }"#;
+        let response: Response = serde_json::from_str(json).unwrap();
+
+        assert_eq!(response.offer.unwrap().sdp, "v=0\r\n");
+        assert_eq!(response.offer_id, Some(Binary([b'a'; 20])));
+        assert_eq!(response.peer_id.unwrap().0[..8], *b"-WW0105-");
+        assert!(response.answer.is_none());
+    }
+}
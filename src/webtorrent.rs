@@ -0,0 +1,225 @@
+use tokio::sync::{mpsc, watch};
+use tracing::warn;
+
+use crate::{peer::Peer, session::Progress};
+
+#[cfg(feature = "webtorrent")]
+use {
+    crate::{
+        download::MIN_INTERVAL,
+        peer::{self, Transport},
+        rtc,
+        tracker::{
+            self,
+            ws::{self, Binary, Description},
+        },
+    },
+    anyhow::anyhow,
+    std::{collections::HashMap, future::Future, net::SocketAddr, time::Duration},
+    tokio::time::Instant,
+    tracing::{debug, info, Instrument},
+};
+
+/// Offers sent with each announce, each of which the tracker relays to a
+/// different peer.
+#[cfg(feature = "webtorrent")]
+const OFFERS: usize = 10;
+
+/// How often to announce until the tracker says otherwise; WebTorrent
+/// trackers ask for every two minutes.
+#[cfg(feature = "webtorrent")]
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(120);
+
+/// How long to wait before connecting again to a tracker that failed.
+#[cfg(feature = "webtorrent")]
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// How long a WebRTC connection, including the handshake, may take.
+#[cfg(feature = "webtorrent")]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Joins the WebTorrent swarm of `info_hash` through the WebSocket trackers
+/// at `urls`, and returns the WebRTC peers that complete a handshake, or
+/// nothing if there are no such trackers to use.
+///
+/// The trackers are announced to until the receiver is dropped, with the
+/// transfer totals of `progress`.
+pub(crate) fn join(
+    urls: &[String],
+    info_hash: [u8; 20],
+    progress: watch::Receiver<Progress>,
+) -> Option<mpsc::Receiver<Peer>> {
+    if urls.is_empty() {
+        return None;
+    }
+
+    #[cfg(feature = "webtorrent")]
+    {
+        let (tx, rx) = mpsc::channel(OFFERS);
+        for url in urls {
+            let announce = announce(url.clone(), info_hash, progress.clone(), tx.clone());
+            tokio::spawn(announce.in_current_span());
+        }
+        Some(rx)
+    }
+    #[cfg(not(feature = "webtorrent"))]
+    {
+        let _ = (info_hash, progress);
+        warn!(
+            trackers = urls.len(),
+            "Skipping WebSocket trackers, which need a build with the webtorrent feature"
+        );
+        None
+    }
+}
+
+/// Announces to the tracker at `url` for as long as `peers` is open,
+/// connecting again whenever the connection fails.
+#[cfg(feature = "webtorrent")]
+async fn announce(
+    url: String,
+    info_hash: [u8; 20],
+    progress: watch::Receiver<Progress>,
+    peers: mpsc::Sender<Peer>,
+) {
+    let mut event = Some(tracker::Event::Started);
+    loop {
+        match announce_until_closed(&url, info_hash, &progress, &peers, &mut event).await {
+            Ok(()) => return,
+            Err(e) => warn!(url, error = %e, "WebSocket tracker failed"),
+        }
+
+        tokio::select! {
+            () = tokio::time::sleep(RECONNECT_DELAY) => {}
+            () = peers.closed() => return,
+        }
+    }
+}
+
+/// Connects to the tracker at `url`, announces with fresh offers every
+/// interval, and answers the offers it relays. Returns once `peers` is
+/// closed, after announcing that we stopped.
+#[cfg(feature = "webtorrent")]
+async fn announce_until_closed(
+    url: &str,
+    info_hash: [u8; 20],
+    progress: &watch::Receiver<Progress>,
+    peers: &mpsc::Sender<Peer>,
+    event: &mut Option<tracker::Event>,
+) -> anyhow::Result<()> {
+    let mut connection = ws::Connection::connect(url).await?;
+    info!(url, "Connected to WebSocket tracker");
+
+    // Our offers not answered yet, dropped at the next announce.
+    let mut offers: HashMap<Binary, rtc::Offer> = HashMap::new();
+    let mut interval = DEFAULT_INTERVAL;
+    let mut next_announce = Instant::now();
+    loop {
+        tokio::select! {
+            () = tokio::time::sleep_until(next_announce) => {
+                offers.clear();
+                let mut request = request(info_hash, progress, event.take());
+                for _ in 0..OFFERS {
+                    let offer = rtc::Offer::new().await?;
+                    let offer_id = Binary(rand::random());
+                    request.offers.push(ws::Offer {
+                        offer: Description::offer(offer.sdp()),
+                        offer_id,
+                    });
+                    offers.insert(offer_id, offer);
+                }
+                connection.send(&request).await?;
+                next_announce = Instant::now() + interval;
+            }
+            response = connection.recv() => {
+                let response = response?;
+                if let Some(reason) = response.failure_reason {
+                    return Err(anyhow!("tracker failed: {reason}"));
+                }
+                if let Some(message) = response.warning_message {
+                    warn!(url, message, "WebSocket tracker warning");
+                }
+                if let Some(secs) = response.interval {
+                    interval = Duration::from_secs(secs).max(MIN_INTERVAL);
+                }
+
+                match (response.offer, response.answer, response.peer_id, response.offer_id) {
+                    (Some(offer), _, Some(peer_id), Some(offer_id)) => {
+                        let answer = match rtc::Answer::new(&offer.sdp).await {
+                            Ok(answer) => answer,
+                            Err(e) => {
+                                debug!(error = %e, "Could not answer WebRTC offer");
+                                continue;
+                            }
+                        };
+                        connection
+                            .send(&ws::Answer {
+                                action: "announce",
+                                info_hash: Binary(info_hash),
+                                peer_id: Binary(*peer::local_id()),
+                                to_peer_id: peer_id,
+                                answer: Description::answer(answer.sdp()),
+                                offer_id,
+                            })
+                            .await?;
+                        tokio::spawn(handshake(answer.open(), info_hash, peers.clone()).in_current_span());
+                    }
+                    (None, Some(answer), _, Some(offer_id)) => {
+                        if let Some(offer) = offers.remove(&offer_id) {
+                            let accept = async move { offer.accept(&answer.sdp).await };
+                            tokio::spawn(handshake(accept, info_hash, peers.clone()).in_current_span());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            () = peers.closed() => {
+                let request = request(info_hash, progress, Some(tracker::Event::Stopped));
+                let _ = connection.send(&request).await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// An announce of our transfer totals so far, without offers.
+#[cfg(feature = "webtorrent")]
+fn request(
+    info_hash: [u8; 20],
+    progress: &watch::Receiver<Progress>,
+    event: Option<tracker::Event>,
+) -> ws::Request {
+    let progress = *progress.borrow();
+    ws::Request {
+        action: "announce",
+        info_hash: Binary(info_hash),
+        peer_id: Binary(*peer::local_id()),
+        uploaded: progress.uploaded,
+        downloaded: progress.downloaded,
+        left: progress.total_bytes - progress.bytes,
+        event: event.and_then(tracker::Event::as_str),
+        numwant: OFFERS,
+        offers: Vec::new(),
+    }
+}
+
+/// Handshakes with the peer behind `channel` once it opens, and hands the
+/// peer on to `peers`.
+#[cfg(feature = "webtorrent")]
+async fn handshake(
+    channel: impl Future<Output = anyhow::Result<(SocketAddr, rtc::DataChannel)>>,
+    info_hash: [u8; 20],
+    peers: mpsc::Sender<Peer>,
+) {
+    let connect = async {
+        let (addr, channel) = channel.await?;
+        Peer::over(addr, Transport::WebRtc(channel), &info_hash).await
+    };
+    match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+        Ok(Ok(peer)) => {
+            let _ = peers.send(peer).await;
+        }
+        Ok(Err(e)) => debug!(error = %e, "Could not connect over WebRTC"),
+        Err(_) => debug!("WebRTC connection timed out"),
+    }
+}